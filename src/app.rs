@@ -1,14 +1,41 @@
+mod backup;
+mod bundle;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cli;
 mod error;
+mod explorer;
+mod gc;
+mod history;
+mod launcher;
+mod network;
 mod platform;
+mod profile;
 mod rdx_runner;
+mod recovery;
+mod share;
+mod topics;
 
 use std::collections::BTreeSet;
 
+use backup::ProfileBackup;
+use bundle::StateBundles;
 use egui::ScrollArea;
+use egui_material_icons::icons;
+use explorer::ExplorerWindow;
+use gc::GarbageCollector;
+use history::HistoryWindow;
+pub use launcher::Launcher;
+use network::NetworkSettings;
+use platform::offline::offline_queue;
+use platform::supervisor::{ConnectionState, Supervisor};
 pub(crate) use platform::Platform;
 use platform::Settings;
+pub use profile::{Profile, ProfileError};
 use rdx::layer::Inner as _;
-use rdx_runner::RdxRunner;
+use rdx_runner::{LoadStatus, RdxRunner, State};
+use recovery::RecoveryWindow;
+use share::ShareWindow;
+use topics::TopicsPanel;
 use web_time::Instant;
 
 const APP_KEY: &str = concat!("eframe-app-", env!("CARGO_PKG_NAME"));
@@ -23,6 +50,14 @@ pub struct MultinodeApp {
 
     settings: Settings,
 
+    /// Bootstrap and listen endpoints
+    #[serde(default)]
+    network: NetworkSettings,
+
+    /// Pub/sub topics, keeping the subscriptions
+    #[serde(default)]
+    topics: TopicsPanel,
+
     /// Last time we saved the app state
     #[serde(skip, default = "default_last_save")]
     last_save: Instant,
@@ -33,6 +68,34 @@ pub struct MultinodeApp {
 
     /// Open plugins
     open: BTreeSet<String>,
+
+    /// Plugin state history window
+    #[serde(skip)]
+    history: HistoryWindow,
+
+    /// Quarantined plugin state recovery window
+    #[serde(skip)]
+    recovery: RecoveryWindow,
+
+    /// Plugin state export and import
+    #[serde(skip)]
+    bundles: StateBundles,
+
+    /// Plugin state sharing with peers
+    #[serde(skip)]
+    share: ShareWindow,
+
+    /// Blockstore garbage collection
+    #[serde(default)]
+    gc: GarbageCollector,
+
+    /// Blockstore explorer window
+    #[serde(skip)]
+    explorer: ExplorerWindow,
+
+    /// Profile backup and restore
+    #[serde(skip)]
+    backup: ProfileBackup,
 }
 
 impl Default for MultinodeApp {
@@ -40,9 +103,18 @@ impl Default for MultinodeApp {
         Self {
             platform: Platform::default(),
             settings: Settings::default(),
+            network: NetworkSettings::default(),
+            topics: TopicsPanel::default(),
             last_save: default_last_save(),
             needs_save: true,
             open: BTreeSet::new(),
+            history: HistoryWindow::default(),
+            recovery: RecoveryWindow::default(),
+            bundles: StateBundles::default(),
+            share: ShareWindow::default(),
+            gc: GarbageCollector::default(),
+            explorer: ExplorerWindow::default(),
+            backup: ProfileBackup::default(),
         }
     }
 }
@@ -56,13 +128,19 @@ impl MultinodeApp {
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         // This is also where you can customize the look and feel of egui using
         // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
+        Self::load(cc.storage)
+    }
 
-        eprintln!("app_key: {}", APP_KEY);
+    /// Load the active profile's app state (if any) from the eframe storage.
+    pub(crate) fn load(storage: Option<&dyn eframe::Storage>) -> Self {
+        let app_key = Profile::active().app_key(APP_KEY);
+
+        eprintln!("app_key: {}", app_key);
 
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
-        if let Some(storage) = cc.storage {
-            if let Some(app) = eframe::get_value(storage, APP_KEY) {
+        if let Some(storage) = storage {
+            if let Some(app) = eframe::get_value(storage, &app_key) {
                 tracing::info!("💾 Loaded app state from disk");
                 return app;
             }
@@ -71,22 +149,39 @@ impl MultinodeApp {
         tracing::info!("🆕 ⛔ No app state found on disk");
         Default::default()
     }
+
+    /// Connect the node with the network settings, subscribing to the saved topics
+    fn connect(&mut self, ctx: &egui::Context) {
+        self.platform.connect(&self.network);
+        self.topics.resubscribe(ctx, &self.platform.rdx_runner);
+    }
+
+    /// Clone of the [State] of the plugin with the given name, if loaded
+    fn plugin_state(&self, name: &str) -> Option<State> {
+        self.platform.rdx_runner.state(name)
+    }
 }
 
 impl eframe::App for MultinodeApp {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        // a restored backup is loaded on restart, keep the state it wrote
+        if rdx_runner::backup::restart_pending() {
+            return;
+        }
         tracing::info!("💾 Saving app state");
-        eframe::set_value(storage, APP_KEY, self);
-        // also call State's Inner::save for each plugin state
+        eframe::set_value(storage, &Profile::active().app_key(APP_KEY), self);
+        // also write plugin states with changes waiting on the save debounce
+        rdx_runner::state_saves().flush_all();
+        self.last_save = Instant::now();
+    }
+
+    /// Called once on shutdown, after [Self::save]. Waits for the plugin states to be written.
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        tracing::info!("💾 Flushing plugin states");
         self.platform
             .rdx_runner
-            .plugins
-            .iter_mut()
-            .for_each(|(_name, plugin)| {
-                plugin.plugin.lock().unwrap().store().data().save();
-            });
-        self.last_save = Instant::now();
+            .flush_on_exit(rdx_runner::FLUSH_TIMEOUT);
     }
 
     fn auto_save_interval(&self) -> std::time::Duration {
@@ -94,7 +189,7 @@ impl eframe::App for MultinodeApp {
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
 
@@ -111,10 +206,18 @@ impl eframe::App for MultinodeApp {
             self.needs_save = false;
         }
 
+        // derive the keyring once the wallet is unlocked, so encrypted plugin states can load
+        self.platform.rdx_runner.unlock_keyring();
+
+        self.bundles.install_pending(ctx, &self.platform.rdx_runner);
+        self.gc.tick(ctx, &self.platform.rdx_runner);
+
         // pass the ctx to the platform
         if !self.platform.egui_ctx() {
             egui_material_icons::initialize(ctx);
             self.platform.set_egui_ctx(ctx.clone());
+            // the network settings are loaded by now
+            self.connect(ctx);
         }
 
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
@@ -132,7 +235,44 @@ impl eframe::App for MultinodeApp {
                     ui.add_space(16.0);
                 }
 
+                ui.menu_button("Network", |ui| {
+                    if ui.button("Endpoints…").clicked() {
+                        self.network.open();
+                        ui.close_menu();
+                    }
+                    if ui.button("Reconnect").clicked() {
+                        self.connect(ctx);
+                        ui.close_menu();
+                    }
+                    ui.separator();
+                    if ui.button("Topics…").clicked() {
+                        self.topics.open = true;
+                        ui.close_menu();
+                    }
+                });
+                ui.add_space(16.0);
+
+                ui.menu_button("Storage", |ui| {
+                    if ui.button("Explore blocks…").clicked() {
+                        self.explorer.open(ctx, &self.platform.rdx_runner);
+                        ui.close_menu();
+                    }
+                    ui.separator();
+                    let unlocked = self.platform.rdx_runner.keyring.is_unlocked();
+                    self.backup.menu_items(ctx, ui, unlocked);
+                    ui.separator();
+                    self.gc.menu_items(ctx, ui, &self.platform.rdx_runner);
+                });
+                ui.add_space(16.0);
+
                 egui::widgets::global_theme_preference_buttons(ui);
+
+                ui.separator();
+                ui.label(format!("👤 {}", Profile::active().name()))
+                    .on_hover_text("Active profile");
+
+                ui.separator();
+                connection_status(ui, self.platform.supervisor());
             });
         });
 
@@ -157,16 +297,76 @@ impl eframe::App for MultinodeApp {
                 ui.separator();
 
                 self.platform.load_plugin(ctx, ui);
+                self.bundles.import_button(ctx, ui);
+                self.bundles.show_status(ui);
+                self.share.inbox_button(ui, &self.platform.shares.inbox);
 
                 ui.separator();
                 ScrollArea::vertical().show(ui, |ui| {
                     ui.with_layout(egui::Layout::top_down_justified(egui::Align::LEFT), |ui| {
                         // list plugins here
                         for name in plugin_names {
-                            let open = &mut self.open;
-                            let mut is_open = open.contains(&name);
-                            ui.toggle_value(&mut is_open, name.clone());
-                            set_open(open, &name, is_open);
+                            ui.horizontal(|ui| {
+                                if ui
+                                    .small_button(icons::ICON_HISTORY)
+                                    .on_hover_text("State history")
+                                    .clicked()
+                                {
+                                    if let Some(state) = self.plugin_state(&name) {
+                                        self.history.open(ctx, state);
+                                    }
+                                }
+
+                                if let Some(state) = self.plugin_state(&name) {
+                                    encryption_toggle(ui, &state);
+                                }
+
+                                let mut is_open = self.open.contains(&name);
+                                ui.toggle_value(&mut is_open, name.clone())
+                                    .context_menu(|ui| {
+                                        if ui.button("Export state…").clicked() {
+                                            if let Some(state) = self.plugin_state(&name) {
+                                                self.bundles.export(ctx, state);
+                                            }
+                                            ui.close_menu();
+                                        }
+                                        if ui.button("Share state…").clicked() {
+                                            self.share.open(&name);
+                                            ui.close_menu();
+                                        }
+                                    });
+                                set_open(&mut self.open, &name, is_open);
+
+                                match self.platform.rdx_runner.load_status(&name) {
+                                    Some(LoadStatus::Loading) => {
+                                        ui.spinner().on_hover_text("Loading saved state");
+                                    }
+                                    Some(LoadStatus::Failed(e)) => {
+                                        if ui
+                                            .small_button(icons::ICON_ERROR)
+                                            .on_hover_text(format!(
+                                                "{}\nChanges aren't saved, click to load it again",
+                                                e
+                                            ))
+                                            .clicked()
+                                        {
+                                            self.platform.rdx_runner.retry_load(&name);
+                                        }
+                                    }
+                                    Some(LoadStatus::Corrupted(_)) => {
+                                        if ui
+                                            .small_button(icons::ICON_WARNING)
+                                            .on_hover_text("Saved state is corrupted, recover it")
+                                            .clicked()
+                                        {
+                                            if let Some(state) = self.plugin_state(&name) {
+                                                self.recovery.open(ctx, state);
+                                            }
+                                        }
+                                    }
+                                    _ => {}
+                                }
+                            });
                         }
                     });
                 });
@@ -190,6 +390,37 @@ impl eframe::App for MultinodeApp {
                 self.platform.chat(ctx, ui);
             });
 
+        let history_state = self
+            .history
+            .plugin()
+            .and_then(|name| self.plugin_state(name));
+        self.history.show(ctx, history_state);
+
+        self.recovery.show(ctx, &self.platform.rdx_runner);
+
+        self.share
+            .show(ctx, &self.platform.rdx_runner, &self.platform.shares);
+
+        self.explorer.show(ctx, &self.platform.rdx_runner);
+
+        if self.network.show(ctx) {
+            self.connect(ctx);
+        }
+        self.topics
+            .show(ctx, &self.platform.rdx_runner, &self.platform.topics);
+
+        // capture the app state as eframe persists it, to include it in the backup
+        let app_key = Profile::active().app_key(APP_KEY);
+        if self.backup.take_request() {
+            let app_state = frame.storage_mut().and_then(|storage| {
+                eframe::set_value(storage, &app_key, self);
+                storage.get_string(&app_key)
+            });
+            self.backup.run(ctx, &self.platform.rdx_runner, app_state);
+        }
+        self.backup.write_restored(frame, &app_key);
+        self.backup.show(ctx, &self.platform.rdx_runner);
+
         egui::TopBottomPanel::bottom("footer").show(ctx, |ui| {
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
                 powered_by_egui_and_eframe(ui);
@@ -209,24 +440,104 @@ impl eframe::App for MultinodeApp {
             ui.vertical(|ui| {
                 if let Some(addr) = self.platform.addr() {
                     ui.label(format!("Node Address: {:?}", addr));
-                    self.settings.show(ctx, ui, &addr);
+                    self.settings
+                        .show(ctx, ui, &addr, &self.platform.rdx_runner.vault);
                 }
                 self.platform.show(ctx, ui);
             });
 
             // Show plugins
+            let mut retry = None;
             let RdxRunner { plugins, .. } = &mut self.platform.rdx_runner;
             for (name, plugin) in plugins.iter_mut() {
                 // if is open
                 if !self.open.contains(name) {
                     continue;
                 }
+                let state = plugin.plugin.lock().unwrap().store().data().clone();
+                match state.load_status() {
+                    // the scope is still empty, so don't render the plugin with it yet
+                    LoadStatus::Loading => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label(format!("Loading {}…", name));
+                        });
+                        continue;
+                    }
+                    LoadStatus::Failed(e) => {
+                        ui.horizontal(|ui| {
+                            ui.colored_label(
+                                ui.visuals().error_fg_color,
+                                format!(
+                                    "{} {}: failed to load the saved data, changes aren't saved: {}",
+                                    icons::ICON_ERROR,
+                                    name,
+                                    e
+                                ),
+                            );
+                            if ui.button("Retry").clicked() {
+                                retry = Some(name.clone());
+                            }
+                        });
+                    }
+                    LoadStatus::Corrupted(_) => {
+                        ui.horizontal(|ui| {
+                            ui.colored_label(
+                                ui.visuals().warn_fg_color,
+                                format!(
+                                    "{} {}: the saved data is corrupted, changes aren't saved",
+                                    icons::ICON_WARNING,
+                                    name
+                                ),
+                            );
+                            if ui.button("Recover…").clicked() {
+                                self.recovery.open(ctx, state.clone());
+                            }
+                        });
+                    }
+                    LoadStatus::Ready => {}
+                }
+                if state.is_locked() {
+                    ui.label(format!(
+                        "{} {}: unlock your wallet to load the saved data",
+                        icons::ICON_LOCK,
+                        name
+                    ));
+                }
+                if state.is_held_back() {
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        "Changes are saved once the wallet is unlocked, \
+                         they're lost if you quit before",
+                    );
+                }
                 plugin.render_rhai(ctx.clone());
             }
+            if let Some(name) = retry {
+                self.platform.rdx_runner.retry_load(&name);
+            }
         });
     }
 }
 
+/// Colored dot and name of the connection state, with the details on hover
+fn connection_status(ui: &mut egui::Ui, supervisor: &Supervisor) {
+    let state = supervisor.state();
+    let color = match state {
+        ConnectionState::Connected => egui::Color32::from_rgb(0x4c, 0xaf, 0x50),
+        ConnectionState::Connecting => ui.visuals().weak_text_color(),
+        ConnectionState::Degraded => ui.visuals().warn_fg_color,
+        ConnectionState::Offline => ui.visuals().error_fg_color,
+    };
+    let mut details = supervisor.details();
+    let queued = offline_queue().len();
+    if queued > 0 {
+        details.push_str(&format!("\n{} network commands waiting to connect", queued));
+    }
+    ui.colored_label(color, format!("● {}", state))
+        .on_hover_text(details);
+}
+
 fn set_open(open: &mut BTreeSet<String>, key: &String, is_open: bool) {
     if is_open {
         if !open.contains(key) {
@@ -237,6 +548,24 @@ fn set_open(open: &mut BTreeSet<String>, key: &String, is_open: bool) {
     }
 }
 
+/// Toggles whether the plugin state is encrypted at rest with the wallet key
+fn encryption_toggle(ui: &mut egui::Ui, state: &State) {
+    let public = state.is_public();
+    let (icon, hover) = match public {
+        true => (
+            icons::ICON_LOCK_OPEN,
+            "Saved in plaintext. Click to encrypt.",
+        ),
+        false => (
+            icons::ICON_LOCK,
+            "Encrypted with the wallet. Click to save in plaintext.",
+        ),
+    };
+    if ui.small_button(icon).on_hover_text(hover).clicked() {
+        state.set_public(!public);
+    }
+}
+
 fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
     ui.horizontal(|ui| {
         ui.spacing_mut().item_spacing.x = 0.0;
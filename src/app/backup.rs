@@ -0,0 +1,338 @@
+//! Back up the whole profile to one file, and restore it, from the Storage menu.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::app::gc::format_size;
+use crate::app::history::format_timestamp;
+use crate::app::platform;
+use crate::app::rdx_runner::backup::{self, BackupArchive};
+use crate::app::rdx_runner::RdxRunner;
+
+/// Profile backup and restore
+#[derive(Default)]
+pub(crate) struct ProfileBackup {
+    /// Encrypt backups with the wallet
+    encrypt: bool,
+    /// Set from the menu, the app state is captured on the next frame
+    requested: bool,
+    /// Whether a backup or restore is in progress
+    running: Arc<AtomicBool>,
+    /// Message about the last backup or restore
+    status: Arc<Mutex<Option<String>>>,
+    /// Backup picked for restore, waiting for the user to confirm
+    picked: Arc<Mutex<Option<BackupArchive>>>,
+    /// App state from a restored backup, to be written to the eframe storage
+    restored: Arc<Mutex<Option<String>>>,
+}
+
+impl ProfileBackup {
+    /// Backup and restore items for the Storage menu
+    pub(crate) fn menu_items(&mut self, ctx: &egui::Context, ui: &mut egui::Ui, unlocked: bool) {
+        let idle = !self.running.load(Ordering::SeqCst) && !backup::restart_pending();
+
+        if ui
+            .add_enabled(idle, egui::Button::new("Back up profile…"))
+            .on_hover_text("Save every block, plugin state and app setting to one file")
+            .clicked()
+        {
+            self.requested = true;
+            ui.close_menu();
+        }
+        ui.add_enabled(
+            unlocked,
+            egui::Checkbox::new(&mut self.encrypt, "Encrypt with wallet"),
+        )
+        .on_disabled_hover_text("Unlock the wallet to encrypt backups");
+        if !unlocked {
+            self.encrypt = false;
+        }
+
+        if ui
+            .add_enabled(idle, egui::Button::new("Restore backup…"))
+            .clicked()
+        {
+            self.pick(ctx);
+            ui.close_menu();
+        }
+    }
+
+    /// Whether a backup was requested, so the app state should be captured for [Self::run]
+    pub(crate) fn take_request(&mut self) -> bool {
+        std::mem::take(&mut self.requested)
+    }
+
+    /// Spawn the backup, then ask the user where to save it
+    pub(crate) fn run(
+        &mut self,
+        ctx: &egui::Context,
+        rdx_runner: &RdxRunner,
+        app_state: Option<String>,
+    ) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let task = rdx_runner.backup(app_state, self.encrypt);
+        let running = self.running.clone();
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match save_backup(task).await {
+                Ok(Some(message)) => message,
+                Ok(None) => "Backup cancelled".to_string(),
+                Err(e) => {
+                    tracing::error!("Backup failed: {:?}", e);
+                    format!("Backup failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            running.store(false, Ordering::SeqCst);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Ask the user for a backup file and read it, to confirm before restoring
+    fn pick(&mut self, ctx: &egui::Context) {
+        let picked = self.picked.clone();
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let Some(file) = rfd::AsyncFileDialog::new()
+                .add_filter("Profile backup", &["json"])
+                .pick_file()
+                .await
+            else {
+                return;
+            };
+
+            match BackupArchive::from_bytes(&file.read().await) {
+                Ok(archive) => {
+                    picked.lock().unwrap().replace(archive);
+                }
+                Err(e) => {
+                    tracing::error!("Failed to read backup: {:?}", e);
+                    status
+                        .lock()
+                        .unwrap()
+                        .replace(format!("Failed to read backup: {}", e));
+                }
+            }
+            ctx.request_repaint();
+        });
+    }
+
+    /// Spawn the restore of only the wallet carried by an encrypted backup
+    fn restore_wallet(
+        &mut self,
+        ctx: &egui::Context,
+        rdx_runner: &RdxRunner,
+        archive: BackupArchive,
+    ) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let task = rdx_runner.restore_wallet(archive);
+        let running = self.running.clone();
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match task.await {
+                Ok(()) => "Wallet restored. Restart the app, unlock the wallet, then restore \
+                           this backup again for everything else"
+                    .to_string(),
+                Err(e) => {
+                    tracing::error!("Restoring the wallet failed: {:?}", e);
+                    format!("Restore failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            running.store(false, Ordering::SeqCst);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Spawn the restore of a confirmed backup
+    fn restore(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner, archive: BackupArchive) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let task = rdx_runner.restore(archive);
+        let running = self.running.clone();
+        let status = self.status.clone();
+        let restored = self.restored.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match task.await {
+                Ok(app_state) => {
+                    *restored.lock().unwrap() = app_state;
+                    "Backup restored, restart the app to load it".to_string()
+                }
+                Err(e) => {
+                    tracing::error!("Restore failed: {:?}", e);
+                    format!("Restore failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            running.store(false, Ordering::SeqCst);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Write the restored app state, if any, to the eframe storage under the given key
+    pub(crate) fn write_restored(&self, frame: &mut eframe::Frame, app_key: &str) {
+        let Some(app_state) = self.restored.lock().unwrap().take() else {
+            return;
+        };
+        match frame.storage_mut() {
+            Some(storage) => {
+                storage.set_string(app_key, app_state);
+                storage.flush();
+            }
+            None => tracing::warn!("No app storage, the backed up app state was not restored"),
+        }
+    }
+
+    /// Show the restore confirmation and the outcome of the last backup or restore
+    pub(crate) fn show(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        let picked = self.picked.lock().unwrap().clone();
+        if let Some(archive) = picked {
+            let mut confirmed = None;
+            // the sealed part needs the wallet, which a fresh machine restores first
+            let wallet_only = archive.is_encrypted()
+                && !rdx_runner.keyring.is_unlocked()
+                && archive.wallet.is_some();
+            egui::Window::new("Restore backup")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    let manifest = &archive.manifest;
+                    ui.label(format!(
+                        "Profile \"{}\", backed up {}",
+                        manifest.profile,
+                        format_timestamp(manifest.created)
+                    ));
+                    ui.label(format!(
+                        "{} blocks, {} stored values{}",
+                        manifest.blocks,
+                        manifest.keys,
+                        if manifest.app_state {
+                            " and app settings"
+                        } else {
+                            ""
+                        }
+                    ));
+                    if !manifest.missing.is_empty() {
+                        ui.label(format!(
+                            "{} linked blocks were missing when it was made.",
+                            manifest.missing.len()
+                        ))
+                        .on_hover_text(manifest.missing.join("\n"));
+                    }
+                    if wallet_only {
+                        ui.label(
+                            "Encrypted and the wallet is locked. Restore the wallet first, \
+                             then restart, unlock it and restore this backup again.",
+                        );
+                    } else if archive.is_encrypted() {
+                        ui.label("Encrypted, the wallet that made it must be unlocked.");
+                    }
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        "Plugin states and settings in this profile will be replaced.",
+                    );
+
+                    ui.horizontal(|ui| {
+                        let label = match wallet_only {
+                            true => "Restore wallet",
+                            false => "Restore",
+                        };
+                        if ui.button(label).clicked() {
+                            confirmed = Some(true);
+                        }
+                        if ui.button("Cancel").clicked() {
+                            confirmed = Some(false);
+                        }
+                    });
+                });
+
+            if let Some(confirmed) = confirmed {
+                self.picked.lock().unwrap().take();
+                match (confirmed, wallet_only) {
+                    (true, true) => self.restore_wallet(ctx, rdx_runner, archive),
+                    (true, false) => self.restore(ctx, rdx_runner, archive),
+                    _ => {}
+                }
+            }
+        }
+
+        let running = self.running.load(Ordering::SeqCst);
+        let mut status = self.status.lock().unwrap();
+        if !running && status.is_none() {
+            return;
+        }
+
+        let mut dismissed = false;
+        egui::Window::new("Profile backup")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::RIGHT_BOTTOM, [-8.0, -32.0])
+            .show(ctx, |ui| {
+                if running {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Working…");
+                    });
+                } else if let Some(message) = status.as_ref() {
+                    ui.label(message);
+                    // stays up until restart once restored
+                    if !backup::restart_pending() && ui.button("Dismiss").clicked() {
+                        dismissed = true;
+                    }
+                }
+            });
+        if dismissed {
+            status.take();
+        }
+    }
+}
+
+/// Wait for the backup, then write it to a file picked by the user.
+///
+/// Returns a message about the backup, or None if the user cancelled.
+async fn save_backup(
+    task: impl std::future::Future<Output = Result<BackupArchive, backup::BackupError>>,
+) -> anyhow::Result<Option<String>> {
+    let archive = task.await?;
+    let bytes = archive.to_bytes()?;
+
+    let Some(file) = rfd::AsyncFileDialog::new()
+        .add_filter("Profile backup", &["json"])
+        .set_file_name(archive.file_name())
+        .save_file()
+        .await
+    else {
+        return Ok(None);
+    };
+
+    file.write(&bytes).await?;
+
+    let missing = match archive.manifest.missing.len() {
+        0 => String::new(),
+        n => format!(", {} linked blocks were missing", n),
+    };
+    Ok(Some(format!(
+        "Backed up {} blocks and {} stored values, {}, to {}{}{}",
+        archive.manifest.blocks,
+        archive.manifest.keys,
+        format_size(bytes.len() as u64),
+        file.file_name(),
+        if archive.is_encrypted() {
+            ", encrypted"
+        } else {
+            ""
+        },
+        missing
+    )))
+}
@@ -0,0 +1,144 @@
+//! Export and import plugin state bundles from the plugin side panel.
+//!
+//! The file dialogs and blockstore work run in spawned tasks. An imported bundle waits in
+//! `pending` until the next frame, when the plugin it belongs to can be looked up.
+use std::sync::{Arc, Mutex};
+
+use crate::app::platform;
+use crate::app::rdx_runner::bundle::{self, StateBundle};
+use crate::app::rdx_runner::{RdxRunner, State};
+
+/// Export and import actions, with the outcome of the last one
+#[derive(Default)]
+pub(crate) struct StateBundles {
+    /// An imported bundle waiting to be installed
+    pending: Arc<Mutex<Option<StateBundle>>>,
+    /// Message about the last export or import
+    status: Arc<Mutex<Option<String>>>,
+}
+
+impl StateBundles {
+    /// Spawn a task to bundle the plugin's state and save it to a file picked by the user
+    pub(crate) fn export(&self, ctx: &egui::Context, state: State) {
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match save_bundle(&state).await {
+                Ok(Some(file_name)) => format!("Exported {} to {}", state.name(), file_name),
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::error!("Error exporting state: {:?}", e);
+                    format!("Export failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Button to pick a bundle file, which is installed once it has been read
+    pub(crate) fn import_button(&self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        if !ui.button("Import state…").clicked() {
+            return;
+        }
+
+        let task = rfd::AsyncFileDialog::new()
+            .add_filter("State bundle", &["json"])
+            .pick_file();
+        let pending = self.pending.clone();
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let Some(file) = task.await else {
+                return;
+            };
+
+            match StateBundle::from_bytes(&file.read().await) {
+                Ok(bundle) => {
+                    pending.lock().unwrap().replace(bundle);
+                }
+                Err(e) => {
+                    tracing::error!("Error reading state bundle: {:?}", e);
+                    status
+                        .lock()
+                        .unwrap()
+                        .replace(format!("Import failed: {}", e));
+                }
+            }
+            ctx.request_repaint();
+        });
+    }
+
+    /// Install a pending imported bundle as the state of its plugin, if that plugin is loaded.
+    pub(crate) fn install_pending(&self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        let Some(bundle) = self.pending.lock().unwrap().take() else {
+            return;
+        };
+
+        let Some(deets) = rdx_runner.plugins.get(&bundle.plugin) else {
+            self.status.lock().unwrap().replace(format!(
+                "Import failed: load {} before importing its state",
+                bundle.plugin
+            ));
+            return;
+        };
+
+        let state = deets.plugin.lock().unwrap().store().data().clone();
+        let arc_plugin = deets.plugin.clone();
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match bundle::install(&state, &bundle).await {
+                Ok(head) => {
+                    // re-initialize so the plugin picks up the imported scope
+                    if let Err(e) = arc_plugin.lock().unwrap().call("init", &[]) {
+                        tracing::warn!("Failed to call init on plugin: {:?}", e);
+                    }
+                    format!("Imported {} at {}", bundle.plugin, head)
+                }
+                Err(e) => {
+                    tracing::error!("Error importing state: {:?}", e);
+                    format!("Import failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Show the outcome of the last export or import, until dismissed
+    pub(crate) fn show_status(&self, ui: &mut egui::Ui) {
+        let mut status = self.status.lock().unwrap();
+        let Some(message) = status.as_ref() else {
+            return;
+        };
+
+        let mut dismiss = false;
+        ui.horizontal_wrapped(|ui| {
+            ui.small(message);
+            dismiss = ui.small_button("✖").clicked();
+        });
+
+        if dismiss {
+            status.take();
+        }
+    }
+}
+
+/// Bundle the state and write it to a file picked by the user.
+///
+/// Returns the file name, or None if the user cancelled.
+async fn save_bundle(state: &State) -> anyhow::Result<Option<String>> {
+    let bundle = bundle::export(state).await?;
+
+    let Some(file) = rfd::AsyncFileDialog::new()
+        .set_file_name(bundle.file_name())
+        .save_file()
+        .await
+    else {
+        return Ok(None);
+    };
+
+    file.write(&bundle.to_bytes()?).await?;
+    Ok(Some(file.file_name()))
+}
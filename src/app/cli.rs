@@ -0,0 +1,78 @@
+//! Headless commands, run instead of the GUI when named on the command line.
+//!
+//! ```text
+//! multinode [--profile <name>] car export <root-cid> <file>
+//! multinode [--profile <name>] car import <file>
+//! ```
+//!
+//! Commands open the active profile's blockstore directly, so run them while the app
+//! isn't running. The keyring is never unlocked here, so links inside encrypted plugin
+//! state aren't followed on export.
+use peerpiper::core::Cid;
+
+use super::platform::blocks::Pins;
+use super::platform::platform::open_blockstore;
+use super::profile::Profile;
+use super::rdx_runner::car::{self, Car};
+use super::rdx_runner::keyring::Keyring;
+
+/// How to use the headless commands
+pub const USAGE: &str = "Usage:
+  multinode [--profile <name>] car export <root-cid> <file>
+  multinode [--profile <name>] car import <file>";
+
+/// Run the headless command named in the arguments, in the given profile or the default.
+///
+/// Returns None when no command is named, so the GUI should start.
+pub async fn run(
+    args: impl IntoIterator<Item = String>,
+    profile: Option<Profile>,
+) -> Option<anyhow::Result<()>> {
+    // the profile flag is parsed by the caller
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            args.next();
+        } else if !arg.starts_with("--profile=") {
+            positional.push(arg);
+        }
+    }
+
+    let command = positional.iter().map(String::as_str).collect::<Vec<_>>();
+    if command.first() != Some(&"car") {
+        return None;
+    }
+
+    if let Err(e) = profile.unwrap_or_default().activate() {
+        return Some(Err(e.into()));
+    }
+
+    match command.as_slice() {
+        ["car", "export", root, file] => Some(export(root, file).await),
+        ["car", "import", file] => Some(import(file).await),
+        _ => Some(Err(anyhow::anyhow!("{}", USAGE))),
+    }
+}
+
+async fn export(root: &str, file: &str) -> anyhow::Result<()> {
+    let root = Cid::try_from(root)?;
+    let blockstore = open_blockstore();
+    let car = car::export(&blockstore, &root, &Keyring::default()).await?;
+    std::fs::write(file, car.to_bytes())?;
+
+    println!("Exported {} blocks to {}", car.blocks.len(), file);
+    if car.locked {
+        println!("Links inside encrypted plugin state were not followed");
+    }
+    Ok(())
+}
+
+async fn import(file: &str) -> anyhow::Result<()> {
+    let car = Car::from_bytes(&std::fs::read(file)?)?;
+    let blockstore = open_blockstore();
+    car::import(&blockstore, &Pins::default(), &car).await?;
+
+    println!("Imported {} blocks from {}", car.blocks.len(), file);
+    Ok(())
+}
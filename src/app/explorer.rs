@@ -0,0 +1,528 @@
+//! Blockstore explorer window.
+//!
+//! Lists every tracked block with its codec, size and what references it, decodes a
+//! selected block for inspection, and deletes blocks which are neither pinned nor a
+//! plugin's current state.
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use blockstore::Blockstore as _;
+use cid::Cid;
+
+use crate::app::gc::format_size;
+use crate::app::history::{format_timestamp, short_cid};
+use crate::app::platform;
+use crate::app::platform::blocks::Pins;
+use crate::app::platform::platform::Blockstore;
+use crate::app::rdx_runner::car::{self, Car, CarError, CAR_EXTENSION};
+use crate::app::rdx_runner::gc::Reference;
+use crate::app::rdx_runner::inspect::{codec_name, Decoded};
+use crate::app::rdx_runner::RdxRunner;
+
+/// Shown when PeerPiper, and so the blockstore, isn't ready yet
+const NOT_STARTED: &str = "The node isn't started yet";
+
+/// Bytes of a binary block shown as hex
+const HEX_PREVIEW: usize = 256;
+
+/// A block in the listing
+#[derive(Debug, Clone)]
+struct Row {
+    cid: String,
+    codec: String,
+    size: u64,
+    added: i64,
+    refs: Vec<Reference>,
+}
+
+impl Row {
+    fn is_pinned(&self) -> bool {
+        self.refs.iter().any(|r| matches!(r, Reference::Pinned(_)))
+    }
+
+    fn is_state(&self) -> bool {
+        self.refs.iter().any(|r| matches!(r, Reference::State(_)))
+    }
+}
+
+/// The async loaded listing
+#[derive(Debug, Clone, Default)]
+struct Listing {
+    rows: Vec<Row>,
+    /// Links from encrypted states are missing while the keyring is locked
+    locked: bool,
+    is_loading: bool,
+    error: Option<String>,
+}
+
+/// The block selected for inspection
+#[derive(Debug, Clone)]
+struct Inspected {
+    cid: String,
+    block: Result<Decoded, String>,
+}
+
+/// Window listing the blocks in the local blockstore
+#[derive(Default)]
+pub(crate) struct ExplorerWindow {
+    is_open: bool,
+    /// Only show blocks whose CID, codec or references contain this
+    filter: String,
+    listing: Arc<Mutex<Listing>>,
+    inspected: Arc<Mutex<Option<Inspected>>>,
+    /// Message about the last CAR export or import
+    status: Arc<Mutex<Option<String>>>,
+    /// Set when blocks were added, so the listing is reloaded on the next frame
+    stale: Arc<AtomicBool>,
+}
+
+impl ExplorerWindow {
+    /// Open the window and load the listing
+    pub(crate) fn open(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        self.is_open = true;
+        self.refresh(ctx, rdx_runner);
+    }
+
+    /// Spawn a task to reload the listing
+    fn refresh(&self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        self.listing.lock().unwrap().is_loading = true;
+
+        let blockstore = rdx_runner.blockstore();
+        let references = rdx_runner.block_references();
+        let listing = self.listing.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let result = match (blockstore.await, references.await) {
+                (Some(blockstore), Ok(mut refs)) => {
+                    let rows = blockstore
+                        .index()
+                        .entries()
+                        .into_iter()
+                        .map(|(cid, entry)| Row {
+                            codec: Cid::try_from(cid.as_str())
+                                .map(|c| codec_name(&c))
+                                .unwrap_or_default(),
+                            refs: refs
+                                .by_cid
+                                .remove(&cid)
+                                .unwrap_or_default()
+                                .into_iter()
+                                .collect(),
+                            cid,
+                            size: entry.size,
+                            added: entry.added,
+                        })
+                        .collect::<Vec<_>>();
+                    Ok((rows, refs.locked))
+                }
+                (None, _) => Err(NOT_STARTED.to_string()),
+                (_, Err(e)) => Err(e.to_string()),
+            };
+
+            let mut listing = listing.lock().unwrap();
+            match result {
+                Ok((rows, locked)) => {
+                    listing.rows = rows;
+                    listing.locked = locked;
+                    listing.error = None;
+                }
+                Err(e) => {
+                    tracing::error!("Error listing blocks: {}", e);
+                    listing.error = Some(e);
+                }
+            }
+            listing.is_loading = false;
+            ctx.request_repaint();
+        });
+    }
+
+    /// Spawn a task to load and decode the block
+    fn inspect(&self, ctx: &egui::Context, rdx_runner: &RdxRunner, cid: String) {
+        let blockstore = rdx_runner.blockstore();
+        let inspected = self.inspected.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let block = get_block(blockstore, &cid).await;
+            inspected.lock().unwrap().replace(Inspected { cid, block });
+            ctx.request_repaint();
+        });
+    }
+
+    /// Spawn a task to delete the block and drop it from the listing
+    fn delete(&self, ctx: &egui::Context, rdx_runner: &RdxRunner, cid: String) {
+        let blockstore = rdx_runner.blockstore();
+        let listing = self.listing.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let result = remove_block(blockstore, &cid).await;
+
+            let mut listing = listing.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    tracing::info!("Deleted block {}", cid);
+                    listing.rows.retain(|row| row.cid != cid);
+                }
+                Err(e) => {
+                    tracing::error!("Error deleting block {}: {}", cid, e);
+                    listing.error = Some(format!("Failed to delete {}: {}", cid, e));
+                }
+            }
+            ctx.request_repaint();
+        });
+    }
+
+    /// Spawn a task to export the block and everything it links to as a CAR file
+    fn export_car(&self, ctx: &egui::Context, rdx_runner: &RdxRunner, root: Cid) {
+        let task = rdx_runner.export_car(root);
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match save_car(task).await {
+                Ok(Some(message)) => message,
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::error!("Error exporting CAR: {:?}", e);
+                    format!("Export failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Spawn a task to pick a CAR file and import its blocks, then reload the listing
+    fn import_car(&self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        let blockstore = rdx_runner.blockstore();
+        let status = self.status.clone();
+        let stale = self.stale.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match load_car(blockstore).await {
+                Ok(Some(car)) => format!(
+                    "Imported {} blocks, {}",
+                    car.blocks.len(),
+                    format_size(car.size())
+                ),
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::error!("Error importing CAR: {:?}", e);
+                    format!("Import failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            stale.store(true, Ordering::SeqCst);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Show the window, if open
+    pub(crate) fn show(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        if !self.is_open {
+            return;
+        }
+        if self.stale.swap(false, Ordering::SeqCst) {
+            self.refresh(ctx, rdx_runner);
+        }
+
+        let listing = self.listing.lock().unwrap().clone();
+        let mut is_open = true;
+
+        egui::Window::new("Blockstore")
+            .open(&mut is_open)
+            .resizable(true)
+            .default_width(560.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui
+                        .add_enabled(!listing.is_loading, egui::Button::new("Refresh"))
+                        .clicked()
+                    {
+                        self.refresh(ctx, rdx_runner);
+                    }
+                    if listing.is_loading {
+                        ui.spinner();
+                    }
+
+                    let total = listing.rows.iter().map(|row| row.size).sum();
+                    ui.label(format!(
+                        "{} blocks, {}",
+                        listing.rows.len(),
+                        format_size(total)
+                    ));
+
+                    ui.add(egui::TextEdit::singleline(&mut self.filter).hint_text("Filter"));
+
+                    if ui.button("Import CAR…").clicked() {
+                        self.import_car(ctx, rdx_runner);
+                    }
+                });
+
+                let mut status = self.status.lock().unwrap();
+                if let Some(message) = status.as_ref() {
+                    let mut dismiss = false;
+                    ui.horizontal_wrapped(|ui| {
+                        ui.small(message);
+                        dismiss = ui.small_button("✖").clicked();
+                    });
+                    if dismiss {
+                        status.take();
+                    }
+                }
+                drop(status);
+
+                if listing.locked {
+                    ui.colored_label(
+                        egui::Color32::YELLOW,
+                        "Unlock the wallet to see links from encrypted plugin state.",
+                    );
+                }
+                if let Some(error) = &listing.error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+
+                let filter = self.filter.to_lowercase();
+                let rows = listing.rows.iter().filter(|row| {
+                    filter.is_empty()
+                        || row.cid.to_lowercase().contains(&filter)
+                        || row.codec.contains(&filter)
+                        || row
+                            .refs
+                            .iter()
+                            .any(|r| r.name().to_lowercase().contains(&filter))
+                });
+
+                egui::ScrollArea::vertical()
+                    .id_salt("blocks")
+                    .max_height(280.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("block_grid")
+                            .num_columns(5)
+                            .striped(true)
+                            .show(ui, |ui| {
+                                ui.strong("CID");
+                                ui.strong("Codec");
+                                ui.strong("Size");
+                                ui.strong("Referenced by");
+                                ui.label("");
+                                ui.end_row();
+
+                                for row in rows {
+                                    if ui
+                                        .link(egui::RichText::new(short_cid(&row.cid)).monospace())
+                                        .on_hover_text(format!(
+                                            "{}\nadded {}",
+                                            row.cid,
+                                            format_timestamp(row.added)
+                                        ))
+                                        .clicked()
+                                    {
+                                        self.inspect(ctx, rdx_runner, row.cid.clone());
+                                    }
+                                    ui.label(&row.codec);
+                                    ui.label(format_size(row.size));
+
+                                    match row.refs.as_slice() {
+                                        [] => {
+                                            ui.weak("unreferenced");
+                                        }
+                                        [only] => {
+                                            ui.label(only.to_string());
+                                        }
+                                        [first, rest @ ..] => {
+                                            let all = row
+                                                .refs
+                                                .iter()
+                                                .map(|r| r.to_string())
+                                                .collect::<Vec<_>>()
+                                                .join("\n");
+                                            ui.label(format!("{} (+{})", first, rest.len()))
+                                                .on_hover_text(all);
+                                        }
+                                    }
+
+                                    let deletable = !row.is_pinned() && !row.is_state();
+                                    let hover = match deletable {
+                                        true if row.refs.is_empty() => "Delete this block",
+                                        true => "Delete this block, breaking the links to it",
+                                        false => "Pinned and current state blocks can't be deleted",
+                                    };
+                                    if ui
+                                        .add_enabled(deletable, egui::Button::new("Delete"))
+                                        .on_hover_text(hover)
+                                        .on_disabled_hover_text(hover)
+                                        .clicked()
+                                    {
+                                        self.delete(ctx, rdx_runner, row.cid.clone());
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                    });
+
+                ui.separator();
+
+                let inspected = self.inspected.lock().unwrap().clone();
+                match inspected {
+                    Some(inspected) => {
+                        if let Ok(root) = Cid::try_from(inspected.cid.as_str()) {
+                            if ui
+                                .button("Export CAR…")
+                                .on_hover_text("Save this block and every block it links to")
+                                .clicked()
+                            {
+                                self.export_car(ctx, rdx_runner, root);
+                            }
+                        }
+                        show_block(ui, &inspected);
+                    }
+                    None => {
+                        ui.label("Select a CID to inspect the block.");
+                    }
+                }
+            });
+
+        if !is_open {
+            self.is_open = false;
+        }
+    }
+}
+
+/// Get and decode the block
+async fn get_block(
+    blockstore: impl Future<Output = Option<Blockstore>>,
+    cid: &str,
+) -> Result<Decoded, String> {
+    let parsed = Cid::try_from(cid).map_err(|e| e.to_string())?;
+    let blockstore = blockstore.await.ok_or(NOT_STARTED)?;
+    match blockstore.get(&parsed).await.map_err(|e| e.to_string())? {
+        Some(bytes) => Ok(Decoded::new(&parsed, &bytes)),
+        None => Err("Block not found".to_string()),
+    }
+}
+
+/// Remove the block from the blockstore
+async fn remove_block(
+    blockstore: impl Future<Output = Option<Blockstore>>,
+    cid: &str,
+) -> Result<(), String> {
+    let parsed = Cid::try_from(cid).map_err(|e| e.to_string())?;
+    let blockstore = blockstore.await.ok_or(NOT_STARTED)?;
+    blockstore.remove(&parsed).await.map_err(|e| e.to_string())
+}
+
+/// Export the CAR and write it to a file picked by the user.
+///
+/// Returns a message about the export, or None if the user cancelled.
+async fn save_car(
+    task: impl Future<Output = Result<Car, CarError>>,
+) -> anyhow::Result<Option<String>> {
+    let car = task.await?;
+
+    let Some(file) = rfd::AsyncFileDialog::new()
+        .add_filter("CAR file", &[CAR_EXTENSION])
+        .set_file_name(car.file_name())
+        .save_file()
+        .await
+    else {
+        return Ok(None);
+    };
+
+    file.write(&car.to_bytes()).await?;
+
+    let mut message = format!(
+        "Exported {} blocks, {}, to {}",
+        car.blocks.len(),
+        format_size(car.size()),
+        file.file_name()
+    );
+    if car.locked {
+        message.push_str(". Unlock the wallet to include blocks linked from encrypted state.");
+    }
+    Ok(Some(message))
+}
+
+/// Read a CAR file picked by the user and import it.
+///
+/// Returns the imported CAR, or None if the user cancelled.
+async fn load_car(
+    blockstore: impl Future<Output = Option<Blockstore>>,
+) -> anyhow::Result<Option<Car>> {
+    let Some(file) = rfd::AsyncFileDialog::new()
+        .add_filter("CAR file", &[CAR_EXTENSION])
+        .pick_file()
+        .await
+    else {
+        return Ok(None);
+    };
+
+    let car = Car::from_bytes(&file.read().await)?;
+    let blockstore = blockstore.await.ok_or(CarError::NotReady)?;
+    car::import(&blockstore, &Pins::default(), &car).await?;
+    Ok(Some(car))
+}
+
+/// Show the decoded block
+fn show_block(ui: &mut egui::Ui, inspected: &Inspected) {
+    ui.monospace(&inspected.cid);
+
+    let block = match &inspected.block {
+        Ok(block) => block,
+        Err(e) => {
+            ui.colored_label(egui::Color32::RED, e);
+            return;
+        }
+    };
+
+    let text = match block {
+        Decoded::State(state) => {
+            ui.label(format!(
+                "Plugin state saved {}",
+                format_timestamp(state.timestamp)
+            ));
+            if let Some(prev) = &state.prev {
+                ui.label(format!("Previous state: {}", prev));
+            }
+            if state.sealed.is_some() {
+                ui.label("Some variables are encrypted and not shown.");
+            }
+            pretty(&state.scope)
+        }
+        Decoded::Json(value) | Decoded::Cbor(value) => {
+            ui.label(block.kind());
+            pretty(value)
+        }
+        Decoded::Text(text) => {
+            ui.label(block.kind());
+            text.clone()
+        }
+        Decoded::Binary(bytes) => {
+            ui.label(format!("{} bytes of binary data", bytes.len()));
+            let mut hex = bytes
+                .iter()
+                .take(HEX_PREVIEW)
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            if bytes.len() > HEX_PREVIEW {
+                hex.push_str(" …");
+            }
+            hex
+        }
+    };
+
+    egui::ScrollArea::vertical()
+        .id_salt("block")
+        .max_height(240.0)
+        .show(ui, |ui| {
+            ui.add(
+                egui::TextEdit::multiline(&mut text.as_str())
+                    .code_editor()
+                    .desired_width(f32::INFINITY),
+            );
+        });
+}
+
+fn pretty(value: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+}
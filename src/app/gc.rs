@@ -0,0 +1,135 @@
+//! Run blockstore garbage collection from the Storage menu, now or on a schedule.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use web_time::Instant;
+
+use crate::app::platform;
+use crate::app::rdx_runner::RdxRunner;
+
+/// Garbage collection settings, persisted with the app, and the outcome of the last run
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub(crate) struct GarbageCollector {
+    /// Whether to collect garbage on a schedule
+    scheduled: bool,
+    /// Minutes between scheduled runs
+    interval_mins: u32,
+    /// When the last run started, scheduled runs wait a full interval after startup
+    #[serde(skip, default = "Instant::now")]
+    last_run: Instant,
+    /// Whether a run is in progress
+    #[serde(skip)]
+    running: Arc<AtomicBool>,
+    /// Message about the last run
+    #[serde(skip)]
+    status: Arc<Mutex<Option<String>>>,
+}
+
+impl Default for GarbageCollector {
+    fn default() -> Self {
+        Self {
+            scheduled: false,
+            interval_mins: 60,
+            last_run: Instant::now(),
+            running: Default::default(),
+            status: Default::default(),
+        }
+    }
+}
+
+impl GarbageCollector {
+    /// Spawn a garbage collection pass, unless one is already running
+    pub(crate) fn run(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.last_run = Instant::now();
+
+        let task = rdx_runner.collect_garbage();
+        let running = self.running.clone();
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match task.await {
+                Ok(report) => format!(
+                    "Removed {} of {} blocks, reclaimed {}",
+                    report.removed,
+                    report.scanned,
+                    format_size(report.reclaimed)
+                ),
+                Err(e) => {
+                    tracing::error!("Garbage collection failed: {:?}", e);
+                    format!("Garbage collection failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            running.store(false, Ordering::SeqCst);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Run if a scheduled run is due. Call every frame.
+    pub(crate) fn tick(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        if !self.scheduled {
+            return;
+        }
+        let interval = Duration::from_secs(self.interval_mins.max(1) as u64 * 60);
+        match interval.checked_sub(self.last_run.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => ctx.request_repaint_after(remaining),
+            _ => self.run(ctx, rdx_runner),
+        }
+    }
+
+    /// Garbage collection items for the Storage menu
+    pub(crate) fn menu_items(
+        &mut self,
+        ctx: &egui::Context,
+        ui: &mut egui::Ui,
+        rdx_runner: &RdxRunner,
+    ) {
+        let running = self.running.load(Ordering::SeqCst);
+        if ui
+            .add_enabled(!running, egui::Button::new("Collect garbage"))
+            .on_hover_text("Remove blocks no plugin state links to and nothing has pinned")
+            .clicked()
+        {
+            self.run(ctx, rdx_runner);
+        }
+
+        ui.checkbox(&mut self.scheduled, "Collect on a schedule");
+        ui.add_enabled_ui(self.scheduled, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Every");
+                ui.add(egui::DragValue::new(&mut self.interval_mins).range(1..=10_080));
+                ui.label("minutes");
+            });
+        });
+
+        if running {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Collecting…");
+            });
+        } else if let Some(status) = self.status.lock().unwrap().as_ref() {
+            ui.separator();
+            ui.label(status);
+        }
+    }
+}
+
+/// Human readable size, like `1.5 MiB`
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
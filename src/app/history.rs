@@ -0,0 +1,246 @@
+//! Plugin state history window.
+//!
+//! Lists the saved snapshots of a plugin's state, diffs any two of them and
+//! rolls the plugin back to an earlier snapshot.
+use chrono::TimeZone as _;
+
+use crate::app::platform;
+use crate::app::rdx_runner::history::{diff, Change, Snapshot, MAX_SNAPSHOTS};
+use crate::app::rdx_runner::State;
+
+/// The async loaded history of a plugin, kept in the egui temp data
+#[derive(Default, Clone)]
+struct HistoryState {
+    snapshots: Vec<Snapshot>,
+    is_loading: bool,
+    error: Option<String>,
+}
+
+/// Window which shows the state history of a single plugin
+#[derive(Default)]
+pub(crate) struct HistoryWindow {
+    /// The plugin whose history is shown. The window is closed when None.
+    plugin: Option<String>,
+    /// Older snapshot to compare, index into the snapshots
+    older: Option<usize>,
+    /// Newer snapshot to compare, index into the snapshots
+    newer: Option<usize>,
+}
+
+impl HistoryWindow {
+    /// Open the window for the given plugin state, loading its history.
+    pub(crate) fn open(&mut self, ctx: &egui::Context, state: State) {
+        self.plugin = Some(state.name().to_string());
+        self.older = None;
+        self.newer = None;
+        load(ctx, state);
+    }
+
+    /// The plugin whose history is shown, if the window is open
+    pub(crate) fn plugin(&self) -> Option<&str> {
+        self.plugin.as_deref()
+    }
+
+    /// Show the window, if open
+    pub(crate) fn show(&mut self, ctx: &egui::Context, state: Option<State>) {
+        let Some(plugin) = self.plugin.clone() else {
+            return;
+        };
+
+        let Some(state) = state else {
+            self.plugin = None;
+            return;
+        };
+
+        let history = ctx.data_mut(|data| {
+            data.get_temp::<HistoryState>(state_id(&plugin))
+                .unwrap_or_default()
+        });
+
+        let mut open = true;
+
+        egui::Window::new(format!("History: {}", plugin))
+            .open(&mut open)
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui
+                        .add_enabled(!history.is_loading, egui::Button::new("Refresh"))
+                        .clicked()
+                    {
+                        load(ctx, state.clone());
+                    }
+
+                    if history.is_loading {
+                        ui.spinner();
+                    }
+                });
+
+                if let Some(error) = &history.error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+
+                if history.snapshots.is_empty() && !history.is_loading {
+                    ui.label("No saved snapshots yet.");
+                    return;
+                }
+
+                egui::ScrollArea::vertical()
+                    .id_salt("snapshots")
+                    .max_height(240.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("snapshot_grid")
+                            .num_columns(4)
+                            .striped(true)
+                            .show(ui, |ui| {
+                                ui.strong("Saved");
+                                ui.strong("CID");
+                                ui.strong("Compare");
+                                ui.label("");
+                                ui.end_row();
+
+                                for (i, snapshot) in history.snapshots.iter().enumerate() {
+                                    ui.label(format_timestamp(snapshot.block.timestamp));
+                                    ui.monospace(short_cid(&snapshot.cid))
+                                        .on_hover_text(&snapshot.cid);
+
+                                    ui.horizontal(|ui| {
+                                        ui.radio_value(&mut self.older, Some(i), "A");
+                                        ui.radio_value(&mut self.newer, Some(i), "B");
+                                    });
+
+                                    // the newest snapshot is the current state
+                                    if i == 0 {
+                                        ui.label("current");
+                                    } else if ui.button("Roll back").clicked() {
+                                        rollback(ctx, state.clone(), snapshot.cid.clone());
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                    });
+
+                ui.separator();
+
+                let (Some(a), Some(b)) = (self.older, self.newer) else {
+                    ui.label("Select snapshots A and B to compare.");
+                    return;
+                };
+
+                let (Some(a), Some(b)) = (history.snapshots.get(a), history.snapshots.get(b))
+                else {
+                    return;
+                };
+
+                let changes = match (a.block.to_scope(), b.block.to_scope()) {
+                    (Ok(a), Ok(b)) => diff(&a, &b),
+                    (Err(e), _) | (_, Err(e)) => {
+                        ui.colored_label(egui::Color32::RED, format!("Error: {:?}", e));
+                        return;
+                    }
+                };
+
+                if changes.is_empty() {
+                    ui.label("No differences.");
+                    return;
+                }
+
+                egui::ScrollArea::vertical()
+                    .id_salt("changes")
+                    .show(ui, |ui| {
+                        for change in changes {
+                            match change {
+                                Change::Added { name, value } => {
+                                    ui.colored_label(
+                                        egui::Color32::GREEN,
+                                        format!("+ {}: {}", name, value),
+                                    );
+                                }
+                                Change::Removed { name, value } => {
+                                    ui.colored_label(
+                                        egui::Color32::RED,
+                                        format!("- {}: {}", name, value),
+                                    );
+                                }
+                                Change::Changed { name, old, new } => {
+                                    ui.colored_label(
+                                        egui::Color32::YELLOW,
+                                        format!("~ {}: {} → {}", name, old, new),
+                                    );
+                                }
+                            }
+                        }
+                    });
+            });
+
+        if !open {
+            self.plugin = None;
+        }
+    }
+}
+
+fn state_id(plugin: &str) -> egui::Id {
+    egui::Id::new("state_history").with(plugin)
+}
+
+/// Spawn a task to load the history of the given state into the egui temp data
+fn load(ctx: &egui::Context, state: State) {
+    let state_id = state_id(state.name());
+
+    let mut history =
+        ctx.data_mut(|data| data.get_temp::<HistoryState>(state_id).unwrap_or_default());
+    history.is_loading = true;
+    history.error = None;
+    ctx.data_mut(|data| data.insert_temp(state_id, history.clone()));
+
+    let ctx = ctx.clone();
+    platform::spawn(async move {
+        match state.history(MAX_SNAPSHOTS).await {
+            Ok(snapshots) => {
+                history.snapshots = snapshots;
+            }
+            Err(e) => {
+                tracing::error!("Error loading history: {:?}", e);
+                history.error = Some(format!("Error: {:?}", e));
+            }
+        }
+
+        history.is_loading = false;
+        ctx.data_mut(|data| data.insert_temp(state_id, history));
+        ctx.request_repaint();
+    });
+}
+
+/// Spawn a task to roll the state back to the given CID, then reload the history
+fn rollback(ctx: &egui::Context, state: State, cid: String) {
+    let ctx = ctx.clone();
+    platform::spawn(async move {
+        match state.rollback(&cid).await {
+            Ok(head) => tracing::info!("Rolled back to {}, new head {}", cid, head),
+            Err(e) => tracing::error!("Error rolling back to {}: {:?}", cid, e),
+        }
+        load(&ctx, state);
+    });
+}
+
+/// Local date and time of a unix timestamp, for display
+pub(crate) fn format_timestamp(timestamp: i64) -> String {
+    // states saved before the history was introduced have no timestamp
+    if timestamp == 0 {
+        return "unknown".to_string();
+    }
+
+    chrono::Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// Shortens a CID to its last 8 characters, for display
+pub(crate) fn short_cid(cid: &str) -> String {
+    match cid.len() {
+        0..=8 => cid.to_string(),
+        len => format!("…{}", &cid[len - 8..]),
+    }
+}
@@ -0,0 +1,85 @@
+//! Starts the [MultinodeApp] in the chosen profile.
+//!
+//! Shows the profile picker first, unless the profile was already given, such as with
+//! the `--profile` flag on native.
+use eframe::App as _;
+
+use super::profile::{ProfileList, ProfilePicker, PROFILES_KEY};
+use super::{MultinodeApp, Profile};
+
+/// The eframe app: the profile picker, then the [MultinodeApp] once a profile is chosen.
+pub struct Launcher {
+    /// Picker shown until a profile is chosen, also keeps the list of known profiles
+    picker: ProfilePicker,
+    /// The running app, once a profile is chosen
+    app: Option<MultinodeApp>,
+}
+
+impl Launcher {
+    /// Called once before the first frame.
+    ///
+    /// Starts straight into the given profile, otherwise shows the picker.
+    pub fn new(cc: &eframe::CreationContext<'_>, profile: Option<Profile>) -> Self {
+        let list = cc
+            .storage
+            .and_then(|storage| eframe::get_value::<ProfileList>(storage, PROFILES_KEY))
+            .unwrap_or_default();
+
+        let mut launcher = Self {
+            picker: ProfilePicker::new(list),
+            app: None,
+        };
+
+        if let Some(profile) = profile {
+            launcher.start(profile, cc.storage);
+        }
+
+        launcher
+    }
+
+    /// Activate the profile and start the app in it
+    fn start(&mut self, profile: Profile, storage: Option<&dyn eframe::Storage>) {
+        tracing::info!("👤 Starting profile {}", profile.name());
+        self.picker.list.opened(&profile);
+
+        if let Err(e) = profile.activate() {
+            tracing::error!("{}", e);
+        }
+
+        self.app = Some(MultinodeApp::load(storage));
+    }
+}
+
+impl eframe::App for Launcher {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, PROFILES_KEY, &self.picker.list);
+        if let Some(app) = &mut self.app {
+            app.save(storage);
+        }
+    }
+
+    fn on_exit(&mut self, gl: Option<&eframe::glow::Context>) {
+        if let Some(app) = &mut self.app {
+            app.on_exit(gl);
+        }
+    }
+
+    fn auto_save_interval(&self) -> std::time::Duration {
+        match &self.app {
+            Some(app) => app.auto_save_interval(),
+            None => std::time::Duration::from_secs(30),
+        }
+    }
+
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        if let Some(app) = &mut self.app {
+            app.update(ctx, frame);
+            return;
+        }
+
+        if let Some(profile) = self.picker.show(ctx) {
+            self.start(profile, frame.storage());
+            ctx.request_repaint();
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Bootstrap and listen endpoints of the node, shared by native and web.
+use multiaddr::Multiaddr;
+
+/// Bootstrap endpoint used until the user sets their own
+pub(crate) const DEFAULT_BOOTSTRAP: &str = "/dnsaddr/peerpiper.io";
+
+/// Persisted network endpoints
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub(crate) struct NetworkSettings {
+    /// Multiaddrs dialed on connect, `/dnsaddr` entries included
+    pub bootstrap: Vec<String>,
+    /// Addresses the native node listens on, the node picks its own if empty
+    pub listen: Vec<String>,
+
+    /// Whether the settings window is open
+    #[serde(skip)]
+    open: bool,
+    /// Bootstrap entry being typed
+    #[serde(skip)]
+    new_bootstrap: String,
+    /// Listen entry being typed
+    #[serde(skip)]
+    new_listen: String,
+    /// Why the last entry was refused
+    #[serde(skip)]
+    error: Option<String>,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            bootstrap: vec![DEFAULT_BOOTSTRAP.to_string()],
+            listen: vec![],
+            open: false,
+            new_bootstrap: String::new(),
+            new_listen: String::new(),
+            error: None,
+        }
+    }
+}
+
+impl NetworkSettings {
+    pub(crate) fn open(&mut self) {
+        self.open = true;
+    }
+
+    /// Show the settings window, if open. Returns whether the user asked to reconnect.
+    pub(crate) fn show(&mut self, ctx: &egui::Context) -> bool {
+        let mut reconnect = false;
+        let mut open = self.open;
+        egui::Window::new("Network")
+            .open(&mut open)
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.strong("Bootstrap peers");
+                ui.label(
+                    "Dialed on connect. /dnsaddr entries are resolved to every peer they list.",
+                );
+                endpoints(ui, "bootstrap", &mut self.bootstrap);
+                if let Err(e) = add_entry(ui, &mut self.new_bootstrap, &mut self.bootstrap) {
+                    self.error = Some(e);
+                }
+
+                ui.separator();
+                ui.strong("Listen addresses");
+                if cfg!(target_arch = "wasm32") {
+                    ui.label("Only used by the desktop node.");
+                } else {
+                    ui.label("Leave empty to let the node choose.");
+                }
+                endpoints(ui, "listen", &mut self.listen);
+                if let Err(e) = add_entry(ui, &mut self.new_listen, &mut self.listen) {
+                    self.error = Some(e);
+                }
+
+                if let Some(error) = &self.error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui
+                        .button("Reconnect")
+                        .on_hover_text("Connect again with these endpoints")
+                        .clicked()
+                    {
+                        reconnect = true;
+                    }
+                    if ui.button("Reset to defaults").clicked() {
+                        *self = Self {
+                            open: true,
+                            ..Default::default()
+                        };
+                    }
+                });
+            });
+        self.open = open && self.open;
+        reconnect
+    }
+}
+
+/// The end of a peer ID, enough to tell peers apart
+pub(crate) fn short_peer(peer: &str) -> &str {
+    let start = peer.char_indices().rev().nth(7).map_or(0, |(i, _)| i);
+    &peer[start..]
+}
+
+/// List of endpoints, each with a remove button
+fn endpoints(ui: &mut egui::Ui, id: &str, list: &mut Vec<String>) {
+    let mut remove = None;
+    egui::Grid::new(id).num_columns(2).show(ui, |ui| {
+        for (i, addr) in list.iter().enumerate() {
+            ui.monospace(addr);
+            if ui.small_button("🗑").on_hover_text("Remove").clicked() {
+                remove = Some(i);
+            }
+            ui.end_row();
+        }
+    });
+    if let Some(i) = remove {
+        list.remove(i);
+    }
+}
+
+/// Text field and add button, adding the typed multiaddr to the list if valid
+fn add_entry(ui: &mut egui::Ui, entry: &mut String, list: &mut Vec<String>) -> Result<(), String> {
+    let mut result = Ok(());
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(entry).hint_text("/ip4/…/tcp/…"));
+        if ui.button("Add").clicked() {
+            result = parse_entry(entry).map(|addr| {
+                if !list.contains(&addr) {
+                    list.push(addr);
+                }
+                entry.clear();
+            });
+        }
+    });
+    result
+}
+
+/// The entry as a normalized multiaddr string
+fn parse_entry(entry: &str) -> Result<String, String> {
+    entry
+        .trim()
+        .parse::<Multiaddr>()
+        .map(|addr| addr.to_string())
+        .map_err(|e| format!("Invalid multiaddr {}: {}", entry.trim(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_default_and_parse() {
+        // settings saved before listen addresses existed still load
+        let settings: NetworkSettings =
+            serde_json::from_str(r#"{"bootstrap":["/dnsaddr/example.com"]}"#).unwrap();
+        assert_eq!(settings.bootstrap, ["/dnsaddr/example.com"]);
+        assert!(settings.listen.is_empty());
+        assert_eq!(NetworkSettings::default().bootstrap, [DEFAULT_BOOTSTRAP]);
+
+        assert_eq!(
+            parse_entry(" /ip4/127.0.0.1/tcp/4001 ").unwrap(),
+            "/ip4/127.0.0.1/tcp/4001"
+        );
+        assert!(parse_entry("not an address").is_err());
+    }
+}
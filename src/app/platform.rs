@@ -17,13 +17,17 @@ pub mod web;
 #[cfg(target_arch = "wasm32")]
 pub use web as platform;
 
-pub(crate) use platform::{spawn, Platform, Settings};
+pub(crate) use platform::{sleep, spawn, Platform, Settings};
 
 //pub trait System {
 ///// Put bytes into the local system
 //fn put(&self, bytes: Vec<u8>) -> Cid;
 //}
 
+pub mod blocks;
+pub(crate) mod offline;
 pub mod piper;
+pub mod storage;
+pub(crate) mod supervisor;
 
 pub use platform::StringStore;
@@ -0,0 +1,363 @@
+//! Reference tracking for the blockstore.
+//!
+//! The platform blockstores can't list their contents, so [Tracked] wraps one and records
+//! every block put through it in a [BlockIndex]. Garbage collection only ever removes
+//! blocks in the index. [Pins] keep blocks alive which nothing else links to, such as
+//! plugin binaries and blocks plugins put themselves.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+use blockstore::Blockstore;
+use cid::CidGeneric;
+use web_time::SystemTime;
+
+use super::storage::{self, Storage, StorageError};
+
+/// Storage key for the [BlockIndex]
+pub(crate) const INDEX_KEY: &str = "blockstore-index";
+
+/// Storage key for the changes to the [BlockIndex] since it was last written in full
+pub(crate) const JOURNAL_KEY: &str = "blockstore-index-journal";
+
+/// Changes kept in the journal before the whole index is written again
+const JOURNAL_MAX: usize = 256;
+
+/// Storage key for the [Pins]
+pub(crate) const PINS_KEY: &str = "blockstore-pins";
+
+/// The first bytes of every wasm binary, to tell plugin binaries apart from other blocks
+pub(crate) const WASM_MAGIC: &[u8] = b"\0asm";
+
+/// Pins are read, changed and written back, so only one change at a time
+static PINS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Whether the key holds the [BlockIndex], which is rebuilt rather than backed up
+pub(crate) fn is_index_key(key: &str) -> bool {
+    key == INDEX_KEY || key == JOURNAL_KEY
+}
+
+/// Seconds since the unix epoch
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// A block recorded in the [BlockIndex]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IndexEntry {
+    /// Size of the block in bytes
+    pub size: u64,
+    /// Unix timestamp (seconds) of when the block was last put
+    pub added: i64,
+}
+
+/// A change to the [BlockIndex], appended to its journal
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Insert(String, IndexEntry),
+    Remove(String),
+}
+
+impl Change {
+    fn apply(self, entries: &mut BTreeMap<String, IndexEntry>) {
+        match self {
+            Change::Insert(cid, entry) => {
+                entries.insert(cid, entry);
+            }
+            Change::Remove(cid) => {
+                entries.remove(&cid);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct IndexState {
+    entries: BTreeMap<String, IndexEntry>,
+    /// Changes since the index was last written in full
+    journal: Vec<Change>,
+}
+
+/// Every block put through [Tracked], persisted in [Storage]. Cheap to clone.
+///
+/// Each change is appended to a short journal, and the whole index is only written again
+/// once the journal is full, so putting a block doesn't rewrite every entry.
+#[derive(Debug, Clone)]
+pub struct BlockIndex {
+    state: Arc<Mutex<IndexState>>,
+    storage: Arc<dyn Storage>,
+}
+
+impl BlockIndex {
+    /// Load the index from storage, replaying its journal
+    pub fn open(storage: Arc<dyn Storage>) -> Self {
+        let mut entries: BTreeMap<String, IndexEntry> = read(storage.as_ref(), INDEX_KEY);
+        let journal: Vec<Change> = read(storage.as_ref(), JOURNAL_KEY);
+        for change in journal.iter().cloned() {
+            change.apply(&mut entries);
+        }
+        Self {
+            state: Arc::new(Mutex::new(IndexState { entries, journal })),
+            storage,
+        }
+    }
+
+    /// The indexed blocks by CID
+    pub fn entries(&self) -> BTreeMap<String, IndexEntry> {
+        self.state.lock().unwrap().entries.clone()
+    }
+
+    /// Record a block
+    pub fn insert(&self, cid: String, size: u64) {
+        let entry = IndexEntry { size, added: now() };
+        self.change(Change::Insert(cid, entry));
+    }
+
+    /// Forget a block
+    pub fn remove(&self, cid: &str) {
+        if self.state.lock().unwrap().entries.contains_key(cid) {
+            self.change(Change::Remove(cid.to_string()));
+        }
+    }
+
+    fn change(&self, change: Change) {
+        let mut state = self.state.lock().unwrap();
+        change.clone().apply(&mut state.entries);
+        state.journal.push(change);
+
+        let result = match state.journal.len() >= JOURNAL_MAX {
+            true => self.compact(&mut state),
+            false => write(self.storage.as_ref(), JOURNAL_KEY, &state.journal),
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to save blockstore index: {:?}", e);
+        }
+    }
+
+    /// Write the whole index, then empty the journal
+    fn compact(&self, state: &mut IndexState) -> Result<(), StorageError> {
+        write(self.storage.as_ref(), INDEX_KEY, &state.entries)?;
+        state.journal.clear();
+        self.storage.remove(JOURNAL_KEY)
+    }
+}
+
+/// Read a JSON value from storage, empty if it's missing or invalid
+fn read<T: serde::de::DeserializeOwned + Default>(storage: &dyn Storage, key: &str) -> T {
+    match storage.get_string(key) {
+        Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            tracing::error!("Failed to decode {}: {:?}", key, e);
+            T::default()
+        }),
+        Ok(None) => T::default(),
+        Err(e) => {
+            tracing::error!("Failed to read {}: {:?}", key, e);
+            T::default()
+        }
+    }
+}
+
+fn write<T: serde::Serialize>(
+    storage: &dyn Storage,
+    key: &str,
+    value: &T,
+) -> Result<(), StorageError> {
+    let json = serde_json::to_string(value).map_err(|e| StorageError::Backend(e.to_string()))?;
+    storage.set_string(key, json)
+}
+
+/// A blockstore which records every block put into it in a [BlockIndex]
+#[derive(Debug, Clone)]
+pub struct Tracked<B> {
+    inner: B,
+    index: BlockIndex,
+}
+
+impl<B> Tracked<B> {
+    /// Track the blockstore, with the index in the platform storage
+    pub fn new(inner: B) -> Self {
+        Self::with_index(inner, BlockIndex::open(storage::open_default()))
+    }
+
+    pub fn with_index(inner: B, index: BlockIndex) -> Self {
+        Self { inner, index }
+    }
+
+    /// The blocks put through this store
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+}
+
+impl<B: Blockstore> Blockstore for Tracked<B> {
+    async fn get<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+    ) -> blockstore::Result<Option<Vec<u8>>> {
+        self.inner.get(cid).await
+    }
+
+    async fn put_keyed<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+        data: &[u8],
+    ) -> blockstore::Result<()> {
+        self.inner.put_keyed(cid, data).await?;
+        self.index.insert(cid.to_string(), data.len() as u64);
+        Ok(())
+    }
+
+    async fn remove<const S: usize>(&self, cid: &CidGeneric<S>) -> blockstore::Result<()> {
+        self.inner.remove(cid).await?;
+        self.index.remove(&cid.to_string());
+        Ok(())
+    }
+
+    async fn close(self) -> blockstore::Result<()> {
+        self.inner.close().await
+    }
+}
+
+/// Blocks kept by garbage collection even when no state links to them.
+///
+/// Each pinned CID has one or more reasons, such as `plugin:<name>` for blocks a plugin
+/// put itself or `binary:<name>` for its binary. The block stays pinned until every
+/// reason is removed.
+#[derive(Debug, Clone)]
+pub struct Pins {
+    storage: Arc<dyn Storage>,
+}
+
+impl Default for Pins {
+    fn default() -> Self {
+        Self::new(storage::open_default())
+    }
+}
+
+impl Pins {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    /// All pinned CIDs and their reasons
+    pub fn list(&self) -> Result<BTreeMap<String, BTreeSet<String>>, StorageError> {
+        match self.storage.get_string(PINS_KEY)? {
+            Some(json) => {
+                serde_json::from_str(&json).map_err(|e| StorageError::Backend(e.to_string()))
+            }
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Whether the CID is pinned for any reason
+    pub fn is_pinned(&self, cid: &str) -> bool {
+        self.list().is_ok_and(|pins| pins.contains_key(cid))
+    }
+
+    /// Pin the CID for the reason
+    pub fn pin(&self, cid: &str, reason: &str) -> Result<(), StorageError> {
+        self.update(|pins| {
+            pins.entry(cid.to_string())
+                .or_default()
+                .insert(reason.to_string())
+        })
+    }
+
+    /// Pin the CID for the reason, removing the reason from every other CID, such as when
+    /// a plugin binary is replaced
+    pub fn replace(&self, cid: &str, reason: &str) -> Result<(), StorageError> {
+        self.update(|pins| {
+            for reasons in pins.values_mut() {
+                reasons.remove(reason);
+            }
+            pins.retain(|_, reasons| !reasons.is_empty());
+            pins.entry(cid.to_string())
+                .or_default()
+                .insert(reason.to_string());
+            true
+        })
+    }
+
+    /// Remove the reason for pinning the CID, or every reason if None
+    pub fn unpin(&self, cid: &str, reason: Option<&str>) -> Result<(), StorageError> {
+        self.update(|pins| {
+            let Some(reasons) = pins.get_mut(cid) else {
+                return false;
+            };
+            match reason {
+                Some(reason) => reasons.remove(reason),
+                None => reasons.clear(),
+            };
+            if reasons.is_empty() {
+                pins.remove(cid);
+            }
+            true
+        })
+    }
+
+    /// Change the pins, saving them if `f` returns true
+    fn update(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, BTreeSet<String>>) -> bool,
+    ) -> Result<(), StorageError> {
+        let _guard = PINS_LOCK.lock().unwrap();
+        let mut pins = self.list()?;
+        if f(&mut pins) {
+            let json =
+                serde_json::to_string(&pins).map_err(|e| StorageError::Backend(e.to_string()))?;
+            self.storage.set_string(PINS_KEY, json)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::storage::MemoryStore;
+
+    #[test]
+    fn test_pins_need_every_reason_removed() {
+        let pins = Pins::new(Arc::new(MemoryStore::default()));
+
+        pins.pin("cid", "plugin:a").unwrap();
+        pins.pin("cid", "plugin:b").unwrap();
+        pins.unpin("cid", Some("plugin:a")).unwrap();
+        assert!(pins.is_pinned("cid"));
+
+        pins.unpin("cid", Some("plugin:b")).unwrap();
+        assert!(!pins.is_pinned("cid"));
+
+        // a replaced binary is unpinned, unless pinned for another reason too
+        pins.replace("old", "binary:a").unwrap();
+        pins.pin("kept", "binary:a").unwrap();
+        pins.pin("kept", "plugin:a").unwrap();
+        pins.replace("new", "binary:a").unwrap();
+        assert!(!pins.is_pinned("old"));
+        assert!(pins.is_pinned("new"));
+        assert_eq!(
+            pins.list().unwrap()["kept"],
+            BTreeSet::from(["plugin:a".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_index_replays_its_journal() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStore::default());
+        let index = BlockIndex::open(storage.clone());
+        for i in 0..JOURNAL_MAX + 3 {
+            index.insert(format!("cid{}", i), i as u64);
+        }
+        index.remove("cid0");
+
+        // compacted once, the rest is in the journal
+        let journal: Vec<Change> = read(storage.as_ref(), JOURNAL_KEY);
+        assert_eq!(journal.len(), 4);
+        let reopened = BlockIndex::open(storage);
+        assert_eq!(reopened.entries(), index.entries());
+        assert_eq!(reopened.entries().len(), JOURNAL_MAX + 2);
+    }
+}
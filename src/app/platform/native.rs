@@ -4,13 +4,23 @@
 //! to a remote node, which is handled in the `web` module.
 mod chat;
 mod cloudflare;
+mod deferred;
+mod dnsaddr;
 mod error;
+mod event_log;
+mod peers;
+mod republish;
 mod settings;
 mod storage;
 
 use chat::ChatWidget;
 pub use error::Error;
-pub use peerpiper_native::NativeBlockstore as Blockstore;
+use event_log::{EventKind, EventLog, LogEntry, LogFilter};
+use peers::PeerDashboard;
+/// The blockstore, tracked so unreferenced blocks can be garbage collected,
+/// opening in the background
+pub type Blockstore = super::blocks::Tracked<Deferred<peerpiper_native::NativeBlockstore>>;
+pub(crate) use deferred::Deferred;
 use peerpiper_native::NativeBlockstoreBuilder;
 pub(crate) use settings::Settings;
 pub use storage::StringStore;
@@ -22,15 +32,48 @@ use std::collections::HashMap;
 use std::future::Future;
 use std::sync::{Arc, Mutex};
 
-use crate::app::rdx_runner::RdxRunner;
+use crate::app::network::{short_peer, NetworkSettings};
+use crate::app::profile::Profile;
+use crate::app::rdx_runner::share::Shares;
+use crate::app::rdx_runner::{RdxRunner, FLUSH_TIMEOUT};
+use crate::app::topics::TopicInbox;
+
+use super::piper::{AllCommands, PeerPiper};
+use super::supervisor::Supervisor;
+
+/// Open the active profile's blockstore in the background,
+/// in the profile's own directory unless it's the default profile
+pub(crate) fn open_blockstore() -> Blockstore {
+    Blockstore::new(Deferred::new(async {
+        open_native().await.map_err(|e| {
+            tracing::error!("{}", e);
+            e.to_string()
+        })
+    }))
+}
 
-use super::piper::PeerPiper;
+async fn open_native() -> anyhow::Result<peerpiper_native::NativeBlockstore> {
+    let profile = Profile::active();
+    let builder = match profile.data_dir() {
+        Some(dir) if !profile.is_default() => NativeBlockstoreBuilder::new(dir.join("blocks")),
+        _ => NativeBlockstoreBuilder::default(),
+    };
+    let blockstore = builder
+        .open()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to open blockstore: {:?}", e))?;
+    Ok(blockstore)
+}
 
 pub fn spawn(f: impl Future<Output = ()> + Send + 'static) {
     tracing::trace!("Spawning tokio task");
     tokio::spawn(f);
 }
 
+pub async fn sleep(duration: std::time::Duration) {
+    tokio::time::sleep(duration).await;
+}
+
 /// Track whether the Context has been set
 #[derive(Debug, Default)]
 pub(crate) struct ContextSet {
@@ -59,7 +102,14 @@ impl ContextSet {
 }
 
 pub(crate) struct Platform {
-    log: Arc<Mutex<Vec<String>>>,
+    /// Latest node events
+    log: Arc<Mutex<EventLog>>,
+
+    /// Which node events the log shows
+    log_filter: LogFilter,
+
+    /// Peers, round trip times and listen addresses
+    peers: PeerDashboard,
 
     /// Clone of the [egui::Context] so that the platform can trigger repaints
     ctx: Arc<Mutex<ContextSet>>,
@@ -69,112 +119,75 @@ pub(crate) struct Platform {
 
     pub rdx_runner: RdxRunner,
 
+    /// The node, to connect to the network
+    peerpiper: Arc<AsyncMutex<PeerPiper>>,
+
+    /// Sender for node events, which feed the log and dashboard
+    on_event: tokio::sync::mpsc::Sender<PublicEvent>,
+
+    /// Keeps the node connected
+    supervisor: Supervisor,
+
     /// Chat Widget for the platform
     chat_widget: ChatWidget,
+
+    /// Plugin states shared with us by peers, and shared by us
+    pub(crate) shares: Shares,
+
+    /// Messages received on pub/sub topics
+    pub(crate) topics: TopicInbox,
 }
 
 impl Default for Platform {
     fn default() -> Self {
         // Collection of the Plugins
         let arc_collection_plugins = Arc::new(Mutex::new(HashMap::new()));
+        let shares = Shares::default();
 
-        let log = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::new(Mutex::new(EventLog::default()));
         let ctx: Arc<Mutex<ContextSet>> = Arc::new(Mutex::new(ContextSet::new()));
         let addr = Arc::new(Mutex::new(None));
 
-        // setup oneshot channel to pass blockstore from task back to this thread
-        // the receiver must not be async
-        let (tx, rx) = std::sync::mpsc::channel();
-
-        super::spawn(async move {
-            // 1. First we need a NativeBlockstore from NativeBlockstoreBuilder
-            let blockstore = NativeBlockstoreBuilder::default();
-            let blockstore = blockstore
-                .open()
-                .await
-                .map_err(|e| {
-                    tracing::error!("Failed to open blockstore: {:?}", e);
-                })
-                .unwrap();
-            if let Err(e) = tx.send(blockstore) {
-                tracing::error!("Failed to send blockstore: {:?}", e);
-            }
-        });
-
-        let blockstore = rx.recv().unwrap();
+        // 1. First we need a NativeBlockstore from NativeBlockstoreBuilder.
+        // It opens in the background, block reads and writes wait for it.
+        let blockstore = open_blockstore();
 
         let peerpiper = Arc::new(AsyncMutex::new(PeerPiper::new(
             blockstore,
             arc_collection_plugins.clone(),
+            shares.clone(),
         )));
 
+        let peers = PeerDashboard::default();
+        let supervisor = Supervisor::default();
+
         let log_clone = log.clone();
+        let peers_clone = peers.peers.clone();
+        let supervisor_clone = supervisor.clone();
+        let topics = TopicInbox::default();
+        let topics_clone = topics.clone();
         let ctx_clone = ctx.clone();
         let addr_clone = addr.clone();
 
         //let (on_event, mut plugin_evts) = mpsc::channel(16);
         let (on_event, mut rx_evts) = tokio::sync::mpsc::channel(32);
 
-        // task for listening on plugin events and updating the log accoringly
-        let peerpiper_clone = peerpiper.clone();
-        super::spawn(async move {
-            let libp2p_endpoints = vec![];
-            let listen = match peerpiper_clone.lock().await.connect(libp2p_endpoints).await {
-                Ok(listen) => listen,
-                Err(e) => {
-                    tracing::error!("Failed to connect to the network: {:?}", e);
-                    return;
-                }
-            };
-
-            listen(on_event);
-        });
-
+        // task for listening on node events and updating the log accordingly,
+        // the node connects once the network settings are loaded, see [Platform::connect]
         tokio::task::spawn(async move {
             while let Some(event) = rx_evts.recv().await {
-                let msg = format!("{:?}", event);
-                tracing::debug!("Received event: {:?}", msg);
-
-                let timestamp = chrono::Local::now().format("%H:%M:%S%.3f");
-
-                match event {
-                    PublicEvent::ListenAddr { address: addr, .. } => {
-                        tracing::debug!("Node Address: {}", &addr.to_string());
-                        // Update rules: only update if this is an/ip6/ AND there isn't already an
-                        // /ip6/ address in the addr
-
-                        // first, check if empty
-                        let mut lock = addr_clone.lock().unwrap();
-                        if lock.is_none() {
-                            *lock = Some(addr);
-                        } else {
-                            // not empty, check if it's an ip6 address repalcing an ip4 address
-                            // if so, update it
-                            let is_ip4 = lock.as_ref().unwrap().to_string().starts_with("/ip4/");
-                            if is_ip4 && addr.to_string().starts_with("/ip6/") {
-                                *lock = Some(addr);
-                            }
-                        }
-
-                        log_clone.lock().unwrap().push(msg);
-                    }
-                    PublicEvent::Message { topic, data, peer } => {
-                        // check if data decodes into a utf8 string, if not skip it
-                        if let Ok(maybe_str) = std::str::from_utf8(&data) {
-                            let msg = format!(
-                                "[{}] 📬 Message from {}: {}: {}",
-                                timestamp, peer, topic, maybe_str
-                            );
-                            tracing::debug!("Received Message: {:?}", msg);
-                            log_clone.lock().unwrap().push(msg);
-                        }
-                    }
-                    PublicEvent::Pong { peer, rtt } => {
-                        let msg = format!("[{}] 🏓 Pong {}ms from {}", timestamp, rtt, peer);
-                        log_clone.lock().unwrap().push(msg);
-                    }
-                    _ => {
-                        log_clone.lock().unwrap().push(msg);
+                tracing::debug!("Received event: {:?}", event);
+                log_clone.lock().unwrap().push(LogEntry::from_event(&event));
+                peers_clone.lock().unwrap().record(&event);
+                supervisor_clone.observe(&event);
+                topics_clone.record(&event);
+
+                if let PublicEvent::ListenAddr { address: addr, .. } = event {
+                    tracing::debug!("Node Address: {}", &addr.to_string());
+                    // prefer IPv6, following it when it changes, see [republish::replaces]
+                    let mut lock = addr_clone.lock().unwrap();
+                    if republish::replaces(lock.as_ref(), &addr) {
+                        *lock = Some(addr);
                     }
                 }
 
@@ -182,7 +195,7 @@ impl Default for Platform {
             }
         });
 
-        let mut rdx_runner = RdxRunner::new(peerpiper, None);
+        let mut rdx_runner = RdxRunner::new(peerpiper.clone(), None);
 
         let mut builtins = crate::BUILTIN_PLUGINS.to_vec();
 
@@ -198,6 +211,7 @@ impl Default for Platform {
             .unwrap();
 
         let arc_wallet = rdx_runner.load(wallet_name, wallet_bytes);
+        log.lock().unwrap().push(plugin_loaded(wallet_name));
 
         // set self.arc_wallet to arc_wallet
         rdx_runner.arc_wallet = Some(arc_wallet);
@@ -205,20 +219,31 @@ impl Default for Platform {
         // now the rest of the plugins
         for (name, bytes) in builtins {
             let _ = rdx_runner.load(name, bytes);
+            log.lock().unwrap().push(plugin_loaded(name));
         }
 
         Self {
             log,
+            log_filter: LogFilter::default(),
+            peers,
             ctx,
             addr,
             rdx_runner,
+            peerpiper,
+            on_event,
+            supervisor,
             chat_widget: Default::default(),
+            shares,
+            topics,
         }
     }
 }
 
 impl Drop for Platform {
     fn drop(&mut self) {
+        // write the latest plugin states, the debounced saves die with the runtime,
+        // unless they were already written on exit
+        self.rdx_runner.flush_on_exit(FLUSH_TIMEOUT);
         // Kill the server process using thread_handle
         self.close();
     }
@@ -236,23 +261,118 @@ impl Platform {
         self.ctx.lock().unwrap().set = true;
     }
 
+    /// Connect the node to the bootstrap endpoints and listen on the listen addresses.
+    /// Called again to reconnect with changed settings.
+    pub(crate) fn connect(&mut self, settings: &NetworkSettings) {
+        let endpoints = settings.bootstrap.clone();
+        let listen_addrs: Vec<Multiaddr> = settings
+            .listen
+            .iter()
+            .filter_map(|addr| match addr.parse::<Multiaddr>() {
+                Ok(addr) => Some(addr),
+                Err(e) => {
+                    tracing::warn!("Skipping listen address {}: {}", addr, e);
+                    None
+                }
+            })
+            .collect();
+        let peerpiper = self.peerpiper.clone();
+        let on_event = self.on_event.clone();
+        let connect = move |endpoints: Vec<String>| {
+            let peerpiper = peerpiper.clone();
+            let on_event = on_event.clone();
+            let listen_addrs = listen_addrs.clone();
+            async move {
+                // connect a clone, so the lock isn't held while the node starts
+                let mut piper = peerpiper.lock().await.clone();
+                let listen = piper
+                    .connect(endpoints, listen_addrs)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+                *peerpiper.lock().await = piper;
+                listen(on_event);
+                Ok(())
+            }
+        };
+
+        let peerpiper = self.peerpiper.clone();
+        let redial = move |endpoints: Vec<String>| {
+            let peerpiper = peerpiper.clone();
+            async move {
+                let piper = peerpiper.lock().await.clone();
+                for endpoint in endpoints {
+                    let Ok(addr) = endpoint.parse::<Multiaddr>() else {
+                        continue;
+                    };
+                    if let Err(e) = piper.order(AllCommands::Dial(addr)).await {
+                        tracing::warn!("Failed to dial {}: {:?}", endpoint, e);
+                    }
+                }
+            }
+        };
+
+        super::spawn(
+            self.supervisor
+                .clone()
+                .supervise(endpoints, connect, redial),
+        );
+    }
+
+    /// Connection state of the node
+    pub(crate) fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
     // This is where you would put platform-specific methods
     pub(crate) fn close(&mut self) {}
 
     /// Platform specific UI to show
-    pub(crate) fn show(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
+    pub(crate) fn show(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        ui.toggle_value(&mut self.peers.open, "🖧 Peers");
+        self.peers.show(ctx, &self.rdx_runner);
+
         // Bottom Up inner panel
         egui::TopBottomPanel::bottom("log")
             .resizable(true)
             .show_inside(ui, |ui| {
                 ui.collapsing("Node Log", |ui| {
-                    // SCROLLABLE SECTION for the log
-                    egui::ScrollArea::vertical().show(ui, |ui| {
-                        ui.vertical(|ui| {
-                            for line in self.log.lock().unwrap().iter().rev() {
-                                ui.label(line);
-                            }
-                        });
+                    let rows = {
+                        let mut log = self.log.lock().unwrap();
+                        log_filter_ui(ui, &mut log, &mut self.log_filter);
+                        log.filtered(&self.log_filter).count()
+                    };
+
+                    // SCROLLABLE SECTION for the log, newest first, only the rows in view
+                    let row_height = ui
+                        .text_style_height(&egui::TextStyle::Body)
+                        .max(ui.text_style_height(&egui::TextStyle::Monospace));
+                    egui::ScrollArea::vertical().show_rows(ui, row_height, rows, |ui, range| {
+                        let page = self
+                            .log
+                            .lock()
+                            .unwrap()
+                            .newest_page(&self.log_filter, range);
+                        egui::Grid::new("node_log")
+                            .num_columns(4)
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for entry in page {
+                                    ui.monospace(entry.time());
+                                    ui.label(entry.kind.to_string());
+                                    match &entry.peer {
+                                        Some(peer) => {
+                                            ui.monospace(short_peer(peer)).on_hover_text(peer);
+                                        }
+                                        None => {
+                                            ui.label("");
+                                        }
+                                    }
+                                    // one line per row, for the row height
+                                    ui.add(egui::Label::new(&entry.text).truncate())
+                                        .on_hover_text(&entry.text);
+                                    ui.end_row();
+                                }
+                            });
                     });
                 });
             });
@@ -272,6 +392,7 @@ impl Platform {
                     .unwrap_or_else(|err| panic!("Failed to read file: {}", err));
 
                 self.rdx_runner.load(&file_name, &bytes);
+                self.log.lock().unwrap().push(plugin_loaded(&file_name));
                 ctx.request_repaint();
             }
         }
@@ -281,3 +402,61 @@ impl Platform {
         self.chat_widget.ui(ctx, ui);
     }
 }
+
+/// Log entry for a loaded plugin
+fn plugin_loaded(name: &str) -> LogEntry {
+    LogEntry::new(EventKind::Plugin, None, format!("🔌 Loaded {}", name))
+}
+
+/// Kind and peer filters, search, export and clear for the node log
+fn log_filter_ui(ui: &mut egui::Ui, log: &mut EventLog, filter: &mut LogFilter) {
+    ui.horizontal_wrapped(|ui| {
+        for kind in EventKind::ALL {
+            let mut shown = filter.kinds.contains(&kind);
+            if ui.checkbox(&mut shown, kind.to_string()).changed() {
+                if shown {
+                    filter.kinds.insert(kind);
+                } else {
+                    filter.kinds.remove(&kind);
+                }
+            }
+        }
+    });
+
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_id_salt("node_log_peer")
+            .selected_text(filter.peer.as_deref().map_or("All peers", short_peer))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut filter.peer, None, "All peers");
+                for peer in log.peers() {
+                    let label = short_peer(&peer).to_string();
+                    ui.selectable_value(&mut filter.peer, Some(peer), label);
+                }
+            });
+
+        ui.add(egui::TextEdit::singleline(&mut filter.search).hint_text("Search"));
+
+        if ui
+            .button("Export…")
+            .on_hover_text("Save the shown entries as JSON lines")
+            .clicked()
+        {
+            if let Some(path) = rfd::FileDialog::new()
+                .add_filter("JSON lines", &["jsonl"])
+                .set_file_name("node-log.jsonl")
+                .save_file()
+            {
+                let written = log
+                    .to_json_lines(filter)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|lines| Ok(std::fs::write(&path, lines)?));
+                if let Err(e) = written {
+                    tracing::error!("Failed to export the node log: {:?}", e);
+                }
+            }
+        }
+        if ui.button("Clear").clicked() {
+            log.clear();
+        }
+    });
+}
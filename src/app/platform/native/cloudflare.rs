@@ -1,17 +1,17 @@
-//! Cloudflare DNS API integration for adding a multiaddr to a TXT record.
+//! Cloudflare DNS API backend for publishing a multiaddr as a TXT record.
 //!
-//! Creates a separate TXT record for up to [MAX_RECORDS] multiaddrs, then overwrites older records with newer ones.
-//!
-//! Cloudflare [cloudflare::endpoints::dns::DnsRecord] provides a unique descriptions for specific records, so we use
-//! comments to insert a timestamp for each record. This is used to determine which records to keep
-//! and which to remove.
+//! Cloudflare [cloudflare::endpoints::dns::DnsRecord]s carry their creation time, which
+//! [plan](super::dnsaddr::plan) uses to determine which records to keep and which to
+//! remove.
 use cloudflare::endpoints::dns::{
-    CreateDnsRecord, DeleteDnsRecord, DnsContent, ListDnsRecords, ListDnsRecordsParams,
+    CreateDnsRecord, CreateDnsRecordParams, DeleteDnsRecord, DnsContent, ListDnsRecords,
+    ListDnsRecordsParams,
 };
 use cloudflare::framework::response::ApiFailure;
 use cloudflare::framework::{async_api::Client, auth::Credentials, Environment};
 use cloudflare::framework::{HttpApiClientConfig, OrderDirection};
-use multiaddr::Multiaddr;
+
+use super::dnsaddr::{DnsAddrError, DnsAddrPublisher, TxtRecord};
 
 /// Cloudflare DNS Errors
 #[derive(Debug, thiserror::Error)]
@@ -24,148 +24,211 @@ pub enum CloudflareError {
     ApiFailure(#[from] ApiFailure),
 }
 
-/// Add a multiaddr to a TXT record in Cloudflare. removes older records if there are more than [MAX_RECORDS]
-#[bon::builder]
-pub async fn add_address(
-    api_token: String,
-    zone_id: String,
-    txt_name: String,
-    multiaddr: Multiaddr,
-) -> Result<Vec<String>, CloudflareError> {
-    let mut log = vec![];
-
-    /// The maximum number of TXT records to keep in Cloudflare
-    const MAX_RECORDS: usize = 2;
-
-    let credentials = Credentials::UserAuthToken { token: api_token };
-    let api_client = Client::new(
-        credentials,
-        HttpApiClientConfig::default(),
-        Environment::Production,
-    )
-    .map_err(|e| {
-        tracing::error!("❌ Error creating Cloudflare API client: {:?}", e);
-        CloudflareError::ApiError(e)
-    })?;
-
-    let mut existing_records = api_client
-        .request(&ListDnsRecords {
-            zone_identifier: &zone_id,
-            params: ListDnsRecordsParams {
-                direction: Some(OrderDirection::Descending),
-                name: Some(txt_name.clone()),
-                page: Some(1),
-                per_page: Some(100),
-                ..Default::default()
-            },
-        })
-        .await
+/// Records listed per request, the most Cloudflare allows
+const PER_PAGE: u32 = 100;
+
+/// The Cloudflare API, used when no other base URL is set
+pub(crate) const API_URL: &str = "https://api.cloudflare.com/client/v4/";
+
+/// Cloudflare zone the TXT records are published to
+pub(crate) struct Cloudflare {
+    pub api_token: String,
+    pub zone_id: String,
+    pub txt_name: String,
+    /// API base URL, the Cloudflare API if empty
+    pub api_url: String,
+}
+
+impl Cloudflare {
+    fn environment(&self) -> Result<Environment, DnsAddrError> {
+        let url = self.api_url.trim();
+        if url.is_empty() {
+            return Ok(Environment::Production);
+        }
+        // endpoint paths are joined onto the base, which must end in a slash to keep it
+        let url = format!("{}/", url.trim_end_matches('/'));
+        reqwest::Url::parse(&url)
+            .map(Environment::Custom)
+            .map_err(|e| DnsAddrError::Config(format!("Invalid Cloudflare API URL: {}", e)))
+    }
+
+    fn client(&self) -> Result<Client, DnsAddrError> {
+        let credentials = Credentials::UserAuthToken {
+            token: self.api_token.clone(),
+        };
+        Ok(Client::new(
+            credentials,
+            HttpApiClientConfig::default(),
+            self.environment()?,
+        )
         .map_err(|e| {
-            tracing::error!("❌ Error listing DNS records: {:?}", e);
-            CloudflareError::ApiFailure(e)
-        })?
-        .result;
-
-    existing_records.sort_by(|a, b| a.created_on.cmp(&b.created_on).reverse());
-
-    // check to see if the multiaddr is already in an existing record, if so, return
-    if existing_records.iter().any(|record| {
-        if let DnsContent::TXT { content } = &record.content {
-            content.contains(&multiaddr.to_string())
-        } else {
-            false
+            tracing::error!("❌ Error creating Cloudflare API client: {:?}", e);
+            CloudflareError::ApiError(e)
+        })?)
+    }
+}
+
+impl DnsAddrPublisher for Cloudflare {
+    async fn list(&self) -> Result<Vec<TxtRecord>, DnsAddrError> {
+        let client = self.client()?;
+        let mut records = vec![];
+
+        // every page, until one comes back short
+        for page in 1.. {
+            let batch = client
+                .request(&ListDnsRecords {
+                    zone_identifier: &self.zone_id,
+                    params: ListDnsRecordsParams {
+                        direction: Some(OrderDirection::Descending),
+                        name: Some(self.txt_name.clone()),
+                        page: Some(page),
+                        per_page: Some(PER_PAGE),
+                        ..Default::default()
+                    },
+                })
+                .await
+                .map_err(CloudflareError::from)?
+                .result;
+            let last = batch.len() < PER_PAGE as usize;
+
+            records.extend(batch.into_iter().filter_map(|record| match record.content {
+                DnsContent::TXT { content } => Some(TxtRecord {
+                    id: record.id,
+                    content,
+                    created: Some(record.created_on),
+                }),
+                _ => None,
+            }));
+            if last {
+                break;
+            }
         }
-    }) {
-        let msg = format!("🔍 Multiaddr already exists in TXT record: {:?}", multiaddr);
-        tracing::info!("{}", msg);
-        log.push(msg);
-        return Ok(log);
+
+        Ok(records)
     }
 
-    // Create new record
-    // turn the addr into a dnsaddr
-    let content = format!("dnsaddr={}", multiaddr);
-    api_client
-        .request(&CreateDnsRecord {
-            zone_identifier: &zone_id,
-            params: cloudflare::endpoints::dns::CreateDnsRecordParams {
-                ttl: None,
-                priority: None,
-                proxied: None,
-                name: &txt_name,
-                content: DnsContent::TXT {
-                    content: content.to_string(),
+    async fn create(&self, content: &str) -> Result<(), DnsAddrError> {
+        self.client()?
+            .request(&CreateDnsRecord {
+                zone_identifier: &self.zone_id,
+                params: CreateDnsRecordParams {
+                    ttl: None,
+                    priority: None,
+                    proxied: None,
+                    name: &self.txt_name,
+                    content: DnsContent::TXT {
+                        content: content.to_string(),
+                    },
                 },
-            },
-        })
-        .await?;
-    let msg = format!("🆕 TXT record created successfully {:?}", content);
-    tracing::info!("{}", msg);
-    log.push(msg);
-
-    // if num <= MAX_RECORDS, return
-    if existing_records.len() <= MAX_RECORDS {
-        let msg = format!(
-            "📦 TXT records within limit (found {})",
-            existing_records.len()
-        );
-        tracing::info!("{}", msg);
-        log.push(msg);
-        return Ok(log);
+            })
+            .await
+            .map_err(CloudflareError::from)?;
+        Ok(())
     }
 
-    let msg = format!(
-        "🗑 Deleting old TXT records ({} > {})",
-        existing_records.len(),
-        MAX_RECORDS
-    );
-    tracing::info!("{}", msg);
-
-    // Delete all but most recent MAX_RECORDS
-    for record in existing_records.iter().skip(MAX_RECORDS) {
-        api_client
+    async fn delete(&self, record: &TxtRecord) -> Result<(), DnsAddrError> {
+        self.client()?
             .request(&DeleteDnsRecord {
-                zone_identifier: &zone_id,
+                zone_identifier: &self.zone_id,
                 identifier: &record.id,
             })
-            .await?;
-        let msg = format!(
-            "🗑 Deleted old TXT record [{}]: {:?}",
-            record.created_on, record.id
-        );
-        tracing::info!("{}", msg);
-        log.push(msg);
+            .await
+            .map_err(CloudflareError::from)?;
+        Ok(())
     }
-
-    Ok(log)
 }
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+    use crate::app::platform::native::dnsaddr::publish;
+    use crate::app::platform::native::dnsaddr::test_util::{mock_server, PEER};
+    use multiaddr::Multiaddr;
+
+    /// Answer each request with the next result in a Cloudflare API envelope, returning
+    /// the requests received
+    async fn mock_api(
+        results: Vec<serde_json::Value>,
+    ) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let bodies = results
+            .into_iter()
+            .map(|result| {
+                serde_json::json!({
+                    "success": true,
+                    "errors": [],
+                    "messages": [],
+                    "result": result,
+                })
+                .to_string()
+            })
+            .collect();
+        mock_server("/client/v4", bodies).await
+    }
 
-    use multiaddr::{Multiaddr, Protocol};
-    use std::net::Ipv6Addr;
+    fn record(id: &str, port: u16, created: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "type": "TXT",
+            "name": "_dnsaddr.example.com",
+            "content": format!("dnsaddr=/ip4/1.1.1.1/tcp/{}/p2p/{}", port, PEER),
+            "proxiable": false,
+            "proxied": false,
+            "locked": false,
+            "ttl": 1,
+            "meta": { "auto_added": false },
+            "zone_id": "zone",
+            "zone_name": "example.com",
+            "created_on": created,
+            "modified_on": created,
+        })
+    }
 
     #[tokio::test]
-    async fn test_add_address() {
-        // Create a multiaddr
-
-        let address_webrtc = Multiaddr::from(Ipv6Addr::UNSPECIFIED)
-            .with(Protocol::Udp(0))
-            .with(Protocol::WebRTCDirect);
-
-        assert_eq!(
-            address_webrtc.to_string(),
-            "/ip6/::/udp/0/webrtc-direct".to_string()
-        );
-
-        //let r = add_address()
-        //    .api_token("api_token".to_string())
-        //    .zone_id("zone_id".to_string())
-        //    .txt_name("txt_name".to_string())
-        //    .multiaddr(&address_webrtc)
-        //    .call()
-        //    .await;
+    async fn test_cloudflare_against_mock_api() {
+        let listed = serde_json::json!([
+            record("new", 2, "2024-01-02T00:00:00Z"),
+            record("old", 1, "2024-01-01T00:00:00Z"),
+        ]);
+        let created = record("created", 4001, "2024-01-03T00:00:00Z");
+        let deleted = serde_json::json!({ "id": "old" });
+        let (api_url, server) = mock_api(vec![listed, created, deleted]).await;
+        let cloudflare = Cloudflare {
+            api_token: "secret".to_string(),
+            zone_id: "zone".to_string(),
+            txt_name: "_dnsaddr.example.com".to_string(),
+            api_url,
+        };
+
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", PEER)
+            .parse()
+            .unwrap();
+        publish(&cloudflare, &addr, 1).await.unwrap();
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("get /client/v4/zones/zone/dns_records?"));
+        assert!(requests[1].starts_with("post /client/v4/zones/zone/dns_records "));
+        // the oldest record goes
+        assert!(requests[2].starts_with("delete /client/v4/zones/zone/dns_records/old "));
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[test]
+    fn test_api_url() {
+        let mut cloudflare = Cloudflare {
+            api_token: "".to_string(),
+            zone_id: "".to_string(),
+            txt_name: "".to_string(),
+            api_url: "".to_string(),
+        };
+        assert!(matches!(
+            cloudflare.environment(),
+            Ok(Environment::Production)
+        ));
+
+        cloudflare.api_url = "not a url".to_string();
+        assert!(matches!(
+            cloudflare.environment(),
+            Err(DnsAddrError::Config(_))
+        ));
     }
 }
@@ -0,0 +1,66 @@
+//! A blockstore which opens in the background.
+//!
+//! Opening the native blockstore touches the disk, so startup doesn't wait on it. Every
+//! call waits until it's open instead, and fails if it couldn't be opened.
+use std::fmt;
+use std::future::Future;
+
+use blockstore::Blockstore;
+use cid::CidGeneric;
+use futures::future::{BoxFuture, FutureExt as _, Shared};
+
+/// A blockstore still being opened, cheap to clone
+#[derive(Clone)]
+pub struct Deferred<B> {
+    open: Shared<BoxFuture<'static, Result<B, String>>>,
+}
+
+impl<B> fmt::Debug for Deferred<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Deferred")
+            .field("open", &self.open.peek().is_some())
+            .finish()
+    }
+}
+
+impl<B: Clone + Send + Sync + 'static> Deferred<B> {
+    /// Start opening the blockstore with the future on the runtime
+    pub fn new(open: impl Future<Output = Result<B, String>> + Send + 'static) -> Self {
+        let open = open.boxed().shared();
+        tokio::spawn(open.clone().map(|_| ()));
+        Self { open }
+    }
+
+    /// The opened blockstore
+    async fn inner(&self) -> blockstore::Result<B> {
+        self.open
+            .clone()
+            .await
+            .map_err(blockstore::Error::FatalDatabaseError)
+    }
+}
+
+impl<B: Blockstore + Clone + Send + Sync + 'static> Blockstore for Deferred<B> {
+    async fn get<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+    ) -> blockstore::Result<Option<Vec<u8>>> {
+        self.inner().await?.get(cid).await
+    }
+
+    async fn put_keyed<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+        data: &[u8],
+    ) -> blockstore::Result<()> {
+        self.inner().await?.put_keyed(cid, data).await
+    }
+
+    async fn remove<const S: usize>(&self, cid: &CidGeneric<S>) -> blockstore::Result<()> {
+        self.inner().await?.remove(cid).await
+    }
+
+    async fn close(self) -> blockstore::Result<()> {
+        self.inner().await?.close().await
+    }
+}
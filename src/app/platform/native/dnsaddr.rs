@@ -0,0 +1,421 @@
+//! Publishing the node's multiaddr as a `dnsaddr` TXT record.
+//!
+//! Each backend only lists, creates and deletes TXT records under the configured name.
+//! [plan] and [apply] hold the logic shared by all of them: create a record for the
+//! multiaddr if none has it yet, then prune this node's oldest records beyond a limit.
+mod resolve;
+mod rfc2136;
+mod webhook;
+mod wire;
+
+use std::future::Future;
+
+use chrono::{DateTime, Utc};
+use multiaddr::{Multiaddr, Protocol};
+
+use super::cloudflare::{Cloudflare, CloudflareError};
+use crate::app::rdx_runner::vault::VaultError;
+
+pub(crate) use resolve::{verify, Resolver, DEFAULT_DOH};
+pub(crate) use rfc2136::Rfc2136;
+pub(crate) use webhook::Webhook;
+
+/// The default number of this node's TXT records to keep, besides the one just created
+pub(crate) const MAX_RECORDS: usize = 2;
+
+/// DNS address publishing errors
+#[derive(Debug, thiserror::Error)]
+pub enum DnsAddrError {
+    #[error(transparent)]
+    Cloudflare(#[from] CloudflareError),
+    /// Webhook request failed
+    #[error("Webhook Error: {0}")]
+    Http(#[from] reqwest::Error),
+    /// Webhook answered with something other than the expected JSON
+    #[error("Invalid webhook response: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Socket error talking to the DNS server
+    #[error("DNS IO Error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The DNS server refused or failed the query or update
+    #[error("DNS Error: {0}")]
+    Dns(String),
+    /// The backend settings are incomplete or invalid
+    #[error("Invalid settings: {0}")]
+    Config(String),
+    /// The backend secrets couldn't be read from the vault
+    #[error("Vault Error: {0}")]
+    Vault(#[from] VaultError),
+}
+
+/// A TXT record under the dnsaddr name
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxtRecord {
+    /// What the backend needs to delete the record
+    pub id: String,
+    /// The record text, ie. `dnsaddr=/ip4/…`
+    pub content: String,
+    /// When the record was created, if the backend knows
+    pub created: Option<DateTime<Utc>>,
+}
+
+/// A place TXT records can be published to
+pub trait DnsAddrPublisher: Send + Sync {
+    /// The TXT records under the dnsaddr name
+    fn list(&self) -> impl Future<Output = Result<Vec<TxtRecord>, DnsAddrError>> + Send;
+
+    /// Create a TXT record under the dnsaddr name
+    fn create(&self, content: &str) -> impl Future<Output = Result<(), DnsAddrError>> + Send;
+
+    /// Delete a record returned by [DnsAddrPublisher::list]
+    fn delete(&self, record: &TxtRecord) -> impl Future<Output = Result<(), DnsAddrError>> + Send;
+}
+
+/// The backend picked in the settings
+pub(crate) enum Publisher {
+    Cloudflare(Cloudflare),
+    Rfc2136(Rfc2136),
+    Webhook(Webhook),
+}
+
+impl DnsAddrPublisher for Publisher {
+    async fn list(&self) -> Result<Vec<TxtRecord>, DnsAddrError> {
+        match self {
+            Publisher::Cloudflare(p) => p.list().await,
+            Publisher::Rfc2136(p) => p.list().await,
+            Publisher::Webhook(p) => p.list().await,
+        }
+    }
+
+    async fn create(&self, content: &str) -> Result<(), DnsAddrError> {
+        match self {
+            Publisher::Cloudflare(p) => p.create(content).await,
+            Publisher::Rfc2136(p) => p.create(content).await,
+            Publisher::Webhook(p) => p.create(content).await,
+        }
+    }
+
+    async fn delete(&self, record: &TxtRecord) -> Result<(), DnsAddrError> {
+        match self {
+            Publisher::Cloudflare(p) => p.delete(record).await,
+            Publisher::Rfc2136(p) => p.delete(record).await,
+            Publisher::Webhook(p) => p.delete(record).await,
+        }
+    }
+}
+
+/// What publishing an address does, worked out before changing anything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    /// Record to create, none if the address is already published
+    pub create: Option<String>,
+    /// This node's older records to delete
+    pub delete: Vec<TxtRecord>,
+    /// What was found, ie. records kept because they belong to other peers
+    pub notes: Vec<String>,
+}
+
+impl Plan {
+    /// The planned operations, one per line
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = self.notes.clone();
+        match &self.create {
+            Some(content) => lines.push(format!("🆕 Create {}", content)),
+            None => lines.push("✔ Nothing to create".to_string()),
+        }
+        for record in &self.delete {
+            lines.push(format!("🗑 Delete {}", record.content));
+        }
+        lines
+    }
+}
+
+/// The peer ID the multiaddr ends with, for a relayed address the node's own
+fn own_peer(multiaddr: &Multiaddr) -> Option<String> {
+    multiaddr
+        .iter()
+        .filter_map(|p| match p {
+            Protocol::P2p(peer) => Some(peer.to_string()),
+            _ => None,
+        })
+        .last()
+}
+
+/// Work out how to add a multiaddr as a TXT record, and which of this node's older
+/// records to remove beyond `max_records`.
+///
+/// Only records carrying the same `/p2p/` peer ID as the multiaddr are pruned, so peers
+/// sharing the name keep theirs. Records are pruned newest first by creation time.
+/// Records without one, from backends which can't tell, are kept in the order the backend
+/// listed them, after dated ones.
+pub async fn plan(
+    publisher: &impl DnsAddrPublisher,
+    multiaddr: &Multiaddr,
+    max_records: usize,
+) -> Result<Plan, DnsAddrError> {
+    let mut plan = Plan::default();
+
+    let mut existing_records = publisher.list().await.map_err(|e| {
+        tracing::error!("❌ Error listing TXT records: {:?}", e);
+        e
+    })?;
+    // stable, so undated records keep the backend's order
+    existing_records.sort_by(|a, b| b.created.cmp(&a.created));
+
+    // check to see if the multiaddr is already in an existing record, if so, nothing to do
+    if existing_records
+        .iter()
+        .any(|record| record.content.contains(&multiaddr.to_string()))
+    {
+        plan.notes.push(format!(
+            "🔍 Multiaddr already exists in TXT record: {:?}",
+            multiaddr
+        ));
+        return Ok(plan);
+    }
+
+    // turn the addr into a dnsaddr
+    plan.create = Some(format!("dnsaddr={}", multiaddr));
+
+    let Some(peer) = own_peer(multiaddr) else {
+        plan.notes.push(format!(
+            "⚠ No /p2p/ peer ID in {}, not pruning any of the {} records",
+            multiaddr,
+            existing_records.len()
+        ));
+        return Ok(plan);
+    };
+    let suffix = format!("/p2p/{}", peer);
+    let (own, others): (Vec<_>, Vec<_>) = existing_records
+        .into_iter()
+        .partition(|record| record.content.trim_end().ends_with(&suffix));
+    if !others.is_empty() {
+        plan.notes.push(format!(
+            "👥 Keeping {} records of other peers",
+            others.len()
+        ));
+    }
+
+    if own.len() <= max_records {
+        plan.notes.push(format!(
+            "📦 TXT records within limit (found {} of {})",
+            own.len(),
+            max_records
+        ));
+        return Ok(plan);
+    }
+
+    plan.notes.push(format!(
+        "🗑 Deleting old TXT records ({} > {})",
+        own.len(),
+        max_records
+    ));
+    // Delete all but most recent max_records
+    plan.delete = own.into_iter().skip(max_records).collect();
+    Ok(plan)
+}
+
+/// Carry out a plan, returning a log of what was done
+pub async fn apply(
+    publisher: &impl DnsAddrPublisher,
+    plan: &Plan,
+) -> Result<Vec<String>, DnsAddrError> {
+    let mut log = plan.notes.clone();
+    for msg in &log {
+        tracing::info!("{}", msg);
+    }
+
+    if let Some(content) = &plan.create {
+        publisher.create(content).await?;
+        let msg = format!("🆕 TXT record created successfully {:?}", content);
+        tracing::info!("{}", msg);
+        log.push(msg);
+    }
+
+    for record in &plan.delete {
+        publisher.delete(record).await?;
+        let created = record
+            .created
+            .map_or_else(|| "unknown".to_string(), |c| c.to_string());
+        let msg = format!("🗑 Deleted old TXT record [{}]: {:?}", created, record.id);
+        tracing::info!("{}", msg);
+        log.push(msg);
+    }
+
+    Ok(log)
+}
+
+/// Add a multiaddr as a TXT record, then remove this node's older records if there are
+/// more than `max_records`, see [plan]. Returns a log of what was done.
+pub async fn publish(
+    publisher: &impl DnsAddrPublisher,
+    multiaddr: &Multiaddr,
+    max_records: usize,
+) -> Result<Vec<String>, DnsAddrError> {
+    let plan = plan(publisher, multiaddr, max_records).await?;
+    apply(publisher, &plan).await
+}
+
+/// Peers and a mock HTTP server shared by the backend and resolver tests
+#[cfg(test)]
+pub(crate) mod test_util {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    /// The node's peer ID
+    pub(crate) const PEER: &str = "12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA";
+    /// Another peer publishing under the same name
+    pub(crate) const OTHER: &str = "QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N";
+
+    /// Answer each request with the next body, returning the URL of `path` on the server
+    /// and the requests received
+    pub(crate) async fn mock_server(
+        path: &str,
+        bodies: Vec<String>,
+    ) -> (String, JoinHandle<Vec<String>>) {
+        let (listener, url) = listen(path).await;
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for body in bodies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                requests.push(read_request(&mut stream).await);
+                respond(&mut stream, &body).await;
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    /// Answer every request with the body made from it, returning the URL of `path`
+    pub(crate) async fn mock_responder(
+        path: &str,
+        answer: impl Fn(&str) -> String + Send + 'static,
+    ) -> String {
+        let (listener, url) = listen(path).await;
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await;
+                respond(&mut stream, &answer(&request)).await;
+            }
+        });
+        url
+    }
+
+    async fn listen(path: &str) -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), path);
+        (listener, url)
+    }
+
+    async fn respond(stream: &mut TcpStream, body: &str) {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    /// The request head and body, head lowercased
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = vec![];
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                assert!(n > 0, "connection closed mid request");
+                continue;
+            };
+            let head = head.to_lowercase();
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .map_or(0, |len| len.trim().parse().unwrap());
+            if n == 0 || body.len() >= length {
+                return format!("{}\r\n\r\n{}", head, body);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::{OTHER, PEER};
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Publisher keeping its records in memory, each created a second after the last
+    #[derive(Default)]
+    struct Memory(Mutex<(i64, Vec<TxtRecord>)>);
+
+    impl DnsAddrPublisher for Memory {
+        async fn list(&self) -> Result<Vec<TxtRecord>, DnsAddrError> {
+            Ok(self.0.lock().unwrap().1.clone())
+        }
+
+        async fn create(&self, content: &str) -> Result<(), DnsAddrError> {
+            let (next, records) = &mut *self.0.lock().unwrap();
+            *next += 1;
+            records.push(TxtRecord {
+                id: next.to_string(),
+                content: content.to_string(),
+                created: DateTime::from_timestamp(*next, 0),
+            });
+            Ok(())
+        }
+
+        async fn delete(&self, record: &TxtRecord) -> Result<(), DnsAddrError> {
+            self.0.lock().unwrap().1.retain(|r| r.id != record.id);
+            Ok(())
+        }
+    }
+
+    fn addr(port: u16, peer: &str) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", port, peer)
+            .parse()
+            .unwrap()
+    }
+
+    async fn contents(memory: &Memory) -> Vec<String> {
+        let records = memory.list().await.unwrap();
+        records.into_iter().map(|r| r.content).collect()
+    }
+
+    #[tokio::test]
+    async fn test_publish_creates_then_prunes_own_records() {
+        let memory = Memory::default();
+        // another peer sharing the name
+        publish(&memory, &addr(9, OTHER), MAX_RECORDS)
+            .await
+            .unwrap();
+        for port in 1..=4 {
+            publish(&memory, &addr(port, PEER), MAX_RECORDS)
+                .await
+                .unwrap();
+        }
+
+        let expected: Vec<_> = [addr(9, OTHER), addr(2, PEER), addr(3, PEER), addr(4, PEER)]
+            .iter()
+            .map(|a| format!("dnsaddr={}", a))
+            .collect();
+        assert_eq!(contents(&memory).await, expected);
+
+        // publishing an address already there changes nothing
+        let log = publish(&memory, &addr(4, PEER), MAX_RECORDS).await.unwrap();
+        assert!(log[0].contains("already exists"));
+        assert_eq!(memory.list().await.unwrap().len(), 4);
+
+        // a dry run plans without changing anything
+        let plan = plan(&memory, &addr(5, PEER), 1).await.unwrap();
+        assert_eq!(plan.create, Some(format!("dnsaddr={}", addr(5, PEER))));
+        let deleted: Vec<_> = plan.delete.iter().map(|r| r.content.clone()).collect();
+        // newest first
+        assert_eq!(deleted, [expected[2].clone(), expected[1].clone()]);
+        assert_eq!(contents(&memory).await, expected);
+    }
+}
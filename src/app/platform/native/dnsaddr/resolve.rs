@@ -0,0 +1,285 @@
+//! Resolving `/dnsaddr` names natively, to check published records are discoverable.
+//!
+//! Like the web `fetch_dns_query`, but the TXT records come from a configurable
+//! DNS-over-HTTPS endpoint or the system name server, and nested `/dnsaddr` entries are
+//! followed.
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+
+use multiaddr::{Multiaddr, Protocol};
+
+use super::wire::{exchange, query_message, rcode, refused, txt_answers, RCODE_NXDOMAIN, TYPE_TXT};
+use super::DnsAddrError;
+
+/// DNS-over-HTTPS endpoint used until the user sets their own
+pub(crate) const DEFAULT_DOH: &str = "https://cloudflare-dns.com/dns-query";
+
+/// Most names looked up in one resolution, so loops end
+const MAX_LOOKUPS: usize = 32;
+
+/// Where TXT records are looked up
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Resolver {
+    /// JSON API of a DNS-over-HTTPS endpoint, ie. `https://cloudflare-dns.com/dns-query`
+    DnsOverHttps(String),
+    /// The first name server of `/etc/resolv.conf`, or [DEFAULT_DOH] where there is
+    /// none, such as on Windows
+    System,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::DnsOverHttps(DEFAULT_DOH.to_string())
+    }
+}
+
+/// DNS-over-HTTPS JSON answer
+#[derive(serde::Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u16,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohRecord>,
+}
+
+#[derive(serde::Deserialize)]
+struct DohRecord {
+    #[serde(rename = "type")]
+    rtype: u16,
+    data: String,
+}
+
+impl Resolver {
+    /// Contents of the TXT records of the name, none if it doesn't exist
+    pub async fn txt(&self, name: &str) -> Result<Vec<String>, DnsAddrError> {
+        match self {
+            Resolver::DnsOverHttps(url) => doh_txt(url, name).await,
+            Resolver::System => {
+                let server = match system_name_server() {
+                    Ok(server) => server,
+                    Err(e) => {
+                        tracing::debug!("No system name server, using {}: {}", DEFAULT_DOH, e);
+                        return doh_txt(DEFAULT_DOH, name).await;
+                    }
+                };
+                let id = rand::random();
+                let response = exchange(server, id, &query_message(id, name)?).await?;
+                match rcode(&response) {
+                    0 => txt_answers(&response),
+                    RCODE_NXDOMAIN => Ok(vec![]),
+                    rcode => Err(refused(rcode)),
+                }
+            }
+        }
+    }
+
+    /// Every multiaddr `/dnsaddr/{domain}` resolves to, following nested `/dnsaddr` entries
+    pub async fn resolve(&self, domain: &str) -> Result<Vec<Multiaddr>, DnsAddrError> {
+        let mut resolved = vec![];
+        let mut seen = HashSet::new();
+        let mut domains = VecDeque::from([domain.trim_end_matches('.').to_string()]);
+
+        while let Some(domain) = domains.pop_front() {
+            if !seen.insert(domain.clone()) {
+                continue;
+            }
+            if seen.len() > MAX_LOOKUPS {
+                tracing::warn!(
+                    "Stopped resolving /dnsaddr/{} after {} lookups",
+                    domain,
+                    MAX_LOOKUPS
+                );
+                break;
+            }
+
+            for txt in self.txt(&format!("_dnsaddr.{}", domain)).await? {
+                let Some(entry) = txt.strip_prefix("dnsaddr=") else {
+                    continue;
+                };
+                let Ok(addr) = entry.parse::<Multiaddr>() else {
+                    tracing::warn!("Skipping invalid dnsaddr entry {}", entry);
+                    continue;
+                };
+                match addr.iter().next() {
+                    Some(Protocol::Dnsaddr(nested)) => domains.push_back(nested.to_string()),
+                    _ => resolved.push(addr),
+                }
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// Contents of the TXT records of the name from a DNS-over-HTTPS endpoint
+async fn doh_txt(url: &str, name: &str) -> Result<Vec<String>, DnsAddrError> {
+    let body = reqwest::Client::new()
+        .get(url)
+        .query(&[("name", name), ("type", "TXT")])
+        .header(reqwest::header::ACCEPT, "application/dns-json")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let response: DohResponse = serde_json::from_str(&body)?;
+    match response.status {
+        0 => Ok(response
+            .answer
+            .iter()
+            .filter(|record| record.rtype == TYPE_TXT)
+            .map(|record| unquote(&record.data))
+            .collect()),
+        RCODE_NXDOMAIN => Ok(vec![]),
+        status => Err(refused(status)),
+    }
+}
+
+/// The name server the system resolves with
+#[cfg(windows)]
+fn system_name_server() -> Result<SocketAddr, DnsAddrError> {
+    Err(DnsAddrError::Config(
+        "Reading the system name server isn't supported on Windows".to_string(),
+    ))
+}
+
+/// The name server the system resolves with
+#[cfg(not(windows))]
+fn system_name_server() -> Result<SocketAddr, DnsAddrError> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|ip| ip.trim().parse::<std::net::IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| DnsAddrError::Config("No name server in /etc/resolv.conf".to_string()))
+}
+
+/// The text of a presentation format TXT record, its quoted strings joined
+fn unquote(data: &str) -> String {
+    if !data.starts_with('"') {
+        return data.to_string();
+    }
+    let mut text = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => text.extend(chars.next()),
+            c if quoted => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Whether two addresses are the same, one of them possibly without the `/p2p/` peer ID
+fn same_addr(a: &Multiaddr, b: &Multiaddr) -> bool {
+    let split = |addr: &Multiaddr| {
+        let mut addr = addr.clone();
+        let peer = match addr.iter().last() {
+            Some(Protocol::P2p(peer)) => Some(peer),
+            _ => None,
+        };
+        if peer.is_some() {
+            addr.pop();
+        }
+        (addr, peer)
+    };
+    let ((a, a_peer), (b, b_peer)) = (split(a), split(b));
+    a == b && (a_peer.is_none() || b_peer.is_none() || a_peer == b_peer)
+}
+
+/// What resolving the dnsaddr name found
+#[derive(Debug, Clone)]
+pub struct Verification {
+    /// The domain resolved, ie. `example.com` for `/dnsaddr/example.com`
+    pub domain: String,
+    /// Every address it resolves to
+    pub resolved: Vec<Multiaddr>,
+    /// Whether the node's address is among them
+    pub found: bool,
+}
+
+/// Resolve the domain of the TXT name and look for the node's address
+pub async fn verify(
+    resolver: &Resolver,
+    txt_name: &str,
+    addr: &Multiaddr,
+) -> Result<Verification, DnsAddrError> {
+    let domain = txt_name.strip_prefix("_dnsaddr.").unwrap_or(txt_name);
+    let resolved = resolver.resolve(domain).await?;
+    Ok(Verification {
+        domain: domain.to_string(),
+        found: resolved.iter().any(|r| same_addr(r, addr)),
+        resolved,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::native::dnsaddr::test_util::{mock_responder, PEER};
+
+    /// DNS-over-HTTPS server answering from `(name, TXT data)` pairs
+    async fn mock_doh(records: Vec<(&'static str, String)>) -> String {
+        mock_responder("/dns-query", move |request| {
+            let answer: Vec<_> = records
+                .iter()
+                .filter(|(name, _)| request.contains(&format!("name={}&", name)))
+                .map(|(_, data)| serde_json::json!({ "type": 16, "data": data }))
+                .collect();
+            serde_json::json!({ "Status": 0, "Answer": answer }).to_string()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_resolve_follows_nested_dnsaddr() {
+        let node: Multiaddr = format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", PEER)
+            .parse()
+            .unwrap();
+        let url = mock_doh(vec![
+            (
+                "_dnsaddr.example.com",
+                r#""dnsaddr=/dnsaddr/nodes.example.com""#.to_string(),
+            ),
+            (
+                "_dnsaddr.example.com",
+                r#""dnsaddr=/dnsaddr/example.com""#.to_string(),
+            ),
+            (
+                "_dnsaddr.nodes.example.com",
+                format!(r#""dnsaddr={}""#, node),
+            ),
+        ])
+        .await;
+        let resolver = Resolver::DnsOverHttps(url);
+
+        assert_eq!(
+            resolver.resolve("example.com").await.unwrap(),
+            [node.clone()]
+        );
+
+        // the node's address is found with or without its peer ID
+        let without_peer = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let verification = verify(&resolver, "_dnsaddr.example.com", &without_peer)
+            .await
+            .unwrap();
+        assert!(verification.found);
+        let other = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+        let verification = verify(&resolver, "_dnsaddr.example.com", &other)
+            .await
+            .unwrap();
+        assert!(!verification.found);
+    }
+
+    #[test]
+    fn test_unquote() {
+        assert_eq!(
+            unquote(r#""dnsaddr=/ip4/1.2.3.4" "/tcp/1""#),
+            "dnsaddr=/ip4/1.2.3.4/tcp/1"
+        );
+        assert_eq!(unquote(r#""a\"b""#), r#"a"b"#);
+        assert_eq!(unquote("plain"), "plain");
+    }
+}
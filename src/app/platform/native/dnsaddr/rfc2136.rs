@@ -0,0 +1,378 @@
+//! RFC 2136 dynamic update backend, for DNS servers such as BIND, Knot or PowerDNS.
+//!
+//! Records are listed with a plain TXT query to the server and added or removed with
+//! UPDATE messages over UDP, signed with a TSIG HMAC-SHA256 key (RFC 8945) if one is set.
+//! DNS keeps no creation time, so the time this node created each record is kept in the
+//! platform storage to prune the oldest. Records created elsewhere count as the oldest.
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::DateTime;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::wire::{
+    encode_name, exchange, header, query_message, rcode, refused, txt_answers, txt_rdata, CLASS_IN,
+    RCODE_NXDOMAIN, TYPE_TXT,
+};
+use super::{DnsAddrError, DnsAddrPublisher, TxtRecord};
+use crate::app::platform::storage::Storage;
+
+/// Storage key of when each record was created, by TXT name then content
+const CREATED_KEY: &str = "dnsaddr-rfc2136-created";
+
+/// How far the TSIG time may be off, in seconds
+const FUDGE: u16 = 300;
+
+const TYPE_SOA: u16 = 6;
+const TYPE_TSIG: u16 = 250;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const OPCODE_UPDATE: u16 = 5 << 11;
+const TSIG_ALGORITHM: &str = "hmac-sha256";
+
+/// DNS server accepting dynamic updates for the zone
+pub(crate) struct Rfc2136 {
+    /// Server address, `host:port`, port 53 if left out
+    pub server: String,
+    /// Zone being updated, ie. `example.com`
+    pub zone: String,
+    /// Name of the TXT records, ie. `_dnsaddr.example.com`
+    pub txt_name: String,
+    /// TTL of created records, in seconds
+    pub ttl: u32,
+    /// TSIG key name, updates are not signed if empty
+    pub key_name: String,
+    /// TSIG HMAC-SHA256 secret, base64 encoded
+    pub key_secret: String,
+    /// Where the creation time of each record is kept
+    pub storage: Arc<dyn Storage>,
+}
+
+impl Rfc2136 {
+    async fn server_addr(&self) -> Result<SocketAddr, DnsAddrError> {
+        let server = self.server.trim();
+        if server.is_empty() {
+            return Err(DnsAddrError::Config("DNS server is empty".to_string()));
+        }
+        if let Ok(addr) = server.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+        if let Ok(ip) = server.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, 53));
+        }
+        let has_port = server
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        let mut addrs = match has_port {
+            true => tokio::net::lookup_host(server).await?,
+            false => tokio::net::lookup_host((server, 53)).await?,
+        };
+        addrs
+            .next()
+            .ok_or_else(|| DnsAddrError::Config(format!("Can't resolve DNS server {}", server)))
+    }
+
+    /// Send the update, signed if there is a key, and check the server accepted it
+    async fn update(&self, class: u16, ttl: u32, content: &str) -> Result<(), DnsAddrError> {
+        let id = rand::random();
+        let mut message = update_message(id, &self.zone, &self.txt_name, class, ttl, content)?;
+        if !self.key_name.is_empty() {
+            let secret = BASE64
+                .decode(self.key_secret.trim())
+                .map_err(|e| DnsAddrError::Config(format!("Invalid TSIG secret: {}", e)))?;
+            let now = chrono::Utc::now().timestamp() as u64;
+            message = sign(message, &self.key_name, &secret, now)?;
+        }
+        let response = exchange(self.server_addr().await?, id, &message).await?;
+        match rcode(&response) {
+            0 => Ok(()),
+            rcode => Err(refused(rcode)),
+        }
+    }
+
+    /// When each record under the TXT name was created, by content
+    fn created(&self) -> BTreeMap<String, i64> {
+        self.all_created()
+            .remove(&self.txt_name)
+            .unwrap_or_default()
+    }
+
+    fn all_created(&self) -> BTreeMap<String, BTreeMap<String, i64>> {
+        match self.storage.get_string(CREATED_KEY) {
+            Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_default(),
+            Ok(None) => BTreeMap::new(),
+            Err(e) => {
+                tracing::warn!("Failed to read when DNS records were created: {:?}", e);
+                BTreeMap::new()
+            }
+        }
+    }
+
+    /// Change the creation times under the TXT name. Only affects pruning, so failing to
+    /// save is logged rather than failing the update.
+    fn update_created(&self, f: impl FnOnce(&mut BTreeMap<String, i64>)) {
+        let mut all = self.all_created();
+        let created = all.entry(self.txt_name.clone()).or_default();
+        f(created);
+        if created.is_empty() {
+            all.remove(&self.txt_name);
+        }
+        let saved = serde_json::to_string(&all)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                self.storage
+                    .set_string(CREATED_KEY, json)
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(e) = saved {
+            tracing::warn!("Failed to save when DNS records were created: {}", e);
+        }
+    }
+}
+
+impl DnsAddrPublisher for Rfc2136 {
+    async fn list(&self) -> Result<Vec<TxtRecord>, DnsAddrError> {
+        let id = rand::random();
+        let query = query_message(id, &self.txt_name)?;
+        let response = exchange(self.server_addr().await?, id, &query).await?;
+        match rcode(&response) {
+            0 => {}
+            RCODE_NXDOMAIN => return Ok(vec![]),
+            rcode => return Err(refused(rcode)),
+        }
+        let answers = txt_answers(&response)?;
+
+        // forget records which are gone
+        if self
+            .created()
+            .keys()
+            .any(|content| !answers.contains(content))
+        {
+            self.update_created(|created| created.retain(|content, _| answers.contains(content)));
+        }
+
+        let created = self.created();
+        Ok(answers
+            .into_iter()
+            .map(|content| TxtRecord {
+                // deleting by content is how RFC 2136 removes a single record
+                id: content.clone(),
+                created: created
+                    .get(&content)
+                    .and_then(|at| DateTime::from_timestamp(*at, 0)),
+                content,
+            })
+            .collect())
+    }
+
+    async fn create(&self, content: &str) -> Result<(), DnsAddrError> {
+        self.update(CLASS_IN, self.ttl, content).await?;
+        let now = chrono::Utc::now().timestamp();
+        self.update_created(|created| {
+            created.insert(content.to_string(), now);
+        });
+        Ok(())
+    }
+
+    async fn delete(&self, record: &TxtRecord) -> Result<(), DnsAddrError> {
+        self.update(CLASS_NONE, 0, &record.content).await?;
+        self.update_created(|created| {
+            created.remove(&record.content);
+        });
+        Ok(())
+    }
+}
+
+/// Update adding (class IN) or deleting (class NONE) the TXT record of the name
+fn update_message(
+    id: u16,
+    zone: &str,
+    name: &str,
+    class: u16,
+    ttl: u32,
+    content: &str,
+) -> Result<Vec<u8>, DnsAddrError> {
+    let mut message = header(id, OPCODE_UPDATE, [1, 0, 1, 0]);
+    // zone section
+    encode_name(&mut message, zone)?;
+    message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // update section
+    let rdata = txt_rdata(content);
+    encode_name(&mut message, name)?;
+    message.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    message.extend_from_slice(&class.to_be_bytes());
+    message.extend_from_slice(&ttl.to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(&rdata);
+    Ok(message)
+}
+
+/// Append a TSIG record signing the message with HMAC-SHA256
+fn sign(
+    mut message: Vec<u8>,
+    key_name: &str,
+    secret: &[u8],
+    time_signed: u64,
+) -> Result<Vec<u8>, DnsAddrError> {
+    let mut key = vec![];
+    encode_name(&mut key, key_name)?;
+    let mut algorithm = vec![];
+    encode_name(&mut algorithm, TSIG_ALGORITHM)?;
+    let time = &time_signed.to_be_bytes()[2..];
+
+    // the message, then the TSIG variables
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .map_err(|e| DnsAddrError::Config(format!("Invalid TSIG secret: {}", e)))?;
+    mac.update(&message);
+    mac.update(&key);
+    mac.update(&CLASS_ANY.to_be_bytes());
+    mac.update(&0u32.to_be_bytes()); // TTL
+    mac.update(&algorithm);
+    mac.update(time);
+    mac.update(&FUDGE.to_be_bytes());
+    mac.update(&0u16.to_be_bytes()); // error
+    mac.update(&0u16.to_be_bytes()); // other len
+    let mac = mac.finalize().into_bytes();
+
+    let mut rdata = algorithm;
+    rdata.extend_from_slice(time);
+    rdata.extend_from_slice(&FUDGE.to_be_bytes());
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&mac);
+    rdata.extend_from_slice(&message[..2]); // original ID
+    rdata.extend_from_slice(&0u16.to_be_bytes()); // error
+    rdata.extend_from_slice(&0u16.to_be_bytes()); // other len
+
+    message.extend_from_slice(&key);
+    message.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+    message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    message.extend_from_slice(&0u32.to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(&rdata);
+
+    let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::native::dnsaddr::publish;
+    use crate::app::platform::native::dnsaddr::test_util::PEER;
+    use crate::app::platform::native::dnsaddr::wire::{skip_name, FLAG_RD};
+    use crate::app::platform::storage::MemoryStore;
+    use multiaddr::Multiaddr;
+    use tokio::net::UdpSocket;
+
+    /// Answer a TXT query with the records, then accept every update, returning the updates
+    async fn mock_server(
+        records: Vec<String>,
+        updates: usize,
+    ) -> (String, tokio::task::JoinHandle<Vec<Vec<u8>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0; 65535];
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let query = &buf[..n];
+            let mut answer = header(
+                u16::from_be_bytes([query[0], query[1]]),
+                0x8000 | FLAG_RD,
+                [1, records.len() as u16, 0, 0],
+            );
+            // the question, without the EDNS0 record
+            let question = skip_name(query, 12).unwrap() + 4;
+            answer.extend_from_slice(&query[12..question]);
+            for record in &records {
+                let rdata = txt_rdata(record);
+                answer.extend_from_slice(&[0xC0, 12]);
+                answer.extend_from_slice(&TYPE_TXT.to_be_bytes());
+                answer.extend_from_slice(&CLASS_IN.to_be_bytes());
+                answer.extend_from_slice(&300u32.to_be_bytes());
+                answer.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                answer.extend_from_slice(&rdata);
+            }
+            socket.send_to(&answer, peer).await.unwrap();
+
+            let mut received = vec![];
+            for _ in 0..updates {
+                let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let update = buf[..n].to_vec();
+                let ok = header(
+                    u16::from_be_bytes([update[0], update[1]]),
+                    0x8000 | OPCODE_UPDATE,
+                    [0; 4],
+                );
+                socket.send_to(&ok, peer).await.unwrap();
+                received.push(update);
+            }
+            received
+        });
+        (addr, server)
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[tokio::test]
+    async fn test_rfc2136_against_mock_server() {
+        let record = |port: u16| format!("dnsaddr=/ip4/1.1.1.1/tcp/{}/p2p/{}", port, PEER);
+        let old = record(1);
+        // listed newest first, but the stored creation times say otherwise
+        let (server, updates) = mock_server(vec![old.clone(), record(2)], 2).await;
+        let storage = Arc::new(MemoryStore::default());
+        let created = serde_json::json!({
+            "_dnsaddr.example.com": { old.clone(): 100, record(2): 200, record(9): 50 }
+        });
+        storage
+            .set_string(CREATED_KEY, created.to_string())
+            .unwrap();
+        let publisher = Rfc2136 {
+            server,
+            zone: "example.com".to_string(),
+            txt_name: "_dnsaddr.example.com".to_string(),
+            ttl: 60,
+            key_name: "update-key".to_string(),
+            key_secret: BASE64.encode(b"secret"),
+            storage,
+        };
+
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", PEER)
+            .parse()
+            .unwrap();
+        publish(&publisher, &addr, 1).await.unwrap();
+
+        let updates = updates.await.unwrap();
+        let [create, delete] = &updates[..] else {
+            panic!("expected two updates");
+        };
+        // add with class IN, delete with class NONE, both signed
+        let mut added = TYPE_TXT.to_be_bytes().to_vec();
+        added.extend_from_slice(&CLASS_IN.to_be_bytes());
+        added.extend_from_slice(&60u32.to_be_bytes());
+        assert!(contains(create, &added));
+        assert!(contains(create, &txt_rdata(&format!("dnsaddr={}", addr))));
+        let mut removed = TYPE_TXT.to_be_bytes().to_vec();
+        removed.extend_from_slice(&CLASS_NONE.to_be_bytes());
+        assert!(contains(delete, &removed));
+        assert!(contains(delete, &txt_rdata(&old)));
+        for update in &updates {
+            assert_eq!(u16::from_be_bytes([update[2], update[3]]), OPCODE_UPDATE);
+            assert_eq!(&update[10..12], &[0, 1]);
+            assert!(contains(update, b"\x0bhmac-sha256\x00"));
+        }
+
+        // the created record is remembered, the deleted and vanished ones forgotten
+        let created = publisher.created();
+        assert_eq!(
+            created.keys().collect::<Vec<_>>(),
+            [&record(2), &format!("dnsaddr={}", addr)]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Generic HTTP webhook backend, for DNS providers reached through a small relay service.
+//!
+//! The service manages the TXT records under one name:
+//! - `GET {url}` lists them as `[{"id": "…", "content": "dnsaddr=…", "created": 1700000000}]`,
+//!   `created` being optional unix seconds
+//! - `POST {url}` with `{"content": "dnsaddr=…"}` creates one
+//! - `DELETE {url}/{id}` deletes one
+//!
+//! Requests carry the token, if any, as a bearer token.
+use chrono::DateTime;
+
+use super::{DnsAddrError, DnsAddrPublisher, TxtRecord};
+
+/// Record as listed by the webhook
+#[derive(serde::Deserialize)]
+struct WebhookRecord {
+    id: String,
+    content: String,
+    #[serde(default)]
+    created: Option<i64>,
+}
+
+/// HTTP service the TXT records are published through
+pub(crate) struct Webhook {
+    /// Base URL, ie. `https://dns.example.com/records`
+    pub url: String,
+    /// Bearer token, sent if not empty
+    pub token: String,
+}
+
+impl Webhook {
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let request = reqwest::Client::new().request(method, url);
+        match self.token.is_empty() {
+            true => request,
+            false => request.bearer_auth(&self.token),
+        }
+    }
+
+    fn base(&self) -> Result<&str, DnsAddrError> {
+        match self.url.trim_end_matches('/') {
+            "" => Err(DnsAddrError::Config("Webhook URL is empty".to_string())),
+            url => Ok(url),
+        }
+    }
+}
+
+impl DnsAddrPublisher for Webhook {
+    async fn list(&self) -> Result<Vec<TxtRecord>, DnsAddrError> {
+        let body = self
+            .request(reqwest::Method::GET, self.base()?)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let records: Vec<WebhookRecord> = serde_json::from_str(&body)?;
+        Ok(records
+            .into_iter()
+            .map(|record| TxtRecord {
+                id: record.id,
+                content: record.content,
+                created: record.created.and_then(|c| DateTime::from_timestamp(c, 0)),
+            })
+            .collect())
+    }
+
+    async fn create(&self, content: &str) -> Result<(), DnsAddrError> {
+        self.request(reqwest::Method::POST, self.base()?)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::json!({ "content": content }).to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn delete(&self, record: &TxtRecord) -> Result<(), DnsAddrError> {
+        // the id is one path segment, whatever it contains
+        let mut url = reqwest::Url::parse(self.base()?)
+            .map_err(|e| DnsAddrError::Config(format!("Invalid webhook URL: {}", e)))?;
+        url.path_segments_mut()
+            .map_err(|()| DnsAddrError::Config("Invalid webhook URL".to_string()))?
+            .push(&record.id);
+        self.request(reqwest::Method::DELETE, url.as_str())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::native::dnsaddr::publish;
+    use crate::app::platform::native::dnsaddr::test_util::{mock_server, OTHER, PEER};
+    use multiaddr::Multiaddr;
+
+    #[tokio::test]
+    async fn test_webhook_against_mock_server() {
+        let record = |id: &str, peer: &str, port: u16, created: i64| {
+            let content = format!("dnsaddr=/ip4/1.1.1.1/tcp/{}/p2p/{}", port, peer);
+            serde_json::json!({ "id": id, "content": content, "created": created })
+        };
+        let listed = serde_json::json!([
+            record("a/b c", PEER, 1, 100),
+            record("b", PEER, 2, 300),
+            record("c", PEER, 3, 200),
+            // another peer's record stays, however old
+            record("d", OTHER, 4, 50),
+        ]);
+        let (url, server) =
+            mock_server("/records", vec![listed.to_string(), "".into(), "".into()]).await;
+        let webhook = Webhook {
+            url,
+            token: "secret".to_string(),
+        };
+
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", PEER)
+            .parse()
+            .unwrap();
+        publish(&webhook, &addr, 2).await.unwrap();
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("get /records "));
+        assert!(requests[0].contains("authorization: bearer secret"));
+        assert!(requests[1].starts_with("post /records "));
+        let created = serde_json::json!({ "content": format!("dnsaddr={}", addr) });
+        assert!(requests[1].ends_with(&created.to_string()));
+        // the oldest record goes
+        assert!(requests[2].starts_with("delete /records/a%2fb%20c "));
+        assert_eq!(requests.len(), 3);
+    }
+}
@@ -0,0 +1,278 @@
+//! DNS messages on the wire (RFC 1035), shared by the RFC 2136 backend and the resolver.
+//!
+//! Queries advertise a larger UDP payload with EDNS0 (RFC 6891), and an answer which is
+//! still truncated is asked again over TCP.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use super::DnsAddrError;
+
+/// How long to wait for the server to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(super) const TYPE_TXT: u16 = 16;
+pub(super) const CLASS_IN: u16 = 1;
+pub(super) const FLAG_RD: u16 = 1 << 8;
+const FLAG_TC: u16 = 1 << 9;
+pub(super) const RCODE_NXDOMAIN: u16 = 3;
+const TYPE_OPT: u16 = 41;
+
+/// UDP payload size advertised with EDNS0
+const EDNS_PAYLOAD: u16 = 4096;
+
+/// Send the message and wait for the answer to it, over TCP if the UDP answer was
+/// truncated
+pub(super) async fn exchange(
+    server: SocketAddr,
+    id: u16,
+    message: &[u8],
+) -> Result<Vec<u8>, DnsAddrError> {
+    let response = exchange_udp(server, id, message).await?;
+    let flags = u16::from_be_bytes([response[2], response[3]]);
+    match flags & FLAG_TC != 0 {
+        true => exchange_tcp(server, id, message).await,
+        false => Ok(response),
+    }
+}
+
+async fn exchange_udp(
+    server: SocketAddr,
+    id: u16,
+    message: &[u8],
+) -> Result<Vec<u8>, DnsAddrError> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(message).await?;
+
+    let mut buf = vec![0; 65535];
+    let response = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            // ignore stray answers to other messages
+            if n >= 12 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                return Ok::<_, std::io::Error>(buf[..n].to_vec());
+            }
+        }
+    })
+    .await
+    .map_err(|_| DnsAddrError::Dns(format!("{} did not answer", server)))??;
+    Ok(response)
+}
+
+/// The message over TCP, each way prefixed with its length
+async fn exchange_tcp(
+    server: SocketAddr,
+    id: u16,
+    message: &[u8],
+) -> Result<Vec<u8>, DnsAddrError> {
+    let response = tokio::time::timeout(TIMEOUT, async {
+        let mut stream = TcpStream::connect(server).await?;
+        let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(message);
+        stream.write_all(&framed).await?;
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut response = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    })
+    .await
+    .map_err(|_| DnsAddrError::Dns(format!("{} did not answer over TCP", server)))??;
+
+    if response.len() < 12 || u16::from_be_bytes([response[0], response[1]]) != id {
+        return Err(malformed());
+    }
+    Ok(response)
+}
+
+/// The response code of the answer
+pub(super) fn rcode(response: &[u8]) -> u16 {
+    u16::from_be_bytes([response[2], response[3]]) & 0xF
+}
+
+/// Error for a response code other than success
+pub(super) fn refused(rcode: u16) -> DnsAddrError {
+    let name = match rcode {
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => "an unknown error",
+    };
+    DnsAddrError::Dns(format!("The server answered {}", name))
+}
+
+/// Message header with the counts of question, answer, authority and additional records
+pub(super) fn header(id: u16, flags: u16, counts: [u16; 4]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(512);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&flags.to_be_bytes());
+    for count in counts {
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+    message
+}
+
+/// Append the name in wire format, lowercased
+pub(super) fn encode_name(message: &mut Vec<u8>, name: &str) -> Result<(), DnsAddrError> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsAddrError::Config(format!("Invalid DNS name {}", name)));
+        }
+        message.push(label.len() as u8);
+        message.extend(label.bytes().map(|b| b.to_ascii_lowercase()));
+    }
+    message.push(0);
+    Ok(())
+}
+
+/// TXT record data, the content split in strings of at most 255 bytes
+pub(super) fn txt_rdata(content: &str) -> Vec<u8> {
+    let mut rdata = vec![];
+    for chunk in content.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    rdata
+}
+
+/// Query for the TXT records of the name, with an EDNS0 OPT record
+pub(super) fn query_message(id: u16, name: &str) -> Result<Vec<u8>, DnsAddrError> {
+    let mut message = header(id, FLAG_RD, [1, 0, 0, 1]);
+    encode_name(&mut message, name)?;
+    message.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // root name, the payload size in place of the class, no extended flags or options
+    message.push(0);
+    message.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    message.extend_from_slice(&EDNS_PAYLOAD.to_be_bytes());
+    message.extend_from_slice(&0u32.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    Ok(message)
+}
+
+/// Position after the name starting at `pos`, following no pointers
+pub(super) fn skip_name(message: &[u8], mut pos: usize) -> Result<usize, DnsAddrError> {
+    loop {
+        let len = *message.get(pos).ok_or_else(malformed)? as usize;
+        match len {
+            0 => return Ok(pos + 1),
+            // compression pointer, ends the name
+            l if l & 0xC0 == 0xC0 => return Ok(pos + 2),
+            l => pos += l + 1,
+        }
+    }
+}
+
+pub(super) fn malformed() -> DnsAddrError {
+    DnsAddrError::Dns("Malformed answer".to_string())
+}
+
+/// Contents of the TXT records in the answer section
+pub(super) fn txt_answers(message: &[u8]) -> Result<Vec<String>, DnsAddrError> {
+    let count = |at: usize| -> Result<usize, DnsAddrError> {
+        let bytes = message.get(at..at + 2).ok_or_else(malformed)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+    };
+    let questions = count(4)?;
+    let answers = count(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+
+    let mut contents = vec![];
+    for _ in 0..answers {
+        pos = skip_name(message, pos)?;
+        let rtype = count(pos)? as u16;
+        let rdlength = count(pos + 8)?;
+        pos += 10;
+        let rdata = message.get(pos..pos + rdlength).ok_or_else(malformed)?;
+        pos += rdlength;
+        if rtype != TYPE_TXT {
+            continue;
+        }
+
+        let mut content = vec![];
+        let mut at = 0;
+        while at < rdata.len() {
+            let len = rdata[at] as usize;
+            let chunk = rdata.get(at + 1..at + 1 + len).ok_or_else(malformed)?;
+            content.extend_from_slice(chunk);
+            at += 1 + len;
+        }
+        contents.push(String::from_utf8_lossy(&content).to_string());
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_txt_is_split() {
+        let content = "a".repeat(300);
+        let rdata = txt_rdata(&content);
+        assert_eq!((rdata[0], rdata[256]), (255, 45));
+
+        let mut answer = header(1, 0x8000, [0, 1, 0, 0]);
+        encode_name(&mut answer, "_dnsaddr.example.com").unwrap();
+        answer.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        answer.extend_from_slice(&CLASS_IN.to_be_bytes());
+        answer.extend_from_slice(&0u32.to_be_bytes());
+        answer.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        answer.extend_from_slice(&rdata);
+        assert_eq!(txt_answers(&answer).unwrap(), [content]);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answer_is_asked_over_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = udp.local_addr().unwrap();
+        let tcp = tokio::net::TcpListener::bind(server).await.unwrap();
+        let query = query_message(7, "_dnsaddr.example.com").unwrap();
+        assert_eq!(&query[10..12], &[0, 1]);
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            let (n, peer) = udp.recv_from(&mut buf).await.unwrap();
+            let truncated = header(
+                u16::from_be_bytes([buf[0], buf[1]]),
+                0x8000 | FLAG_TC,
+                [0; 4],
+            );
+            assert!(n > 12);
+            udp.send_to(&truncated, peer).await.unwrap();
+
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).await.unwrap();
+            let answer = header(u16::from_be_bytes([query[0], query[1]]), 0x8000, [0; 4]);
+            let mut framed = (answer.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&answer);
+            stream.write_all(&framed).await.unwrap();
+        });
+
+        let response = exchange(server, 7, &query).await.unwrap();
+        let flags = u16::from_be_bytes([response[2], response[3]]);
+        assert_eq!(flags & FLAG_TC, 0);
+    }
+}
@@ -0,0 +1,293 @@
+//! Bounded log of node events, with filters and JSON lines export.
+//!
+//! Keeps the latest [CAPACITY] entries, dropping the oldest.
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+
+use chrono::TimeZone as _;
+use peerpiper::core::events::PublicEvent;
+use serde::{Deserialize, Serialize};
+
+/// Most entries kept, older ones are dropped
+pub(crate) const CAPACITY: usize = 2_000;
+
+/// Longest message text kept in an entry, in characters
+pub(crate) const MAX_TEXT: usize = 512;
+
+/// What a log entry is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EventKind {
+    ListenAddr,
+    Message,
+    Pong,
+    Connection,
+    Plugin,
+    Other,
+}
+
+impl EventKind {
+    pub(crate) const ALL: [EventKind; 6] = [
+        EventKind::ListenAddr,
+        EventKind::Message,
+        EventKind::Pong,
+        EventKind::Connection,
+        EventKind::Plugin,
+        EventKind::Other,
+    ];
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EventKind::ListenAddr => "Listen address",
+            EventKind::Message => "Message",
+            EventKind::Pong => "Pong",
+            EventKind::Connection => "Connection",
+            EventKind::Plugin => "Plugin",
+            EventKind::Other => "Other",
+        })
+    }
+}
+
+/// A single logged event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LogEntry {
+    /// Unix timestamp in milliseconds
+    pub timestamp: i64,
+    pub kind: EventKind,
+    /// The peer the event came from, if any
+    pub peer: Option<String>,
+    pub text: String,
+}
+
+impl LogEntry {
+    pub(crate) fn new(kind: EventKind, peer: Option<String>, text: impl Into<String>) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            kind,
+            peer,
+            text: text.into(),
+        }
+    }
+
+    /// The entry for a node event.
+    /// Messages which aren't UTF-8 are logged by size, long ones cut at [MAX_TEXT].
+    pub(crate) fn from_event(event: &PublicEvent) -> Self {
+        match event {
+            PublicEvent::ListenAddr { address, .. } => {
+                Self::new(EventKind::ListenAddr, None, format!("📡 {}", address))
+            }
+            PublicEvent::Message { topic, data, peer } => {
+                let text = match std::str::from_utf8(data) {
+                    Ok(text) => format!("📬 {}: {}", topic, truncate(text, data.len())),
+                    Err(_) => format!("📬 {}: {} bytes", topic, data.len()),
+                };
+                Self::new(EventKind::Message, Some(peer.to_string()), text)
+            }
+            PublicEvent::Pong { peer, rtt } => Self::new(
+                EventKind::Pong,
+                Some(peer.to_string()),
+                format!("🏓 Pong {}ms", rtt),
+            ),
+            PublicEvent::NewConnection { peer } => Self::new(
+                EventKind::Connection,
+                Some(peer.to_string()),
+                "🔗 Connected",
+            ),
+            other => Self::new(EventKind::Other, None, format!("{:?}", other)),
+        }
+    }
+
+    /// Local time of the entry, for display
+    pub(crate) fn time(&self) -> String {
+        chrono::Local
+            .timestamp_millis_opt(self.timestamp)
+            .single()
+            .map(|time| time.format("%H:%M:%S%.3f").to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// The text cut at [MAX_TEXT] characters, noting the full size if it was
+fn truncate(text: &str, size: usize) -> String {
+    match text.char_indices().nth(MAX_TEXT) {
+        Some((end, _)) => format!("{}… ({} bytes)", &text[..end], size),
+        None => text.to_string(),
+    }
+}
+
+/// Which entries to show
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LogFilter {
+    pub kinds: BTreeSet<EventKind>,
+    /// Only entries from this peer, if set
+    pub peer: Option<String>,
+    /// Case insensitive text to search for in the entry and its peer
+    pub search: String,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            kinds: EventKind::ALL.into_iter().collect(),
+            peer: None,
+            search: String::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    pub(crate) fn matches(&self, entry: &LogEntry) -> bool {
+        if !self.kinds.contains(&entry.kind) {
+            return false;
+        }
+        if self.peer.is_some() && entry.peer != self.peer {
+            return false;
+        }
+        if self.search.is_empty() {
+            return true;
+        }
+        let search = self.search.to_lowercase();
+        entry.text.to_lowercase().contains(&search)
+            || entry
+                .peer
+                .as_ref()
+                .is_some_and(|peer| peer.to_lowercase().contains(&search))
+    }
+}
+
+/// Ring buffer of the latest node events
+#[derive(Debug, Clone)]
+pub(crate) struct EventLog {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::with_capacity(CAPACITY)
+    }
+}
+
+impl EventLog {
+    /// A log keeping the latest `capacity` entries, at least one
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            entries: VecDeque::with_capacity(capacity.min(CAPACITY)),
+            capacity,
+        }
+    }
+
+    /// Add the entry, dropping the oldest if full
+    pub(crate) fn push(&mut self, entry: LogEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Entries matching the filter, oldest first
+    pub(crate) fn filtered<'a>(
+        &'a self,
+        filter: &'a LogFilter,
+    ) -> impl DoubleEndedIterator<Item = &'a LogEntry> + 'a {
+        self.entries.iter().filter(|entry| filter.matches(entry))
+    }
+
+    /// Copies of the entries matching the filter in the range, newest first, so only the
+    /// rows shown are copied
+    pub(crate) fn newest_page(&self, filter: &LogFilter, rows: Range<usize>) -> Vec<LogEntry> {
+        self.filtered(filter)
+            .rev()
+            .skip(rows.start)
+            .take(rows.len())
+            .cloned()
+            .collect()
+    }
+
+    /// Peers seen in the log, for the peer filter
+    pub(crate) fn peers(&self) -> BTreeSet<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.peer.clone())
+            .collect()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Entries matching the filter as JSON lines, oldest first
+    pub(crate) fn to_json_lines(&self, filter: &LogFilter) -> Result<String, serde_json::Error> {
+        let mut lines = String::new();
+        for entry in self.filtered(filter) {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_is_bounded_and_filtered() {
+        let mut log = EventLog::with_capacity(3);
+        for i in 0..4 {
+            let peer = format!("peer{}", i % 2);
+            log.push(LogEntry::new(
+                EventKind::Pong,
+                Some(peer),
+                format!("pong {}", i),
+            ));
+        }
+        log.push(LogEntry::new(EventKind::Plugin, None, "Loaded Counter"));
+
+        // the two oldest were dropped
+        let all = LogFilter::default();
+        let texts: Vec<_> = log.filtered(&all).map(|e| e.text.as_str()).collect();
+        assert_eq!(texts, ["pong 2", "pong 3", "Loaded Counter"]);
+        assert_eq!(log.peers().len(), 2);
+
+        let by_peer = LogFilter {
+            peer: Some("peer1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(log.filtered(&by_peer).count(), 1);
+
+        let search = LogFilter {
+            search: "COUNTER".to_string(),
+            ..Default::default()
+        };
+        let lines = log.to_json_lines(&search).unwrap();
+        let entry: LogEntry = serde_json::from_str(lines.trim_end()).unwrap();
+        assert_eq!(entry.kind, EventKind::Plugin);
+
+        let mut no_pongs = LogFilter::default();
+        no_pongs.kinds.remove(&EventKind::Pong);
+        assert_eq!(log.filtered(&no_pongs).count(), 1);
+
+        let page = log.newest_page(&all, 1..3);
+        let texts: Vec<_> = page.iter().map(|e| e.text.as_str()).collect();
+        assert_eq!(texts, ["pong 3", "pong 2"]);
+
+        // an empty log still keeps the latest entry
+        let mut log = EventLog::with_capacity(0);
+        log.push(LogEntry::new(EventKind::Plugin, None, "kept"));
+        assert_eq!(log.filtered(&all).count(), 1);
+    }
+
+    #[test]
+    fn test_long_messages_are_truncated() {
+        let long = "é".repeat(MAX_TEXT + 10);
+        let text = truncate(&long, long.len());
+        assert!(text.starts_with(&"é".repeat(MAX_TEXT)));
+        assert!(text.ends_with(&format!("… ({} bytes)", long.len())));
+        assert_eq!(truncate("short", 5), "short");
+    }
+}
@@ -0,0 +1,308 @@
+//! Peer and connection dashboard.
+//!
+//! Built from node events: connections, the protocols each peer was seen using,
+//! round trip times from pongs and the node's listen addresses.
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use multiaddr::Multiaddr;
+use peerpiper::core::events::{AllCommands, PublicEvent};
+
+use crate::app::network::short_peer;
+use crate::app::platform;
+use crate::app::rdx_runner::RdxRunner;
+
+/// Most round trip times kept per peer
+pub(crate) const RTT_HISTORY: usize = 120;
+
+/// Most disconnected peers kept, the longest gone are dropped first
+pub(crate) const MAX_DISCONNECTED: usize = 64;
+
+/// What we know about a peer
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerInfo {
+    pub connected: bool,
+    /// Protocols the peer was seen using
+    pub protocols: BTreeSet<&'static str>,
+    /// Round trip times in milliseconds, oldest first
+    pub rtts: VecDeque<f32>,
+    /// Number of the last event about the peer, to find the longest gone
+    seen: u64,
+}
+
+impl PeerInfo {
+    fn rtt(&mut self, rtt: f32) {
+        if self.rtts.len() == RTT_HISTORY {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(rtt);
+    }
+}
+
+/// Peers and listen addresses, updated from node events
+#[derive(Debug, Default)]
+pub(crate) struct Peers {
+    /// Connected peers, and up to [MAX_DISCONNECTED] others
+    peers: BTreeMap<String, PeerInfo>,
+    /// Events recorded so far
+    events: u64,
+    /// Every address the node listens on, in the order they were reported
+    listen_addrs: Vec<Multiaddr>,
+}
+
+impl Peers {
+    /// Update from a node event
+    pub(crate) fn record(&mut self, event: &PublicEvent) {
+        match event {
+            PublicEvent::ListenAddr { address, .. } => {
+                if !self.listen_addrs.contains(address) {
+                    self.listen_addrs.push(address.clone());
+                }
+            }
+            PublicEvent::NewConnection { peer } => {
+                self.peer(peer.to_string()).connected = true;
+            }
+            PublicEvent::ConnectionClosed { peer, .. } => {
+                self.hung_up(&peer.to_string());
+            }
+            PublicEvent::Pong { peer, rtt } => {
+                let info = self.peer(peer.to_string());
+                info.connected = true;
+                info.protocols.insert("ping");
+                info.rtt(*rtt as f32);
+            }
+            PublicEvent::Message { peer, .. } => {
+                let info = self.peer(peer.to_string());
+                info.protocols.insert("gossipsub");
+            }
+            _ => {}
+        }
+        self.evict();
+    }
+
+    /// Mark the peer as disconnected, keeping its history until it's evicted
+    pub(crate) fn hung_up(&mut self, peer: &str) {
+        self.events += 1;
+        if let Some(info) = self.peers.get_mut(peer) {
+            info.connected = false;
+            info.seen = self.events;
+        }
+        self.evict();
+    }
+
+    /// Drop the disconnected peers gone longest, beyond [MAX_DISCONNECTED]
+    fn evict(&mut self) {
+        let disconnected = self.peers.values().filter(|info| !info.connected).count();
+        for _ in MAX_DISCONNECTED..disconnected {
+            let oldest = self
+                .peers
+                .iter()
+                .filter(|(_, info)| !info.connected)
+                .min_by_key(|(_, info)| info.seen)
+                .map(|(peer, _)| peer.clone());
+            if let Some(peer) = oldest {
+                self.peers.remove(&peer);
+            }
+        }
+    }
+
+    pub(crate) fn listen_addrs(&self) -> &[Multiaddr] {
+        &self.listen_addrs
+    }
+
+    fn peer(&mut self, peer: String) -> &mut PeerInfo {
+        self.events += 1;
+        let info = self.peers.entry(peer).or_default();
+        info.seen = self.events;
+        info
+    }
+}
+
+/// Window with the connected peers, their round trip times and listen addresses,
+/// with dial and hang up controls
+#[derive(Default)]
+pub(crate) struct PeerDashboard {
+    pub(crate) open: bool,
+    pub(crate) peers: Arc<Mutex<Peers>>,
+    /// Multiaddr to dial, as typed
+    dial_addr: String,
+    /// Show peers which are no longer connected
+    show_disconnected: bool,
+    /// Outcome of the last dial or hang up
+    status: Arc<Mutex<Option<String>>>,
+}
+
+impl PeerDashboard {
+    pub(crate) fn show(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        if !self.open {
+            return;
+        }
+
+        let mut open = self.open;
+        let mut command = None;
+        egui::Window::new("Peers")
+            .open(&mut open)
+            .default_width(420.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.dial_addr)
+                            .hint_text("/ip4/…/p2p/…")
+                            .desired_width(280.0),
+                    );
+                    if ui.button("Dial").clicked() {
+                        match self.dial_addr.trim().parse::<Multiaddr>() {
+                            Ok(addr) => command = Some(AllCommands::Dial(addr)),
+                            Err(e) => {
+                                self.status
+                                    .lock()
+                                    .unwrap()
+                                    .replace(format!("Invalid multiaddr: {}", e));
+                            }
+                        }
+                    }
+                });
+                if let Some(status) = self.status.lock().unwrap().as_ref() {
+                    ui.label(status);
+                }
+
+                let peers = self.peers.lock().unwrap();
+
+                ui.separator();
+                ui.collapsing(
+                    format!("Listen addresses ({})", peers.listen_addrs().len()),
+                    |ui| {
+                        for addr in peers.listen_addrs() {
+                            ui.horizontal(|ui| {
+                                ui.monospace(addr.to_string());
+                                if ui.small_button("📋").on_hover_text("Copy").clicked() {
+                                    ui.ctx().copy_text(addr.to_string());
+                                }
+                            });
+                        }
+                    },
+                );
+
+                ui.separator();
+                ui.checkbox(&mut self.show_disconnected, "Show disconnected peers");
+                let mut hang_up = None;
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    egui::Grid::new("peers")
+                        .num_columns(4)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for (peer, info) in &peers.peers {
+                                if !info.connected && !self.show_disconnected {
+                                    continue;
+                                }
+                                ui.monospace(short_peer(peer)).on_hover_text(peer);
+                                ui.label(
+                                    info.protocols
+                                        .iter()
+                                        .copied()
+                                        .collect::<Vec<_>>()
+                                        .join(", "),
+                                );
+                                ui.horizontal(|ui| rtt_chart(ui, &info.rtts));
+                                if info.connected && ui.button("Hang up").clicked() {
+                                    hang_up = Some(peer.clone());
+                                }
+                                ui.end_row();
+                            }
+                        });
+                });
+
+                if let Some(peer) = hang_up {
+                    command = Some(AllCommands::HangUp { peer_id: peer });
+                }
+            });
+        self.open = open;
+
+        if let Some(command) = command {
+            // the peer only counts as hung up once the node did it
+            let hanging_up = match &command {
+                AllCommands::HangUp { peer_id } => Some(peer_id.clone()),
+                _ => None,
+            };
+            let task = rdx_runner.order(command);
+            let peers = self.peers.clone();
+            let status = self.status.clone();
+            let ctx = ctx.clone();
+            platform::spawn(async move {
+                let message = match task.await {
+                    Ok(_) => {
+                        if let Some(peer) = hanging_up {
+                            peers.lock().unwrap().hung_up(&peer);
+                        }
+                        "Done".to_string()
+                    }
+                    Err(e) => {
+                        tracing::error!("Peer command failed: {:?}", e);
+                        format!("{}", e)
+                    }
+                };
+                status.lock().unwrap().replace(message);
+                ctx.request_repaint();
+            });
+        }
+    }
+}
+
+/// Sparkline of the round trip times, with the latest one
+fn rtt_chart(ui: &mut egui::Ui, rtts: &VecDeque<f32>) {
+    let (rect, response) = ui.allocate_exact_size(egui::vec2(120.0, 24.0), egui::Sense::hover());
+    let Some(latest) = rtts.back() else {
+        ui.label("–");
+        return;
+    };
+
+    let max = rtts.iter().copied().fold(1.0, f32::max);
+    let step = rect.width() / (RTT_HISTORY - 1) as f32;
+    let points: Vec<egui::Pos2> = rtts
+        .iter()
+        .enumerate()
+        .map(|(i, rtt)| {
+            egui::pos2(
+                rect.left() + i as f32 * step,
+                rect.bottom() - rect.height() * rtt / max,
+            )
+        })
+        .collect();
+    let stroke = egui::Stroke::new(1.5, ui.visuals().selection.bg_fill);
+    ui.painter().add(egui::Shape::line(points, stroke));
+
+    let min = rtts.iter().copied().fold(f32::INFINITY, f32::min);
+    response.on_hover_text(format!("min {:.0}ms, max {:.0}ms", min, max));
+    ui.label(format!("{:.0}ms", latest));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_history_is_bounded() {
+        let mut info = PeerInfo::default();
+        for rtt in 0..RTT_HISTORY + 5 {
+            info.rtt(rtt as f32);
+        }
+        assert_eq!(info.rtts.len(), RTT_HISTORY);
+        assert_eq!(info.rtts.front(), Some(&5.0));
+    }
+
+    #[test]
+    fn test_disconnected_peers_are_evicted() {
+        let mut peers = Peers::default();
+        peers.peer("connected".to_string()).connected = true;
+        for peer in 0..MAX_DISCONNECTED + 3 {
+            peers.peer(peer.to_string()).connected = true;
+            peers.hung_up(&peer.to_string());
+        }
+
+        assert_eq!(peers.peers.len(), MAX_DISCONNECTED + 1);
+        assert!(peers.peers.contains_key("connected"));
+        // the longest gone are dropped first
+        assert!(!peers.peers.contains_key("2"));
+        assert!(peers.peers.contains_key("3"));
+    }
+}
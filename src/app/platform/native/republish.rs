@@ -0,0 +1,257 @@
+//! Keeps the dnsaddr record following the node's address.
+//!
+//! The node address changes as listen addresses are reported, such as when the IPv6
+//! prefix changes mid-session. Each change is published once it has settled for
+//! [DEBOUNCE], and every publication is kept in a short history. A failed publish is
+//! retried with backoff, as the address only counts as published once it succeeded.
+use std::collections::VecDeque;
+use std::net::Ipv6Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use multiaddr::{Multiaddr, Protocol};
+use web_time::Instant;
+
+use crate::app::platform::supervisor::Backoff;
+
+/// How long a new address must stay before it is published
+pub(crate) const DEBOUNCE: Duration = Duration::from_secs(30);
+
+/// Most publications kept in the history
+pub(crate) const HISTORY: usize = 50;
+
+/// First wait before retrying a failed publish, doubling up to [RETRY_MAX]
+const RETRY_INITIAL: Duration = Duration::from_secs(30);
+
+/// Longest wait before retrying a failed publish
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Whether the new listen address should become the node address.
+///
+/// IPv6 is preferred over IPv4. A routable IPv6 address replaces the current IPv6 one
+/// when it uses the same transport, as the same listener moved to a new address.
+pub(crate) fn replaces(current: Option<&Multiaddr>, new: &Multiaddr) -> bool {
+    let Some(current) = current else {
+        return true;
+    };
+    match (ip6(current), ip6(new)) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(old), Some(ip)) => {
+            old != ip && is_routable(ip) && transport(current) == transport(new)
+        }
+    }
+}
+
+fn ip6(addr: &Multiaddr) -> Option<Ipv6Addr> {
+    match addr.iter().next() {
+        Some(Protocol::Ip6(ip)) => Some(ip),
+        _ => None,
+    }
+}
+
+/// Not loopback, unspecified or link local
+fn is_routable(ip: Ipv6Addr) -> bool {
+    !ip.is_loopback() && !ip.is_unspecified() && (ip.segments()[0] & 0xffc0) != 0xfe80
+}
+
+/// The protocols after the IP address, ports left out
+fn transport(addr: &Multiaddr) -> Vec<&'static str> {
+    addr.iter().skip(1).map(|p| p.tag()).collect()
+}
+
+/// A publish of the node address, automatic or manual
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Publication {
+    /// Unix timestamp (seconds) of when it finished
+    pub at: i64,
+    pub addr: String,
+    pub backend: String,
+    /// The publish log, or why it failed
+    pub outcome: Result<Vec<String>, String>,
+}
+
+/// Publications, newest last. Cheap to clone, clones share the history.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct History(Arc<Mutex<VecDeque<Publication>>>);
+
+impl History {
+    pub(crate) fn push(&self, publication: Publication) {
+        let mut history = self.0.lock().unwrap();
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(publication);
+    }
+
+    /// Copy of the publications, newest first
+    pub(crate) fn newest_first(&self) -> Vec<Publication> {
+        self.0.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+impl serde::Serialize for History {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&*self.0.lock().unwrap(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for History {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut publications = VecDeque::<Publication>::deserialize(deserializer)?;
+        while publications.len() > HISTORY {
+            publications.pop_front();
+        }
+        Ok(Self(Arc::new(Mutex::new(publications))))
+    }
+}
+
+/// How the last publishes went, shared with the running publish
+#[derive(Debug)]
+struct Outcome {
+    /// Address last published successfully
+    published: Option<Multiaddr>,
+    /// Address which failed to publish, and when to try it again
+    retry: Option<(Multiaddr, Instant)>,
+    backoff: Backoff,
+}
+
+impl Default for Outcome {
+    fn default() -> Self {
+        Self {
+            published: None,
+            retry: None,
+            backoff: Backoff::new(RETRY_INITIAL, RETRY_MAX),
+        }
+    }
+}
+
+/// Decides when the node address should be published again
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Republisher {
+    outcome: Arc<Mutex<Outcome>>,
+    /// Changed address waiting to settle, and since when
+    pending: Option<(Multiaddr, Instant)>,
+    /// Whether a publish is running, so they don't overlap
+    pub(crate) busy: Arc<AtomicBool>,
+}
+
+impl Republisher {
+    /// The address to publish now, if it's the first one or a change that settled,
+    /// and it isn't backing off after failing.
+    pub(crate) fn due(&mut self, addr: &Multiaddr, now: Instant) -> Option<Multiaddr> {
+        let outcome = self.outcome.lock().unwrap();
+        if outcome.published.as_ref() == Some(addr) {
+            self.pending = None;
+            return None;
+        }
+        if self.busy.load(Ordering::SeqCst) {
+            return None;
+        }
+        if matches!(&outcome.retry, Some((failed, at)) if failed == addr && now < *at) {
+            return None;
+        }
+        if outcome.published.is_none() {
+            return Some(addr.clone());
+        }
+        match &self.pending {
+            Some((pending, since)) if pending == addr => {
+                if now.saturating_duration_since(*since) >= DEBOUNCE {
+                    return Some(addr.clone());
+                }
+            }
+            _ => self.pending = Some((addr.clone(), now)),
+        }
+        None
+    }
+
+    /// How long until the pending address or a retry is due, to check again then
+    pub(crate) fn wait(&self, now: Instant) -> Option<Duration> {
+        let outcome = self.outcome.lock().unwrap();
+        let retry = |addr: Option<&Multiaddr>| {
+            outcome
+                .retry
+                .as_ref()
+                .filter(|(failed, _)| addr.is_none_or(|addr| addr == failed))
+                .map(|(_, at)| at.saturating_duration_since(now))
+        };
+        match &self.pending {
+            Some((addr, since)) => {
+                let settle = DEBOUNCE.saturating_sub(now.saturating_duration_since(*since));
+                Some(retry(Some(addr)).map_or(settle, |retry| retry.max(settle)))
+            }
+            None => retry(None),
+        }
+    }
+
+    /// Record how publishing the address went, backing off before trying it again if
+    /// it failed
+    pub(crate) fn finished(&self, addr: &Multiaddr, ok: bool, now: Instant) {
+        let mut outcome = self.outcome.lock().unwrap();
+        match ok {
+            true => {
+                outcome.published = Some(addr.clone());
+                outcome.retry = None;
+                outcome.backoff.reset();
+            }
+            false => {
+                let delay = outcome.backoff.next_delay();
+                outcome.retry = Some((addr.clone(), now + delay));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replaces_prefers_routable_ip6() {
+        let ip4: Multiaddr = "/ip4/10.0.0.1/udp/4001/webrtc-direct".parse().unwrap();
+        let loopback: Multiaddr = "/ip6/::1/udp/4001/webrtc-direct".parse().unwrap();
+        let old: Multiaddr = "/ip6/2001:db8::1/udp/4001/webrtc-direct".parse().unwrap();
+        let new: Multiaddr = "/ip6/2001:db8::2/udp/4002/webrtc-direct".parse().unwrap();
+        let tcp: Multiaddr = "/ip6/2001:db8::3/tcp/4001".parse().unwrap();
+
+        assert!(replaces(None, &ip4));
+        assert!(replaces(Some(&ip4), &loopback));
+        assert!(!replaces(Some(&old), &ip4));
+        assert!(!replaces(Some(&old), &loopback));
+        assert!(!replaces(Some(&old), &tcp));
+        assert!(replaces(Some(&old), &new));
+    }
+
+    #[test]
+    fn test_changes_are_debounced() {
+        let first: Multiaddr = "/ip6/2001:db8::1/udp/4001/webrtc-direct".parse().unwrap();
+        let second: Multiaddr = "/ip6/2001:db8::2/udp/4001/webrtc-direct".parse().unwrap();
+        let start = Instant::now();
+        let mut republisher = Republisher::default();
+
+        // the first address goes out right away, once published
+        assert_eq!(republisher.due(&first, start), Some(first.clone()));
+        republisher.finished(&first, true, start);
+        assert_eq!(republisher.due(&first, start), None);
+
+        // a change waits to settle
+        assert_eq!(republisher.due(&second, start), None);
+        assert_eq!(republisher.wait(start), Some(DEBOUNCE));
+        assert_eq!(republisher.due(&second, start + DEBOUNCE / 2), None);
+        assert_eq!(
+            republisher.due(&second, start + DEBOUNCE),
+            Some(second.clone())
+        );
+
+        // a failed publish is retried after backing off
+        republisher.finished(&second, false, start + DEBOUNCE);
+        let retry = start + DEBOUNCE + RETRY_INITIAL;
+        assert_eq!(republisher.due(&second, start + DEBOUNCE), None);
+        assert_eq!(republisher.wait(start + DEBOUNCE), Some(RETRY_INITIAL));
+        assert_eq!(republisher.due(&second, retry), Some(second.clone()));
+        republisher.finished(&second, true, retry);
+        assert_eq!(republisher.due(&second, retry), None);
+        assert_eq!(republisher.wait(retry), None);
+    }
+}
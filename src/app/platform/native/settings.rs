@@ -1,67 +1,262 @@
 //! Settings specific to the native platform
 
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{future::Future, pin::Pin};
 
-use crate::app::platform;
+use web_time::Instant;
 
-use super::cloudflare::{add_address, CloudflareError};
+use crate::app::platform::{self, storage};
+
+use super::cloudflare::{Cloudflare, API_URL};
+use super::dnsaddr::{
+    apply, plan, publish, verify, DnsAddrError, Plan, Publisher, Resolver, Rfc2136, Webhook,
+    DEFAULT_DOH, MAX_RECORDS,
+};
+use super::republish::{History, Publication, Republisher};
+use crate::app::history::format_timestamp;
+use crate::app::rdx_runner::vault::{SecretHandle, Vault, VaultError};
 
 use egui::vec2;
 use egui_material_icons::icons;
+use futures::future::{AbortHandle, Abortable};
 use multiaddr::Multiaddr;
 
+/// How many times a verification resolves, while the records propagate
+const VERIFY_ATTEMPTS: u32 = 10;
+
+/// Time between verification attempts
+const VERIFY_INTERVAL: Duration = Duration::from_secs(30);
+
 /// Cloudflare settings
 /// CF_API_TOKEN, CF_ZONE_ID, CF_DOMAIN, CF_TXT_NAME   
 #[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct CloudflareSettings {
-    /// Cloudflare API Token.
-    pub cf_api_token: String,
+    /// Cloudflare API Token, kept in the vault
+    #[serde(default = "cloudflare_token")]
+    pub api_token: SecretHandle,
+    /// Plaintext token saved before the vault, moved into it once the wallet unlocks
+    #[serde(
+        rename = "cf_api_token",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    legacy_api_token: String,
     /// Cloudflare Zone ID, ie. 123abc456d9ca31a8372d0c353
     pub cf_zone_id: String,
     /// Cloudflare Domain, ie. example.com
     pub cf_domain: String,
     /// Cloudflare TXT Name, ie. _dnsaddr.example.com
     pub cf_txt_name: String,
+    /// Cloudflare API base URL, empty for the Cloudflare API itself
+    #[serde(default)]
+    pub cf_api_url: String,
 
-    /// Auto Update on startup
-    pub auto_update: bool,
+    /// Auto update saved here before it applied to every backend, moved to [Settings]
+    #[serde(rename = "auto_update", default, skip_serializing)]
+    legacy_auto_update: bool,
+}
+
+fn cloudflare_token() -> SecretHandle {
+    SecretHandle::new("dnsaddr/cloudflare-api-token")
 }
 
 impl Default for CloudflareSettings {
     fn default() -> Self {
         Self {
-            cf_api_token: "".to_string(),
+            api_token: cloudflare_token(),
+            legacy_api_token: "".to_string(),
             cf_zone_id: "".to_string(),
             cf_domain: "example.com".to_string(),
             cf_txt_name: "_dnsaddr.example.com".to_string(),
-            auto_update: false,
+            cf_api_url: "".to_string(),
+            legacy_auto_update: false,
+        }
+    }
+}
+
+/// Where the dnsaddr TXT records are published
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Backend {
+    #[default]
+    Cloudflare,
+    /// Any DNS server accepting RFC 2136 dynamic updates
+    Rfc2136,
+    /// HTTP webhook, see [super::dnsaddr] for what it must answer
+    Webhook,
+}
+
+impl Backend {
+    const ALL: [Backend; 3] = [Backend::Cloudflare, Backend::Rfc2136, Backend::Webhook];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Backend::Cloudflare => "Cloudflare",
+            Backend::Rfc2136 => "RFC 2136 dynamic update",
+            Backend::Webhook => "HTTP webhook",
+        }
+    }
+}
+
+/// RFC 2136 dynamic update settings
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Rfc2136Settings {
+    /// DNS server, ie. ns1.example.com:53
+    pub server: String,
+    /// Zone, ie. example.com
+    pub zone: String,
+    /// TXT Name, ie. _dnsaddr.example.com
+    pub txt_name: String,
+    /// TTL of created records, in seconds
+    pub ttl: u32,
+    /// TSIG key name, empty to send unsigned updates
+    pub key_name: String,
+    /// TSIG HMAC-SHA256 secret, base64, kept in the vault
+    pub tsig_secret: SecretHandle,
+    /// Plaintext secret saved before the vault, moved into it once the wallet unlocks
+    #[serde(rename = "key_secret", skip_serializing_if = "String::is_empty")]
+    legacy_key_secret: String,
+}
+
+impl Default for Rfc2136Settings {
+    fn default() -> Self {
+        Self {
+            server: "".to_string(),
+            zone: "example.com".to_string(),
+            txt_name: "_dnsaddr.example.com".to_string(),
+            ttl: 300,
+            key_name: "".to_string(),
+            tsig_secret: SecretHandle::new("dnsaddr/rfc2136-tsig-secret"),
+            legacy_key_secret: "".to_string(),
+        }
+    }
+}
+
+/// HTTP webhook settings
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct WebhookSettings {
+    /// Records URL, ie. https://dns.example.com/records
+    pub url: String,
+    /// Bearer token, optional, kept in the vault
+    pub bearer_token: SecretHandle,
+    /// Plaintext token saved before the vault, moved into it once the wallet unlocks
+    #[serde(rename = "token", skip_serializing_if = "String::is_empty")]
+    legacy_token: String,
+    /// TXT Name the webhook publishes to, ie. _dnsaddr.example.com. Used to verify.
+    pub txt_name: String,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            url: "".to_string(),
+            bearer_token: SecretHandle::new("dnsaddr/webhook-bearer-token"),
+            legacy_token: "".to_string(),
+            txt_name: "_dnsaddr.example.com".to_string(),
         }
     }
 }
 
 /// Settings specific to the native platform
-#[derive(Default, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct Settings {
+    /// Backend the dnsaddr records are published to
+    #[serde(default)]
+    pub backend: Backend,
+
     /// Optional Cloudflare Settings
     pub cloudflare: CloudflareSettings,
 
+    /// Optional RFC 2136 Settings
+    #[serde(default)]
+    pub rfc2136: Rfc2136Settings,
+
+    /// Optional webhook Settings
+    #[serde(default)]
+    pub webhook: WebhookSettings,
+
+    /// How many of this node's TXT records to keep, besides the one just created
+    #[serde(default = "default_max_records")]
+    pub max_records: usize,
+
+    /// Where published records are resolved to verify them
+    #[serde(default)]
+    pub resolver: Resolver,
+
+    /// Publish on startup and whenever the address changes, with any backend
+    #[serde(default)]
+    pub auto_update: bool,
+
     /// Whether the settings window is open
     open: bool,
 
-    /// Updated on startup flag. Used to auto-update on startup.
-    /// Skipped in serialization so the flag is not saved.
+    /// Publishes the node address again when it changes.
+    /// Skipped in serialization so each launch publishes afresh.
+    #[serde(skip)]
+    republisher: Republisher,
+
+    /// Past publications, kept across launches
+    #[serde(default)]
+    history: History,
+
+    /// Dry run of an update, to apply as previewed
     #[serde(skip)]
-    updated_on_startup: bool,
+    preview: Arc<Mutex<Option<Preview>>>,
+
+    /// Progress of the last verification
+    #[serde(skip)]
+    verification: Arc<Mutex<Option<String>>>,
+
+    /// Stops the running verification, replaced by the next one
+    #[serde(skip)]
+    verifying: Arc<Mutex<Option<AbortHandle>>>,
+}
+
+fn default_max_records() -> usize {
+    MAX_RECORDS
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            cloudflare: CloudflareSettings::default(),
+            rfc2136: Rfc2136Settings::default(),
+            webhook: WebhookSettings::default(),
+            max_records: MAX_RECORDS,
+            resolver: Resolver::default(),
+            auto_update: false,
+            open: false,
+            republisher: Republisher::default(),
+            history: History::default(),
+            preview: Arc::default(),
+            verification: Arc::default(),
+            verifying: Arc::default(),
+        }
+    }
 }
 
 impl Settings {
     /// Show the current settings
-    pub fn show(&mut self, ctx: &egui::Context, ui: &mut egui::Ui, addr: &Multiaddr) {
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        ui: &mut egui::Ui,
+        addr: &Multiaddr,
+        vault: &Vault,
+    ) {
+        self.migrate_secrets(vault);
+        self.migrate_auto_update();
+
         // auto update
-        self.auto_update(addr);
+        self.auto_update(ctx, addr, vault);
 
-        egui::Window::new("Cloudflare Options")
-            .open(&mut self.open)
+        let mut open = self.open;
+        egui::Window::new("DNS Address Options")
+            .open(&mut open)
             .resizable(true)
             .show(ctx, |ui| {
                 egui::Grid::new("my_grid")
@@ -71,53 +266,66 @@ impl Settings {
                     .spacing([40.0, 4.0])
                     .striped(true)
                     .show(ui, |ui| {
-                        ui.add(egui::Hyperlink::from_label_and_url(
-                            "Cloudflare API Token",
-                            "https://dash.cloudflare.com/profile/api-tokens",
-                        ));
-                        ui.add(
-                            egui::TextEdit::singleline(&mut self.cloudflare.cf_api_token)
-                                .desired_width(f32::INFINITY)
-                                .password(true),
-                        );
+                        ui.label("Publish to");
+                        egui::ComboBox::from_id_salt("dnsaddr_backend")
+                            .selected_text(self.backend.label())
+                            .show_ui(ui, |ui| {
+                                for backend in Backend::ALL {
+                                    ui.selectable_value(
+                                        &mut self.backend,
+                                        backend,
+                                        backend.label(),
+                                    );
+                                }
+                            });
                         ui.end_row();
 
-                        ui.add(egui::Hyperlink::from_label_and_url(
-                            "Zone ID",
-                            "https://dash.cloudflare.com/",
-                        ));
-                        ui.add(
-                            egui::TextEdit::singleline(&mut self.cloudflare.cf_zone_id)
-                                .desired_width(f32::INFINITY),
-                        );
+                        match self.backend {
+                            Backend::Cloudflare => cloudflare_rows(ui, &mut self.cloudflare, vault),
+                            Backend::Rfc2136 => rfc2136_rows(ui, &mut self.rfc2136, vault),
+                            Backend::Webhook => webhook_rows(ui, &mut self.webhook, vault),
+                        }
+
+                        ui.label("Records to keep");
+                        ui.add(egui::DragValue::new(&mut self.max_records).range(1..=20))
+                            .on_hover_text(
+                                "This node's records kept besides the newest one. \
+                                 Records of other peers under the name are left alone.",
+                            );
                         ui.end_row();
 
-                        ui.add(egui::Hyperlink::from_label_and_url(
-                            "Domain",
-                            "https://dash.cloudflare.com/",
-                        ));
-                        ui.add(
-                            egui::TextEdit::singleline(&mut self.cloudflare.cf_domain)
-                                .desired_width(f32::INFINITY),
-                        );
-                        ui.end_row();
-
-                        ui.add(egui::Hyperlink::from_label_and_url(
-                            "TXT Name",
-                            "https://dash.cloudflare.com/",
-                        ));
-                        ui.add(
-                            egui::TextEdit::singleline(&mut self.cloudflare.cf_txt_name)
-                                .desired_width(f32::INFINITY),
-                        );
+                        ui.label("Resolver");
+                        ui.horizontal(|ui| {
+                            let doh = matches!(self.resolver, Resolver::DnsOverHttps(_));
+                            if ui.selectable_label(doh, "DNS over HTTPS").clicked() && !doh {
+                                self.resolver = Resolver::DnsOverHttps(DEFAULT_DOH.to_string());
+                            }
+                            if ui
+                                .selectable_label(!doh, "System")
+                                .on_hover_text(
+                                    "The system name server, or the default DNS over HTTPS \
+                                     where there is none",
+                                )
+                                .clicked()
+                            {
+                                self.resolver = Resolver::System;
+                            }
+                            if let Resolver::DnsOverHttps(url) = &mut self.resolver {
+                                ui.add(
+                                    egui::TextEdit::singleline(url).desired_width(f32::INFINITY),
+                                );
+                            }
+                        });
                         ui.end_row();
 
                         ui.label("Auto Update");
-                        ui.checkbox(&mut self.cloudflare.auto_update, " on Startup");
+                        ui.checkbox(
+                            &mut self.auto_update,
+                            " on Startup and when the address changes",
+                        );
                         ui.end_row();
 
-                        // call add_address in a spawned block then display the log text once
-                        // done.
+                        // publish in a spawned block then display the log text once done.
 
                         ui.label("Multiaddr");
                         ui.vertical(|ui| {
@@ -130,16 +338,24 @@ impl Settings {
                         ui.label("Manually Update");
 
                         ui.vertical(|ui| {
-                            spawner(ctx, ui, || -> ClosureFut {
-                                Box::pin(
-                                    add_address()
-                                        .api_token(self.cloudflare.cf_api_token.clone())
-                                        .zone_id(self.cloudflare.cf_zone_id.clone())
-                                        .txt_name(self.cloudflare.cf_txt_name.clone())
-                                        .multiaddr(addr.clone())
-                                        .call(),
-                                )
-                            });
+                            spawner(ctx, ui, || self.publishing(vault, addr, None));
+                            if ui
+                                .button("Preview")
+                                .on_hover_text("Show what an update would do, without doing it")
+                                .clicked()
+                            {
+                                self.preview(ctx, addr, vault);
+                            }
+                            if ui
+                                .button("Verify")
+                                .on_hover_text("Resolve the published records to find this address")
+                                .clicked()
+                            {
+                                self.verify(ctx, addr);
+                            }
+                            if let Some(status) = self.verification.lock().unwrap().as_ref() {
+                                ui.label(status);
+                            }
                         });
                         ui.end_row();
 
@@ -147,8 +363,13 @@ impl Settings {
                         ui.label("");
                         ui.end_row();
                     });
+
+                self.preview_ui(ctx, ui, addr, vault);
+                history_ui(ui, &self.history);
             });
 
+        self.open = open;
+
         if ui
             .add_sized(
                 vec2(40.0, 40.0),
@@ -168,23 +389,65 @@ impl Settings {
         }
     }
 
-    /// Auto-updates if auto_update is add_enabled
-    /// spawns the future right away
-    pub fn auto_update(&mut self, addr: &Multiaddr) {
-        if self.cloudflare.auto_update && !self.updated_on_startup {
-            self.updated_on_startup = true;
-            let fut = Box::pin(
-                add_address()
-                    .api_token(self.cloudflare.cf_api_token.clone())
-                    .zone_id(self.cloudflare.cf_zone_id.clone())
-                    .txt_name(self.cloudflare.cf_txt_name.clone())
-                    .multiaddr(addr.clone())
-                    .call(),
-            );
+    /// Move plaintext secrets saved before the vault into it, once it's unlocked
+    fn migrate_secrets(&mut self, vault: &Vault) {
+        vault.migrate(
+            &self.cloudflare.api_token,
+            &mut self.cloudflare.legacy_api_token,
+        );
+        vault.migrate(
+            &self.rfc2136.tsig_secret,
+            &mut self.rfc2136.legacy_key_secret,
+        );
+        vault.migrate(&self.webhook.bearer_token, &mut self.webhook.legacy_token);
+    }
+
+    /// Move auto update out of the Cloudflare settings, where it was saved before
+    fn migrate_auto_update(&mut self) {
+        if std::mem::take(&mut self.cloudflare.legacy_auto_update) {
+            self.auto_update = true;
+        }
+    }
 
+    /// The selected backend, configured from the settings and its secrets from the vault
+    fn publisher(&self, vault: &Vault) -> Result<Publisher, VaultError> {
+        let secret = |handle: &SecretHandle| vault.get(handle).map(Option::unwrap_or_default);
+        Ok(match self.backend {
+            Backend::Cloudflare => Publisher::Cloudflare(Cloudflare {
+                api_token: secret(&self.cloudflare.api_token)?,
+                zone_id: self.cloudflare.cf_zone_id.clone(),
+                txt_name: self.cloudflare.cf_txt_name.clone(),
+                api_url: self.cloudflare.cf_api_url.clone(),
+            }),
+            Backend::Rfc2136 => Publisher::Rfc2136(Rfc2136 {
+                server: self.rfc2136.server.clone(),
+                zone: self.rfc2136.zone.clone(),
+                txt_name: self.rfc2136.txt_name.clone(),
+                ttl: self.rfc2136.ttl,
+                key_name: self.rfc2136.key_name.clone(),
+                key_secret: secret(&self.rfc2136.tsig_secret)?,
+                storage: storage::open_default(),
+            }),
+            Backend::Webhook => Publisher::Webhook(Webhook {
+                url: self.webhook.url.clone(),
+                token: secret(&self.webhook.bearer_token)?,
+            }),
+        })
+    }
+
+    /// Auto-updates if auto_update is enabled: on startup, then whenever the address
+    /// changes and settles. Spawns the future right away.
+    ///
+    /// Waits for the wallet to unlock, as the backend secrets are in the vault.
+    pub fn auto_update(&mut self, ctx: &egui::Context, addr: &Multiaddr, vault: &Vault) {
+        if !self.auto_update || !vault.is_unlocked() {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(addr) = self.republisher.due(addr, now) {
+            tracing::trace!("Auto Updating {}", addr);
+            let fut = self.publishing(vault, &addr, None);
             platform::spawn(async move {
-                // Fetch data
-                tracing::trace!("Auto Updating");
                 match fut.await {
                     Ok(data) => {
                         tracing::debug!("Auto updated data: {:?}", data);
@@ -195,9 +458,240 @@ impl Settings {
                 }
             });
         }
+        if let Some(wait) = self.republisher.wait(now) {
+            ctx.request_repaint_after(wait);
+        }
+    }
+
+    /// Publish the address with the selected backend, adding the outcome to the history.
+    /// Carries out the plan if given, as previewed, rather than planning afresh.
+    fn publishing(&self, vault: &Vault, addr: &Multiaddr, plan: Option<Plan>) -> ClosureFut {
+        let publisher = self.publisher(vault);
+        let backend = self.backend.label().to_string();
+        let max_records = self.max_records;
+        let addr = addr.clone();
+        let republisher = self.republisher.clone();
+        let history = self.history.clone();
+        republisher.busy.store(true, Ordering::SeqCst);
+        Box::pin(async move {
+            let result = match (publisher, plan) {
+                (Err(e), _) => Err(e.into()),
+                (Ok(publisher), Some(plan)) => apply(&publisher, &plan).await,
+                (Ok(publisher), None) => publish(&publisher, &addr, max_records).await,
+            };
+            history.push(Publication {
+                at: chrono::Utc::now().timestamp(),
+                addr: addr.to_string(),
+                backend,
+                outcome: result.as_ref().cloned().map_err(|e| e.to_string()),
+            });
+            republisher.finished(&addr, result.is_ok(), Instant::now());
+            republisher.busy.store(false, Ordering::SeqCst);
+            result
+        })
+    }
+
+    /// The TXT name of the selected backend
+    fn txt_name(&self) -> &str {
+        match self.backend {
+            Backend::Cloudflare => &self.cloudflare.cf_txt_name,
+            Backend::Rfc2136 => &self.rfc2136.txt_name,
+            Backend::Webhook => &self.webhook.txt_name,
+        }
+    }
+
+    /// Spawn checks that the address resolves from the TXT name, retrying while the
+    /// records propagate. A verification still running is stopped first.
+    fn verify(&self, ctx: &egui::Context, addr: &Multiaddr) {
+        let (abort, registration) = AbortHandle::new_pair();
+        if let Some(running) = self.verifying.lock().unwrap().replace(abort) {
+            running.abort();
+        }
+
+        let resolver = self.resolver.clone();
+        let txt_name = self.txt_name().to_string();
+        let addr = addr.clone();
+        let status = self.verification.clone();
+        let ctx = ctx.clone();
+        let report = move |message: String| {
+            status.lock().unwrap().replace(message);
+            ctx.request_repaint();
+        };
+        report("⏳ Resolving…".to_string());
+        let verifying = async move {
+            for attempt in 1..=VERIFY_ATTEMPTS {
+                let verification = match verify(&resolver, &txt_name, &addr).await {
+                    Ok(verification) => verification,
+                    Err(e) => {
+                        tracing::error!("Verify Error: {:?}", e);
+                        report(format!("❌ {}", e));
+                        return;
+                    }
+                };
+                if verification.found {
+                    report(format!(
+                        "✅ Discoverable at /dnsaddr/{} (attempt {}, {} addresses resolved)",
+                        verification.domain,
+                        attempt,
+                        verification.resolved.len()
+                    ));
+                    return;
+                }
+                if attempt == VERIFY_ATTEMPTS {
+                    report(format!(
+                        "❌ Not discoverable at /dnsaddr/{} after {} attempts, it resolves to: {}",
+                        verification.domain,
+                        VERIFY_ATTEMPTS,
+                        verification
+                            .resolved
+                            .iter()
+                            .map(|a| a.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ));
+                    return;
+                }
+                report(format!(
+                    "⏳ Not visible yet at /dnsaddr/{} ({} addresses resolved), attempt {} of {}, \
+                     retrying in {}s",
+                    verification.domain,
+                    verification.resolved.len(),
+                    attempt,
+                    VERIFY_ATTEMPTS,
+                    VERIFY_INTERVAL.as_secs()
+                ));
+                platform::sleep(VERIFY_INTERVAL).await;
+            }
+        };
+        platform::spawn(async move {
+            let _ = Abortable::new(verifying, registration).await;
+        });
+    }
+
+    /// Spawn a dry run of an update, shown by [Self::preview_ui]
+    fn preview(&self, ctx: &egui::Context, addr: &Multiaddr, vault: &Vault) {
+        let preview = self.preview.clone();
+        let publisher = match self.publisher(vault) {
+            Ok(publisher) => publisher,
+            Err(e) => {
+                preview.lock().unwrap().replace(Preview {
+                    addr: addr.clone(),
+                    planned: Err(e.to_string()),
+                });
+                return;
+            }
+        };
+        let max_records = self.max_records;
+        let addr = addr.clone();
+        let ctx = ctx.clone();
+        preview.lock().unwrap().take();
+        platform::spawn(async move {
+            let planned = plan(&publisher, &addr, max_records)
+                .await
+                .map_err(|e| e.to_string());
+            preview.lock().unwrap().replace(Preview { addr, planned });
+            ctx.request_repaint();
+        });
+    }
+
+    /// The previewed operations, to apply or discard
+    fn preview_ui(
+        &mut self,
+        ctx: &egui::Context,
+        ui: &mut egui::Ui,
+        addr: &Multiaddr,
+        vault: &Vault,
+    ) {
+        let Some(preview) = self.preview.lock().unwrap().clone() else {
+            return;
+        };
+        let mut done = false;
+        ui.group(|ui| {
+            ui.strong("Preview");
+            match &preview.planned {
+                Ok(plan) => {
+                    for line in plan.describe() {
+                        ui.label(line);
+                    }
+                    // the plan is only right for the address it was made for
+                    let changed = &preview.addr != addr;
+                    if changed {
+                        ui.colored_label(
+                            ui.visuals().warn_fg_color,
+                            "The address changed since the preview",
+                        );
+                    }
+                    ui.horizontal(|ui| {
+                        if changed {
+                            if ui.button("Preview again").clicked() {
+                                self.preview(ctx, addr, vault);
+                            }
+                        } else if ui.button("Apply").clicked() {
+                            let fut = self.publishing(vault, &preview.addr, Some(plan.clone()));
+                            platform::spawn(async move {
+                                if let Err(e) = fut.await {
+                                    tracing::error!("Applying the preview failed: {:?}", e);
+                                }
+                            });
+                            done = true;
+                        }
+                        if ui.button("Discard").clicked() {
+                            done = true;
+                        }
+                    });
+                }
+                Err(e) => {
+                    ui.colored_label(ui.visuals().error_fg_color, e);
+                    if ui.button("Dismiss").clicked() {
+                        done = true;
+                    }
+                }
+            }
+        });
+        if done {
+            self.preview.lock().unwrap().take();
+            ctx.request_repaint();
+        }
     }
 }
 
+/// A dry run of an update, with the address it was planned for
+#[derive(Debug, Clone)]
+struct Preview {
+    addr: Multiaddr,
+    planned: Result<Plan, String>,
+}
+
+/// Past publications, newest first
+fn history_ui(ui: &mut egui::Ui, history: &History) {
+    let publications = history.newest_first();
+    ui.collapsing(format!("Publish history ({})", publications.len()), |ui| {
+        if publications.is_empty() {
+            ui.label("Nothing published yet.");
+            return;
+        }
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .show(ui, |ui| {
+                egui::Grid::new("dnsaddr_history")
+                    .num_columns(4)
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for publication in publications {
+                            ui.label(format_timestamp(publication.at));
+                            ui.label(&publication.backend);
+                            ui.monospace(&publication.addr);
+                            match &publication.outcome {
+                                Ok(log) => ui.label("✅").on_hover_text(log.join("\n")),
+                                Err(e) => ui.colored_label(ui.visuals().error_fg_color, e),
+                            };
+                            ui.end_row();
+                        }
+                    });
+            });
+    });
+}
+
 //type FutStringList =
 //    Pin<Box<dyn Future<Output = Result<Vec<String>, Box<dyn std::error::Error>>> + Send>>;
 
@@ -208,7 +702,138 @@ struct FetchState {
     error: Option<String>,
 }
 
-type ClosureFut = Pin<Box<dyn Future<Output = Result<Vec<String>, CloudflareError>> + Send>>;
+type ClosureFut = Pin<Box<dyn Future<Output = Result<Vec<String>, DnsAddrError>> + Send>>;
+
+/// Password field for a secret in the vault, sealed once editing is done.
+///
+/// The typing goes into a buffer in egui's memory, saved when the field loses focus,
+/// such as on Enter, rather than sealing and writing the vault on every keystroke.
+fn secret_edit(ui: &mut egui::Ui, vault: &Vault, handle: &SecretHandle) {
+    if !vault.is_unlocked() {
+        ui.label("🔒 Unlock the wallet to edit")
+            .on_hover_text("Secrets are kept in the vault, encrypted with the wallet");
+        return;
+    }
+    let id = ui.id().with(("secret_edit", handle.to_string()));
+    let error_id = id.with("error");
+    let buffer = ui.data_mut(|data| data.get_temp::<String>(id));
+    let edited = buffer.is_some();
+    let mut secret = match buffer {
+        Some(buffer) => buffer,
+        None => match vault.get(handle) {
+            Ok(secret) => secret.unwrap_or_default(),
+            Err(e) => {
+                ui.colored_label(ui.visuals().error_fg_color, e.to_string());
+                return;
+            }
+        },
+    };
+    ui.vertical(|ui| {
+        let response = ui.add(
+            egui::TextEdit::singleline(&mut secret)
+                .id(id)
+                .desired_width(f32::INFINITY)
+                .password(true),
+        );
+        if response.lost_focus() && (edited || response.changed()) {
+            match vault.set(handle, &secret) {
+                Ok(()) => ui.data_mut(|data| {
+                    data.remove::<String>(id);
+                    data.remove::<String>(error_id);
+                }),
+                // kept in the buffer, so the typing isn't lost
+                Err(e) => {
+                    tracing::error!("Failed to save {}: {:?}", handle, e);
+                    ui.data_mut(|data| {
+                        data.insert_temp(id, secret);
+                        data.insert_temp(error_id, e.to_string());
+                    });
+                }
+            }
+        } else if edited || response.changed() {
+            ui.data_mut(|data| data.insert_temp(id, secret));
+        }
+        if let Some(error) = ui.data_mut(|data| data.get_temp::<String>(error_id)) {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+    });
+}
+
+/// Secret field filling the grid cell
+fn secret_row(ui: &mut egui::Ui, label: &str, vault: &Vault, handle: &SecretHandle) {
+    ui.label(label);
+    secret_edit(ui, vault, handle);
+    ui.end_row();
+}
+
+/// Text field filling the grid cell
+fn text_row(ui: &mut egui::Ui, label: &str, value: &mut String) {
+    ui.label(label);
+    ui.add(egui::TextEdit::singleline(value).desired_width(f32::INFINITY));
+    ui.end_row();
+}
+
+fn cloudflare_rows(ui: &mut egui::Ui, cloudflare: &mut CloudflareSettings, vault: &Vault) {
+    ui.add(egui::Hyperlink::from_label_and_url(
+        "Cloudflare API Token",
+        "https://dash.cloudflare.com/profile/api-tokens",
+    ));
+    secret_edit(ui, vault, &cloudflare.api_token);
+    ui.end_row();
+
+    ui.add(egui::Hyperlink::from_label_and_url(
+        "Zone ID",
+        "https://dash.cloudflare.com/",
+    ));
+    ui.add(egui::TextEdit::singleline(&mut cloudflare.cf_zone_id).desired_width(f32::INFINITY));
+    ui.end_row();
+
+    ui.add(egui::Hyperlink::from_label_and_url(
+        "Domain",
+        "https://dash.cloudflare.com/",
+    ));
+    ui.add(egui::TextEdit::singleline(&mut cloudflare.cf_domain).desired_width(f32::INFINITY));
+    ui.end_row();
+
+    ui.add(egui::Hyperlink::from_label_and_url(
+        "TXT Name",
+        "https://dash.cloudflare.com/",
+    ));
+    ui.add(egui::TextEdit::singleline(&mut cloudflare.cf_txt_name).desired_width(f32::INFINITY));
+    ui.end_row();
+
+    ui.label("API URL")
+        .on_hover_text("Leave empty for the Cloudflare API, or set a compatible endpoint");
+    ui.add(
+        egui::TextEdit::singleline(&mut cloudflare.cf_api_url)
+            .hint_text(API_URL)
+            .desired_width(f32::INFINITY),
+    );
+    ui.end_row();
+}
+
+fn rfc2136_rows(ui: &mut egui::Ui, rfc2136: &mut Rfc2136Settings, vault: &Vault) {
+    text_row(ui, "DNS Server", &mut rfc2136.server);
+    text_row(ui, "Zone", &mut rfc2136.zone);
+    text_row(ui, "TXT Name", &mut rfc2136.txt_name);
+
+    ui.label("TTL");
+    ui.add(
+        egui::DragValue::new(&mut rfc2136.ttl)
+            .range(1..=86400)
+            .suffix(" s"),
+    );
+    ui.end_row();
+
+    text_row(ui, "TSIG Key Name", &mut rfc2136.key_name);
+    secret_row(ui, "TSIG Secret (HMAC-SHA256)", vault, &rfc2136.tsig_secret);
+}
+
+fn webhook_rows(ui: &mut egui::Ui, webhook: &mut WebhookSettings, vault: &Vault) {
+    text_row(ui, "Webhook URL", &mut webhook.url);
+    secret_row(ui, "Bearer Token", vault, &webhook.bearer_token);
+    text_row(ui, "TXT Name", &mut webhook.txt_name);
+}
 
 /// Takes care of resolving async operations and displaying the results
 pub fn spawner(ctx: &egui::Context, ui: &mut egui::Ui, fut_closure: impl FnOnce() -> ClosureFut) {
@@ -278,3 +903,24 @@ pub fn spawner(ctx: &egui::Context, ui: &mut egui::Ui, fut_closure: impl FnOnce(
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_update_moves_out_of_cloudflare() {
+        let mut saved = serde_json::to_value(Settings::default()).unwrap();
+        saved.as_object_mut().unwrap().remove("auto_update");
+        saved["cloudflare"]["auto_update"] = true.into();
+
+        let mut settings: Settings = serde_json::from_value(saved).unwrap();
+        assert!(!settings.auto_update);
+        settings.migrate_auto_update();
+        assert!(settings.auto_update);
+
+        let saved = serde_json::to_value(&settings).unwrap();
+        assert_eq!(saved["auto_update"], true);
+        assert!(saved["cloudflare"].get("auto_update").is_none());
+    }
+}
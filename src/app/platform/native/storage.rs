@@ -2,40 +2,130 @@
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-/// Storage interface for native platform
+use crate::app::platform::storage::{Storage, StorageError};
+use crate::app::profile::Profile;
+
+/// Extension of the temporary file written before it's renamed over the value
+const TMP_EXTENSION: &str = "tmp";
+
+/// Storage interface for native platform, one file per key
 #[derive(Debug, Default, Clone)]
 pub struct StringStore {
     dir: PathBuf,
 }
 
 impl StringStore {
-    // use dirs to get the default storage directory
-    pub fn new() -> Self {
-        let dir = dirs::data_dir().unwrap().join("multinode/cids/");
-        fs::create_dir_all(&dir).unwrap();
-        Self { dir }
+    /// Open the store in the active profile's data directory
+    pub fn new() -> Result<Self, StorageError> {
+        let dir = Profile::active()
+            .data_dir()
+            .ok_or_else(|| StorageError::Unavailable("no data directory".to_string()))?
+            .join("cids/");
+        Self::with_dir(dir)
+    }
+
+    /// Open the store in the given directory, creating it if needed
+    pub fn with_dir(dir: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// The file path for the key, rejecting keys which would escape the directory
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let invalid = key.is_empty()
+            || key.contains(['/', '\\'])
+            || key == "."
+            || key == ".."
+            || Path::new(key)
+                .extension()
+                .is_some_and(|ext| ext == TMP_EXTENSION);
+        if invalid {
+            return Err(StorageError::InvalidKey(key.to_string()));
+        }
+        Ok(self.dir.join(key))
     }
+}
+
+impl Storage for StringStore {
     /// Get the value from disk
-    pub fn get_string(&self, key: &str) -> Option<String> {
-        let path = PathBuf::from(key);
-        let path = self.dir.join(path);
-        fs::read_to_string(path).ok()
-    }
-
-    /// Save the value to disk
-    pub fn set_string(&self, filename: &str, value: String) -> std::io::Result<()> {
-        // create the file if it hasn't been created,
-        // otherwise, open the file and write the value
-        let path = self.dir.join(filename);
-        let mut file = File::create(path.clone())?;
-        tracing::info!("Saving to {:?}", path);
-        file.write_all(value.as_bytes())
-    }
-
-    //pub fn flush(&mut self) {
-    //    // rm the directory contents
-    //    fs::remove_dir_all(&self.dir).unwrap();
-    //}
+    fn get_string(&self, key: &str) -> Result<Option<String>, StorageError> {
+        match fs::read_to_string(self.path(key)?) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the value to disk.
+    ///
+    /// Writes to a temporary file first then renames it over the old value,
+    /// so a crash mid-write never leaves a truncated value behind.
+    fn set_string(&self, key: &str, value: String) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        let tmp = path.with_extension(match path.extension() {
+            Some(ext) => format!("{}.{}", ext.to_string_lossy(), TMP_EXTENSION),
+            None => TMP_EXTENSION.to_string(),
+        });
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(value.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+
+        tracing::info!("Saved to {:?}", path);
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<(), StorageError> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>, StorageError> {
+        let mut keys = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            // skip leftovers from interrupted writes
+            .filter(|name| self.path(name).is_ok())
+            .collect::<Vec<_>>();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_native_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StringStore::with_dir(dir.path()).unwrap();
+
+        store
+            .set_string("plugin.wasm", "cid-1".to_string())
+            .unwrap();
+        store
+            .set_string("plugin.wasm", "cid-2".to_string())
+            .unwrap();
+        assert_eq!(
+            store.get_string("plugin.wasm").unwrap(),
+            Some("cid-2".to_string())
+        );
+        assert_eq!(store.keys().unwrap(), vec!["plugin.wasm"]);
+
+        assert!(matches!(
+            store.get_string("../escape"),
+            Err(StorageError::InvalidKey(_))
+        ));
+
+        store.remove("plugin.wasm").unwrap();
+        assert_eq!(store.get_string("plugin.wasm").unwrap(), None);
+    }
 }
@@ -0,0 +1,245 @@
+//! Queue for network commands ordered before the node is connected.
+//!
+//! Until [PeerPiper::connect](super::piper::PeerPiper::connect) gives the commander a
+//! network sender, network commands would fail, such as a plugin republishing its record
+//! in `init`. Instead they wait here and are replayed once connected, their results going
+//! back to whoever ordered them. Identical commands which only set something up, like a
+//! subscription, are ordered once, others are each replayed in order. Commands left
+//! waiting too long expire. Commands queue again once the node stops.
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use peerpiper::core::events::AllCommands;
+use peerpiper::core::ReturnValues;
+use web_time::Instant;
+
+/// How long a command waits for the node to connect
+pub(crate) const QUEUE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Most distinct commands waiting, further ones are refused
+pub(crate) const QUEUE_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum QueueError {
+    #[error("The network command expired before the node connected")]
+    Expired,
+    #[error("Too many network commands are waiting for the node to connect")]
+    Full,
+    #[error("The queued network command was dropped")]
+    Dropped,
+    #[error("The queued network command failed: {0}")]
+    Failed(String),
+}
+
+type Waiter = oneshot::Sender<Result<ReturnValues, QueueError>>;
+
+/// A command waiting to be replayed, with everyone waiting on it
+struct Queued {
+    /// Debug form of a command which is only ordered once, to dedupe it
+    key: Option<String>,
+    command: AllCommands,
+    queued_at: Instant,
+    waiters: Vec<Waiter>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Whether the commander has a network sender, so commands are ordered directly
+    online: bool,
+    /// Waiting commands, in the order they were first queued
+    queued: Vec<Queued>,
+}
+
+/// Network commands waiting for the node to connect
+#[derive(Default)]
+pub(crate) struct OfflineQueue {
+    inner: Mutex<Inner>,
+}
+
+/// The process wide queue
+pub(crate) fn offline_queue() -> &'static OfflineQueue {
+    static QUEUE: OnceLock<OfflineQueue> = OnceLock::new();
+    QUEUE.get_or_init(OfflineQueue::default)
+}
+
+/// Whether the command needs the network, rather than the local blockstore
+pub(crate) fn is_network(command: &AllCommands) -> bool {
+    !matches!(command, AllCommands::System(_))
+}
+
+/// Whether ordering the command twice does the same as ordering it once, so it's deduped
+fn is_idempotent(command: &AllCommands) -> bool {
+    matches!(
+        command,
+        AllCommands::Subscribe { .. } | AllCommands::ListenOn(_) | AllCommands::PutRecord { .. }
+    )
+}
+
+impl OfflineQueue {
+    /// Queue the command if it needs the network and the node isn't connected yet.
+    ///
+    /// Returns the eventual result, or None if the command should be ordered now.
+    pub(crate) fn enqueue(
+        &self,
+        command: &AllCommands,
+    ) -> Option<impl Future<Output = Result<ReturnValues, QueueError>>> {
+        if !is_network(command) {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.online {
+            return None;
+        }
+        inner.expire(Instant::now());
+
+        let (tx, rx) = oneshot::channel();
+        let key = is_idempotent(command).then(|| format!("{:?}", command));
+        let same = inner
+            .queued
+            .iter_mut()
+            .find(|queued| key.is_some() && queued.key == key);
+        match same {
+            Some(queued) => queued.waiters.push(tx),
+            None if inner.queued.len() >= QUEUE_CAPACITY => {
+                let _ = tx.send(Err(QueueError::Full));
+            }
+            None => {
+                tracing::info!("Queueing network command until connected");
+                inner.queued.push(Queued {
+                    key,
+                    command: command.clone(),
+                    queued_at: Instant::now(),
+                    waiters: vec![tx],
+                });
+            }
+        }
+
+        Some(async move { rx.await.unwrap_or(Err(QueueError::Dropped)) })
+    }
+
+    /// Mark the node connected and order every waiting command with `order`, in the order
+    /// they were queued, sending each result to its waiters.
+    pub(crate) async fn replay<F, Fut>(&self, order: F)
+    where
+        F: Fn(AllCommands) -> Fut,
+        Fut: Future<Output = Result<ReturnValues, String>>,
+    {
+        let queued = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.online = true;
+            inner.expire(Instant::now());
+            std::mem::take(&mut inner.queued)
+        };
+        if !queued.is_empty() {
+            tracing::info!("Replaying {} queued network commands", queued.len());
+        }
+
+        for queued in queued {
+            let result = order(queued.command).await.map_err(QueueError::Failed);
+            for waiter in queued.waiters {
+                let _ = waiter.send(result.clone());
+            }
+        }
+    }
+
+    /// Mark the node stopped, so network commands wait for the next [Self::replay]
+    pub(crate) fn went_offline(&self) {
+        self.inner.lock().unwrap().online = false;
+    }
+
+    /// Number of commands waiting
+    pub(crate) fn len(&self) -> usize {
+        self.inner.lock().unwrap().queued.len()
+    }
+}
+
+impl Inner {
+    /// Drop commands queued longer than [QUEUE_TTL], telling their waiters
+    fn expire(&mut self, now: Instant) {
+        let (expired, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.queued)
+            .into_iter()
+            .partition(|q| now.saturating_duration_since(q.queued_at) >= QUEUE_TTL);
+        self.queued = kept;
+        for q in expired {
+            tracing::warn!("Network command expired before the node connected");
+            for waiter in q.waiters {
+                let _ = waiter.send(Err(QueueError::Expired));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn publish(topic: &str) -> AllCommands {
+        AllCommands::Publish {
+            topic: topic.to_string(),
+            data: vec![1, 2, 3],
+        }
+    }
+
+    fn subscribe(topic: &str) -> AllCommands {
+        AllCommands::Subscribe {
+            topic: topic.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_queue_dedupes_and_replays() {
+        let queue = OfflineQueue::default();
+        let first = queue.enqueue(&subscribe("a")).unwrap();
+        let duplicate = queue.enqueue(&subscribe("a")).unwrap();
+        let message = queue.enqueue(&publish("a")).unwrap();
+        let repeated = queue.enqueue(&publish("a")).unwrap();
+        let other = queue.enqueue(&publish("b")).unwrap();
+        assert_eq!(queue.len(), 4);
+
+        let ordered = Mutex::new(Vec::new());
+        block_on(queue.replay(|command| {
+            ordered.lock().unwrap().push(format!("{:?}", command));
+            async move {
+                match command {
+                    AllCommands::Publish { topic, .. } if topic == "b" => {
+                        Err("no peers".to_string())
+                    }
+                    _ => Ok(ReturnValues::Data(vec![7])),
+                }
+            }
+        }));
+        // in the order they were queued, publishing twice as ordered twice
+        let expected = [subscribe("a"), publish("a"), publish("a"), publish("b")]
+            .map(|command| format!("{:?}", command));
+        assert_eq!(*ordered.lock().unwrap(), expected);
+
+        assert!(matches!(block_on(first), Ok(ReturnValues::Data(d)) if d == [7]));
+        assert!(matches!(block_on(duplicate), Ok(ReturnValues::Data(d)) if d == [7]));
+        assert!(block_on(message).is_ok());
+        assert!(block_on(repeated).is_ok());
+        assert_eq!(
+            block_on(other).unwrap_err(),
+            QueueError::Failed("no peers".to_string())
+        );
+
+        // once connected, commands are ordered directly, until the node stops
+        assert!(queue.enqueue(&publish("c")).is_none());
+        queue.went_offline();
+        assert!(queue.enqueue(&publish("c")).is_some());
+    }
+
+    #[test]
+    fn test_queue_expires_old_commands() {
+        let queue = OfflineQueue::default();
+        let waiting = queue.enqueue(&publish("a")).unwrap();
+
+        let later = Instant::now() + QUEUE_TTL;
+        queue.inner.lock().unwrap().expire(later);
+
+        assert_eq!(queue.len(), 0);
+        assert_eq!(block_on(waiting).unwrap_err(), QueueError::Expired);
+    }
+}
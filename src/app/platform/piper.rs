@@ -6,7 +6,9 @@ use std::sync::{Arc, Mutex};
 
 use super::platform::Error;
 use crate::app::platform;
+use crate::app::platform::offline::offline_queue;
 use crate::app::platform::platform::Blockstore;
+use crate::app::rdx_runner::share::{Shares, StateShare, SHARE_TOPIC};
 use crate::app::rdx_runner::{LayerPlugin, State};
 
 use futures::{
@@ -14,9 +16,11 @@ use futures::{
         mpsc::{self},
         oneshot,
     },
+    future::{self, Either},
     StreamExt,
 };
 
+use multiaddr::{Multiaddr, Protocol};
 pub use peerpiper::core::events::AllCommands;
 use peerpiper::core::events::Events;
 use peerpiper::core::events::PublicEvent;
@@ -26,6 +30,9 @@ pub use peerpiper::core::ReturnValues;
 use rdx::layer::{Instantiator as _, List, ListType, RecordType, Value, ValueType};
 use tokio::sync::mpsc::Sender;
 
+/// Response to inbound requests which need no data back
+pub const ACK: &[u8] = b"ok";
+
 /// Simplify the plugins signature type with alias
 pub type Plugins = Arc<Mutex<HashMap<String, Arc<Mutex<LayerPlugin<State>>>>>>;
 
@@ -34,33 +41,51 @@ pub struct PeerPiper {
     pub commander: Commander<Blockstore>,
     /// The collection of plugins
     pub plugins: Plugins,
+    /// Plugin states shared with us by peers, and shared by us
+    pub shares: Shares,
+    /// The running node, stopped before connecting again
+    node: Arc<Mutex<Option<Node>>>,
+}
+
+/// Handle to a running node
+struct Node {
+    /// Dropping or sending stops the node
+    stop: oneshot::Sender<()>,
+    /// Resolves once the node's swarm and listeners are dropped
+    stopped: oneshot::Receiver<()>,
 }
 
 impl PeerPiper {
     /// Crate a new PeerPiper instanc ewith the given struct which impls both
     /// [peerpiper::core::Blockstore] and
-    pub fn new(blockstore: Blockstore, arc_collection: Plugins) -> PeerPiper {
+    pub fn new(blockstore: Blockstore, arc_collection: Plugins, shares: Shares) -> PeerPiper {
         let commander = Commander::new(blockstore);
         Self {
             commander,
             plugins: arc_collection,
+            shares,
+            node: Default::default(),
         }
     }
 
     /// Send Commands to PeerPiper whether connected or not.
     ///
-    /// Throws an error if network command are sent before connecting to the network.
+    /// Throws an error if network command are sent before connecting to the network,
+    /// use [offline_queue] to have them wait instead.
     ///
     /// put and get can store and retrieve data locally without network connection.
     pub async fn order(&self, command: AllCommands) -> Result<ReturnValues, Error> {
         Ok(self.commander.order(command).await?)
     }
 
-    /// Try to connect to the list of endpoints.
+    /// Try to connect to the list of endpoints, then listen on the given addresses too.
     /// Send the `on_event` callback to the Commander to be called when an event is received.
+    ///
+    /// A node started by an earlier call is stopped first, so its listeners free their ports.
     pub async fn connect(
         &mut self,
         libp2p_endpoints: Vec<String>,
+        listen_addrs: Vec<Multiaddr>,
     ) -> Result<impl FnOnce(Sender<PublicEvent>), Error> {
         // 16 is arbitrary, but should be enough for now
         let (tx_evts, mut rx_evts) = mpsc::channel(16);
@@ -74,29 +99,85 @@ impl PeerPiper {
 
         let bstore = self.commander.blockstore.clone();
 
+        let previous = self.node.lock().unwrap().take();
+        if let Some(Node { stop, stopped }) = previous {
+            tracing::info!("Stopping the running node before connecting again");
+            let _ = stop.send(());
+            let _ = stopped.await;
+        }
+
+        let (stop, rx_stop) = oneshot::channel();
+        let (tx_stopped, stopped) = oneshot::channel();
+        self.node.lock().unwrap().replace(Node { stop, stopped });
+
         platform::spawn(async move {
-            peerpiper::start(
+            let node = peerpiper::start(
                 tx_evts,
                 network_command_receiver,
                 tx_client,
                 libp2p_endpoints,
                 bstore,
-            )
-            .await
-            .expect("never end")
+            );
+            futures::pin_mut!(node);
+            match future::select(node, rx_stop).await {
+                Either::Left((result, _)) => result.expect("never end"),
+                // the swarm is dropped with the node future
+                Either::Right(_) => tracing::info!("Node stopped"),
+            }
+            // network commands wait for the next node
+            offline_queue().went_offline();
+            let _ = tx_stopped.send(());
         });
 
         // wait on rx_client to get the client handle
         let client_handle = rx_client.await?;
+        // kept to answer inbound requests
+        let mut client = client_handle.clone();
 
         self.commander
             .with_network(network_command_sender)
             .with_client(client_handle);
 
+        // network commands ordered before we were connected
+        let commander = self.commander.clone();
+        platform::spawn(async move {
+            offline_queue()
+                .replay(|command| {
+                    let commander = commander.clone();
+                    async move {
+                        commander
+                            .order(command)
+                            .await
+                            .map_err(|e| format!("{:?}", e))
+                    }
+                })
+                .await
+        });
+
+        // listen for plugin states shared by peers
+        let subscribe = AllCommands::Subscribe {
+            topic: SHARE_TOPIC.to_string(),
+        };
+        if let Err(e) = self.commander.order(subscribe).await {
+            tracing::warn!("Failed to subscribe to {}: {:?}", SHARE_TOPIC, e);
+        }
+
+        for addr in listen_addrs {
+            if let Err(e) = self
+                .commander
+                .order(AllCommands::ListenOn(addr.clone()))
+                .await
+            {
+                tracing::warn!("Failed to listen on {}: {:?}", addr, e);
+            }
+        }
+
         // enable caller to Start listening for events from the network and handle them.
         // Any [Libp2pEvent] received will be handled by the plugins.
         // Any [PublicEvent] received will be sent to the `on_event` callback.
         let plugins = self.plugins.clone();
+        let shares = self.shares.clone();
+        let commander = self.commander.clone();
         let listen = |on_event: Sender<PublicEvent>| {
             platform::spawn(async move {
                 while let Some(event) = rx_evts.next().await {
@@ -104,17 +185,34 @@ impl PeerPiper {
                         // Outter/Public events are not handled by plugins
                         Events::Outer(public_event) => {
                             tracing::debug!("Received event: {:?}", &public_event);
+                            match &public_event {
+                                PublicEvent::Message { topic, data, peer }
+                                    if topic == SHARE_TOPIC =>
+                                {
+                                    // gossip is fetched from whoever published it
+                                    if let Some(share) = StateShare::from_bytes(data) {
+                                        shares.inbox.push(Some(peer.to_string()), true, share);
+                                    }
+                                }
+                                PublicEvent::ListenAddr { address, .. } => {
+                                    if let Some(Protocol::P2p(peer)) = address.iter().last() {
+                                        shares.outbox.set_local_peer(peer.to_string());
+                                    }
+                                }
+                                _ => {}
+                            }
                             on_event.send(public_event).await.unwrap();
                         }
                         // Inner events are events that can be handled by plugins
                         Events::Inner(libp2p_evt) => {
                             tracing::debug!("Received inner libp2p event: {:?}", &libp2p_evt);
                             match libp2p_evt {
-                                Libp2pEvent::InboundRequest {
-                                    request,
-                                    channel: _,
-                                } => {
+                                Libp2pEvent::InboundRequest { request, channel } => {
                                     tracing::debug!("Received inbound request: {:?}", &request);
+                                    // every request is answered, so the sender isn't left
+                                    // waiting until it times out
+                                    let response = shares.answer(&request, &commander).await;
+                                    client.respond_bytes(response, channel).await;
                                 }
                                 Libp2pEvent::DhtProviderRequest { key: _, channel: _ } => todo!(),
                                 Libp2pEvent::PutRecordRequest { source, record } => {
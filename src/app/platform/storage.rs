@@ -0,0 +1,100 @@
+//! Key value string storage, shared by the native and web platforms.
+//!
+//! Each platform's [StringStore](super::StringStore) implements [Storage], and [MemoryStore]
+//! implements it in memory for tests and as a fallback when the platform store can't be opened.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::StringStore;
+
+/// Storage errors
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    /// Reading or writing the native store failed
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The platform storage backend failed, such as browser localStorage being full
+    #[error("Storage backend error: {0}")]
+    Backend(String),
+    /// The key can't be used with this store
+    #[error("Invalid storage key: {0:?}")]
+    InvalidKey(String),
+    /// The platform has no storage available, such as no data directory
+    #[error("Storage unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// String values stored by key
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// Get the value for the key, None if it's not set
+    fn get_string(&self, key: &str) -> Result<Option<String>, StorageError>;
+
+    /// Set the value for the key, replacing any existing value
+    fn set_string(&self, key: &str, value: String) -> Result<(), StorageError>;
+
+    /// Remove the key. Removing a key which isn't set is not an error.
+    fn remove(&self, key: &str) -> Result<(), StorageError>;
+
+    /// All the keys in the store, sorted
+    fn keys(&self) -> Result<Vec<String>, StorageError>;
+}
+
+/// In memory [Storage], nothing is persisted. Cheap to clone, clones share the values.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    values: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl Storage for MemoryStore {
+    fn get_string(&self, key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn set_string(&self, key: &str, value: String) -> Result<(), StorageError> {
+        self.values.lock().unwrap().insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<(), StorageError> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, StorageError> {
+        Ok(self.values.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+/// Open the platform [StringStore].
+///
+/// Falls back to a [MemoryStore] if it can't be opened, so the app still runs,
+/// but nothing stored in it will be there next time.
+pub fn open_default() -> Arc<dyn Storage> {
+    match StringStore::new() {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            tracing::error!("Failed to open storage, falling back to memory: {:?}", e);
+            Arc::new(MemoryStore::default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::default();
+        assert_eq!(store.get_string("a").unwrap(), None);
+
+        store.set_string("b", "2".to_string()).unwrap();
+        store.set_string("a", "1".to_string()).unwrap();
+        assert_eq!(store.get_string("a").unwrap(), Some("1".to_string()));
+        assert_eq!(store.keys().unwrap(), vec!["a", "b"]);
+
+        store.remove("a").unwrap();
+        store.remove("a").unwrap();
+        assert_eq!(store.keys().unwrap(), vec!["b"]);
+    }
+}
@@ -0,0 +1,370 @@
+//! Keeps the node connected, on native and web.
+//!
+//! The supervisor starts the node with every known endpoint, retrying with exponential
+//! backoff while it can't. Once up, it watches node events: if no peer is heard from for
+//! [LIVENESS], the connection is degraded and the endpoints are dialed again, backing off
+//! the same way until a peer answers.
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use peerpiper::core::events::PublicEvent;
+// the tokio clock on native, so tests can pause it
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::Instant;
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+use crate::app::platform;
+
+/// A connection with no peer heard from for this long is degraded
+pub(crate) const LIVENESS: Duration = Duration::from_secs(60);
+
+/// How often a running node is checked for liveness
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where the node is in connecting to the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum ConnectionState {
+    /// Starting the node, or waiting for the first peer
+    #[default]
+    Connecting,
+    /// A peer was heard from recently
+    Connected,
+    /// The node runs, but no peer was heard from recently, so the endpoints are redialed
+    Degraded,
+    /// The node failed to start, waiting to retry
+    Offline,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionState::Connecting => "Connecting",
+            ConnectionState::Connected => "Connected",
+            ConnectionState::Degraded => "Degraded",
+            ConnectionState::Offline => "Offline",
+        })
+    }
+}
+
+/// Exponential backoff between attempts
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+impl Backoff {
+    pub(crate) fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// The delay before the next attempt, doubling each time up to the max
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    state: ConnectionState,
+    /// Bumped on every start, so the previous run stops
+    generation: u64,
+    /// When a peer was last heard from
+    last_heard: Option<Instant>,
+    /// Failed attempts in a row
+    failures: u32,
+    /// Why the last attempt failed
+    error: Option<String>,
+    /// When the next attempt is due, while backing off
+    retry_at: Option<Instant>,
+}
+
+/// How long the supervisor waits, shortened in tests
+#[derive(Debug, Clone)]
+struct Timing {
+    liveness: Duration,
+    check_interval: Duration,
+    backoff: Backoff,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            liveness: LIVENESS,
+            check_interval: CHECK_INTERVAL,
+            backoff: Backoff::default(),
+        }
+    }
+}
+
+/// Connection state shared between the supervising task, the event loop and the UI.
+/// Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Supervisor {
+    inner: Arc<Mutex<Inner>>,
+    timing: Timing,
+}
+
+impl Supervisor {
+    pub(crate) fn state(&self) -> ConnectionState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// A line about the state, such as the last error and the next retry
+    pub(crate) fn details(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut details = inner.state.to_string();
+        if let Some(heard) = inner.last_heard {
+            details.push_str(&format!(
+                ", last peer heard {}s ago",
+                heard.elapsed().as_secs()
+            ));
+        }
+        if inner.failures > 0 {
+            details.push_str(&format!(", {} failed attempts", inner.failures));
+        }
+        if let Some(retry_at) = inner.retry_at {
+            let wait = retry_at.saturating_duration_since(Instant::now());
+            details.push_str(&format!(", retrying in {}s", wait.as_secs()));
+        }
+        if let Some(error) = &inner.error {
+            details.push_str(&format!("\n{}", error));
+        }
+        details
+    }
+
+    /// Note a node event, any peer activity means the node is connected
+    pub(crate) fn observe(&self, event: &PublicEvent) {
+        if matches!(
+            event,
+            PublicEvent::NewConnection { .. }
+                | PublicEvent::Pong { .. }
+                | PublicEvent::Message { .. }
+        ) {
+            self.heard();
+        }
+    }
+
+    /// A peer was heard from, so the node is connected
+    fn heard(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_heard = Some(Instant::now());
+        inner.state = ConnectionState::Connected;
+        inner.failures = 0;
+        inner.error = None;
+        inner.retry_at = None;
+    }
+
+    /// Start supervising the node, stopping any previous run.
+    ///
+    /// `connect` starts the node with the endpoints, `redial` dials them again on the
+    /// running node.
+    pub(crate) async fn supervise<C, CF, R, RF>(self, endpoints: Vec<String>, connect: C, redial: R)
+    where
+        C: Fn(Vec<String>) -> CF,
+        CF: Future<Output = anyhow::Result<()>>,
+        R: Fn(Vec<String>) -> RF,
+        RF: Future<Output = ()>,
+    {
+        let generation = {
+            let mut inner = self.inner.lock().unwrap();
+            let generation = inner.generation + 1;
+            *inner = Inner {
+                generation,
+                ..Default::default()
+            };
+            generation
+        };
+        let mut backoff = self.timing.backoff.clone();
+
+        // bring the node up
+        loop {
+            if !self.is_current(generation) {
+                return;
+            }
+            self.set_state(ConnectionState::Connecting);
+            match connect(endpoints.clone()).await {
+                Ok(()) => break,
+                Err(e) => {
+                    tracing::warn!("Failed to connect to the network: {:?}", e);
+                    let delay = backoff.next_delay();
+                    self.failed(ConnectionState::Offline, format!("{:#}", e), delay);
+                    platform::sleep(delay).await;
+                }
+            }
+        }
+        backoff.reset();
+        let started = Instant::now();
+
+        // keep it connected
+        loop {
+            platform::sleep(self.timing.check_interval).await;
+            if !self.is_current(generation) {
+                return;
+            }
+
+            let last_heard = self.inner.lock().unwrap().last_heard;
+            let since = last_heard.unwrap_or(started);
+            if since.elapsed() < self.timing.liveness {
+                if last_heard.is_some() {
+                    backoff.reset();
+                }
+                continue;
+            }
+
+            let delay = backoff.next_delay();
+            self.failed(
+                ConnectionState::Degraded,
+                format!("No peer heard from for {}s", since.elapsed().as_secs()),
+                delay,
+            );
+            platform::sleep(delay).await;
+            if !self.is_current(generation) {
+                return;
+            }
+            tracing::info!("Redialing {} endpoints", endpoints.len());
+            redial(endpoints.clone()).await;
+            self.inner.lock().unwrap().retry_at = None;
+        }
+    }
+
+    fn is_current(&self, generation: u64) -> bool {
+        self.inner.lock().unwrap().generation == generation
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.inner.lock().unwrap().state = state;
+    }
+
+    fn failed(&self, state: ConnectionState, error: String, delay: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = state;
+        inner.failures += 1;
+        inner.error = Some(error);
+        inner.retry_at = Some(Instant::now() + delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    type Task<T> = std::pin::Pin<Box<dyn Future<Output = T> + Send>>;
+
+    /// Connect and redial which count their calls, failing the first `failures` connects
+    fn fake_node(
+        failures: u32,
+    ) -> (
+        Arc<AtomicU32>,
+        Arc<AtomicU32>,
+        impl Fn(Vec<String>) -> Task<anyhow::Result<()>>,
+        impl Fn(Vec<String>) -> Task<()>,
+    ) {
+        let connects = Arc::new(AtomicU32::new(0));
+        let redials = Arc::new(AtomicU32::new(0));
+        let connects_clone = connects.clone();
+        let connect = move |_| {
+            let attempt = connects_clone.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                match attempt < failures {
+                    true => Err(anyhow::anyhow!("unreachable")),
+                    false => Ok(()),
+                }
+            }) as Task<anyhow::Result<()>>
+        };
+        let redials_clone = redials.clone();
+        let redial = move |_| {
+            redials_clone.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async {}) as Task<()>
+        };
+        (connects, redials, connect, redial)
+    }
+
+    /// Advance the paused clock until the condition holds, failing the test after a second
+    async fn until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervisor_state_machine() {
+        let ms = Duration::from_millis;
+        let supervisor = Supervisor {
+            timing: Timing {
+                liveness: ms(100),
+                check_interval: ms(5),
+                backoff: Backoff::new(ms(20), ms(40)),
+            },
+            ..Default::default()
+        };
+        let endpoints = vec!["/ip4/127.0.0.1/tcp/4001".to_string()];
+        let (connects, redials, connect, redial) = fake_node(2);
+        tokio::spawn(
+            supervisor
+                .clone()
+                .supervise(endpoints.clone(), connect, redial),
+        );
+
+        // offline while the node fails to start, backing off between attempts
+        until(|| connects.load(Ordering::SeqCst) == 1).await;
+        assert_eq!(supervisor.state(), ConnectionState::Offline);
+        until(|| connects.load(Ordering::SeqCst) == 3).await;
+        assert_eq!(supervisor.state(), ConnectionState::Connecting);
+
+        // degraded and redialed when no peer is heard from
+        until(|| supervisor.state() == ConnectionState::Degraded).await;
+        until(|| redials.load(Ordering::SeqCst) > 0).await;
+
+        // connected once a peer is heard from
+        supervisor.heard();
+        assert_eq!(supervisor.state(), ConnectionState::Connected);
+        assert!(supervisor.details().starts_with("Connected"));
+
+        // a new run stops the previous one
+        let (_, new_redials, connect, redial) = fake_node(0);
+        tokio::spawn(supervisor.clone().supervise(endpoints, connect, redial));
+        until(|| new_redials.load(Ordering::SeqCst) > 0).await;
+        let stopped = redials.load(Ordering::SeqCst);
+        tokio::time::advance(ms(300)).await;
+        assert_eq!(redials.load(Ordering::SeqCst), stopped);
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
@@ -13,29 +13,40 @@ mod web_error;
 mod widget;
 
 //pub use peerpiper_browser::opfs::OPFSBlockstore as Blockstore;
-pub use piper::OPFSWrapped as Blockstore;
+/// The blockstore, tracked so unreferenced blocks can be garbage collected
+pub type Blockstore = super::blocks::Tracked<OPFSWrapped>;
 pub(crate) use settings::Settings;
 pub use storage::StringStore;
 pub use web_error::WebError as Error;
 
+use crate::app::network::NetworkSettings;
 use crate::app::platform;
-use crate::app::platform::piper::PeerPiper;
+use crate::app::platform::piper::{AllCommands, PeerPiper};
+use crate::app::platform::supervisor::{ConnectionState, Supervisor};
 use crate::app::platform::web::piper::OPFSWrapped;
+use crate::app::rdx_runner::share::Shares;
+use crate::app::topics::TopicInbox;
 use crate::app::RdxRunner;
-use chrono::TimeZone;
 use multiaddr::Multiaddr;
 use peerpiper::core::events::PublicEvent;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
+/// Most node events kept to show
+const MAX_EVENTS: usize = 500;
+
 pub fn spawn(f: impl Future<Output = ()> + 'static) {
     tracing::debug!("Spawning wasm_bingen future");
     wasm_bindgen_futures::spawn_local(f);
 }
 
+pub async fn sleep(duration: std::time::Duration) {
+    gloo_timers::future::TimeoutFuture::new(duration.as_millis() as u32).await;
+}
+
 /// Reference counted [egui::Context] with a flag to indicate whether it has been set
 /// Track whether the Context has been set
 #[derive(Debug, Default)]
@@ -81,7 +92,7 @@ pub struct Platform {
     /// Clone of the [egui::Context] so that the platform can trigger repaints
     ctx: Rc<RefCell<ContextSet>>,
 
-    /// The node multiaddr to which we are connected
+    /// The node multiaddr to which we are connected, the first bootstrap endpoint by default
     node_multiaddr: String,
 
     /// Plugin bytes Loader
@@ -92,11 +103,28 @@ pub struct Platform {
 
     /// PeerPiper gives us access to the netowrk, storage, and plugins
     pub peerpiper: Rc<RefCell<Option<PeerPiper>>>,
+
+    /// Plugin states shared with us by peers, and shared by us
+    pub(crate) shares: Shares,
+
+    /// Messages received on pub/sub topics
+    pub(crate) topics: TopicInbox,
+
+    /// Keeps the node connected
+    supervisor: Supervisor,
+
+    /// Sender for node events
+    on_event: tokio::sync::mpsc::Sender<PublicEvent>,
+
+    /// Node events, as lines to show, the most recent [MAX_EVENTS]
+    events: Arc<Mutex<VecDeque<String>>>,
 }
 
 impl Default for Platform {
     fn default() -> Self {
         let arc_collection_plugins = Arc::new(Mutex::new(HashMap::new()));
+        let shares = Shares::default();
+        let shares_clone = shares.clone();
 
         let peerpiper = Rc::new(RefCell::new(None));
 
@@ -107,7 +135,11 @@ impl Default for Platform {
                 tracing::error!("Error creating OPFSWrapped instance");
                 return;
             };
-            let peerpiper = PeerPiper::new(blockstore, arc_collection_plugins.clone());
+            let peerpiper = PeerPiper::new(
+                Blockstore::new(blockstore),
+                arc_collection_plugins.clone(),
+                shares_clone,
+            );
 
             // signal to the rdx_runner that the peerpiper is ready
             if let Err(_) = sender.send(peerpiper) {
@@ -140,23 +172,49 @@ impl Default for Platform {
             let _ = rdx_runner.load(name, bytes);
         }
 
+        let ctx = Rc::new(RefCell::new(ContextSet::new()));
+        let supervisor = Supervisor::default();
+        let events = Arc::new(Mutex::new(VecDeque::new()));
+
+        // node events update the connection state and the event lines
+        let (on_event, mut rx_evts) = tokio::sync::mpsc::channel(16);
+        let ctx_clone = ctx.clone();
+        let supervisor_clone = supervisor.clone();
+        let topics = TopicInbox::default();
+        let topics_clone = topics.clone();
+        let events_clone = events.clone();
+        platform::spawn(async move {
+            while let Some(event) = rx_evts.recv().await {
+                tracing::debug!("Received event: {:?}", &event);
+                supervisor_clone.observe(&event);
+                topics_clone.record(&event);
+
+                let formatted_date = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+                let mut events = events_clone.lock().unwrap();
+                if events.len() == MAX_EVENTS {
+                    events.pop_front();
+                }
+                events.push_back(format!("{} {:?}", formatted_date, event));
+                drop(events);
+                ctx_clone.borrow().request_repaint();
+            }
+        });
+
         Self {
-            ctx: Rc::new(RefCell::new(ContextSet::new())),
-            node_multiaddr: "/dnsaddr/peerpiper.io".to_string(),
+            ctx,
+            node_multiaddr: String::new(),
             rdx_runner,
             loader: Default::default(),
             peerpiper,
+            shares,
+            topics,
+            supervisor,
+            on_event,
+            events,
         }
     }
 }
 
-#[derive(Default, Clone)]
-struct ConnectState {
-    response: Vec<String>,
-    is_loading: bool,
-    error: Option<String>,
-    marker: std::marker::PhantomData<()>,
-}
 impl Platform {
     // pub fn close(&mut self) {}
 
@@ -167,6 +225,16 @@ impl Platform {
         Multiaddr::try_from(self.node_multiaddr.clone()).ok()
     }
 
+    /// Connect to the bootstrap endpoints, showing the first one as the node address.
+    ///
+    /// The browser only listens through the node, so listen addresses are not used.
+    pub(crate) fn connect(&mut self, settings: &NetworkSettings) {
+        if let Some(addr) = settings.bootstrap.first() {
+            self.node_multiaddr = addr.clone();
+        }
+        self.supervise(settings.bootstrap.clone());
+    }
+
     /// Returns whether the ctx is set or not
     pub fn egui_ctx(&self) -> bool {
         self.ctx.borrow().set
@@ -217,174 +285,124 @@ impl Platform {
             });
         }
     }
-    /// Connect to node multiaddr and show the state.
-    /// Similar to fetch above, uses some of the ame code and logic,
-    /// but additionally calls peerpiper commander connect() to actually make the connection.
+    /// Node address to dial, with the connection state and node events.
     pub fn dial(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
-        use crate::app::platform::web::widget::fetch_dns_query;
-
-        let url = &mut self.node_multiaddr;
-
-        // Generate an id for the state
-        let state_id = ui.id().with("connection_state_libp2p");
-
-        // Retrieve shared fetch state
-        let mut connect_state =
-            ctx.data_mut(|data| data.get_temp::<ConnectState>(state_id).unwrap_or_default());
-
         ui.label("Addr:");
-        ui.add_sized([300.0, 20.0], egui::TextEdit::singleline(url));
+        ui.add_sized(
+            [300.0, 20.0],
+            egui::TextEdit::singleline(&mut self.node_multiaddr),
+        );
 
         if ui.button("Dial").clicked() {
-            // Update fetch state
-            connect_state.response = Vec::with_capacity(4);
-            connect_state.error = Default::default();
-            connect_state.is_loading = true;
-
-            let ctx_clone = ctx.clone();
+            match self.addr() {
+                Some(addr) => self.supervise(vec![addr.to_string()]),
+                None => {
+                    self.events
+                        .lock()
+                        .unwrap()
+                        .push("Invalid multiaddress".to_string());
+                }
+            }
+            ctx.request_repaint();
+        }
 
-            // show our loading spinner now
-            ctx.data_mut(|data| {
-                data.insert_temp(state_id, connect_state.clone());
-            });
+        match self.supervisor.state() {
+            ConnectionState::Connecting => {
+                ui.spinner();
+            }
+            ConnectionState::Degraded | ConnectionState::Offline => {
+                ui.colored_label(ui.visuals().warn_fg_color, self.supervisor.details());
+            }
+            ConnectionState::Connected => {}
+        }
 
-            let mut connect_state_clone = connect_state.clone();
-            let pp_clone = self.peerpiper.clone();
-            let (on_event, mut rx_evts) = tokio::sync::mpsc::channel(16);
+        // Event display, newest first
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for line in self.events.lock().unwrap().iter().rev() {
+                ui.label(line);
+            }
+        });
+    }
 
-            let maddr = self.addr();
+    /// Keep the node connected to the endpoints, resolving `/dnsaddr` entries first
+    fn supervise(&self, endpoints: Vec<String>) {
+        let pp_clone = self.peerpiper.clone();
+        let on_event = self.on_event.clone();
+        let connect = move |endpoints: Vec<String>| {
+            let pp_clone = pp_clone.clone();
+            let on_event = on_event.clone();
+            async move {
+                let libp2p_endpoints = resolve(endpoints).await;
+                if libp2p_endpoints.is_empty() {
+                    return Err(anyhow::anyhow!("No endpoints to connect to"));
+                }
+                // connect a clone, so the cell isn't borrowed across the await
+                let mut piper = pp_clone
+                    .borrow()
+                    .clone()
+                    .ok_or_else(|| anyhow::anyhow!("PeerPiper is not ready"))?;
+                let listen = piper
+                    .connect(libp2p_endpoints, vec![])
+                    .await
+                    .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+                pp_clone.borrow_mut().replace(piper);
+                listen(on_event);
+                Ok(())
+            }
+        };
 
-            platform::spawn(async move {
-                // if addr is a dnsaddr, fecth dns first to get the libp2p endpoints
-                let libp2p_endpoints = if let Some(addr) = maddr {
-                    if addr
-                        .iter()
-                        .any(|protocol| matches!(protocol, multiaddr::Protocol::Dns(_)))
-                    {
-                        fetch_dns_query(addr.to_string())
-                            .await
-                            .map_err(|e| {
-                                connect_state_clone.error =
-                                    Some(format!("Could not fetch endpoints. Error: {:?}", e));
-                                connect_state_clone.is_loading = false;
-                                connect_state_clone.response = vec!["Endpoints Error".to_string()];
-                                ctx_clone.data_mut(|data| {
-                                    data.insert_temp(state_id, connect_state_clone);
-                                });
-                            })
-                            .unwrap_or_default()
-                    } else {
-                        vec![addr.to_string()]
-                    }
-                } else {
-                    connect_state_clone.error = Some("Invalid multiaddress".to_string());
-                    connect_state_clone.is_loading = false;
-                    connect_state_clone.response = vec!["Invalid multiaddress".to_string()];
-                    ctx_clone.data_mut(|data| {
-                        data.insert_temp(state_id, connect_state_clone);
-                    });
+        let pp_clone = self.peerpiper.clone();
+        let redial = move |endpoints: Vec<String>| {
+            let pp_clone = pp_clone.clone();
+            async move {
+                let Some(piper) = pp_clone.borrow().clone() else {
                     return;
                 };
-
-                let listen = {
-                    match pp_clone
-                        .borrow_mut()
-                        .as_mut()
-                        .unwrap()
-                        .connect(libp2p_endpoints)
-                        .await
-                    {
-                        Ok(listen) => listen,
-                        Err(e) => {
-                            tracing::error!("Failed to connect to the network: {:?}", e);
-                            return;
-                        }
-                    }
-                };
-
-                listen(on_event);
-            });
-
-            let mut connect_state_clone = connect_state.clone();
-            let ctx_clone = ctx.clone();
-            platform::spawn(async move {
-                let timeout = gloo_timers::future::TimeoutFuture::new(10000);
-                tokio::pin!(timeout);
-
-                let connect_state = connect_state_clone.clone();
-                tokio::select! {
-                    _ = &mut timeout => {
-                        connect_state_clone.error = Some("Connection timed out".to_string());
-                        connect_state_clone.is_loading = false;
-                        connect_state_clone.response = vec!["Connect Timeout".to_string()];
-                        ctx_clone.data_mut(|data| {
-                            data.insert_temp(state_id, connect_state_clone);
-                        });
-                        return;
-                    }
-                    // Wait for 5 seconds for a Events::Outer(PublicEvent::NewConnection { peer }) event
-                    // await rx_evts
-                    event = rx_evts.recv() => {
-                        if let Some(PublicEvent::NewConnection { peer }) = event {
-
-                            connect_state_clone.response = vec![format!("Connected to {}", peer)];
-                            connect_state_clone.is_loading = false;
-                            ctx_clone.data_mut(|data| {
-                                data.insert_temp(state_id, connect_state_clone);
-                            });
-                            ctx_clone.request_repaint();
-                        }
+                for endpoint in resolve(endpoints).await {
+                    let Ok(addr) = endpoint.parse::<Multiaddr>() else {
+                        continue;
+                    };
+                    if let Err(e) = piper.order(AllCommands::Dial(addr)).await {
+                        tracing::warn!("Failed to dial {}: {:?}", endpoint, e);
                     }
                 }
+            }
+        };
 
-                while let Some(event) = rx_evts.recv().await {
-                    let mut connect_state_clone = ctx_clone.data_mut(|data| {
-                        data.get_temp::<ConnectState>(state_id)
-                            .unwrap_or(connect_state.clone())
-                    });
-
-                    let unix_timestamp = rdx::layer::SystemTime::now()
-                        .duration_since(rdx::layer::SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs() as i64;
-
-                    let datetime: chrono::DateTime<chrono::Utc> =
-                        chrono::Utc.timestamp_opt(unix_timestamp, 0).unwrap();
-
-                    // format into date, hours,mins, seconds
-                    let formatted_date = datetime.format("%Y-%m-%d %H:%M:%S");
-
-                    // TODO: Wire up these events to the plugins
-                    tracing::debug!("{formatted_date} Received event: {:?}", &event);
-                    // put the event on to the front of .response
-                    connect_state_clone
-                        .response
-                        .push(format!("{} {:?}", formatted_date, event));
-
-                    ctx_clone.data_mut(|data| {
-                        data.insert_temp(state_id, connect_state_clone);
-                    });
-                }
-            });
-        }
+        platform::spawn(
+            self.supervisor
+                .clone()
+                .supervise(endpoints, connect, redial),
+        );
+    }
 
-        // Loading indicator
-        if connect_state.is_loading {
-            ui.spinner();
-        }
+    /// Connection state of the node
+    pub(crate) fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+}
 
-        // Error display
-        if let Some(error) = &connect_state.error {
-            ui.colored_label(egui::Color32::RED, error);
-        }
+/// The libp2p endpoints of the multiaddrs, fetching the peers listed by `/dnsaddr` entries
+async fn resolve(endpoints: Vec<String>) -> Vec<String> {
+    use crate::app::platform::web::widget::fetch_dns_query;
 
-        // Response display
-        egui::ScrollArea::vertical().show(ui, |ui| {
-            //ui.push_id("connect_response");
-            for line in connect_state.response.into_iter().rev() {
-                // print out len of response
-                ui.label(line);
+    let mut resolved = vec![];
+    for endpoint in endpoints {
+        let Ok(addr) = endpoint.parse::<Multiaddr>() else {
+            tracing::warn!("Skipping invalid endpoint {}", endpoint);
+            continue;
+        };
+        if addr
+            .iter()
+            .any(|protocol| matches!(protocol, multiaddr::Protocol::Dnsaddr(_)))
+        {
+            match fetch_dns_query(addr.to_string()).await {
+                Ok(addrs) => resolved.extend(addrs),
+                Err(e) => tracing::warn!("Could not fetch endpoints of {}: {:?}", addr, e),
             }
-        });
+        } else {
+            resolved.push(addr.to_string());
+        }
     }
+    resolved
 }
@@ -2,25 +2,120 @@
 #![allow(dead_code)]
 
 use super::web_error::WebError as Error;
+use crate::app::profile::Profile;
+use eframe::wasm_bindgen::{JsCast as _, JsValue};
 use peerpiper_browser::opfs::OPFSBlockstore;
 use peerpiper_browser::Blockstore;
 use send_wrapper::SendWrapper;
 use std::ops::Deref;
+use wasm_bindgen_futures::{js_sys, JsFuture};
+use web_sys::{
+    DomException, File, FileSystemDirectoryHandle, FileSystemFileHandle,
+    FileSystemGetDirectoryOptions, FileSystemGetFileOptions, FileSystemWritableFileStream,
+};
+
+/// OPFS directory holding a directory of blocks for each named profile
+const PROFILES_DIR: &str = "multinode-profiles";
 
 /// A Wrapper sturct around OPFSBlockstore so that we can make it Send
 #[derive(Debug, Clone)]
 pub struct OPFSWrapped {
-    inner: SendWrapper<OPFSBlockstore>,
+    inner: Inner,
+}
+
+#[derive(Debug, Clone)]
+enum Inner {
+    /// The default profile keeps the blocks where they always were
+    Default(SendWrapper<OPFSBlockstore>),
+    /// A named profile's own directory, one file per CID
+    Profile(SendWrapper<FileSystemDirectoryHandle>),
 }
 
 impl OPFSWrapped {
+    /// Open the active profile's blockstore
     pub async fn new() -> Result<Self, Error> {
-        let handler = OPFSBlockstore::new()
-            .await
-            .map_err(|e| Error::OPFSBlockstore(e.as_string().unwrap_or_default()))?;
-        Ok(Self {
-            inner: SendWrapper::new(handler),
-        })
+        let profile = Profile::active();
+        let inner = match profile.is_default() {
+            true => {
+                let handler = OPFSBlockstore::new()
+                    .await
+                    .map_err(|e| Error::OPFSBlockstore(e.as_string().unwrap_or_default()))?;
+                Inner::Default(SendWrapper::new(handler))
+            }
+            false => {
+                let dir = profile_dir(profile.name())
+                    .await
+                    .map_err(|e| Error::OPFSBlockstore(js_error(e)))?;
+                Inner::Profile(SendWrapper::new(dir))
+            }
+        };
+        Ok(Self { inner })
+    }
+}
+
+/// The profile's blocks directory in OPFS, created if missing
+async fn profile_dir(name: &str) -> Result<FileSystemDirectoryHandle, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+    let root: FileSystemDirectoryHandle =
+        JsFuture::from(window.navigator().storage().get_directory())
+            .await?
+            .dyn_into()?;
+
+    let options = FileSystemGetDirectoryOptions::new();
+    options.set_create(true);
+    let mut dir = root;
+    for name in [PROFILES_DIR, name] {
+        dir = JsFuture::from(dir.get_directory_handle_with_options(name, &options))
+            .await?
+            .dyn_into()?;
+    }
+    Ok(dir)
+}
+
+/// Whether the OPFS call failed because the entry doesn't exist
+fn is_not_found(e: &JsValue) -> bool {
+    e.dyn_ref::<DomException>()
+        .is_some_and(|e| e.name() == "NotFoundError")
+}
+
+fn js_error(e: JsValue) -> String {
+    e.as_string().unwrap_or_else(|| format!("{:?}", e))
+}
+
+fn block_error(e: JsValue) -> blockstore::Error {
+    blockstore::Error::FatalDatabaseError(js_error(e))
+}
+
+async fn get_file(dir: &FileSystemDirectoryHandle, name: &str) -> Result<Option<Vec<u8>>, JsValue> {
+    let handle: FileSystemFileHandle = match JsFuture::from(dir.get_file_handle(name)).await {
+        Ok(handle) => handle.dyn_into()?,
+        Err(e) if is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let file: File = JsFuture::from(handle.get_file()).await?.dyn_into()?;
+    let buffer = JsFuture::from(file.array_buffer()).await?;
+    Ok(Some(js_sys::Uint8Array::new(&buffer).to_vec()))
+}
+
+async fn put_file(dir: &FileSystemDirectoryHandle, name: &str, data: &[u8]) -> Result<(), JsValue> {
+    let options = FileSystemGetFileOptions::new();
+    options.set_create(true);
+    let handle: FileSystemFileHandle =
+        JsFuture::from(dir.get_file_handle_with_options(name, &options))
+            .await?
+            .dyn_into()?;
+    let stream: FileSystemWritableFileStream =
+        JsFuture::from(handle.create_writable()).await?.dyn_into()?;
+    let bytes = js_sys::Uint8Array::from(data);
+    JsFuture::from(stream.write_with_buffer_source(&bytes)?).await?;
+    JsFuture::from(stream.close()).await?;
+    Ok(())
+}
+
+async fn remove_file(dir: &FileSystemDirectoryHandle, name: &str) -> Result<(), JsValue> {
+    match JsFuture::from(dir.remove_entry(name)).await {
+        Err(e) if !is_not_found(&e) => Err(e),
+        _ => Ok(()),
     }
 }
 
@@ -30,7 +125,10 @@ impl Blockstore for OPFSWrapped {
         cid: &cid::CidGeneric<S>,
     ) -> blockstore::Result<Option<Vec<u8>>> {
         tracing::debug!("Getting block from OPFS for CID: {:?}", cid);
-        self.inner.deref().get(cid).await
+        match &self.inner {
+            Inner::Default(store) => store.deref().get(cid).await,
+            Inner::Profile(dir) => get_file(dir, &cid.to_string()).await.map_err(block_error),
+        }
     }
 
     async fn put_keyed<const S: usize>(
@@ -38,12 +136,21 @@ impl Blockstore for OPFSWrapped {
         cid: &cid::CidGeneric<S>,
         data: &[u8],
     ) -> blockstore::Result<()> {
-        self.inner.deref().put_keyed(cid, data).await
+        match &self.inner {
+            Inner::Default(store) => store.deref().put_keyed(cid, data).await,
+            Inner::Profile(dir) => put_file(dir, &cid.to_string(), data)
+                .await
+                .map_err(block_error),
+        }
     }
 
-    async fn remove<const S: usize>(&self, _cid: &cid::CidGeneric<S>) -> blockstore::Result<()> {
-        //todo!();
-        Ok(())
+    async fn remove<const S: usize>(&self, cid: &cid::CidGeneric<S>) -> blockstore::Result<()> {
+        match &self.inner {
+            Inner::Default(store) => store.deref().remove(cid).await,
+            Inner::Profile(dir) => remove_file(dir, &cid.to_string())
+                .await
+                .map_err(block_error),
+        }
     }
 
     async fn close(self) -> blockstore::Result<()> {
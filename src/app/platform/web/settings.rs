@@ -1,6 +1,8 @@
 //! Web speicifc settings
 use multiaddr::Multiaddr;
 
+use crate::app::rdx_runner::vault::Vault;
+
 /// Settings specific to the native platform
 #[derive(Default, Clone, serde::Deserialize, serde::Serialize)]
 pub(crate) struct Settings {
@@ -10,7 +12,13 @@ pub(crate) struct Settings {
 
 impl Settings {
     /// Show the current settings
-    pub fn show(&mut self, ctx: &egui::Context, _ui: &mut egui::Ui, _addr: &Multiaddr) {
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        _ui: &mut egui::Ui,
+        _addr: &Multiaddr,
+        _vault: &Vault,
+    ) {
         egui::Window::new("Browser Options")
             .open(&mut self.open)
             .resizable(true)
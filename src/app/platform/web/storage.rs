@@ -1,39 +1,120 @@
 //! String storage for web platform
 //! uses wasm_bindgen for local storage to store key value pairs
-use eframe::web_sys::Storage;
+use cid::Cid;
+use eframe::web_sys::Storage as LocalStorage;
 use send_wrapper::SendWrapper;
 
+use crate::app::platform::blocks::{INDEX_KEY, JOURNAL_KEY, PINS_KEY};
+use crate::app::platform::storage::{Storage, StorageError};
+use crate::app::profile::{Profile, DEFAULT_KEY_PREFIX, KEY_PREFIX};
+use crate::app::rdx_runner::keyring::PUBLIC_KEY;
+use crate::app::rdx_runner::vault::VAULT_KEY;
+
+/// Keys the default profile stored without a prefix, before it had one. Plugin names
+/// were stored without a prefix too, and are told apart by their CID values.
+const LEGACY_KEYS: &[&str] = &[INDEX_KEY, JOURNAL_KEY, PINS_KEY, PUBLIC_KEY, VAULT_KEY];
+
+/// localStorage key set once the default profile's keys are moved under its prefix
+const MIGRATED_KEY: &str = "multinode-cids-migrated";
+
 /// String storage for web platform
 #[derive(Debug, Clone)]
 pub struct StringStore {
-    storage: SendWrapper<Storage>,
-}
-
-impl Default for StringStore {
-    fn default() -> Self {
-        Self::new()
-    }
+    storage: SendWrapper<LocalStorage>,
+    /// Prefix for the active profile's keys, since localStorage is shared by all profiles
+    prefix: String,
 }
 
 impl StringStore {
-    pub fn new() -> Self {
-        let window = web_sys::window().expect("no global `window` exists");
+    pub fn new() -> Result<Self, StorageError> {
+        let window = web_sys::window()
+            .ok_or_else(|| StorageError::Unavailable("no global `window` exists".to_string()))?;
         let storage = window
             .local_storage()
-            .expect("failed to get local storage")
-            .expect("no local storage found");
-        StringStore {
+            .map_err(backend_error)?
+            .ok_or_else(|| StorageError::Unavailable("no local storage found".to_string()))?;
+        let profile = Profile::active();
+        let store = StringStore {
             storage: SendWrapper::new(storage),
+            prefix: profile.key_prefix(),
+        };
+        if profile.is_default() {
+            store.migrate()?;
         }
+        Ok(store)
     }
 
-    pub fn get_string(&self, key: &str) -> Option<String> {
-        self.storage.get_item(key).ok().flatten()
+    /// Move the default profile's keys from before it had a prefix under it, once
+    fn migrate(&self) -> Result<(), StorageError> {
+        if self
+            .storage
+            .get_item(MIGRATED_KEY)
+            .map_err(backend_error)?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let len = self.storage.length().map_err(backend_error)?;
+        let keys = (0..len)
+            .filter_map(|i| self.storage.key(i).ok().flatten())
+            .filter(|key| !key.starts_with(KEY_PREFIX) && !key.starts_with(DEFAULT_KEY_PREFIX))
+            .collect::<Vec<_>>();
+        for key in keys {
+            let Some(value) = self.storage.get_item(&key).map_err(backend_error)? else {
+                continue;
+            };
+            if LEGACY_KEYS.contains(&key.as_str()) || Cid::try_from(value.trim()).is_ok() {
+                self.storage
+                    .set_item(&self.key(&key), &value)
+                    .map_err(backend_error)?;
+                self.storage.remove_item(&key).map_err(backend_error)?;
+            }
+        }
+        self.storage
+            .set_item(MIGRATED_KEY, "true")
+            .map_err(backend_error)
     }
 
-    pub fn set_string(&self, key: &str, value: String) {
+    /// The localStorage key for the store key
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// The store key for the localStorage key, None if it isn't one of this store's
+    fn strip(&self, key: String) -> Option<String> {
+        key.strip_prefix(&self.prefix).map(str::to_string)
+    }
+}
+
+impl Storage for StringStore {
+    fn get_string(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.storage.get_item(&self.key(key)).map_err(backend_error)
+    }
+
+    fn set_string(&self, key: &str, value: String) -> Result<(), StorageError> {
         self.storage
-            .set_item(key, &value)
-            .expect("failed to set item in local storage");
+            .set_item(&self.key(key), &value)
+            .map_err(backend_error)
     }
+
+    fn remove(&self, key: &str) -> Result<(), StorageError> {
+        self.storage
+            .remove_item(&self.key(key))
+            .map_err(backend_error)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, StorageError> {
+        let len = self.storage.length().map_err(backend_error)?;
+        let mut keys = (0..len)
+            .filter_map(|i| self.storage.key(i).ok().flatten())
+            .filter_map(|key| self.strip(key))
+            .collect::<Vec<_>>();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// localStorage errors are JsValues, keep their debug output
+fn backend_error(e: eframe::wasm_bindgen::JsValue) -> StorageError {
+    StorageError::Backend(format!("{:?}", e))
 }
@@ -0,0 +1,266 @@
+//! Named user profiles, each with its own blockstore, CID map, settings and wallet.
+//!
+//! The profile is chosen once at startup, before the node starts, and stays active for the
+//! life of the process. The `default` profile uses the same locations as before profiles
+//! existed, so existing data shows up there.
+use std::sync::OnceLock;
+
+/// Name of the profile used when none is chosen
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// eframe storage key for the list of known profiles
+pub(crate) const PROFILES_KEY: &str = "multinode-profiles";
+
+/// Start of every non-default profile's prefix in shared key value stores
+pub(crate) const KEY_PREFIX: &str = "profile:";
+
+/// The default profile's prefix in shared key value stores, so its keys aren't mixed up
+/// with everything else stored there
+pub(crate) const DEFAULT_KEY_PREFIX: &str = "cids:";
+
+/// The profile chosen at startup
+static ACTIVE: OnceLock<Profile> = OnceLock::new();
+
+/// Profile errors
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    /// Profile names end up in paths and storage keys, so only simple names are allowed
+    #[error("Invalid profile name {0:?}: use letters, numbers, '-' and '_'")]
+    InvalidName(String),
+    /// A different profile is already active in this process
+    #[error("Profile {0} is already active, restart to switch profiles")]
+    AlreadyActive(String),
+}
+
+/// A named profile
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub struct Profile {
+    name: String,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_PROFILE.to_string(),
+        }
+    }
+}
+
+impl Profile {
+    /// A profile with the given name, if the name is valid
+    pub fn new(name: impl AsRef<str>) -> Result<Self, ProfileError> {
+        let name = name.as_ref().trim();
+        let valid = !name.is_empty()
+            && name.len() <= 64
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        match valid {
+            true => Ok(Self {
+                name: name.to_string(),
+            }),
+            false => Err(ProfileError::InvalidName(name.to_string())),
+        }
+    }
+
+    /// The profile name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether this is the default profile
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_PROFILE
+    }
+
+    /// The active profile, or the default profile if none was activated
+    pub fn active() -> Profile {
+        ACTIVE.get().cloned().unwrap_or_default()
+    }
+
+    /// Make this the active profile. Can only be done once, before the node starts.
+    pub fn activate(self) -> Result<(), ProfileError> {
+        let active = ACTIVE.get_or_init(|| self.clone());
+        match active == &self {
+            true => Ok(()),
+            false => Err(ProfileError::AlreadyActive(active.name.clone())),
+        }
+    }
+
+    /// Parse the `--profile <name>` or `--profile=<name>` flag from the command line arguments
+    pub fn from_args(
+        args: impl IntoIterator<Item = String>,
+    ) -> Option<Result<Profile, ProfileError>> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if let Some(name) = arg.strip_prefix("--profile=") {
+                return Some(Profile::new(name));
+            }
+            if arg == "--profile" {
+                return Some(Profile::new(args.next().unwrap_or_default()));
+            }
+        }
+        None
+    }
+
+    /// eframe storage key for this profile's persisted app state
+    pub(crate) fn app_key(&self, base: &str) -> String {
+        match self.is_default() {
+            true => base.to_string(),
+            false => format!("{}-profile-{}", base, self.name),
+        }
+    }
+
+    /// Prefix for this profile's keys in shared key value stores, like browser localStorage
+    pub(crate) fn key_prefix(&self) -> String {
+        match self.is_default() {
+            true => DEFAULT_KEY_PREFIX.to_string(),
+            false => format!("{}{}/", KEY_PREFIX, self.name),
+        }
+    }
+
+    /// The directory holding all of this profile's data on native
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn data_dir(&self) -> Option<std::path::PathBuf> {
+        let base = dirs::data_dir()?.join("multinode");
+        match self.is_default() {
+            true => Some(base),
+            false => Some(base.join("profiles").join(&self.name)),
+        }
+    }
+}
+
+/// The profiles shown in the picker, persisted in eframe storage
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ProfileList {
+    /// Known profiles, sorted
+    profiles: Vec<Profile>,
+    /// The profile opened last time, selected by default
+    last: Option<Profile>,
+}
+
+impl Default for ProfileList {
+    fn default() -> Self {
+        Self {
+            profiles: vec![Profile::default()],
+            last: None,
+        }
+    }
+}
+
+impl ProfileList {
+    /// Record the profile as known and last used
+    pub(crate) fn opened(&mut self, profile: &Profile) {
+        if !self.profiles.contains(profile) {
+            self.profiles.push(profile.clone());
+            self.profiles.sort();
+        }
+        self.last = Some(profile.clone());
+    }
+}
+
+/// Startup screen to choose or create a profile
+#[derive(Default)]
+pub(crate) struct ProfilePicker {
+    /// The known profiles
+    pub(crate) list: ProfileList,
+    /// Name typed in for a new profile
+    new_name: String,
+    /// Error from the last attempt to create a profile
+    error: Option<String>,
+}
+
+impl ProfilePicker {
+    pub(crate) fn new(list: ProfileList) -> Self {
+        Self {
+            list,
+            ..Default::default()
+        }
+    }
+
+    /// Show the picker, returning the profile once one is chosen
+    pub(crate) fn show(&mut self, ctx: &egui::Context) -> Option<Profile> {
+        let mut chosen = None;
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                ui.heading("Choose a profile");
+                ui.label("Each profile has its own wallet, plugins, data and settings.");
+            });
+
+            ui.separator();
+
+            for profile in &self.list.profiles {
+                let is_last = self.list.last.as_ref() == Some(profile);
+                let button = egui::Button::new(profile.name()).selected(is_last);
+                if ui.add_sized([ui.available_width(), 24.0], button).clicked() {
+                    chosen = Some(profile.clone());
+                }
+            }
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                let edit = ui.add(
+                    egui::TextEdit::singleline(&mut self.new_name).hint_text("New profile name"),
+                );
+                let submit = edit.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if ui.button("Create").clicked() || submit {
+                    match Profile::new(&self.new_name) {
+                        Ok(profile) => {
+                            self.error = None;
+                            chosen = Some(profile);
+                        }
+                        Err(e) => self.error = Some(e.to_string()),
+                    }
+                }
+            });
+
+            if let Some(error) = &self.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+
+        if let Some(profile) = &chosen {
+            self.list.opened(profile);
+        }
+
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_names_and_flag() {
+        assert!(Profile::new("work-2").is_ok());
+        assert!(Profile::new("../escape").is_err());
+        assert!(Profile::new("").is_err());
+
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            Profile::from_args(args(&["multinode", "--profile", "alice"])).map(|p| p.unwrap()),
+            Some(Profile::new("alice").unwrap())
+        );
+        assert_eq!(
+            Profile::from_args(args(&["multinode", "--profile=bob"])).map(|p| p.unwrap()),
+            Some(Profile::new("bob").unwrap())
+        );
+        assert!(Profile::from_args(args(&["multinode"])).is_none());
+
+        // the default profile keeps the locations from before profiles existed
+        assert_eq!(Profile::default().app_key("app"), "app");
+        // but its keys in shared stores are kept apart from everything else
+        assert_eq!(Profile::default().key_prefix(), "cids:");
+        assert_eq!(
+            Profile::new("alice").unwrap().key_prefix(),
+            "profile:alice/"
+        );
+        assert_eq!(
+            Profile::new("alice").unwrap().app_key("app"),
+            "app-profile-alice"
+        );
+    }
+}
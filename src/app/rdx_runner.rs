@@ -1,22 +1,48 @@
 //! The RdxApp struct is the main struct that holds all the plugins and their state.
 #![allow(clippy::arc_with_non_send_sync)]
 
-#[cfg(not(target_arch = "wasm32"))]
-mod debouncer; // debouncer for tokio only
-
+pub(crate) mod backup;
+pub(crate) mod bundle;
+pub(crate) mod car;
+mod debouncer;
+pub(crate) mod gc;
+pub(crate) mod history;
+pub(crate) mod inspect;
+pub(crate) mod keyring;
 mod layer;
+pub(crate) mod quarantine;
+pub(crate) mod share;
+pub(crate) mod vault;
 
 use crate::app::platform;
-
+use crate::app::platform::offline::offline_queue;
+use crate::app::platform::platform::Blockstore;
+use crate::app::platform::storage;
+use crate::app::Profile;
+use peerpiper::core::Cid;
+
+use backup::{BackupArchive, BackupError};
+use car::{Car, CarError};
+use debouncer::{Debouncer, PlatformTimer};
+use gc::{GcError, GcReport, References, Retention};
+use keyring::Keyring;
 pub use layer::LayerPlugin;
+use quarantine::{Corrupted, Recovery};
 use rdx::{
     layer::{rhai::Dynamic, Instantiator, Value},
     PluginDeets,
 };
+use std::future::Future;
+use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
-use std::{ops::Deref, sync::Mutex};
+use std::{
+    ops::Deref,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Mutex, OnceLock},
+};
 #[cfg(not(target_arch = "wasm32"))]
 use tokio::sync::Mutex as AsyncMutex;
+use vault::Vault;
 
 #[cfg(not(target_arch = "wasm32"))]
 mod state;
@@ -32,13 +58,67 @@ use std::rc::Rc;
 #[cfg(target_arch = "wasm32")]
 pub use web::state::State;
 
-use super::platform::piper::PeerPiper;
+use super::platform::piper::{AllCommands, PeerPiper, ReturnValues};
 
 #[cfg(not(target_arch = "wasm32"))]
 type PeerPiperWired = Arc<AsyncMutex<PeerPiper>>;
 #[cfg(target_arch = "wasm32")]
 type PeerPiperWired = Rc<RefCell<Option<PeerPiper>>>;
 
+/// Wallet plugin variables kept in plaintext when its state is encrypted.
+///
+/// The wallet derives the keyring, so it needs its encrypted seed before it can be unlocked.
+const WALLET_PLAINTEXT: &[&str] = &["encrypted_seed"];
+
+/// Where a plugin is in loading its saved state and running its `init`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum LoadStatus {
+    /// Loading the saved state, the plugin isn't ready to use yet
+    #[default]
+    Loading,
+    /// Loaded and initialized
+    Ready,
+    /// The saved state couldn't be loaded, so the plugin started without it
+    Failed(String),
+    /// The saved state is corrupted, so the plugin started without it and won't save
+    /// until it's recovered
+    Corrupted(Corrupted),
+}
+
+/// How long shutdown waits for plugin states to be written
+pub(crate) const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Plugin states save once they stop changing for this long
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// Plugin states which keep changing still save this often
+const SAVE_MAX_WAIT: Duration = Duration::from_secs(5);
+
+/// Debounced plugin state saves, by plugin name
+pub(crate) fn state_saves() -> &'static Debouncer<String, State> {
+    static SAVES: OnceLock<Debouncer<String, State>> = OnceLock::new();
+    SAVES.get_or_init(|| {
+        Debouncer::new(PlatformTimer, SAVE_DELAY, |name, state: State| {
+            platform::spawn(async move {
+                match state.async_save().await {
+                    Ok(cid) => tracing::info!("State of {} saved to CID: {}", name, cid),
+                    Err(e) if history::is_locked(&e) => {
+                        tracing::debug!("State of {} not saved, keyring is locked", name)
+                    }
+                    Err(e) if backup::is_restart_pending(&e) => {
+                        tracing::debug!("State of {} not saved, restart pending", name)
+                    }
+                    Err(e) if quarantine::is_corrupted(&e) => {
+                        tracing::debug!("State of {} not saved, it's quarantined", name)
+                    }
+                    Err(e) => tracing::error!("Error saving state of {}: {:?}", name, e),
+                }
+            });
+        })
+        .with_max_wait(SAVE_MAX_WAIT)
+    })
+}
+
 /// The RdxRunner struct is the main struct that holds all the plugins and their state.
 pub struct RdxRunner {
     /// The PeerPiper pointer
@@ -51,6 +131,14 @@ pub struct RdxRunner {
     pub(crate) arc_wallet: Option<Arc<Mutex<dyn Instantiator<State>>>>,
     /// egui Context option, so a change in State can request a repaint
     ctx: Option<egui::Context>,
+    /// Keys derived from the wallet, used to encrypt plugin state at rest
+    pub(crate) keyring: Keyring,
+    /// Secrets of the settings and plugins, sealed with the keyring
+    pub(crate) vault: Vault,
+    /// Whether the states were flushed on exit, so it's only done once
+    exit_flushed: AtomicBool,
+    /// Set when the wallet's state changes, such as when it's unlocked
+    wallet_changed: Arc<AtomicBool>,
     /// For wasm32, we need to wait for the receiver to be ready before we can use PeerPiper
     #[cfg(target_arch = "wasm32")]
     receiver: Option<futures::channel::oneshot::Receiver<PeerPiper>>,
@@ -63,12 +151,19 @@ impl RdxRunner {
         #[cfg(target_arch = "wasm32")] receiver: futures::channel::oneshot::Receiver<PeerPiper>,
     ) -> Self {
         let all_plugin_deets = HashMap::new();
+        let keyring = Keyring::default();
+        let vault = Vault::new(keyring.clone(), storage::open_default());
 
         Self {
             peerpiper,
             plugins: all_plugin_deets,
             arc_wallet: None,
             ctx,
+            keyring,
+            vault,
+            exit_flushed: AtomicBool::new(false),
+            // ask once at start, the wallet may already be unlocked
+            wallet_changed: Arc::new(AtomicBool::new(true)),
             #[cfg(target_arch = "wasm32")]
             receiver: Some(receiver),
         }
@@ -94,26 +189,30 @@ impl RdxRunner {
             None => None,
         };
 
+        let mut state = State::new(
+            name.to_string(),
+            self.ctx.clone(),
+            self.peerpiper.clone(),
+            self.keyring.clone(),
+        );
+
+        // the first plugin loaded, without a wallet, is the wallet itself
+        if self.arc_wallet.is_none() {
+            state = state
+                .as_wallet(self.wallet_changed.clone())
+                .with_plaintext(WALLET_PLAINTEXT);
+        }
+
         let mut plugin = LayerPlugin::new(
+            name,
             wasm_bytes,
-            State::new(name.to_string(), self.ctx.clone(), self.peerpiper.clone()),
+            state,
+            self.vault.clone(),
             self.arc_wallet.clone(),
             commander,
         );
         let rdx_source = plugin.call("load", &[]).unwrap();
 
-        // If this is NOT wasm32 target_arch, we will have the init scope loaded
-        // with State::new() already thus we can call the init() function here
-        // so that the scope can be loaded into the plugin
-        //
-        // Contrary with wasm32, the init() will have to happen after the commander is ready
-        #[cfg(not(target_arch = "wasm32"))]
-        if let Err(e) = plugin.call("init", &[]) {
-            // it's ok not to have an init function
-            // the plugin just won't be initialized with any loaded scope
-            tracing::warn!("Failed to call init on plugin: {:?}", e);
-        }
-
         let Some(Value::String(rdx_source)) = rdx_source else {
             tracing::error!("RDX Source must be a string");
             // this will never happen, the WIT interface for `load` will always return a string
@@ -128,10 +227,14 @@ impl RdxRunner {
             let piper_clone = self.peerpiper.clone();
             let name = name.to_string();
             let arc_plugin_clone = arc_plugin.clone();
+            let binary = wasm_bytes.to_vec();
             platform::spawn(async move {
-                let binding = piper_clone.lock().await;
-                let mut hash_map = binding.plugins.lock().unwrap();
-                hash_map.insert(name, arc_plugin_clone);
+                {
+                    let binding = piper_clone.lock().await;
+                    let mut hash_map = binding.plugins.lock().unwrap();
+                    hash_map.insert(name.clone(), arc_plugin_clone.clone());
+                }
+                initialize(name, arc_plugin_clone, binary).await;
             });
         }
 
@@ -147,6 +250,7 @@ impl RdxRunner {
                 let receiver = self.receiver.take().unwrap();
                 let name_clone = name.to_string();
                 let arc_plugin_clone = arc_plugin.clone();
+                let binary = wasm_bytes.to_vec();
 
                 platform::spawn(async move {
                     let piper = receiver.await.unwrap();
@@ -165,30 +269,13 @@ impl RdxRunner {
                             .lock()
                             .unwrap()
                             .insert(name_clone.clone(), arc_plugin_clone.clone());
-
-                        tracing::info!("Initializing plugin: {:?}", &name_clone);
-                        let state = {
-                            let plugin = arc_plugin_clone.lock().unwrap();
-                            plugin.store().data().clone()
-                        };
-                        state.init().await;
-                        tracing::info!("Initialized plugin: {:?}", name_clone);
-
-                        // once the scope is loaded, we should call the init() function
-                        // to intiiate the plugin with the given state
-                        // wasm32 happens here, whereas native happens in the loop above after
-                        // State::new() is called
-                        let mut plugin = arc_plugin_clone.lock().unwrap();
-                        if let Err(e) = plugin.call("init", &[]) {
-                            // it's ok not to have an init function
-                            // the plugin just won't be initialized with any loaded scope
-                            tracing::warn!("Failed to call init on plugin: {:?}", e);
-                        }
                     }
+                    initialize(name_clone, arc_plugin_clone, binary).await;
                 });
             } else {
                 let name_clone = name.to_string();
                 let arc_plugin_clone = arc_plugin.clone();
+                let binary = wasm_bytes.to_vec();
                 platform::spawn(async move {
                     // This is a bit of a hack because of the way async work in the browser.
                     // We need to ensure that the peerpiper is ready before we can use it.
@@ -201,31 +288,16 @@ impl RdxRunner {
                         .await;
                     }
 
-                    let binding = peerpiper_clone.borrow();
-                    let piper = binding.as_ref().unwrap();
-                    piper
-                        .plugins
-                        .lock()
-                        .unwrap()
-                        .insert(name_clone.clone(), arc_plugin_clone.clone());
-                    let state = {
-                        let plugin = arc_plugin_clone.lock().unwrap();
-                        plugin.store().data().clone()
-                    };
-                    tracing::info!("Initializing plugin: {:?}", &name_clone);
-                    state.init().await;
-                    tracing::info!("Initialized plugin: {:?}", name_clone);
-
-                    // once the scope is loaded, we should call the init() function
-                    // to intiiate the plugin with the given state
-                    // wasm32 happens here, whereas native happens in the loop above after
-                    // State::new() is called
-                    let mut plugin = arc_plugin_clone.lock().unwrap();
-                    if let Err(e) = plugin.call("init", &[]) {
-                        // it's ok not to have an init function
-                        // the plugin just won't be initialized with any loaded scope
-                        tracing::warn!("Failed to call init on plugin: {:?}", e);
+                    {
+                        let binding = peerpiper_clone.borrow();
+                        let piper = binding.as_ref().unwrap();
+                        piper
+                            .plugins
+                            .lock()
+                            .unwrap()
+                            .insert(name_clone.clone(), arc_plugin_clone.clone());
                     }
+                    initialize(name_clone, arc_plugin_clone, binary).await;
                 });
             }
         }
@@ -267,6 +339,361 @@ impl RdxRunner {
         // to pass to the other plugins which call wallet functions.
         arc_plugin
     }
+
+    /// Whether the named plugin's saved state is loaded, None if there's no such plugin
+    pub fn load_status(&self, name: &str) -> Option<LoadStatus> {
+        self.state(name).map(|state| state.load_status())
+    }
+
+    /// Load the plugin's saved state again after it failed to load, such as after a
+    /// transient read error. Saving stays refused until it loads.
+    pub fn retry_load(&self, name: &str) {
+        let Some(deets) = self.plugins.get(name) else {
+            return;
+        };
+        let arc_plugin = deets.plugin.clone();
+        let state = arc_plugin.lock().unwrap().store().data().clone();
+        if !matches!(state.load_status(), LoadStatus::Failed(_)) {
+            return;
+        }
+        state.set_load_status(LoadStatus::Loading);
+
+        let name = name.to_string();
+        platform::spawn(async move {
+            load_state(&name, &arc_plugin).await;
+        });
+    }
+
+    /// Recover the plugin's quarantined state, then call the plugin's `init` again so it
+    /// picks up the recovered scope
+    pub fn recover(
+        &self,
+        name: &str,
+        recovery: Recovery,
+    ) -> Option<impl Future<Output = anyhow::Result<()>> + 'static> {
+        let arc_plugin = self.plugins.get(name)?.plugin.clone();
+        let state = arc_plugin.lock().unwrap().store().data().clone();
+        Some(async move {
+            quarantine::recover(&state, recovery).await?;
+            call_init(&arc_plugin);
+            Ok(())
+        })
+    }
+
+    /// Clone of the [State] of the plugin with the given name, if loaded
+    pub fn state(&self, name: &str) -> Option<State> {
+        self.plugins
+            .get(name)
+            .map(|deets| deets.plugin.lock().unwrap().store().data().clone())
+    }
+
+    /// [Self::flush] on exit, doing nothing if that already ran, such as when the
+    /// platform drops after [eframe::App::on_exit]
+    pub fn flush_on_exit(&self, timeout: Duration) -> bool {
+        if self.exit_flushed.swap(true, Ordering::SeqCst) {
+            return true;
+        }
+        self.flush(timeout)
+    }
+
+    /// Write every plugin's state now, bypassing the save debounce.
+    ///
+    /// On native this blocks until every state is written or the timeout passes, and
+    /// returns whether they all were. The browser can't block, so there the writes are
+    /// only started and this returns false.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let states = self
+            .plugins
+            .keys()
+            .filter_map(|name| self.state(name))
+            .collect::<Vec<_>>();
+        if states.is_empty() {
+            return true;
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        platform::spawn(async move {
+            let results = futures::future::join_all(states.iter().map(State::flush)).await;
+            for (state, result) in states.iter().zip(results) {
+                if let Err(e) = result {
+                    tracing::error!("Failed to flush state of {}: {:?}", state.name(), e);
+                }
+            }
+            let _ = tx.send(());
+        });
+
+        if cfg!(target_arch = "wasm32") {
+            return false;
+        }
+        match rx.recv_timeout(timeout) {
+            Ok(()) => true,
+            Err(_) => {
+                tracing::warn!("Plugin states not written within {:?}", timeout);
+                false
+            }
+        }
+    }
+
+    /// The blockstore, once PeerPiper is ready
+    pub fn blockstore(&self) -> impl Future<Output = Option<Blockstore>> + 'static {
+        #[cfg(not(target_arch = "wasm32"))]
+        let peerpiper = self.peerpiper.clone();
+        #[cfg(target_arch = "wasm32")]
+        let blockstore = self
+            .peerpiper
+            .borrow()
+            .as_ref()
+            .map(|piper| piper.commander.blockstore.clone());
+
+        async move {
+            #[cfg(not(target_arch = "wasm32"))]
+            let blockstore = Some(peerpiper.lock().await.commander.blockstore.clone());
+            blockstore
+        }
+    }
+
+    /// Send a command through PeerPiper, such as to dial a peer.
+    /// Network commands ordered before the node connects wait for it.
+    pub fn order(
+        &self,
+        command: AllCommands,
+    ) -> impl Future<Output = anyhow::Result<ReturnValues>> + 'static {
+        #[cfg(not(target_arch = "wasm32"))]
+        let peerpiper = self.peerpiper.clone();
+        #[cfg(target_arch = "wasm32")]
+        let peerpiper = self.peerpiper.borrow().clone();
+
+        async move {
+            // network commands wait for the node to connect, if it hasn't yet
+            if let Some(queued) = offline_queue().enqueue(&command) {
+                return Ok(queued.await?);
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            let result = peerpiper.lock().await.order(command).await;
+            #[cfg(target_arch = "wasm32")]
+            let result = match peerpiper {
+                Some(piper) => piper.order(command).await,
+                None => return Err(anyhow::anyhow!("PeerPiper is not ready")),
+            };
+            result.map_err(|e| anyhow::anyhow!("Failed to order command: {:?}", e))
+        }
+    }
+
+    /// Find what references each block in the blockstore
+    pub fn block_references(&self) -> impl Future<Output = Result<References, GcError>> + 'static {
+        let keyring = self.keyring.clone();
+        let blockstore = self.blockstore();
+        async move {
+            let blockstore = blockstore.await.ok_or(GcError::NotReady)?;
+            gc::references(
+                &blockstore,
+                storage::open_default(),
+                &keyring,
+                Retention::default(),
+            )
+            .await
+        }
+    }
+
+    /// Collect the root block and every block linked from it into a CAR file
+    pub fn export_car(&self, root: Cid) -> impl Future<Output = Result<Car, CarError>> + 'static {
+        let keyring = self.keyring.clone();
+        let blockstore = self.blockstore();
+        async move {
+            let blockstore = blockstore.await.ok_or(CarError::NotReady)?;
+            car::export(&blockstore, &root, &keyring).await
+        }
+    }
+
+    /// Back up the active profile's blocks, CID map and the given app state into one
+    /// archive, encrypted with the keyring if `encrypt` is set.
+    pub fn backup(
+        &self,
+        app_state: Option<String>,
+        encrypt: bool,
+    ) -> impl Future<Output = Result<BackupArchive, BackupError>> + 'static {
+        let keyring = self.keyring.clone();
+        let blockstore = self.blockstore();
+        let wallet = self.wallet_name();
+        async move {
+            let blockstore = blockstore.await.ok_or(BackupError::NotReady)?;
+            let collected = backup::collect(
+                &blockstore,
+                storage::open_default(),
+                &keyring,
+                app_state,
+                wallet.as_deref(),
+            )
+            .await?;
+            BackupArchive::new(
+                Profile::active().name(),
+                collected,
+                encrypt.then_some(&keyring),
+            )
+        }
+    }
+
+    /// Check the archive, then restore its blocks and CID map into the active profile.
+    ///
+    /// Returns the backed up app state, for the caller to write to the eframe storage.
+    pub fn restore(
+        &self,
+        archive: BackupArchive,
+    ) -> impl Future<Output = Result<Option<String>, BackupError>> + 'static {
+        let keyring = self.keyring.clone();
+        let blockstore = self.blockstore();
+        async move {
+            let contents = archive.open(&keyring)?;
+            let blockstore = blockstore.await.ok_or(BackupError::NotReady)?;
+            backup::restore(&blockstore, storage::open_default().as_ref(), &contents).await?;
+            Ok(contents.app_state)
+        }
+    }
+
+    /// Check the wallet's state carried by an encrypted archive and restore only it, so
+    /// the wallet can be unlocked after a restart to restore the rest.
+    pub fn restore_wallet(
+        &self,
+        archive: BackupArchive,
+    ) -> impl Future<Output = Result<(), BackupError>> + 'static {
+        let blockstore = self.blockstore();
+        async move {
+            let wallet = archive.open_wallet()?.ok_or_else(|| {
+                BackupError::Manifest("the backup doesn't carry the wallet".to_string())
+            })?;
+            let blockstore = blockstore.await.ok_or(BackupError::NotReady)?;
+            backup::restore_wallet(&blockstore, storage::open_default().as_ref(), &wallet).await
+        }
+    }
+
+    /// Name of the wallet plugin, once it's loaded
+    fn wallet_name(&self) -> Option<String> {
+        self.plugins
+            .keys()
+            .find(|name| self.state(name).is_some_and(|state| state.is_wallet()))
+            .cloned()
+    }
+
+    /// Garbage collect the blockstore, keeping the plugin states, everything they link to
+    /// and pinned blocks.
+    pub fn collect_garbage(&self) -> impl Future<Output = Result<GcReport, GcError>> + 'static {
+        let keyring = self.keyring.clone();
+        let blockstore = self.blockstore();
+        async move {
+            let blockstore = blockstore.await.ok_or(GcError::NotReady)?;
+            gc::collect(
+                &blockstore,
+                storage::open_default(),
+                &keyring,
+                Retention::default(),
+            )
+            .await
+        }
+    }
+
+    /// Unlocks the keyring once the wallet is unlocked, then decrypts every plugin state
+    /// which was waiting on it. Locks it again once the wallet is locked.
+    ///
+    /// Cheap to call every frame, the wallet is only asked whether it's unlocked after its
+    /// state changed.
+    pub fn unlock_keyring(&mut self) {
+        let Some(wallet) = &self.arc_wallet else {
+            return;
+        };
+
+        if !self.wallet_changed.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let unlocked = matches!(
+            wallet.lock().unwrap().call("unlocked", &[]),
+            Ok(Some(Value::Bool(true)))
+        );
+
+        match (unlocked, self.keyring.is_unlocked()) {
+            (true, false) => {}
+            // the vault and sealed states close with the wallet
+            (false, true) => {
+                self.keyring.lock();
+                tracing::info!("🔒 Keyring locked");
+                return;
+            }
+            _ => return,
+        }
+
+        match Keyring::derive_from_wallet(wallet) {
+            Ok(secret) => self.keyring.unlock(&secret),
+            Err(e) => {
+                tracing::error!("Failed to derive keyring from wallet: {:?}", e);
+                return;
+            }
+        }
+
+        tracing::info!("🔑 Keyring unlocked");
+
+        for (name, deets) in self.plugins.iter() {
+            let state = deets.plugin.lock().unwrap().store().data().clone();
+            let arc_plugin = deets.plugin.clone();
+            let name = name.clone();
+            platform::spawn(async move {
+                match state.unlock().await {
+                    Ok(true) => {
+                        tracing::info!("Unlocked state for {:?}", name);
+                        // re-initialize so the plugin picks up the decrypted scope
+                        if let Err(e) = arc_plugin.lock().unwrap().call("init", &[]) {
+                            tracing::warn!("Failed to call init on plugin: {:?}", e);
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        tracing::error!("Failed to unlock state for {:?}: {:?}", name, e);
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Load the plugin's saved state into its scope, call its `init` with it, then pin its
+/// binary. The plugin shows as loading until its `init` has run.
+async fn initialize(name: String, arc_plugin: Arc<Mutex<LayerPlugin<State>>>, binary: Vec<u8>) {
+    tracing::info!("Initializing plugin: {:?}", &name);
+    let state = load_state(&name, &arc_plugin).await;
+    tracing::info!("Initialized plugin: {:?}", name);
+
+    if let Err(e) = state.pin_binary(binary).await {
+        tracing::error!("Failed to pin plugin binary: {:?}", e);
+    }
+}
+
+/// Load the plugin's saved state into its scope and call its `init` with it, setting the
+/// load status once `init` has run. Returns the plugin's state.
+async fn load_state(name: &str, arc_plugin: &Arc<Mutex<LayerPlugin<State>>>) -> State {
+    let state = arc_plugin.lock().unwrap().store().data().clone();
+    let status = match state.init().await {
+        Ok(()) => LoadStatus::Ready,
+        Err(e) => match e.downcast::<Corrupted>() {
+            Ok(corrupted) => LoadStatus::Corrupted(corrupted),
+            Err(e) => {
+                tracing::error!("Failed to load state of {}: {:?}", name, e);
+                LoadStatus::Failed(e.to_string())
+            }
+        },
+    };
+
+    call_init(arc_plugin);
+    state.set_load_status(status);
+    state
+}
+
+/// Call the plugin's `init`, so it picks up the loaded scope
+fn call_init(arc_plugin: &Arc<Mutex<LayerPlugin<State>>>) {
+    // it's ok not to have an init function
+    // the plugin just won't be initialized with any loaded scope
+    if let Err(e) = arc_plugin.lock().unwrap().call("init", &[]) {
+        tracing::warn!("Failed to call init on plugin: {:?}", e);
+    }
 }
 
 /// a function that enables us to register a function by name
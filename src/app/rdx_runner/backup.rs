@@ -0,0 +1,539 @@
+//! Full profile backups.
+//!
+//! A [BackupArchive] holds everything a profile needs to be restored on another machine:
+//! every block in the blockstore, every value in the CID map [Storage] and the persisted
+//! app state. A [Manifest] describes the contents and carries a digest of them, and the
+//! contents can be encrypted with the wallet derived [Keyring].
+//!
+//! An encrypted backup also carries the wallet's own state outside the sealed part, since
+//! only the unlocked wallet can open the rest. On a fresh machine the wallet is restored
+//! first, then unlocked to restore everything else.
+//!
+//! Restoring checks the whole archive before anything is written. Once restored, plugin
+//! states stop saving until restart, so the running app can't overwrite the restored data.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use blockstore::Blockstore;
+use cid::Cid;
+use sha2::{Digest as _, Sha256};
+use web_time::SystemTime;
+
+use super::bundle::{verify_block, BundleBlock, BundleError};
+use super::gc::{self, GcError, Reference, Retention};
+use super::keyring::{Keyring, KeyringError, Sealed, PUBLIC_KEY};
+use super::vault::VAULT_KEY;
+use crate::app::platform::blocks::{is_index_key, Tracked, PINS_KEY};
+use crate::app::platform::storage::{Storage, StorageError};
+
+/// Backup format version, bumped on incompatible changes
+pub const BACKUP_VERSION: u32 = 1;
+
+/// Keyring context for encrypting backups
+const BACKUP_CONTEXT: &str = "backup";
+
+/// Set once a backup is restored, until the app restarts
+static RESTORED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a backup was restored, so nothing should be saved until the app restarts
+pub fn restart_pending() -> bool {
+    RESTORED.load(Ordering::SeqCst)
+}
+
+/// Whether the error is [BackupError::RestartPending], so the state wasn't saved on purpose
+pub fn is_restart_pending(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<BackupError>(),
+        Some(BackupError::RestartPending)
+    )
+}
+
+/// Backup errors
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    /// The blockstore isn't open yet
+    #[error("The node isn't started yet")]
+    NotReady,
+    /// A backup was restored, so saving now would overwrite it
+    #[error("A backup was restored, restart the app to load it")]
+    RestartPending,
+    /// The backup was written by a newer version of the app
+    #[error("Unsupported backup version: {0}")]
+    Version(u32),
+    /// The archive or its contents aren't valid JSON
+    #[error("Invalid backup: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Encrypting or decrypting the contents failed
+    #[error("Backup encryption: {0}")]
+    Keyring(#[from] KeyringError),
+    /// The contents don't match the manifest
+    #[error("Backup is corrupted: {0}")]
+    Manifest(String),
+    /// A block's data doesn't match its CID
+    #[error("Backup is corrupted: {0}")]
+    Block(#[from] BundleError),
+    /// Reading or writing the CID map failed
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    /// Reading or writing the blockstore failed
+    #[error("Blockstore error: {0}")]
+    Blockstore(#[from] blockstore::Error),
+    /// Finding the blocks referenced by plugin state failed
+    #[error(transparent)]
+    References(#[from] GcError),
+}
+
+/// Describes the contents of a [BackupArchive], readable without the wallet
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    /// Backup format version
+    pub version: u32,
+    /// The profile the backup was made from
+    pub profile: String,
+    /// Unix timestamp (seconds) of when the backup was made
+    pub created: i64,
+    /// Number of blocks
+    pub blocks: usize,
+    /// Number of CID map values
+    pub keys: usize,
+    /// Whether the persisted app state is included
+    pub app_state: bool,
+    /// Hex SHA2-256 of the serialized [Contents], before any encryption
+    pub digest: String,
+    /// Blocks plugin state links to which weren't in the blockstore, so aren't backed up
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+    /// Hex SHA2-256 of the serialized wallet [Contents], when the backup carries them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet_digest: Option<String>,
+}
+
+/// The backed up data
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Contents {
+    /// Every block
+    pub blocks: Vec<BundleBlock>,
+    /// Every CID map value by key, except the block index which is rebuilt on restore
+    pub storage: BTreeMap<String, String>,
+    /// The persisted app state, as eframe stored it
+    pub app_state: Option<String>,
+}
+
+/// The contents, in the clear or encrypted
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Payload {
+    Plain(Contents),
+    Sealed(Sealed),
+}
+
+/// A profile backup, ready to be written to a file
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BackupArchive {
+    pub manifest: Manifest,
+    pub payload: Payload,
+    /// The wallet's state in the clear, when the payload is sealed. Its own state block
+    /// keeps everything but the encrypted seed sealed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet: Option<Contents>,
+}
+
+/// Everything [collect] gathered for a backup
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Collected {
+    pub contents: Contents,
+    /// The wallet's CID map value and blocks, which are in `contents` too
+    pub wallet: Contents,
+    /// Blocks plugin state links to which aren't in the blockstore
+    pub missing: Vec<String>,
+}
+
+impl BackupArchive {
+    /// Build the archive, encrypting the contents if a keyring is given
+    pub fn new(
+        profile: &str,
+        collected: Collected,
+        keyring: Option<&Keyring>,
+    ) -> Result<Self, BackupError> {
+        let Collected {
+            contents,
+            wallet,
+            missing,
+        } = collected;
+        let bytes = serde_json::to_vec(&contents)?;
+        let manifest = Manifest {
+            version: BACKUP_VERSION,
+            profile: profile.to_string(),
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+            blocks: contents.blocks.len(),
+            keys: contents.storage.len(),
+            app_state: contents.app_state.is_some(),
+            digest: digest(&bytes),
+            missing,
+            wallet_digest: None,
+        };
+        let mut archive = Self {
+            manifest,
+            payload: Payload::Plain(contents),
+            wallet: None,
+        };
+        if let Some(keyring) = keyring {
+            archive.payload = Payload::Sealed(keyring.seal(BACKUP_CONTEXT, &bytes)?);
+            if !wallet.storage.is_empty() {
+                archive.manifest.wallet_digest = Some(digest(&serde_json::to_vec(&wallet)?));
+                archive.wallet = Some(wallet);
+            }
+        }
+        Ok(archive)
+    }
+
+    /// Whether the contents are encrypted
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.payload, Payload::Sealed(_))
+    }
+
+    /// Suggested file name for the archive
+    pub fn file_name(&self) -> String {
+        let date = chrono::DateTime::from_timestamp(self.manifest.created, 0)
+            .map(|t| t.format("%Y%m%d-%H%M%S").to_string())
+            .unwrap_or_default();
+        format!("{}-{}.backup.json", self.manifest.profile, date)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BackupError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BackupError> {
+        let archive: Self = serde_json::from_slice(bytes)?;
+        if archive.manifest.version > BACKUP_VERSION {
+            return Err(BackupError::Version(archive.manifest.version));
+        }
+        Ok(archive)
+    }
+
+    /// Decrypt the contents if needed, then check them against the manifest and every
+    /// block against its CID.
+    pub fn open(&self, keyring: &Keyring) -> Result<Contents, BackupError> {
+        let (bytes, contents) = match &self.payload {
+            Payload::Plain(contents) => (serde_json::to_vec(contents)?, contents.clone()),
+            Payload::Sealed(sealed) => {
+                let bytes = keyring.open(BACKUP_CONTEXT, sealed)?;
+                let contents = serde_json::from_slice(&bytes)?;
+                (bytes, contents)
+            }
+        };
+
+        let manifest = &self.manifest;
+        if digest(&bytes) != manifest.digest {
+            return Err(BackupError::Manifest("digest mismatch".to_string()));
+        }
+        if contents.blocks.len() != manifest.blocks
+            || contents.storage.len() != manifest.keys
+            || contents.app_state.is_some() != manifest.app_state
+        {
+            return Err(BackupError::Manifest(
+                "contents don't match the manifest".to_string(),
+            ));
+        }
+
+        verify_blocks(&contents.blocks)?;
+
+        Ok(contents)
+    }
+
+    /// Check the wallet's state carried outside the sealed contents, None if there is none.
+    ///
+    /// Opens without the keyring, so the wallet can be restored and unlocked first.
+    pub fn open_wallet(&self) -> Result<Option<Contents>, BackupError> {
+        let Some(wallet) = &self.wallet else {
+            return Ok(None);
+        };
+        let bytes = serde_json::to_vec(wallet)?;
+        if self.manifest.wallet_digest.as_deref() != Some(digest(&bytes).as_str()) {
+            return Err(BackupError::Manifest("wallet digest mismatch".to_string()));
+        }
+        verify_blocks(&wallet.blocks)?;
+        Ok(Some(wallet.clone()))
+    }
+}
+
+/// Check every block against its CID
+fn verify_blocks(blocks: &[BundleBlock]) -> Result<(), BackupError> {
+    for block in blocks {
+        let cid = Cid::try_from(block.cid.as_str())
+            .map_err(|e| BundleError::Cid(block.cid.clone(), e))?;
+        verify_block(
+            &cid,
+            &BASE64.decode(&block.data).map_err(BundleError::from)?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Collect every indexed block, every block referenced by plugin state, and every CID map
+/// value, with the wallet's state set apart.
+///
+/// Blocks referenced but not in the blockstore are listed as missing. Blocks neither
+/// indexed nor referenced can't be found, since the blockstore can't list its contents.
+pub async fn collect<B: Blockstore>(
+    blockstore: &Tracked<B>,
+    storage: Arc<dyn Storage>,
+    keyring: &Keyring,
+    app_state: Option<String>,
+    wallet: Option<&str>,
+) -> Result<Collected, BackupError> {
+    let refs = gc::references(blockstore, storage.clone(), keyring, Retention::ALL).await?;
+    let is_wallet = |reference: &Reference| match reference {
+        Reference::State(name) | Reference::History(name) | Reference::Link(name) => {
+            Some(name.as_str()) == wallet
+        }
+        Reference::Pinned(_) => false,
+    };
+    let wallet_cids = refs
+        .by_cid
+        .iter()
+        .filter(|(_, references)| references.iter().any(is_wallet))
+        .map(|(cid, _)| cid.clone())
+        .collect::<Vec<_>>();
+    let mut cids = blockstore.index().entries().into_keys().collect::<Vec<_>>();
+    cids.extend(refs.by_cid.into_keys());
+    cids.sort();
+    cids.dedup();
+
+    let mut collected = Collected::default();
+    for cid in cids {
+        let Ok(parsed) = Cid::try_from(cid.as_str()) else {
+            continue;
+        };
+        match blockstore.get(&parsed).await? {
+            Some(data) => {
+                let block = BundleBlock {
+                    cid,
+                    data: BASE64.encode(data),
+                };
+                if wallet_cids.contains(&block.cid) {
+                    collected.wallet.blocks.push(block.clone());
+                }
+                collected.contents.blocks.push(block);
+            }
+            None => {
+                tracing::warn!("Block {} not found, listing it as missing", cid);
+                collected.missing.push(cid);
+            }
+        }
+    }
+
+    let mut values = BTreeMap::new();
+    for key in storage.keys()? {
+        if let Some(value) = storage.get_string(&key)? {
+            if !is_backed_up(&key, &value) {
+                continue;
+            }
+            if Some(key.as_str()) == wallet {
+                collected.wallet.storage.insert(key.clone(), value.clone());
+            }
+            values.insert(key, value);
+        }
+    }
+
+    collected.contents.storage = values;
+    collected.contents.app_state = app_state;
+    Ok(collected)
+}
+
+/// Whether the stored value is one the backup carries: a plugin's state CID, the pins,
+/// the encryption opt-outs or the vault. Anything else in the store is left alone.
+fn is_backed_up(key: &str, value: &str) -> bool {
+    [PINS_KEY, PUBLIC_KEY, VAULT_KEY].contains(&key)
+        || (!is_index_key(key) && Cid::try_from(value.trim()).is_ok())
+}
+
+/// Write checked contents back: blocks first, then the CID map values, replacing any
+/// which aren't in the backup. Stops plugin states saving until restart.
+pub async fn restore<B: Blockstore>(
+    blockstore: &B,
+    storage: &dyn Storage,
+    contents: &Contents,
+) -> Result<(), BackupError> {
+    put_blocks(blockstore, &contents.blocks).await?;
+
+    RESTORED.store(true, Ordering::SeqCst);
+
+    replace_values(storage, &contents.storage)?;
+
+    tracing::info!(
+        "Restored {} blocks and {} values",
+        contents.blocks.len(),
+        contents.storage.len()
+    );
+    Ok(())
+}
+
+/// Replace the values the backup carries with its own, leaving the rest of the store alone
+fn replace_values(
+    storage: &dyn Storage,
+    values: &BTreeMap<String, String>,
+) -> Result<(), StorageError> {
+    for key in storage.keys()? {
+        if values.contains_key(&key) {
+            continue;
+        }
+        if let Some(value) = storage.get_string(&key)? {
+            if is_backed_up(&key, &value) {
+                storage.remove(&key)?;
+            }
+        }
+    }
+    for (key, value) in values {
+        if is_backed_up(key, value) {
+            storage.set_string(key, value.clone())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the wallet's checked state back, leaving every other CID map value alone.
+/// Stops plugin states saving until restart, when the wallet can be unlocked to restore
+/// the rest of the backup.
+pub async fn restore_wallet<B: Blockstore>(
+    blockstore: &B,
+    storage: &dyn Storage,
+    wallet: &Contents,
+) -> Result<(), BackupError> {
+    put_blocks(blockstore, &wallet.blocks).await?;
+
+    RESTORED.store(true, Ordering::SeqCst);
+
+    for (key, value) in &wallet.storage {
+        storage.set_string(key, value.clone())?;
+    }
+
+    tracing::info!("Restored the wallet's {} blocks", wallet.blocks.len());
+    Ok(())
+}
+
+async fn put_blocks<B: Blockstore>(
+    blockstore: &B,
+    blocks: &[BundleBlock],
+) -> Result<(), BackupError> {
+    for block in blocks {
+        let cid = Cid::try_from(block.cid.as_str())
+            .map_err(|e| BundleError::Cid(block.cid.clone(), e))?;
+        let data = BASE64.decode(&block.data).map_err(BundleError::from)?;
+        blockstore.put_keyed(&cid, &data).await?;
+    }
+    Ok(())
+}
+
+/// Hex SHA2-256 of the bytes
+fn digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::storage::MemoryStore;
+    use cid::multihash::Multihash;
+
+    fn contents() -> Contents {
+        let data = b"state";
+        let hash = Multihash::<64>::wrap(0x12, &Sha256::digest(data)).unwrap();
+        let cid = Cid::new_v1(0x55, hash).to_string();
+        Contents {
+            blocks: vec![BundleBlock {
+                cid: cid.clone(),
+                data: BASE64.encode(data),
+            }],
+            storage: BTreeMap::from([("plugin.wasm".to_string(), cid)]),
+            app_state: Some("(open: [])".to_string()),
+        }
+    }
+
+    fn collected() -> Collected {
+        let contents = contents();
+        Collected {
+            wallet: Contents {
+                blocks: contents.blocks.clone(),
+                storage: BTreeMap::from([("wallet.wasm".to_string(), "cid".to_string())]),
+                app_state: None,
+            },
+            contents,
+            missing: vec![],
+        }
+    }
+
+    #[test]
+    fn test_backup_checks_before_restore() {
+        let keyring = Keyring::default();
+        keyring.unlock(&[7; 32]);
+
+        for key in [None, Some(&keyring)] {
+            let archive = BackupArchive::new("default", collected(), key).unwrap();
+            let archive = BackupArchive::from_bytes(&archive.to_bytes().unwrap()).unwrap();
+            assert_eq!(archive.is_encrypted(), key.is_some());
+            assert_eq!(archive.open(&keyring).unwrap(), contents());
+        }
+
+        let mut tampered = contents();
+        tampered.blocks[0].data = BASE64.encode(b"other");
+        let mut archive = BackupArchive::new("default", collected(), None).unwrap();
+        archive.payload = Payload::Plain(tampered);
+        assert!(matches!(
+            archive.open(&keyring),
+            Err(BackupError::Manifest(_))
+        ));
+
+        // an encrypted backup can't be opened without the wallet
+        let archive = BackupArchive::new("default", collected(), Some(&keyring)).unwrap();
+        assert!(matches!(
+            archive.open(&Keyring::default()),
+            Err(BackupError::Keyring(KeyringError::Locked))
+        ));
+
+        // but its wallet can, to be unlocked on a fresh machine
+        let wallet = archive.open_wallet().unwrap().unwrap();
+        assert_eq!(wallet, collected().wallet);
+        let plain = BackupArchive::new("default", collected(), None).unwrap();
+        assert_eq!(plain.open_wallet().unwrap(), None);
+
+        let mut tampered = archive.clone();
+        tampered.wallet.as_mut().unwrap().storage.clear();
+        assert!(matches!(
+            tampered.open_wallet(),
+            Err(BackupError::Manifest(_))
+        ));
+    }
+
+    #[test]
+    fn test_restore_leaves_other_keys() {
+        let storage = MemoryStore::default();
+        let old = contents().storage["plugin.wasm"].clone();
+        storage.set_string("gone.wasm", old).unwrap();
+        storage
+            .set_string("egui_memory_ron", "(open: [])".to_string())
+            .unwrap();
+
+        let mut values = contents().storage;
+        values.insert("foreign".to_string(), "not ours".to_string());
+        replace_values(&storage, &values).unwrap();
+
+        // plugin states not in the backup are removed, the rest of the store is kept
+        assert_eq!(storage.get_string("gone.wasm").unwrap(), None);
+        assert_eq!(
+            storage.get_string("plugin.wasm").unwrap(),
+            Some(values["plugin.wasm"].clone())
+        );
+        assert!(storage.get_string("egui_memory_ron").unwrap().is_some());
+        assert_eq!(storage.get_string("foreign").unwrap(), None);
+    }
+}
@@ -0,0 +1,313 @@
+//! Portable plugin state bundles.
+//!
+//! A [StateBundle] holds a plugin's name, its state history chain and every block those
+//! states reference, so the whole state can be written to a single file and installed on
+//! another node.
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use peerpiper::core::Cid;
+use sha2::{Digest as _, Sha256};
+
+use super::history::StateBlock;
+use super::{quarantine, State};
+
+/// Bundle format version, bumped on incompatible changes
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Multihash code for the identity hash, where the digest is the data itself
+const IDENTITY: u64 = 0x00;
+
+/// Multihash code for SHA2-256
+const SHA2_256: u64 = 0x12;
+
+/// Errors reading or installing a [StateBundle]
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    /// The bundle was written by a newer version of the app
+    #[error("Unsupported bundle version: {0}")]
+    Version(u32),
+    /// A CID in the bundle could not be parsed
+    #[error("Invalid CID {0}: {1}")]
+    Cid(String, cid::Error),
+    /// A block's data is not valid base64
+    #[error("Invalid block encoding: {0}")]
+    Encoding(#[from] base64::DecodeError),
+    /// The block was hashed with a function we can't verify
+    #[error("Unsupported hash function {code:#x} for {cid}")]
+    UnsupportedHash { cid: String, code: u64 },
+    /// The block data doesn't match its CID
+    #[error("Block data does not match CID {0}")]
+    HashMismatch(String),
+    /// A CID in the chain has no block in the bundle
+    #[error("Missing block for CID {0}")]
+    MissingBlock(String),
+    /// A state in the chain doesn't link to the state after it
+    #[error("State {0} doesn't link to the next state in the chain")]
+    BrokenChain(String),
+}
+
+/// A single block in a [StateBundle]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BundleBlock {
+    /// CID of the block
+    pub cid: String,
+    /// Base64 encoded block bytes
+    pub data: String,
+}
+
+/// A plugin's state chain and the blocks it references, ready to be written to a file.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StateBundle {
+    /// Bundle format version
+    pub version: u32,
+    /// Name of the plugin this state belongs to
+    pub plugin: String,
+    /// CIDs of the state history chain, newest (the head) first
+    pub chain: Vec<String>,
+    /// Every block in the chain plus the blocks the states link to
+    pub blocks: Vec<BundleBlock>,
+}
+
+impl StateBundle {
+    /// CID of the newest state in the bundle
+    pub fn head(&self) -> Option<&str> {
+        self.chain.first().map(String::as_str)
+    }
+
+    /// Suggested file name for this bundle
+    pub fn file_name(&self) -> String {
+        format!("{}.state.json", self.plugin)
+    }
+
+    /// Serialize the bundle to bytes for writing to a file
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Deserialize a bundle from file bytes
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let bundle: Self = serde_json::from_slice(bytes)?;
+        if bundle.version > BUNDLE_VERSION {
+            return Err(BundleError::Version(bundle.version))?;
+        }
+        Ok(bundle)
+    }
+
+    /// Decode every block and check its bytes against its CID.
+    ///
+    /// Also checks that every CID in the chain has a block, so the bundle is complete, and
+    /// that each state links to the one saved before it.
+    pub fn verify(&self) -> Result<Vec<(Cid, Vec<u8>)>, BundleError> {
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let cid = Cid::try_from(block.cid.as_str())
+                    .map_err(|e| BundleError::Cid(block.cid.clone(), e))?;
+                let data = BASE64.decode(&block.data)?;
+                verify_block(&cid, &data)?;
+                Ok((cid, data))
+            })
+            .collect::<Result<Vec<_>, BundleError>>()?;
+
+        for (index, link) in self.chain.iter().enumerate() {
+            let Some((_, data)) = blocks.iter().find(|(cid, _)| &cid.to_string() == link) else {
+                return Err(BundleError::MissingBlock(link.clone()));
+            };
+            let Some(next) = self.chain.get(index + 1) else {
+                continue;
+            };
+            let prev = StateBlock::decode(data).ok().and_then(|block| block.prev);
+            if prev.as_ref() != Some(next) {
+                return Err(BundleError::BrokenChain(link.clone()));
+            }
+        }
+
+        Ok(blocks)
+    }
+}
+
+/// Check that the data hashes to the multihash in the CID.
+pub fn verify_block(cid: &Cid, data: &[u8]) -> Result<(), BundleError> {
+    let hash = cid.hash();
+    let matches = match hash.code() {
+        IDENTITY => hash.digest() == data,
+        SHA2_256 => hash.digest() == Sha256::digest(data).as_slice(),
+        code => {
+            return Err(BundleError::UnsupportedHash {
+                cid: cid.to_string(),
+                code,
+            })
+        }
+    };
+
+    match matches {
+        true => Ok(()),
+        false => Err(BundleError::HashMismatch(cid.to_string())),
+    }
+}
+
+/// Collect the plugin's state chain and the blocks its states link to into a bundle.
+///
+/// Sealed states are bundled as they are stored, so they stay encrypted in the file.
+/// Links inside sealed states are only followed while the keyring is unlocked.
+pub async fn export(state: &State) -> anyhow::Result<StateBundle> {
+    if state.head().is_none() {
+        return Err(anyhow::anyhow!("{} has no saved state", state.name()))?;
+    }
+
+    let mut chain = Vec::new();
+    let mut blocks = Vec::new();
+    let mut links = Vec::new();
+
+    // the whole chain, so none of it is lost on the other node. The snapshots are opened
+    // to find the links, but the stored bytes are bundled
+    for snapshot in state.history(usize::MAX).await? {
+        let bytes = state.get_block(&snapshot.cid).await?;
+        collect_links(&snapshot.block.scope, &mut links);
+        chain.push(snapshot.cid.clone());
+        blocks.push(BundleBlock {
+            cid: snapshot.cid,
+            data: BASE64.encode(&bytes),
+        });
+    }
+
+    add_linked_blocks(state, links, &mut blocks).await;
+
+    Ok(StateBundle {
+        version: BUNDLE_VERSION,
+        plugin: state.name().to_string(),
+        chain,
+        blocks,
+    })
+}
+
+/// Add the blocks for the linked CIDs which are in the local blockstore
+pub(crate) async fn add_linked_blocks(
+    state: &State,
+    links: Vec<String>,
+    blocks: &mut Vec<BundleBlock>,
+) {
+    for link in links {
+        if blocks.iter().any(|block| block.cid == link) {
+            continue;
+        }
+        match state.get_block(&link).await {
+            Ok(bytes) => blocks.push(BundleBlock {
+                cid: link,
+                data: BASE64.encode(&bytes),
+            }),
+            // strings that look like CIDs may point at blocks which are only on other nodes
+            Err(e) => tracing::debug!("Not bundling {}: {:?}", link, e),
+        }
+    }
+}
+
+/// Verify the bundle, put its blocks into the blockstore and make its head the plugin's state.
+///
+/// Returns the CID of the installed head.
+pub async fn install(state: &State, bundle: &StateBundle) -> anyhow::Result<String> {
+    if bundle.plugin != state.name() {
+        return Err(anyhow::anyhow!(
+            "Bundle is for {}, not {}",
+            bundle.plugin,
+            state.name()
+        ))?;
+    }
+
+    let Some(head) = bundle.head() else {
+        return Err(anyhow::anyhow!("Bundle has no state"))?;
+    };
+
+    put_blocks(state, bundle).await?;
+    state.checkout(head).await?;
+    // the bundle replaces a state which failed to load, if any
+    quarantine::replaced(state);
+
+    Ok(head.to_string())
+}
+
+/// Verify the bundle and put all its blocks into the local blockstore
+pub async fn put_blocks(state: &State, bundle: &StateBundle) -> anyhow::Result<()> {
+    for (cid, bytes) in bundle.verify()? {
+        state.put_block(&cid, bytes).await?;
+    }
+    Ok(())
+}
+
+/// Collect every string in the JSON value which parses as a CID
+pub(crate) fn collect_links(value: &serde_json::Value, links: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(s) => {
+            if Cid::try_from(s.as_str()).is_ok() && !links.contains(s) {
+                links.push(s.clone());
+            }
+        }
+        serde_json::Value::Array(values) => {
+            values.iter().for_each(|v| collect_links(v, links));
+        }
+        serde_json::Value::Object(map) => {
+            map.values().for_each(|v| collect_links(v, links));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::Multihash;
+
+    const RAW: u64 = 0x55;
+
+    fn block(data: &[u8]) -> BundleBlock {
+        let hash = Multihash::<64>::wrap(SHA2_256, &Sha256::digest(data)).unwrap();
+        BundleBlock {
+            cid: Cid::new_v1(RAW, hash).to_string(),
+            data: BASE64.encode(data),
+        }
+    }
+
+    #[test]
+    fn test_verify_bundle() {
+        let head = block(b"state");
+        let mut bundle = StateBundle {
+            version: BUNDLE_VERSION,
+            plugin: "plugin.wasm".to_string(),
+            chain: vec![head.cid.clone()],
+            blocks: vec![head],
+        };
+
+        let bytes = bundle.to_bytes().unwrap();
+        assert_eq!(
+            StateBundle::from_bytes(&bytes)
+                .unwrap()
+                .verify()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // tampered data no longer matches the CID
+        bundle.blocks[0].data = BASE64.encode(b"tampered");
+        assert!(matches!(bundle.verify(), Err(BundleError::HashMismatch(_))));
+
+        // every link in the chain needs its block
+        bundle.blocks.clear();
+        assert!(matches!(bundle.verify(), Err(BundleError::MissingBlock(_))));
+
+        // each state in the chain links to the one after it
+        let state = |prev: Option<String>| {
+            block(&serde_json::to_vec(&StateBlock::new(prev, serde_json::json!({}))).unwrap())
+        };
+        let first = state(None);
+        let second = state(Some(first.cid.clone()));
+        let other = block(b"other");
+        bundle.chain = vec![second.cid.clone(), first.cid.clone()];
+        bundle.blocks = vec![second.clone(), first, other.clone()];
+        assert_eq!(bundle.verify().unwrap().len(), 3);
+
+        // the head doesn't link to the block after it
+        bundle.chain = vec![second.cid.clone(), other.cid];
+        assert!(matches!(bundle.verify(), Err(BundleError::BrokenChain(cid)) if cid == second.cid));
+    }
+}
@@ -0,0 +1,345 @@
+//! CAR v1 files, to back up or move blocks offline.
+//!
+//! A [Car] holds a root CID and every block reachable from it, such as a plugin state
+//! chain or a plog. The format is a varint length prefixed DAG-CBOR header naming the
+//! roots, followed by varint length prefixed sections of CID bytes then block data.
+use cid::Cid;
+
+use super::bundle::{verify_block, BundleError};
+use super::inspect::Decoded;
+use super::keyring::Keyring;
+use crate::app::platform::blocks::Pins;
+use crate::app::platform::storage::StorageError;
+use blockstore::Blockstore;
+
+/// File extension for CAR files
+pub const CAR_EXTENSION: &str = "car";
+
+/// Pin reason for the roots of imported CAR files
+pub const IMPORT_PIN: &str = "car import";
+
+/// CAR errors
+#[derive(Debug, thiserror::Error)]
+pub enum CarError {
+    /// The blockstore isn't open yet
+    #[error("The node isn't started yet")]
+    NotReady,
+    /// The file ended early or a length prefix is invalid
+    #[error("Truncated or malformed CAR file")]
+    Malformed,
+    /// The header isn't a DAG-CBOR map with `version` and `roots`
+    #[error("Invalid CAR header: {0}")]
+    Header(String),
+    /// Only version 1 is supported
+    #[error("Unsupported CAR version: {0}")]
+    Version(u64),
+    /// A section doesn't start with a valid CID
+    #[error("Invalid CID: {0}")]
+    Cid(#[from] cid::Error),
+    /// A block's data doesn't match its CID
+    #[error(transparent)]
+    Block(#[from] BundleError),
+    /// The root block isn't in the blockstore
+    #[error("Root block {0} not found")]
+    MissingRoot(String),
+    /// Reading from or writing to the blockstore failed
+    #[error("Blockstore error: {0}")]
+    Blockstore(#[from] blockstore::Error),
+    /// A sealed plugin state couldn't be opened to follow its links
+    #[error("Failed to read block {0}: {1}")]
+    Decode(String, String),
+    /// Pinning the imported roots failed
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+}
+
+/// The roots and blocks of a CAR file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Car {
+    /// Root CIDs
+    pub roots: Vec<Cid>,
+    /// Every block, roots first
+    pub blocks: Vec<(Cid, Vec<u8>)>,
+    /// Whether links inside sealed plugin states were skipped, because the keyring is locked
+    pub locked: bool,
+}
+
+impl Car {
+    /// Suggested file name for the CAR
+    pub fn file_name(&self) -> String {
+        match self.roots.first() {
+            Some(root) => format!("{}.{}", root, CAR_EXTENSION),
+            None => format!("blocks.{}", CAR_EXTENSION),
+        }
+    }
+
+    /// Total size of the block data, in bytes
+    pub fn size(&self) -> u64 {
+        self.blocks.iter().map(|(_, data)| data.len() as u64).sum()
+    }
+
+    /// Encode as a CAR v1 file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = encode_header(&self.roots);
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, header.len() as u64);
+        bytes.extend(header);
+
+        for (cid, data) in &self.blocks {
+            let cid = cid.to_bytes();
+            write_varint(&mut bytes, (cid.len() + data.len()) as u64);
+            bytes.extend(cid);
+            bytes.extend(data);
+        }
+        bytes
+    }
+
+    /// Decode a CAR v1 file. Block hashes are not checked, see [Car::verify].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CarError> {
+        let mut pos = 0;
+        let header = read_section(bytes, &mut pos)?;
+        let roots = decode_header(header)?;
+
+        let mut blocks = Vec::new();
+        while pos < bytes.len() {
+            let mut section = read_section(bytes, &mut pos)?;
+            let cid = Cid::read_bytes(&mut section)?;
+            blocks.push((cid, section.to_vec()));
+        }
+
+        Ok(Self {
+            roots,
+            blocks,
+            locked: false,
+        })
+    }
+
+    /// Check every block's data against its CID
+    pub fn verify(&self) -> Result<(), CarError> {
+        for (cid, data) in &self.blocks {
+            verify_block(cid, data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collect the root and every block reachable from it: links in JSON, CBOR and plugin
+/// state blocks, and a state's earlier states.
+///
+/// Sealed states are exported as stored, so they stay encrypted in the file. Links inside
+/// them are only followed while the keyring is unlocked. Linked blocks which aren't in
+/// the blockstore are left out.
+pub async fn export<B: Blockstore>(
+    blockstore: &B,
+    root: &Cid,
+    keyring: &Keyring,
+) -> Result<Car, CarError> {
+    let mut car = Car {
+        roots: vec![*root],
+        ..Default::default()
+    };
+    let mut visited = std::collections::BTreeSet::new();
+    let mut queue = std::collections::VecDeque::from([root.to_string()]);
+
+    while let Some(cid) = queue.pop_front() {
+        if !visited.insert(cid.clone()) {
+            continue;
+        }
+        let Ok(parsed) = Cid::try_from(cid.as_str()) else {
+            continue;
+        };
+        let Some(bytes) = blockstore.get(&parsed).await? else {
+            match parsed == *root {
+                true => return Err(CarError::MissingRoot(cid)),
+                false => {
+                    tracing::warn!("Linked block {} not found, leaving it out", cid);
+                    continue;
+                }
+            }
+        };
+
+        let decoded = Decoded::new(&parsed, &bytes);
+        queue.extend(decoded.links());
+        match decoded.sealed_links(keyring) {
+            Ok(Some(links)) => queue.extend(links),
+            Ok(None) => car.locked = true,
+            Err(e) => return Err(CarError::Decode(cid, e.to_string())),
+        }
+        queue.extend(decoded.prev().map(str::to_string));
+
+        car.blocks.push((parsed, bytes));
+    }
+
+    Ok(car)
+}
+
+/// Verify every block, then put them all into the blockstore and pin the roots so
+/// garbage collection keeps them.
+pub async fn import<B: Blockstore>(blockstore: &B, pins: &Pins, car: &Car) -> Result<(), CarError> {
+    car.verify()?;
+
+    for (cid, data) in &car.blocks {
+        blockstore.put_keyed(cid, data).await?;
+    }
+    for root in &car.roots {
+        pins.pin(&root.to_string(), IMPORT_PIN)?;
+    }
+
+    tracing::info!(
+        "Imported {} blocks with roots {:?}",
+        car.blocks.len(),
+        car.roots
+    );
+    Ok(())
+}
+
+/// DAG-CBOR header `{"roots": [..], "version": 1}`, keys in DAG-CBOR canonical order
+fn encode_header(roots: &[Cid]) -> Vec<u8> {
+    let mut header = vec![0xa2];
+    write_cbor_head(&mut header, 3, 5);
+    header.extend(b"roots");
+    write_cbor_head(&mut header, 4, roots.len() as u64);
+    for root in roots {
+        // tag 42, bytes of the CID with a leading zero
+        header.extend([0xd8, 42]);
+        let cid = root.to_bytes();
+        write_cbor_head(&mut header, 2, cid.len() as u64 + 1);
+        header.push(0);
+        header.extend(cid);
+    }
+    write_cbor_head(&mut header, 3, 7);
+    header.extend(b"version");
+    write_cbor_head(&mut header, 0, 1);
+    header
+}
+
+fn decode_header(header: &[u8]) -> Result<Vec<Cid>, CarError> {
+    use serde_cbor::Value;
+
+    let invalid = |reason: &str| CarError::Header(reason.to_string());
+    let Ok(Value::Map(map)) = serde_cbor::from_slice::<Value>(header) else {
+        return Err(invalid("not a CBOR map"));
+    };
+
+    match map.get(&Value::Text("version".to_string())) {
+        Some(Value::Integer(1)) => {}
+        Some(Value::Integer(version)) => {
+            return Err(CarError::Version(
+                u64::try_from(*version).unwrap_or_default(),
+            ))
+        }
+        _ => return Err(invalid("missing version")),
+    }
+
+    let Some(Value::Array(roots)) = map.get(&Value::Text("roots".to_string())) else {
+        return Err(invalid("missing roots"));
+    };
+
+    roots
+        .iter()
+        .map(|root| {
+            // the tag is kept or dropped depending on serde_cbor's features
+            let bytes = match root {
+                Value::Tag(42, inner) => match inner.as_ref() {
+                    Value::Bytes(bytes) => bytes,
+                    _ => return Err(invalid("root is not a CID")),
+                },
+                Value::Bytes(bytes) => bytes,
+                _ => return Err(invalid("root is not a CID")),
+            };
+            match bytes.split_first() {
+                Some((0, cid)) => Ok(Cid::try_from(cid)?),
+                _ => Err(invalid("root is not a CID")),
+            }
+        })
+        .collect()
+}
+
+/// CBOR major type and argument
+fn write_cbor_head(bytes: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => bytes.push(major | value as u8),
+        24..=0xff => bytes.extend([major | 24, value as u8]),
+        0x100..=0xffff => {
+            bytes.push(major | 25);
+            bytes.extend((value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            bytes.push(major | 26);
+            bytes.extend((value as u32).to_be_bytes());
+        }
+        _ => {
+            bytes.push(major | 27);
+            bytes.extend(value.to_be_bytes());
+        }
+    }
+}
+
+/// Unsigned LEB128 varint
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64, CarError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*pos).ok_or(CarError::Malformed)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(CarError::Malformed)
+}
+
+/// Read a varint length prefixed section
+fn read_section<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8], CarError> {
+    let len = read_varint(bytes, pos)? as usize;
+    let end = pos.checked_add(len).ok_or(CarError::Malformed)?;
+    let section = bytes.get(*pos..end).ok_or(CarError::Malformed)?;
+    *pos = end;
+    Ok(section)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::Multihash;
+    use sha2::{Digest as _, Sha256};
+
+    fn block(data: &[u8]) -> (Cid, Vec<u8>) {
+        let hash = Multihash::<64>::wrap(0x12, &Sha256::digest(data)).unwrap();
+        (Cid::new_v1(0x55, hash), data.to_vec())
+    }
+
+    #[test]
+    fn test_car_roundtrip_and_verify() {
+        let root = block(b"root");
+        let car = Car {
+            roots: vec![root.0],
+            blocks: vec![root, block(&[7; 300])],
+            locked: false,
+        };
+
+        let decoded = Car::from_bytes(&car.to_bytes()).unwrap();
+        assert_eq!(decoded, car);
+        decoded.verify().unwrap();
+
+        let mut tampered = decoded;
+        tampered.blocks[1].1[0] = 8;
+        assert!(matches!(
+            tampered.verify(),
+            Err(CarError::Block(BundleError::HashMismatch(_)))
+        ));
+    }
+}
@@ -1,99 +1,300 @@
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
-
-#[derive(Clone)]
-pub(crate) struct Debouncer {
-    pub(crate) cancel_token: Arc<Mutex<Option<tokio::sync::oneshot::Sender<()>>>>,
-    pub(crate) callback: Arc<dyn Fn() + Send + Sync>,
-    pub(crate) delay: Duration,
+//! Debounce work by key, the same way on native and web.
+//!
+//! Each [Debouncer::debounce] call replaces the pending value for its key and pushes
+//! the deadline back, so a burst of calls runs the callback once with the latest value.
+//! With a max wait, a key that keeps changing still runs at least that often.
+//!
+//! Time comes from a [Timer], so tests can drive it with a mock clock.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use web_time::Instant;
+
+/// A clock which can run a task after a delay
+pub(crate) trait Timer: Send + Sync + 'static {
+    /// The current time
+    fn now(&self) -> Instant;
+
+    /// Run the task once the delay has passed
+    fn schedule(&self, delay: Duration, task: Box<dyn FnOnce() + Send>);
 }
 
-impl Debouncer {
-    #[allow(dead_code)]
-    pub(crate) fn new<F>(callback: F, delay_ms: u64) -> Self
-    where
-        F: Fn() + Send + Sync + 'static,
-    {
-        Debouncer {
-            cancel_token: Arc::new(Mutex::new(None)),
-            callback: Arc::new(callback),
-            delay: Duration::from_millis(delay_ms),
-        }
+/// [Timer] on the platform's runtime: tokio on native, browser timeouts on web
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PlatformTimer;
+
+impl Timer for PlatformTimer {
+    fn now(&self) -> Instant {
+        Instant::now()
     }
 
-    pub(crate) async fn debounce(&self) {
-        // Cancel any existing timer
-        {
-            let mut current_token = self.cancel_token.lock().await;
-            if let Some(sender) = current_token.take() {
-                let _ = sender.send(()); // Signal to cancel previous timer
-            }
+    #[cfg(not(target_arch = "wasm32"))]
+    fn schedule(&self, delay: Duration, task: Box<dyn FnOnce() + Send>) {
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            task();
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn schedule(&self, delay: Duration, task: Box<dyn FnOnce() + Send>) {
+        gloo_timers::callback::Timeout::new(delay.as_millis() as u32, task).forget();
+    }
+}
+
+/// Called with the key and its latest value
+type Callback<K, V> = Arc<dyn Fn(K, V) + Send + Sync>;
+
+/// A key waiting to run
+struct Pending<V> {
+    /// When the first call of this burst was made, for the max wait
+    first: Instant,
+    /// Bumped on every call, so superseded timers do nothing when they fire
+    generation: u64,
+    value: V,
+}
+
+struct Inner<K, V, T> {
+    timer: T,
+    delay: Duration,
+    max_wait: Option<Duration>,
+    callback: Callback<K, V>,
+    pending: Mutex<HashMap<K, Pending<V>>>,
+}
+
+/// Runs a callback once per key after calls for that key stop for the delay.
+/// Cheap to clone, clones share the pending keys.
+pub(crate) struct Debouncer<K, V, T = PlatformTimer> {
+    inner: Arc<Inner<K, V, T>>,
+}
+
+impl<K, V, T> Clone for Debouncer<K, V, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
         }
+    }
+}
 
-        // Create a new cancellation channel
-        let (sender, receiver) = tokio::sync::oneshot::channel();
+impl<K, V, T> Debouncer<K, V, T>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Send + 'static,
+    T: Timer,
+{
+    pub(crate) fn new(
+        timer: T,
+        delay: Duration,
+        callback: impl Fn(K, V) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                timer,
+                delay,
+                max_wait: None,
+                callback: Arc::new(callback),
+                pending: Default::default(),
+            }),
+        }
+    }
 
-        // Store the new cancel sender
-        {
-            let mut current_token = self.cancel_token.lock().await;
-            *current_token = Some(sender);
+    /// Run a key at most this long after its first pending call, even if calls keep coming
+    pub(crate) fn with_max_wait(self, max_wait: Duration) -> Self {
+        let inner = Arc::try_unwrap(self.inner)
+            .unwrap_or_else(|_| panic!("max wait must be set before the debouncer is cloned"));
+        Self {
+            inner: Arc::new(Inner {
+                max_wait: Some(max_wait),
+                ..inner
+            }),
         }
+    }
 
-        // Clone what we need for the async block
-        let callback = self.callback.clone();
-        let delay = self.delay;
+    /// Replace the key's pending value and restart its delay
+    pub(crate) fn debounce(&self, key: K, value: V) {
+        let now = self.inner.timer.now();
+        let (generation, deadline) = {
+            let mut pending = self.inner.pending.lock().unwrap();
+            let (first, generation) = match pending.get(&key) {
+                Some(p) => (p.first, p.generation + 1),
+                None => (now, 0),
+            };
+            pending.insert(
+                key.clone(),
+                Pending {
+                    first,
+                    generation,
+                    value,
+                },
+            );
 
-        // Spawn a task that can be cancelled
-        tokio::spawn(async move {
-            tokio::select! {
-                _ = sleep(delay) => {
-                    callback();
-                }
-                _ = receiver => {
-                    // Cancelled, do nothing
-                }
+            let mut deadline = now + self.inner.delay;
+            if let Some(max_wait) = self.inner.max_wait {
+                deadline = deadline.min(first + max_wait);
             }
-        });
+            (generation, deadline)
+        };
+
+        let weak = Arc::downgrade(&self.inner);
+        self.inner.timer.schedule(
+            deadline.saturating_duration_since(now),
+            Box::new(move || fire(weak, key, generation)),
+        );
+    }
+
+    /// Drop the key's pending value without running it. Returns whether one was pending.
+    pub(crate) fn cancel(&self, key: &K) -> bool {
+        self.inner.pending.lock().unwrap().remove(key).is_some()
+    }
+
+    /// Run every pending key now
+    pub(crate) fn flush_all(&self) {
+        let pending = std::mem::take(&mut *self.inner.pending.lock().unwrap());
+        for (key, p) in pending {
+            (self.inner.callback)(key, p.value);
+        }
+    }
+}
+
+/// Run the key if the timer is still the latest for it
+fn fire<K: Eq + Hash, V, T>(inner: Weak<Inner<K, V, T>>, key: K, generation: u64) {
+    let Some(inner) = inner.upgrade() else {
+        return;
+    };
+    let value = {
+        let mut pending = inner.pending.lock().unwrap();
+        match pending.get(&key) {
+            Some(p) if p.generation == generation => pending.remove(&key).map(|p| p.value),
+            _ => None,
+        }
+    };
+    if let Some(value) = value {
+        (inner.callback)(key, value);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::time::sleep;
 
-    #[tokio::test]
-    async fn test_debouncer() {
-        // create a debouncer with a delay of 100delay_ms
-        // and a callback that increments a counter
-        // after a final 400ms delay, count should be one
+    type Task = Box<dyn FnOnce() + Send>;
+    type Runs = Arc<Mutex<Vec<(&'static str, u32)>>>;
 
-        let counter = Arc::new(Mutex::new(0));
-        let counter_clone = counter.clone();
+    /// Clock which only moves when advanced, running due tasks in order
+    #[derive(Clone)]
+    struct MockTimer {
+        start: Instant,
+        elapsed: Arc<Mutex<Duration>>,
+        tasks: Arc<Mutex<Vec<(Duration, Task)>>>,
+    }
 
-        // avoid async closures
+    impl MockTimer {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                elapsed: Default::default(),
+                tasks: Default::default(),
+            }
+        }
 
-        let callback = move || {
-            let counter = counter_clone.clone();
-            tokio::spawn(async move {
-                let mut counter = counter.lock().await;
-                *counter += 1;
-            });
-        };
+        fn advance(&self, by: Duration) {
+            let until = *self.elapsed.lock().unwrap() + by;
+            loop {
+                let due = {
+                    let mut tasks = self.tasks.lock().unwrap();
+                    tasks.sort_by_key(|(at, _)| *at);
+                    match tasks.first() {
+                        Some((at, _)) if *at <= until => Some(tasks.remove(0)),
+                        _ => None,
+                    }
+                };
+                let Some((at, task)) = due else { break };
+                *self.elapsed.lock().unwrap() = at;
+                task();
+            }
+            *self.elapsed.lock().unwrap() = until;
+        }
+    }
+
+    impl Timer for MockTimer {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
 
-        let debouncer = Debouncer::new(callback, 100);
+        fn schedule(&self, delay: Duration, task: Task) {
+            let at = *self.elapsed.lock().unwrap() + delay;
+            self.tasks.lock().unwrap().push((at, task));
+        }
+    }
+
+    fn debouncer(timer: &MockTimer) -> (Debouncer<&'static str, u32, MockTimer>, Runs) {
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let log = runs.clone();
+        let debouncer = Debouncer::new(timer.clone(), Duration::from_millis(100), move |k, v| {
+            log.lock().unwrap().push((k, v))
+        })
+        .with_max_wait(Duration::from_millis(250));
+        (debouncer, runs)
+    }
 
-        debouncer.debounce().await;
-        sleep(Duration::from_millis(50)).await;
-        debouncer.debounce().await;
-        sleep(Duration::from_millis(50)).await;
-        debouncer.debounce().await;
-        sleep(Duration::from_millis(50)).await;
-        debouncer.debounce().await;
-        sleep(Duration::from_millis(400)).await;
+    #[test]
+    fn test_debounce_coalesces_per_key() {
+        let timer = MockTimer::new();
+        let (debouncer, runs) = debouncer(&timer);
+        let ms = Duration::from_millis;
+
+        for value in 1..=3 {
+            debouncer.debounce("a", value);
+            timer.advance(ms(50));
+        }
+        debouncer.debounce("b", 1);
+        assert!(runs.lock().unwrap().is_empty());
+
+        timer.advance(ms(100));
+        assert_eq!(*runs.lock().unwrap(), [("a", 3), ("b", 1)]);
+
+        // cancelled keys never run, flushed keys run at once
+        debouncer.debounce("a", 4);
+        debouncer.debounce("b", 2);
+        assert!(debouncer.cancel(&"a"));
+        debouncer.flush_all();
+        timer.advance(ms(500));
+        assert_eq!(runs.lock().unwrap()[2..], [("b", 2)]);
+    }
+
+    #[test]
+    fn test_max_wait_runs_a_busy_key() {
+        let timer = MockTimer::new();
+        let (debouncer, runs) = debouncer(&timer);
+
+        // a call every 50ms never goes quiet for the 100ms delay
+        for value in 1..=8 {
+            debouncer.debounce("a", value);
+            timer.advance(Duration::from_millis(50));
+        }
+        assert_eq!(*runs.lock().unwrap(), [("a", 5)]);
+
+        timer.advance(Duration::from_millis(100));
+        assert_eq!(*runs.lock().unwrap(), [("a", 5), ("a", 8)]);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn test_debouncer() {
+        let counter = Arc::new(Mutex::new(0));
+        let count = counter.clone();
+        let debouncer = Debouncer::new(PlatformTimer, Duration::from_millis(100), move |_, n| {
+            *count.lock().unwrap() += n
+        });
+
+        for _ in 0..4 {
+            debouncer.debounce((), 1);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(400)).await;
 
-        let counter = counter.lock().await;
-        assert_eq!(*counter, 1);
+        assert_eq!(*counter.lock().unwrap(), 1);
     }
 }
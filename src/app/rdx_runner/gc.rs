@@ -0,0 +1,337 @@
+//! Blockstore garbage collection.
+//!
+//! Blocks are live when they are pinned, are a plugin's current state or one of its
+//! previous states kept by the [Retention], or are linked to by CID from any live state.
+//! Every other block in the [BlockIndex](crate::app::platform::blocks::BlockIndex) is
+//! removed.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use blockstore::Blockstore;
+use cid::Cid;
+use web_time::SystemTime;
+
+use super::history::StateBlock;
+use super::inspect::Decoded;
+use super::keyring::Keyring;
+use crate::app::platform::blocks::{Pins, Tracked};
+use crate::app::platform::storage::{Storage, StorageError};
+
+/// Blocks put within this many seconds are kept, since the state linking to them
+/// may not have been saved yet.
+const GRACE_SECS: i64 = 10 * 60;
+
+/// Which earlier states of each plugin are kept, the rest are reclaimed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Keep this many earlier states
+    pub keep_last: usize,
+    /// Keep earlier states saved less than this many seconds ago too
+    pub keep_secs: i64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            keep_last: 20,
+            keep_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Retention {
+    /// Keep the whole history, such as for a backup
+    pub const ALL: Self = Self {
+        keep_last: usize::MAX,
+        keep_secs: i64::MAX,
+    };
+
+    /// Whether the earlier state this many saves back, saved at `timestamp`, is kept
+    fn keeps(&self, depth: usize, timestamp: Option<i64>, now: i64) -> bool {
+        depth <= self.keep_last || timestamp.is_some_and(|t| now.saturating_sub(t) < self.keep_secs)
+    }
+}
+
+/// Seconds since the unix epoch
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Garbage collection errors
+#[derive(Debug, thiserror::Error)]
+pub enum GcError {
+    /// The blockstore isn't open yet
+    #[error("The node isn't started yet")]
+    NotReady,
+    /// Encrypted states may link to blocks, so they must be readable to collect safely
+    #[error("Unlock the wallet first, encrypted plugin state may link to blocks")]
+    Locked,
+    /// Reading the state heads or pins failed
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    /// Reading or removing a block failed
+    #[error("Blockstore error: {0}")]
+    Blockstore(#[from] blockstore::Error),
+    /// A state block couldn't be read, so its links are unknown
+    #[error("Failed to read state block {0}: {1}")]
+    State(String, String),
+}
+
+/// The outcome of a garbage collection pass
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Number of blocks in the index
+    pub scanned: usize,
+    /// Number of blocks kept
+    pub kept: usize,
+    /// Number of blocks removed
+    pub removed: usize,
+    /// Total size of the removed blocks, in bytes
+    pub reclaimed: u64,
+}
+
+/// Why a block is kept
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reference {
+    /// The current state of the plugin
+    State(String),
+    /// An earlier state of the plugin
+    History(String),
+    /// Linked to from the plugin's state, or from a block pinned for the reason
+    Link(String),
+    /// Pinned for the reason, such as `plugin:<name>` for blocks a plugin put itself
+    Pinned(String),
+}
+
+impl Reference {
+    /// The plugin name, or the pin reason
+    pub fn name(&self) -> &str {
+        match self {
+            Self::State(name) | Self::History(name) | Self::Link(name) | Self::Pinned(name) => name,
+        }
+    }
+}
+
+impl std::fmt::Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::State(name) => write!(f, "current state of {}", name),
+            Self::History(name) => write!(f, "history of {}", name),
+            Self::Link(name) => write!(f, "linked from {}", name),
+            Self::Pinned(reason) => write!(f, "pinned by {}", reason),
+        }
+    }
+}
+
+/// Every referenced CID and what references it
+#[derive(Debug, Clone, Default)]
+pub struct References {
+    /// References by CID
+    pub by_cid: BTreeMap<String, BTreeSet<Reference>>,
+    /// Whether any state was sealed while the keyring is locked,
+    /// in which case the links in its encrypted part are missing
+    pub locked: bool,
+}
+
+impl References {
+    fn add(&mut self, cid: &str, reference: Reference) {
+        self.by_cid
+            .entry(cid.to_string())
+            .or_default()
+            .insert(reference);
+    }
+}
+
+/// Find what references each block: the pins, the plugin state heads in `storage`,
+/// their history kept by the retention, and every block they link to
+pub async fn references<B: Blockstore>(
+    blockstore: &Tracked<B>,
+    storage: Arc<dyn Storage>,
+    keyring: &Keyring,
+    retention: Retention,
+) -> Result<References, GcError> {
+    let mut refs = References::default();
+    let now = now();
+
+    let mut roots = Vec::new();
+    for (cid, reasons) in Pins::new(storage.clone()).list()? {
+        for reason in reasons {
+            roots.push((cid.clone(), Reference::Pinned(reason)));
+        }
+    }
+    // plugin state heads are the only CIDs in the storage values
+    for key in storage.keys()? {
+        if let Some(value) = storage.get_string(&key)? {
+            if Cid::try_from(value.trim()).is_ok() {
+                roots.push((value.trim().to_string(), Reference::State(key)));
+            }
+        }
+    }
+
+    for (root, reference) in roots {
+        let name = reference.name().to_string();
+        let mut visited = BTreeSet::new();
+        // with how many saves back each is, for the retention
+        let mut queue = vec![(root, reference, 0)];
+
+        while let Some((cid, reference, depth)) = queue.pop() {
+            let Ok(parsed) = Cid::try_from(cid.as_str()) else {
+                refs.add(&cid, reference);
+                continue;
+            };
+            // earlier states past the retention are neither kept nor followed
+            if matches!(reference, Reference::History(_)) && depth > retention.keep_last {
+                let timestamp = blockstore
+                    .get(&parsed)
+                    .await?
+                    .and_then(|bytes| StateBlock::decode(&bytes).ok())
+                    .map(|block| block.timestamp);
+                if !retention.keeps(depth, timestamp, now) {
+                    continue;
+                }
+            }
+
+            refs.add(&cid, reference);
+            if !visited.insert(cid.clone()) {
+                continue;
+            }
+            // missing blocks have nothing to follow
+            let Some(bytes) = blockstore.get(&parsed).await? else {
+                continue;
+            };
+
+            let decoded = Decoded::new(&parsed, &bytes);
+            let mut links = decoded.links();
+            match decoded.sealed_links(keyring) {
+                Ok(Some(sealed)) => links.extend(sealed),
+                Ok(None) => refs.locked = true,
+                Err(e) => return Err(GcError::State(cid.clone(), e.to_string())),
+            }
+            if let Some(prev) = decoded.prev() {
+                queue.push((
+                    prev.to_string(),
+                    Reference::History(name.clone()),
+                    depth + 1,
+                ));
+            }
+
+            queue.extend(
+                links
+                    .into_iter()
+                    .map(|link| (link, Reference::Link(name.clone()), depth)),
+            );
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Every live CID, refusing when encrypted states can't be read
+pub async fn live_set<B: Blockstore>(
+    blockstore: &Tracked<B>,
+    storage: Arc<dyn Storage>,
+    keyring: &Keyring,
+    retention: Retention,
+) -> Result<BTreeSet<String>, GcError> {
+    let refs = references(blockstore, storage, keyring, retention).await?;
+    if refs.locked {
+        return Err(GcError::Locked);
+    }
+    Ok(refs.by_cid.into_keys().collect())
+}
+
+/// Remove every indexed block which isn't live
+pub async fn collect<B: Blockstore>(
+    blockstore: &Tracked<B>,
+    storage: Arc<dyn Storage>,
+    keyring: &Keyring,
+    retention: Retention,
+) -> Result<GcReport, GcError> {
+    let live = live_set(blockstore, storage, keyring, retention).await?;
+    let now = now();
+
+    let entries = blockstore.index().entries();
+    let mut report = GcReport {
+        scanned: entries.len(),
+        ..Default::default()
+    };
+
+    for (cid, entry) in entries {
+        if live.contains(&cid) || now - entry.added < GRACE_SECS {
+            report.kept += 1;
+            continue;
+        }
+        let Ok(parsed) = Cid::try_from(cid.as_str()) else {
+            blockstore.index().remove(&cid);
+            continue;
+        };
+        blockstore.remove(&parsed).await?;
+        report.removed += 1;
+        report.reclaimed += entry.size;
+    }
+
+    tracing::info!("🧹 Garbage collection: {:?}", report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::blocks::BlockIndex;
+    use crate::app::platform::storage::MemoryStore;
+    use crate::app::rdx_runner::history::StateBlock;
+    use blockstore::InMemoryBlockstore;
+    use cid::multihash::Multihash;
+    use sha2::{Digest, Sha256};
+
+    async fn put(store: &Tracked<InMemoryBlockstore<64>>, data: &[u8]) -> String {
+        let hash = Multihash::<64>::wrap(0x12, &Sha256::digest(data)).unwrap();
+        let cid = Cid::new_v1(0x55, hash);
+        store.put_keyed(&cid, data).await.unwrap();
+        cid.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_live_set_follows_prev_and_links() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStore::default());
+        let store = Tracked::with_index(
+            InMemoryBlockstore::<64>::new(),
+            BlockIndex::open(storage.clone()),
+        );
+
+        let linked = put(&store, b"linked").await;
+        let pinned = put(&store, b"pinned").await;
+        let orphan = put(&store, b"orphan").await;
+
+        let old = StateBlock::new(None, serde_json::json!({ "link": linked }));
+        let old = put(&store, &serde_json::to_vec(&old).unwrap()).await;
+        let head = StateBlock::new(Some(old.clone()), serde_json::json!({}));
+        let head = put(&store, &serde_json::to_vec(&head).unwrap()).await;
+
+        storage.set_string("plugin.wasm", head.clone()).unwrap();
+        Pins::new(storage.clone()).pin(&pinned, "test").unwrap();
+
+        let live = live_set(&store, storage.clone(), &Keyring::default(), Retention::ALL)
+            .await
+            .unwrap();
+        assert_eq!(
+            live,
+            BTreeSet::from([head.clone(), old.clone(), linked, pinned.clone()])
+        );
+        assert!(!live.contains(&orphan));
+
+        // states past the retention are reclaimed, with the blocks only they link to
+        let retention = Retention {
+            keep_last: 0,
+            keep_secs: 0,
+        };
+        let live = live_set(&store, storage, &Keyring::default(), retention)
+            .await
+            .unwrap();
+        assert_eq!(live, BTreeSet::from([head, pinned]));
+        assert!(!live.contains(&old));
+    }
+}
@@ -0,0 +1,368 @@
+//! Plugin state history.
+//!
+//! Every saved [rhai::Scope] is wrapped in a [StateBlock] which links to the CID of the
+//! block saved before it. Following the `prev` links from the CID in the
+//! [crate::app::platform::StringStore] walks back through every snapshot of a plugin.
+use std::future::Future;
+
+use rdx::layer::rhai::Scope;
+use web_time::SystemTime;
+
+use super::keyring::{Keyring, KeyringError, Sealed};
+
+/// Keyring context for sealing plugin state
+const STATE_CONTEXT: &str = "state";
+
+/// Max number of snapshots the UI walks back through, so a very long chain can't stall it.
+pub const MAX_SNAPSHOTS: usize = 100;
+
+/// A single saved state of a plugin, as stored in the blockstore.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StateBlock {
+    /// CID of the state saved before this one, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+    /// Unix timestamp (seconds) of when this state was saved
+    pub timestamp: i64,
+    /// The serialized plugin [Scope]. When the state is sealed, only the plaintext variables.
+    pub scope: serde_json::Value,
+    /// The encrypted remainder of the [Scope], when the plugin state is encrypted at rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Sealed>,
+}
+
+impl StateBlock {
+    /// Wrap a serialized scope, linking it to the previous state CID.
+    pub fn new(prev: Option<String>, scope: serde_json::Value) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+
+        Self {
+            prev,
+            timestamp,
+            scope,
+            sealed: None,
+        }
+    }
+
+    /// Wrap a scope, encrypting every variable except those named in `plaintext`.
+    pub fn seal(
+        prev: Option<String>,
+        scope: &Scope<'_>,
+        plaintext: &[String],
+        keyring: &Keyring,
+    ) -> anyhow::Result<Self> {
+        let (public, private) = split_scope(scope, plaintext);
+        let sealed = keyring.seal(STATE_CONTEXT, &serde_json::to_vec(&private)?)?;
+
+        Ok(Self {
+            sealed: Some(sealed),
+            ..Self::new(prev, serde_json::to_value(&public)?)
+        })
+    }
+
+    /// Decode a state block from bytes.
+    ///
+    /// States saved before history was introduced are a bare serialized [Scope],
+    /// so those are decoded as a block with no predecessor and no timestamp.
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        if let Ok(block) = serde_json::from_slice::<StateBlock>(bytes) {
+            return Ok(block);
+        }
+
+        let scope = serde_json::from_slice::<serde_json::Value>(bytes)?;
+        Ok(Self {
+            prev: None,
+            timestamp: 0,
+            scope,
+            sealed: None,
+        })
+    }
+
+    /// Deserialize the plaintext part of the wrapped [Scope]
+    pub fn to_scope(&self) -> anyhow::Result<Scope<'static>> {
+        Ok(serde_json::from_value(self.scope.clone())?)
+    }
+
+    /// Deserialize the full [Scope], decrypting the sealed part with the keyring.
+    ///
+    /// Returns [KeyringError::Locked] if the block is sealed and the keyring is locked.
+    pub fn open(&self, keyring: &Keyring) -> anyhow::Result<Scope<'static>> {
+        let mut scope = self.to_scope()?;
+
+        if let Some(sealed) = &self.sealed {
+            let bytes = keyring.open(STATE_CONTEXT, sealed)?;
+            let private: Scope<'static> = serde_json::from_slice(&bytes)?;
+            merge_scope(&mut scope, &private);
+        }
+
+        Ok(scope)
+    }
+
+    /// Deserialize as much of the [Scope] as the keyring allows.
+    ///
+    /// Returns the scope and whether it's locked, in which case only the plaintext
+    /// variables are in the scope.
+    pub fn load(&self, keyring: &Keyring) -> anyhow::Result<(Scope<'static>, bool)> {
+        match self.open(keyring) {
+            Ok(scope) => Ok((scope, false)),
+            Err(e) if is_locked(&e) => Ok((self.to_scope()?, true)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// A copy of this block with the sealed part decrypted into the plaintext scope,
+    /// or unchanged if the keyring is locked.
+    pub fn opened(&self, keyring: &Keyring) -> anyhow::Result<Self> {
+        match self.open(keyring) {
+            Ok(scope) => Ok(Self {
+                scope: serde_json::to_value(&scope)?,
+                sealed: None,
+                ..self.clone()
+            }),
+            Err(e) if is_locked(&e) => Ok(self.clone()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Whether the error is because the keyring is still locked
+pub fn is_locked(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<KeyringError>(), Some(KeyringError::Locked))
+}
+
+/// Split a scope into the variables named in `names` and all the others
+pub(crate) fn split_scope(scope: &Scope<'_>, names: &[String]) -> (Scope<'static>, Scope<'static>) {
+    let mut named = Scope::new();
+    let mut rest = Scope::new();
+
+    for (name, constant, value) in scope.iter() {
+        let target = match names.iter().any(|n| n == name) {
+            true => &mut named,
+            false => &mut rest,
+        };
+        match constant {
+            true => target.push_constant_dynamic(name.to_string(), value),
+            false => target.push_dynamic(name.to_string(), value),
+        };
+    }
+
+    (named, rest)
+}
+
+/// Add the variables from `other` which are not already in `scope`
+pub fn merge_scope(scope: &mut Scope<'static>, other: &Scope<'_>) {
+    for (name, constant, value) in other.iter() {
+        if scope.contains(name) {
+            continue;
+        }
+        match constant {
+            true => scope.push_constant_dynamic(name.to_string(), value),
+            false => scope.push_dynamic(name.to_string(), value),
+        };
+    }
+}
+
+/// Variables from `other` replace those in `scope`, variables only in `scope` are kept
+pub fn overlay_scope(scope: &mut Scope<'static>, other: &Scope<'_>) {
+    let mut merged = Scope::new();
+    merge_scope(&mut merged, other);
+    merge_scope(&mut merged, scope);
+    *scope = merged;
+}
+
+/// A state block along with the CID it is stored under.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// CID of this state block
+    pub cid: String,
+    /// The decoded state block
+    pub block: StateBlock,
+}
+
+/// Walk the history chain starting at `head`, newest first.
+///
+/// `get` fetches the bytes for a CID. The walk stops at the first block without a `prev`,
+/// after `limit` blocks, or at the first block which fails to load.
+pub async fn walk<F, Fut>(head: String, limit: usize, mut get: F) -> anyhow::Result<Vec<Snapshot>>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    let mut snapshots = Vec::new();
+    let mut next = Some(head);
+
+    while let Some(cid) = next.take() {
+        if snapshots.len() >= limit {
+            break;
+        }
+
+        let bytes = match get(cid.clone()).await {
+            Ok(bytes) => bytes,
+            // the head must load, older blocks may have been removed
+            Err(e) if snapshots.is_empty() => return Err(e),
+            Err(e) => {
+                tracing::warn!("History stopped at {}: {:?}", cid, e);
+                break;
+            }
+        };
+
+        let block = StateBlock::decode(&bytes)?;
+        next = block.prev.clone();
+        snapshots.push(Snapshot { cid, block });
+    }
+
+    Ok(snapshots)
+}
+
+/// A single variable difference between two scopes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Variable only exists in the newer scope
+    Added { name: String, value: String },
+    /// Variable only exists in the older scope
+    Removed { name: String, value: String },
+    /// Variable exists in both, with different values
+    Changed {
+        name: String,
+        old: String,
+        new: String,
+    },
+}
+
+/// Compare two scopes, variable by variable.
+pub fn diff(old: &Scope<'_>, new: &Scope<'_>) -> Vec<Change> {
+    let to_map = |scope: &Scope<'_>| {
+        scope
+            .iter()
+            .map(|(name, _constant, value)| {
+                let value = serde_json::to_string(&value).unwrap_or_else(|_| value.to_string());
+                (name.to_string(), value)
+            })
+            .collect::<std::collections::BTreeMap<_, _>>()
+    };
+
+    let old = to_map(old);
+    let new = to_map(new);
+
+    let mut changes = Vec::new();
+
+    for (name, old_value) in &old {
+        match new.get(name) {
+            None => changes.push(Change::Removed {
+                name: name.clone(),
+                value: old_value.clone(),
+            }),
+            Some(new_value) if new_value != old_value => changes.push(Change::Changed {
+                name: name.clone(),
+                old: old_value.clone(),
+                new: new_value.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for (name, value) in &new {
+        if !old.contains_key(name) {
+            changes.push(Change::Added {
+                name: name.clone(),
+                value: value.clone(),
+            });
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_walk_chain() {
+        let mut scope = Scope::new();
+        scope.set_value("count", 1_i64);
+
+        let first = StateBlock::new(None, serde_json::to_value(&scope).unwrap());
+        scope.set_value("count", 2_i64);
+        let second = StateBlock::new(
+            Some("first".to_string()),
+            serde_json::to_value(&scope).unwrap(),
+        );
+
+        let blocks = HashMap::from([
+            ("first".to_string(), serde_json::to_vec(&first).unwrap()),
+            ("second".to_string(), serde_json::to_vec(&second).unwrap()),
+        ]);
+
+        let get = |cid: String| {
+            let bytes = blocks.get(&cid).cloned();
+            async move { bytes.ok_or_else(|| anyhow::anyhow!("missing {}", cid)) }
+        };
+        let snapshots = walk("second".to_string(), MAX_SNAPSHOTS, get)
+            .await
+            .unwrap();
+
+        // the walk stops at the limit
+        let newest = walk("second".to_string(), 1, get).await.unwrap();
+        assert_eq!(newest.len(), 1);
+
+        let cids = snapshots.iter().map(|s| s.cid.as_str()).collect::<Vec<_>>();
+        assert_eq!(cids, vec!["second", "first"]);
+
+        let changes = diff(
+            &snapshots[1].block.to_scope().unwrap(),
+            &snapshots[0].block.to_scope().unwrap(),
+        );
+        assert_eq!(
+            changes,
+            vec![Change::Changed {
+                name: "count".to_string(),
+                old: "1".to_string(),
+                new: "2".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_seal_keeps_plaintext_vars() {
+        let mut scope = Scope::new();
+        scope.set_value("encrypted_seed", "[1,2,3]".to_string());
+        scope.set_value("password", "hunter22".to_string());
+
+        let keyring = Keyring::default();
+        keyring.unlock(b"signature bytes");
+
+        let block =
+            StateBlock::seal(None, &scope, &["encrypted_seed".to_string()], &keyring).unwrap();
+
+        let public = block.to_scope().unwrap();
+        assert!(public.contains("encrypted_seed"));
+        assert!(!public.contains("password"));
+
+        let full = block.open(&keyring).unwrap();
+        assert_eq!(
+            full.get_value::<String>("password"),
+            Some("hunter22".to_string())
+        );
+
+        assert!(is_locked(&block.open(&Keyring::default()).unwrap_err()));
+    }
+
+    #[test]
+    fn test_decode_legacy_scope() {
+        let mut scope = Scope::new();
+        scope.set_value("name", "legacy".to_string());
+        let bytes = serde_json::to_vec(&scope).unwrap();
+
+        let block = StateBlock::decode(&bytes).unwrap();
+        assert!(block.prev.is_none());
+        assert_eq!(
+            block.to_scope().unwrap().get_value::<String>("name"),
+            Some("legacy".to_string())
+        );
+    }
+}
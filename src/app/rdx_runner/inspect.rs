@@ -0,0 +1,184 @@
+//! Decode blockstore blocks for inspection and to find the CIDs they link to.
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use cid::Cid;
+
+use super::bundle::collect_links;
+use super::history::{is_locked, StateBlock};
+use super::keyring::Keyring;
+
+/// Multicodec code for raw bytes
+pub const RAW: u64 = 0x55;
+/// Multicodec code for CBOR
+pub const CBOR: u64 = 0x51;
+/// Multicodec code for DAG-CBOR
+pub const DAG_CBOR: u64 = 0x71;
+/// Multicodec code for DAG-PB
+pub const DAG_PB: u64 = 0x70;
+/// Multicodec code for JSON
+pub const JSON: u64 = 0x0200;
+/// Multicodec code for DAG-JSON
+pub const DAG_JSON: u64 = 0x0129;
+
+/// Name of the CID's codec, or its code in hex if unknown
+pub fn codec_name(cid: &Cid) -> String {
+    match cid.codec() {
+        RAW => "raw".to_string(),
+        CBOR => "cbor".to_string(),
+        DAG_CBOR => "dag-cbor".to_string(),
+        DAG_PB => "dag-pb".to_string(),
+        JSON => "json".to_string(),
+        DAG_JSON => "dag-json".to_string(),
+        code => format!("{:#x}", code),
+    }
+}
+
+/// A decoded block
+#[derive(Debug, Clone)]
+pub enum Decoded {
+    /// A plugin state block
+    State(StateBlock),
+    /// JSON or DAG-JSON
+    Json(serde_json::Value),
+    /// CBOR or DAG-CBOR, shown as DAG-JSON so links read as `{"/": cid}`
+    Cbor(serde_json::Value),
+    /// UTF-8 text
+    Text(String),
+    /// Anything else, such as a plugin binary
+    Binary(Vec<u8>),
+}
+
+impl Decoded {
+    /// Decode the block, using the CID's codec as a hint
+    pub fn new(cid: &Cid, bytes: &[u8]) -> Self {
+        if matches!(cid.codec(), CBOR | DAG_CBOR) {
+            if let Ok(value) = serde_cbor::from_slice::<serde_cbor::Value>(bytes) {
+                return Self::Cbor(cbor_to_json(value));
+            }
+        }
+        if let Ok(block) = serde_json::from_slice::<StateBlock>(bytes) {
+            return Self::State(block);
+        }
+        if let Ok(value) = serde_json::from_slice::<serde_json::Value>(bytes) {
+            return Self::Json(value);
+        }
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Self::Text(text.to_string()),
+            Err(_) => Self::Binary(bytes.to_vec()),
+        }
+    }
+
+    /// Short name of the kind of block
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::State(_) => "plugin state",
+            Self::Json(_) => "json",
+            Self::Cbor(_) => "cbor",
+            Self::Text(_) => "text",
+            Self::Binary(_) => "binary",
+        }
+    }
+
+    /// CIDs in the block's plaintext, not counting a state's `prev` link
+    pub fn links(&self) -> Vec<String> {
+        let mut links = Vec::new();
+        match self {
+            Self::State(block) => collect_links(&block.scope, &mut links),
+            Self::Json(value) | Self::Cbor(value) => collect_links(value, &mut links),
+            Self::Text(_) | Self::Binary(_) => {}
+        }
+        links
+    }
+
+    /// CIDs in the encrypted part of a sealed plugin state.
+    ///
+    /// Empty for other blocks, None when the keyring is locked.
+    pub fn sealed_links(&self, keyring: &Keyring) -> anyhow::Result<Option<Vec<String>>> {
+        let Self::State(block) = self else {
+            return Ok(Some(vec![]));
+        };
+        if block.sealed.is_none() {
+            return Ok(Some(vec![]));
+        }
+        match block.open(keyring) {
+            Ok(scope) => {
+                let mut links = Vec::new();
+                collect_links(&serde_json::to_value(&scope)?, &mut links);
+                Ok(Some(links))
+            }
+            Err(e) if is_locked(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The state's `prev` link, if this is a plugin state with one
+    pub fn prev(&self) -> Option<&str> {
+        match self {
+            Self::State(block) => block.prev.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Convert CBOR to DAG-JSON style JSON: links become `{"/": cid}`,
+/// bytes become `{"/": {"bytes": base64}}`
+fn cbor_to_json(value: serde_cbor::Value) -> serde_json::Value {
+    use serde_cbor::Value;
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Bool(b) => b.into(),
+        Value::Integer(i) => match i64::try_from(i) {
+            Ok(i) => i.into(),
+            Err(_) => i.to_string().into(),
+        },
+        Value::Float(f) => f.into(),
+        Value::Text(s) => s.into(),
+        // DAG-CBOR links are tag 42 over the CID bytes with a leading zero.
+        // The tag is dropped unless serde_cbor keeps tags, so match on the bytes.
+        Value::Bytes(bytes) => match bytes.split_first().map(|(b, cid)| (b, Cid::try_from(cid))) {
+            Some((0, Ok(cid))) => serde_json::json!({ "/": cid.to_string() }),
+            _ => serde_json::json!({ "/": { "bytes": BASE64.encode(bytes) } }),
+        },
+        Value::Tag(_, inner) => cbor_to_json(*inner),
+        Value::Array(values) => values.into_iter().map(cbor_to_json).collect(),
+        Value::Map(map) => map
+            .into_iter()
+            .map(|(k, v)| {
+                let key = match k {
+                    Value::Text(s) => s,
+                    other => cbor_to_json(other).to_string(),
+                };
+                (key, cbor_to_json(v))
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        _ => serde_json::Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::Multihash;
+
+    #[test]
+    fn test_cbor_links_decode_as_dag_json() {
+        let link = Cid::new_v1(RAW, Multihash::<64>::wrap(0x00, b"linked").unwrap());
+        let mut tagged = vec![0];
+        tagged.extend(link.to_bytes());
+
+        let value = serde_cbor::Value::Map(
+            [(
+                serde_cbor::Value::Text("prev".to_string()),
+                serde_cbor::Value::Tag(42, Box::new(serde_cbor::Value::Bytes(tagged))),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let bytes = serde_cbor::to_vec(&value).unwrap();
+        let cid = Cid::new_v1(DAG_CBOR, Multihash::<64>::wrap(0x00, &bytes).unwrap());
+
+        let decoded = Decoded::new(&cid, &bytes);
+        assert_eq!(decoded.kind(), "cbor");
+        assert_eq!(decoded.links(), vec![link.to_string()]);
+    }
+}
@@ -0,0 +1,313 @@
+//! Encryption keys derived from the unlocked wallet.
+//!
+//! The wallet plugin never hands out its seed, so the key material is a signature made by
+//! a seed derived Multikey over a fixed message. Ed25519 signatures are deterministic, so the
+//! same wallet always derives the same keys, on any machine.
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chacha20poly1305::{aead::Aead as _, ChaCha20Poly1305, KeyInit as _, Nonce};
+use hkdf::Hkdf;
+use rdx::layer::{Instantiator, List, ListType, RecordType, Value, ValueType};
+use rdx::wasm_component_layer::Record;
+use sha2::Sha256;
+
+use super::State;
+use crate::app::platform::storage::{Storage, StorageError};
+
+/// Key path passed to the wallet's `get-mk` when deriving the keyring
+const WALLET_KEY_PATH: &str = "/multinode/keyring";
+
+/// Message signed by the wallet to produce the key material
+const WALLET_KEY_MESSAGE: &[u8] = b"multinode keyring v1";
+
+/// First segment of the key paths only the host asks the wallet for
+const HOST_KEY_ROOT: &str = "multinode";
+
+/// Prefix of the messages only the host asks the wallet to sign
+const HOST_MESSAGE_PREFIX: &[u8] = b"multinode ";
+
+/// [Storage] key of the plugins which opted out of encryption at rest. Plugin names map
+/// to their state CIDs in the same store, so the opt-outs are kept apart from them.
+pub(crate) const PUBLIC_KEY: &str = "plugin-states-public";
+
+/// Keyring errors
+#[derive(Debug, thiserror::Error)]
+pub enum KeyringError {
+    /// The wallet has not been unlocked yet
+    #[error("Keyring is locked, unlock the wallet first")]
+    Locked,
+    /// Encryption or decryption failed, usually a wrong key or tampered data
+    #[error("Encryption error")]
+    Crypto,
+    /// The sealed data is not valid base64
+    #[error("Invalid encoding: {0}")]
+    Encoding(#[from] base64::DecodeError),
+    /// The wallet failed to derive the key material
+    #[error("Wallet error: {0}")]
+    Wallet(String),
+}
+
+/// Data encrypted with a keyring subkey
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Sealed {
+    /// Base64 encoded nonce
+    pub nonce: String,
+    /// Base64 encoded ciphertext
+    pub ciphertext: String,
+}
+
+/// Holds the key derived from the wallet, once it's unlocked.
+///
+/// Cheap to clone, all clones share the same key.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    hkdf: Arc<Mutex<Option<Hkdf<Sha256>>>>,
+}
+
+impl Keyring {
+    /// Whether the keyring has key material and can seal and open data
+    pub fn is_unlocked(&self) -> bool {
+        self.hkdf.lock().unwrap().is_some()
+    }
+
+    /// Unlock the keyring with the given key material
+    pub fn unlock(&self, secret: &[u8]) {
+        let hkdf = Hkdf::<Sha256>::new(Some(b"multinode"), secret);
+        self.hkdf.lock().unwrap().replace(hkdf);
+    }
+
+    /// Forget the key material, such as once the wallet is locked again
+    pub fn lock(&self) {
+        self.hkdf.lock().unwrap().take();
+    }
+
+    /// Derive the key material from the wallet plugin.
+    ///
+    /// Fails if the wallet has not been unlocked yet.
+    pub fn derive_from_wallet(
+        wallet: &Arc<Mutex<dyn Instantiator<State>>>,
+    ) -> Result<Vec<u8>, KeyringError> {
+        let mut wallet = wallet.lock().unwrap();
+
+        let key_args = Record::new(
+            RecordType::new(
+                None,
+                vec![
+                    ("key", ValueType::String),
+                    ("codec", ValueType::String),
+                    ("threshold", ValueType::U8),
+                    ("limit", ValueType::U8),
+                ],
+            )
+            .map_err(wallet_error)?,
+            vec![
+                ("key", Value::String(WALLET_KEY_PATH.into())),
+                ("codec", Value::String("ed25519-priv".into())),
+                ("threshold", Value::U8(1)),
+                ("limit", Value::U8(1)),
+            ],
+        )
+        .map_err(wallet_error)?;
+
+        let mk = wallet
+            .call("get-mk", &[Value::Record(key_args)])
+            .map_err(wallet_error)?;
+        let mk = ok_bytes(mk)?;
+
+        let prove_args = Record::new(
+            RecordType::new(
+                None,
+                vec![
+                    ("mk", ValueType::List(ListType::new(ValueType::U8))),
+                    ("data", ValueType::List(ListType::new(ValueType::U8))),
+                ],
+            )
+            .map_err(wallet_error)?,
+            vec![
+                ("mk", bytes_value(&mk)?),
+                ("data", bytes_value(WALLET_KEY_MESSAGE)?),
+            ],
+        )
+        .map_err(wallet_error)?;
+
+        let proof = wallet
+            .call("prove", &[Value::Record(prove_args)])
+            .map_err(wallet_error)?;
+
+        ok_bytes(proof)
+    }
+
+    /// Derive a 32 byte subkey for the given context
+    fn subkey(&self, context: &str) -> Result<[u8; 32], KeyringError> {
+        let lock = self.hkdf.lock().unwrap();
+        let hkdf = lock.as_ref().ok_or(KeyringError::Locked)?;
+        let mut key = [0u8; 32];
+        hkdf.expand(context.as_bytes(), &mut key)
+            .map_err(|_| KeyringError::Crypto)?;
+        Ok(key)
+    }
+
+    /// Encrypt the plaintext with the subkey for the given context
+    pub fn seal(&self, context: &str, plaintext: &[u8]) -> Result<Sealed, KeyringError> {
+        let cipher = ChaCha20Poly1305::new(&self.subkey(context)?.into());
+        let nonce = rand::random::<[u8; 12]>();
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| KeyringError::Crypto)?;
+
+        Ok(Sealed {
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        })
+    }
+
+    /// Decrypt data sealed with the subkey for the given context
+    pub fn open(&self, context: &str, sealed: &Sealed) -> Result<Vec<u8>, KeyringError> {
+        let cipher = ChaCha20Poly1305::new(&self.subkey(context)?.into());
+        let nonce = BASE64.decode(&sealed.nonce)?;
+        if nonce.len() != 12 {
+            return Err(KeyringError::Crypto);
+        }
+        let ciphertext = BASE64.decode(&sealed.ciphertext)?;
+        cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| KeyringError::Crypto)
+    }
+}
+
+/// Map any debuggable error into a [KeyringError::Wallet]
+fn wallet_error(e: impl std::fmt::Debug) -> KeyringError {
+    KeyringError::Wallet(format!("{:?}", e))
+}
+
+/// Bytes into a WIT `list<u8>` value
+fn bytes_value(bytes: &[u8]) -> Result<Value, KeyringError> {
+    Ok(Value::List(
+        List::new(
+            ListType::new(ValueType::U8),
+            bytes.iter().map(|b| Value::U8(*b)).collect::<Vec<_>>(),
+        )
+        .map_err(wallet_error)?,
+    ))
+}
+
+/// Unwrap the bytes from a WIT `result<list<u8>, mk-error>` value
+fn ok_bytes(value: Option<Value>) -> Result<Vec<u8>, KeyringError> {
+    let Some(Value::Result(result)) = value else {
+        return Err(KeyringError::Wallet(format!(
+            "Expected result, found {:?}",
+            value
+        )));
+    };
+
+    match &*result {
+        Ok(Some(Value::List(list))) => Ok(list
+            .iter()
+            .filter_map(|v| match v {
+                Value::U8(u) => Some(u),
+                _ => None,
+            })
+            .collect()),
+        other => Err(KeyringError::Wallet(format!("{:?}", other))),
+    }
+}
+
+/// Whether the wallet key path is reserved for the host, such as the keyring's.
+///
+/// Plugins share the wallet, so they're refused these keys, or they could derive the
+/// keyring and open every other plugin's state.
+pub(crate) fn is_host_key(path: &str) -> bool {
+    path.split('/')
+        .map(str::trim)
+        .find(|segment| !segment.is_empty())
+        .is_some_and(|root| root.eq_ignore_ascii_case(HOST_KEY_ROOT))
+}
+
+/// Whether the message is reserved for the host to sign, such as the keyring's
+pub(crate) fn is_host_message(data: &[u8]) -> bool {
+    data.starts_with(HOST_MESSAGE_PREFIX)
+}
+
+/// Whether the plugin opted out of encryption at rest
+pub(crate) fn is_public(storage: &dyn Storage, name: &str) -> bool {
+    match public_plugins(storage) {
+        Ok(public) => public.contains(name),
+        Err(e) => {
+            tracing::error!("Failed to read {}: {:?}", PUBLIC_KEY, e);
+            false
+        }
+    }
+}
+
+/// Opt the plugin in or out of encryption at rest
+pub(crate) fn set_public(
+    storage: &dyn Storage,
+    name: &str,
+    public: bool,
+) -> Result<(), StorageError> {
+    let mut plugins = public_plugins(storage)?;
+    match public {
+        true => plugins.insert(name.to_string()),
+        false => plugins.remove(name),
+    };
+    let json = serde_json::to_string(&plugins).map_err(|e| StorageError::Backend(e.to_string()))?;
+    storage.set_string(PUBLIC_KEY, json)
+}
+
+/// Names of the plugins which opted out of encryption at rest
+fn public_plugins(storage: &dyn Storage) -> Result<BTreeSet<String>, StorageError> {
+    match storage.get_string(PUBLIC_KEY)? {
+        Some(json) => serde_json::from_str(&json).map_err(|e| StorageError::Backend(e.to_string())),
+        None => Ok(BTreeSet::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::storage::MemoryStore;
+
+    #[test]
+    fn test_seal_open() {
+        let keyring = Keyring::default();
+        assert!(matches!(
+            keyring.seal("state", b"secret"),
+            Err(KeyringError::Locked)
+        ));
+
+        keyring.unlock(b"signature bytes");
+        let sealed = keyring.seal("state", b"secret").unwrap();
+        assert_eq!(keyring.open("state", &sealed).unwrap(), b"secret");
+
+        // subkeys are bound to their context
+        assert!(keyring.open("vault", &sealed).is_err());
+
+        keyring.lock();
+        assert!(matches!(
+            keyring.open("state", &sealed),
+            Err(KeyringError::Locked)
+        ));
+    }
+
+    #[test]
+    fn test_public_opt_out_keeps_plugin_heads() {
+        let storage = MemoryStore::default();
+        storage
+            .set_string("x.public", "bafkhead".to_string())
+            .unwrap();
+
+        set_public(&storage, "x", true).unwrap();
+        assert!(is_public(&storage, "x"));
+        assert!(!is_public(&storage, "x.public"));
+        // a plugin named like the opt-out keeps its state
+        assert_eq!(
+            storage.get_string("x.public").unwrap().as_deref(),
+            Some("bafkhead")
+        );
+
+        set_public(&storage, "x", false).unwrap();
+        assert!(!is_public(&storage, "x"));
+    }
+}
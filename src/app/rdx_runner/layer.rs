@@ -17,7 +17,7 @@ use rdx::layer::{
     Component, Engine, Error, Func, FuncType, Inner, Instance, Linker, List, ListType, Pollable,
     RecordType, Resource, ResourceTable, ResourceType, Store, SystemTime, Value, ValueType,
 };
-use rdx::wasm_component_layer::{ResultType, VariantCase, VariantType};
+use rdx::wasm_component_layer::{Record, ResultType, Variant, VariantCase, VariantType};
 use rdx::{layer::*, wasm_component_layer::ResultValue};
 
 #[cfg(target_arch = "wasm32")]
@@ -33,7 +33,11 @@ use std::ops::DerefMut;
 //use crate::app::platform::peerpiper::PeerPiper;
 
 use crate::app::platform;
+use crate::app::platform::blocks::Pins;
+use crate::app::platform::offline::offline_queue;
 
+use super::keyring;
+use super::vault::{SecretHandle, Vault};
 use super::PeerPiperWired;
 
 /// Use wasm_component_layer to intanitate a plugin and some state data
@@ -48,12 +52,15 @@ pub struct LayerPlugin<T: Inner + Send + Sync> {
 impl<T: Inner + Clone + Send + Sync + 'static> LayerPlugin<T> {
     /// Creates a new with the given wallet layer as a dependency
     pub fn new(
+        name: &str,
         bytes: &[u8],
         data: T,
+        vault: Vault,
         wallet_layer: Option<Arc<Mutex<dyn Instantiator<T>>>>,
         commander: Option<PeerPiperWired>,
     ) -> Self {
-        let (instance, store) = instantiate_instance(bytes, data, wallet_layer, commander);
+        let (instance, store) =
+            instantiate_instance(name, bytes, data, vault, wallet_layer, commander);
 
         Self {
             #[cfg(target_arch = "wasm32")]
@@ -132,13 +139,18 @@ impl<T: Inner + Send + Sync + 'static> Instantiator<T> for LayerPlugin<T> {
 }
 
 pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
+    name: &str,
     bytes: &[u8],
     data: T,
+    vault: Vault,
     wallet_layer: Option<Arc<Mutex<dyn Instantiator<T>>>>,
     peerpiper: Option<PeerPiperWired>,
 ) -> (Instance, Store<T, runtime_layer::Engine>) {
     let table = Arc::new(Mutex::new(ResourceTable::new()));
 
+    // blocks this plugin puts into the blockstore are pinned under this reason
+    let pin_reason = format!("plugin:{}", name);
+
     // Create a new engine for instantiating a component.
     let engine = Engine::new(runtime_layer::Engine::default());
 
@@ -582,6 +594,61 @@ pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
         )
         .unwrap();
 
+    // secrets from the vault, under handles of this plugin's own
+    let get_result = ResultType::new(Some(ValueType::String), Some(ValueType::String));
+    let get_result_clone = get_result.clone();
+    let plugin_name = name.to_string();
+    let vault_clone = vault.clone();
+    host_interface
+        .define_func(
+            "get-secret",
+            Func::new(
+                &mut store,
+                FuncType::new([ValueType::String], [ValueType::Result(get_result)]),
+                move |_store, params, results| {
+                    let Value::String(handle) = &params[0] else {
+                        bail!("Incorrect input type, found {:?}", params[0]);
+                    };
+                    // an unset secret is empty, as setting an empty one removes it
+                    let secret = SecretHandle::plugin(&plugin_name, handle)
+                        .and_then(|handle| vault_clone.get(&handle))
+                        .map(|secret| Some(Value::String(secret.unwrap_or_default().into())))
+                        .map_err(|e| Some(Value::String(e.to_string().into())));
+                    results[0] = Value::Result(ResultValue::new(get_result_clone.clone(), secret)?);
+                    Ok(())
+                },
+            ),
+        )
+        .unwrap();
+
+    let set_result = ResultType::new(None, Some(ValueType::String));
+    let set_result_clone = set_result.clone();
+    let plugin_name = name.to_string();
+    host_interface
+        .define_func(
+            "set-secret",
+            Func::new(
+                &mut store,
+                FuncType::new(
+                    [ValueType::String, ValueType::String],
+                    [ValueType::Result(set_result)],
+                ),
+                move |_store, params, results| {
+                    let (Value::String(handle), Value::String(secret)) = (&params[0], &params[1])
+                    else {
+                        bail!("Incorrect input types, found {:?}", params);
+                    };
+                    let done = SecretHandle::plugin(&plugin_name, handle)
+                        .and_then(|handle| vault.set(&handle, secret))
+                        .map(|()| None)
+                        .map_err(|e| Some(Value::String(e.to_string().into())));
+                    results[0] = Value::Result(ResultValue::new(set_result_clone.clone(), done)?);
+                    Ok(())
+                },
+            ),
+        )
+        .unwrap();
+
     if let Some(wallet_layer) = wallet_layer {
         // add get-mk and prove as host functions.
         // These will be bound to the exports of the wallet plugin.
@@ -601,20 +668,21 @@ pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
 
         // return type is:  result<list<u8>, variant<plog: string, wallet-uninitialized, multikey-error: string, config: string>>
         let ok = list_data.clone();
-        let err = ValueType::Variant(
-            VariantType::new(
-                None,
-                vec![
-                    VariantCase::new("invalid-codec", Some(ValueType::String)),
-                    VariantCase::new("wallet-uninitialized", None),
-                    VariantCase::new("multikey-error", Some(ValueType::String)),
-                    VariantCase::new("key-not-found", Some(ValueType::String)),
-                ],
-            )
-            .unwrap(),
-        );
+        let err_variant = VariantType::new(
+            None,
+            vec![
+                VariantCase::new("invalid-codec", Some(ValueType::String)),
+                VariantCase::new("wallet-uninitialized", None),
+                VariantCase::new("multikey-error", Some(ValueType::String)),
+                VariantCase::new("key-not-found", Some(ValueType::String)),
+            ],
+        )
+        .unwrap();
+        let err = ValueType::Variant(err_variant.clone());
 
         let return_type = ResultType::new(Some(ok.clone()), Some(err.clone()));
+        let get_mk_result = return_type.clone();
+        let get_mk_err = err_variant.clone();
 
         //
         host_interface
@@ -631,6 +699,11 @@ pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
                         let Value::Record(key_args_record) = &params[0] else {
                             panic!("Incorrect input type, found {:?}", params[0]);
                         };
+                        if let Some(reason) = refused_wallet_call(key_args_record) {
+                            results[0] =
+                                wallet_error(&get_mk_result, &get_mk_err, "key-not-found", reason)?;
+                            return Ok(());
+                        }
                         let mk = wallet_clone
                             .lock()
                             .unwrap()
@@ -654,6 +727,7 @@ pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
         .unwrap();
 
         let wallet_clone = wallet_layer.clone();
+        let prove_result = ResultType::new(Some(ok.clone()), Some(err.clone()));
 
         host_interface
             .define_func(
@@ -668,6 +742,15 @@ pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
                         let Value::Record(prove_args_record) = &params[0] else {
                             panic!("Incorrect input type, found {:?}", params[0]);
                         };
+                        if let Some(reason) = refused_wallet_call(prove_args_record) {
+                            results[0] = wallet_error(
+                                &prove_result,
+                                &err_variant,
+                                "multikey-error",
+                                reason,
+                            )?;
+                            return Ok(());
+                        }
                         let proof = wallet_clone
                             .lock()
                             .unwrap()
@@ -1140,13 +1223,25 @@ pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
                         // We need to clone them, and then pass the clone to the async block, or,
                         // we can use a callback channel to send the response to the main thread.
 
-                        platform::spawn(async move {
-                            #[cfg(not(target_arch = "wasm32"))]
-                            let commander = commander.lock().await;
+                        let pin_reason = pin_reason.clone();
 
+                        platform::spawn(async move {
                             tracing::info!("Ordering command: {:?}", command);
 
-                            match commander.order(command.clone()).await {
+                            // network commands wait for the node to connect, if it hasn't yet
+                            let ordered = match offline_queue().enqueue(&command) {
+                                Some(queued) => queued.await.map_err(anyhow::Error::from),
+                                None => {
+                                    #[cfg(not(target_arch = "wasm32"))]
+                                    let commander = commander.lock().await;
+                                    commander
+                                        .order(command.clone())
+                                        .await
+                                        .map_err(|e| anyhow::anyhow!("{:?}", e))
+                                }
+                            };
+
+                            match ordered {
                                 Ok(return_values) => {
                                     tracing::info!(
                                         "Command {:?} order returned: {:?}",
@@ -1154,6 +1249,8 @@ pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
                                         return_values
                                     );
 
+                                    pin_put_block(&pin_reason, &command, &return_values);
+
                                     // same for key
                                     let Some(key) = key else {
                                         tracing::error!(
@@ -1411,6 +1508,69 @@ pub fn instantiate_instance<T: Inner + Clone + Send + Sync + 'static>(
     (linker.instantiate(&mut store, &component).unwrap(), store)
 }
 
+/// Pin a block the plugin put into the blockstore, such as a plog entry,
+/// so garbage collection keeps it even though no state links to it.
+/// Why the plugin's `get-mk` or `prove` call is refused, if it asks for a key or a signature
+/// reserved for the host, such as the keyring's
+fn refused_wallet_call(args: &Record) -> Option<String> {
+    if let Some(Value::String(key)) = args.field("key") {
+        if keyring::is_host_key(&key) {
+            return Some(format!("The key {} is reserved", key));
+        }
+    }
+    if let Some(Value::List(data)) = args.field("data") {
+        let data = data
+            .iter()
+            .filter_map(|v| match v {
+                Value::U8(u) => Some(u),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if keyring::is_host_message(&data) {
+            return Some("The message is reserved".to_string());
+        }
+    }
+    None
+}
+
+/// The wallet's error result, with the given case of its error variant
+fn wallet_error(
+    ty: &ResultType,
+    err: &VariantType,
+    case: &str,
+    reason: String,
+) -> anyhow::Result<Value> {
+    let discriminant = err
+        .cases()
+        .iter()
+        .position(|c| c.name() == case)
+        .ok_or_else(|| anyhow::anyhow!("No {} wallet error", case))?;
+    let error = Variant::new(
+        err.clone(),
+        discriminant,
+        Some(Value::String(reason.into())),
+    )?;
+    Ok(Value::Result(ResultValue::new(
+        ty.clone(),
+        Err(Some(Value::Variant(error))),
+    )?))
+}
+
+fn pin_put_block(reason: &str, command: &AllCommands, return_values: &ReturnValues) {
+    let cid = match (command, return_values) {
+        (AllCommands::System(SystemCommand::PutKeyed { key, .. }), _) => {
+            Cid::try_from(key.as_slice()).ok()
+        }
+        (AllCommands::System(SystemCommand::Put { .. }), ReturnValues::ID(cid)) => Some(*cid),
+        _ => None,
+    };
+    if let Some(cid) = cid {
+        if let Err(e) = Pins::default().pin(&cid.to_string(), reason) {
+            tracing::error!("Failed to pin {}: {:?}", cid, e);
+        }
+    }
+}
+
 struct Truncated<T>((T, usize));
 
 impl<T: std::fmt::Debug> std::fmt::Debug for Truncated<T> {
@@ -1427,6 +1587,50 @@ impl<T: std::fmt::Debug> std::fmt::Debug for Truncated<T> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    /// Wallet call arguments with the given key path and message
+    fn wallet_args(key: &str, data: &[u8]) -> Record {
+        let bytes = List::new(
+            ListType::new(ValueType::U8),
+            data.iter().map(|b| Value::U8(*b)).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        Record::new(
+            RecordType::new(
+                None,
+                vec![
+                    ("key", ValueType::String),
+                    ("data", ValueType::List(ListType::new(ValueType::U8))),
+                ],
+            )
+            .unwrap(),
+            vec![
+                ("key", Value::String(key.into())),
+                ("data", Value::List(bytes)),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_plugins_refused_keyring_derivation() {
+        // the keyring's own key, however the path is written
+        for key in [
+            "/multinode/keyring",
+            "multinode/keyring",
+            "//Multinode/other/",
+        ] {
+            assert!(refused_wallet_call(&wallet_args(key, b"data")).is_some());
+        }
+        // or its message, signed with any key
+        assert!(refused_wallet_call(&wallet_args("/app/key", b"multinode keyring v1")).is_some());
+
+        assert_eq!(
+            refused_wallet_call(&wallet_args("/app/multinode", b"data")),
+            None
+        );
+    }
 
     // test to verify that a HashMap turns into a rhai Dynamic map
     #[test]
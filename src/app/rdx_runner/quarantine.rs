@@ -0,0 +1,205 @@
+//! Plugin states whose saved block can't be loaded.
+//!
+//! Starting over with an empty scope would lose the saved state on the next save, so
+//! instead the block is quarantined: pinned so garbage collection keeps it, and the
+//! plugin's saves are refused until the user recovers the state. Recovery edits the raw
+//! block, retries it, restores an earlier snapshot or starts empty.
+use peerpiper::core::events::{AllCommands, SystemCommand};
+use peerpiper::core::ReturnValues;
+
+use super::history::{self, Snapshot, StateBlock};
+use super::{LoadStatus, State};
+use crate::app::platform::blocks::Pins;
+
+/// A plugin's saved state block which failed to load
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("The saved state {cid} can't be loaded: {error}")]
+pub struct Corrupted {
+    /// CID of the quarantined block
+    pub cid: String,
+    /// Why it failed to load
+    pub error: String,
+}
+
+impl Corrupted {
+    /// Quarantine the plugin's block, pinning it so garbage collection keeps it
+    pub fn quarantine(name: &str, cid: &str, error: &anyhow::Error) -> Self {
+        tracing::warn!("Quarantining state {} of {}: {:?}", cid, name, error);
+        if let Err(e) = Pins::default().pin(cid, &pin_reason(name)) {
+            tracing::error!("Failed to pin quarantined state {}: {:?}", cid, e);
+        }
+        Self {
+            cid: cid.to_string(),
+            error: format!("{:#}", error),
+        }
+    }
+
+    /// Release the block from quarantine, once the state is recovered
+    fn release(&self, name: &str) {
+        if let Err(e) = Pins::default().unpin(&self.cid, Some(&pin_reason(name))) {
+            tracing::error!("Failed to unpin quarantined state {}: {:?}", self.cid, e);
+        }
+    }
+}
+
+/// A plugin's saved state which failed to load for another reason, such as a read error.
+/// Saving is refused until loading it again succeeds, so the empty scope doesn't replace it.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("The saved state of {name} failed to load, load it again before saving: {error}")]
+pub struct LoadFailed {
+    pub name: String,
+    pub error: String,
+}
+
+/// A way to recover a quarantined state
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recovery {
+    /// Load the quarantined block again
+    Retry,
+    /// Save the edited block text as the state
+    SaveEdit(String),
+    /// Restore the snapshot with the given CID
+    Restore(String),
+    /// Start empty
+    Discard,
+}
+
+/// Pin reason for a plugin's quarantined block
+fn pin_reason(name: &str) -> String {
+    format!("quarantine:{}", name)
+}
+
+/// Whether the error is a [Corrupted] or [LoadFailed] state, so saving is refused on purpose
+pub fn is_corrupted(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Corrupted>().is_some() || e.downcast_ref::<LoadFailed>().is_some()
+}
+
+/// The block as editable text: pretty printed if it's JSON, otherwise as lossy UTF-8
+pub fn raw_text(bytes: &[u8]) -> String {
+    serde_json::from_slice::<serde_json::Value>(bytes)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or_else(|_| String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// The state saved before the corrupted block, if its `prev` link can still be read
+pub fn prev_of(bytes: &[u8]) -> Option<String> {
+    serde_json::from_slice::<serde_json::Value>(bytes)
+        .ok()?
+        .get("prev")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// The plugin's quarantined block, if any
+fn corrupted(state: &State) -> anyhow::Result<Corrupted> {
+    match state.load_status() {
+        LoadStatus::Corrupted(corrupted) => Ok(corrupted),
+        _ => Err(anyhow::anyhow!(
+            "The state of {} isn't quarantined",
+            state.name()
+        )),
+    }
+}
+
+/// Mark the state recovered and release its block
+fn recovered(state: &State, corrupted: &Corrupted) {
+    corrupted.release(state.name());
+    state.set_load_status(LoadStatus::Ready);
+    tracing::info!("Recovered state of {}", state.name());
+}
+
+/// The snapshots saved before the corrupted block, newest first
+pub async fn snapshots(state: &State, bytes: &[u8]) -> anyhow::Result<Vec<Snapshot>> {
+    let Some(prev) = prev_of(bytes) else {
+        return Ok(vec![]);
+    };
+    history::walk(prev, history::MAX_SNAPSHOTS, |cid| async move {
+        state.get_block(&cid).await
+    })
+    .await
+}
+
+/// Load the quarantined block again, such as after the wallet is unlocked
+async fn retry(state: &State) -> anyhow::Result<()> {
+    let corrupted = corrupted(state)?;
+    state.init().await?;
+    recovered(state, &corrupted);
+    Ok(())
+}
+
+/// Save the edited block and make it the plugin's state, if it loads
+async fn save_edited(state: &State, text: &str) -> anyhow::Result<()> {
+    let corrupted = corrupted(state)?;
+    let bytes = text.as_bytes().to_vec();
+    StateBlock::decode(&bytes)?.to_scope()?;
+
+    let ReturnValues::ID(cid) = state
+        .order(AllCommands::System(SystemCommand::Put { bytes }))
+        .await?
+    else {
+        return Err(anyhow::anyhow!("Failed to put the edited state"));
+    };
+    state.checkout(&cid.to_string()).await?;
+    recovered(state, &corrupted);
+    Ok(())
+}
+
+/// Make an earlier snapshot the plugin's state
+async fn restore(state: &State, cid: &str) -> anyhow::Result<()> {
+    let corrupted = corrupted(state)?;
+    state.checkout(cid).await?;
+    recovered(state, &corrupted);
+    Ok(())
+}
+
+/// Keep the empty scope, the next save replaces the corrupted state
+fn discard(state: &State) -> anyhow::Result<()> {
+    let corrupted = corrupted(state)?;
+    recovered(state, &corrupted);
+    Ok(())
+}
+
+/// The state was replaced as a whole, such as by an installed bundle. Releases its
+/// quarantined block and lets saves through again.
+pub fn replaced(state: &State) {
+    match state.load_status() {
+        LoadStatus::Corrupted(corrupted) => recovered(state, &corrupted),
+        LoadStatus::Failed(_) => state.set_load_status(LoadStatus::Ready),
+        _ => {}
+    }
+}
+
+/// Recover the state the given way
+pub async fn recover(state: &State, recovery: Recovery) -> anyhow::Result<()> {
+    match recovery {
+        Recovery::Retry => retry(state).await,
+        Recovery::SaveEdit(text) => save_edited(state, &text).await,
+        Recovery::Restore(cid) => restore(state, &cid).await,
+        Recovery::Discard => discard(state),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corrupted_block_keeps_its_prev() {
+        // valid JSON whose scope isn't a serialized rhai Scope
+        let bytes = br#"{"prev":"bafkprev","timestamp":1,"scope":7}"#;
+        assert!(StateBlock::decode(bytes).unwrap().to_scope().is_err());
+        assert_eq!(prev_of(bytes).as_deref(), Some("bafkprev"));
+        assert!(raw_text(bytes).contains("\n  \"prev\": \"bafkprev\""));
+
+        // truncated JSON is shown as is, with no way back
+        let bytes = br#"{"prev":"bafkprev","sco"#;
+        assert_eq!(prev_of(bytes), None);
+        assert_eq!(raw_text(bytes), String::from_utf8_lossy(bytes));
+
+        let e = anyhow::Error::from(Corrupted {
+            cid: "bafkhead".to_string(),
+            error: "invalid".to_string(),
+        });
+        assert!(is_corrupted(&e));
+    }
+}
@@ -0,0 +1,549 @@
+//! Share plugin state with peers by CID.
+//!
+//! Sharing puts a plaintext snapshot of the plugin's current scope into the blockstore and
+//! announces its CID to a peer or a gossipsub topic. Receivers fetch the blocks from the
+//! sender on request, so announcements stay small enough for gossipsub. Received shares wait
+//! in the [ShareInbox] until the user views or merges them. Shared blocks stay pinned, and
+//! served, until the share is withdrawn.
+//!
+//! Only public states are shared, never the wallet's, and never the variables a plugin keeps
+//! in plaintext, such as an encrypted seed.
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use peerpiper::core::events::{AllCommands, SystemCommand};
+use peerpiper::core::{Cid, Commander, ReturnValues};
+use rdx::layer::{rhai::Scope, Inner as _};
+use web_time::SystemTime;
+
+use super::bundle::{self, BundleBlock};
+use super::history::{self, StateBlock};
+use super::State;
+use crate::app::platform::blocks::Pins;
+use crate::app::platform::piper::ACK;
+use crate::app::platform::platform::Blockstore;
+
+/// Topic every node subscribes to for shared plugin states
+pub const SHARE_TOPIC: &str = "multinode/state-share";
+
+/// Most block bytes sent in one response, well under the request-response size limit
+const MAX_RESPONSE_BYTES: usize = 512 * 1024;
+
+/// Most requests made fetching one share, so a sender can't keep us fetching forever
+const MAX_FETCHES: usize = 64;
+
+/// Most shares kept in the inbox, the oldest are dropped first
+const MAX_SHARES: usize = 64;
+
+/// Most shares kept in the inbox from one sender, so one peer can't crowd out the rest
+const MAX_SHARES_PER_PEER: usize = 8;
+
+/// Pin reason prefix for the blocks of a share, followed by the CID of the shared state
+const SHARE_PIN_PREFIX: &str = "share:";
+
+/// Where to send a shared state
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShareTarget {
+    /// Publish to everyone subscribed to the gossipsub topic
+    Topic(String),
+    /// Send directly to the peer with this PeerId
+    Peer(String),
+}
+
+/// Announcement of a shared state. The blocks are fetched from the sender.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StateShare {
+    /// Name of the plugin whose state is shared
+    pub plugin: String,
+    /// CID of the shared state
+    pub cid: String,
+    /// CIDs of the state and the blocks it links to, which the sender serves
+    pub blocks: Vec<String>,
+    /// PeerId of the sender, to fetch the blocks from when it's a direct request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+/// Messages of the share protocol
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ShareMessage {
+    /// A state shared with us
+    StateShare(StateShare),
+    /// Ask the sender of a share for its blocks
+    BlockRequest { cids: Vec<String> },
+    /// Requested blocks, as many as fit in one response
+    Blocks { blocks: Vec<BundleBlock> },
+}
+
+impl ShareMessage {
+    /// Decode a message from bytes, None if the bytes are something else
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        serde_json::from_slice(bytes).ok()
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+}
+
+impl StateShare {
+    /// Decode an announcement from message bytes, None if the message is something else
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match ShareMessage::from_bytes(bytes)? {
+            ShareMessage::StateShare(share) => Some(share),
+            _ => None,
+        }
+    }
+}
+
+/// A share received from the network
+#[derive(Debug, Clone)]
+pub struct ReceivedShare {
+    /// PeerId of the sender, when known
+    pub from: Option<String>,
+    /// Whether the network vouched for the sender. Direct requests name their own sender.
+    pub verified: bool,
+    /// Unix timestamp (seconds) of when the share arrived
+    pub received: i64,
+    /// The share itself
+    pub share: StateShare,
+}
+
+/// Shares received from the network, newest last. Cheap to clone, clones share the inbox.
+#[derive(Debug, Clone, Default)]
+pub struct ShareInbox(Arc<Mutex<Vec<ReceivedShare>>>);
+
+impl ShareInbox {
+    /// Add a share from the given peer, replacing an older share of the same state.
+    ///
+    /// Past [MAX_SHARES_PER_PEER] from the peer or [MAX_SHARES] in all, the oldest are dropped.
+    pub fn push(&self, from: Option<String>, verified: bool, share: StateShare) {
+        let received = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+
+        let mut inbox = self.0.lock().unwrap();
+        inbox.retain(|r| r.from != from || r.share.cid != share.cid);
+        let from_peer = inbox.iter().filter(|r| r.from == from).count();
+        if from_peer >= MAX_SHARES_PER_PEER {
+            if let Some(oldest) = inbox.iter().position(|r| r.from == from) {
+                inbox.remove(oldest);
+            }
+        }
+        if inbox.len() >= MAX_SHARES {
+            inbox.remove(0);
+        }
+        inbox.push(ReceivedShare {
+            from,
+            verified,
+            received,
+            share,
+        });
+    }
+
+    /// Copy of the received shares
+    pub fn list(&self) -> Vec<ReceivedShare> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Remove the share at the given index
+    pub fn remove(&self, index: usize) {
+        let mut inbox = self.0.lock().unwrap();
+        if index < inbox.len() {
+            inbox.remove(index);
+        }
+    }
+
+    /// Number of received shares
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// Whether no shares have been received
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// What we share: the blocks peers may fetch, and our PeerId to fetch them from.
+///
+/// The blocks of each share are pinned under `share:<cid>`, so they survive garbage
+/// collection and restarts until the share is withdrawn. Cheap to clone, clones share the
+/// outbox.
+#[derive(Debug, Clone, Default)]
+pub struct ShareOutbox {
+    /// Pins of the shared blocks
+    pins: Pins,
+    /// Our PeerId, once the node reports an address with it
+    local_peer: Arc<Mutex<Option<String>>>,
+}
+
+impl ShareOutbox {
+    #[cfg(test)]
+    fn new(pins: Pins) -> Self {
+        Self {
+            pins,
+            local_peer: Default::default(),
+        }
+    }
+
+    /// Whether peers may fetch the block
+    pub fn serves(&self, cid: &str) -> bool {
+        self.pins.list().is_ok_and(|pins| {
+            pins.get(cid)
+                .is_some_and(|reasons| reasons.iter().any(|r| r.starts_with(SHARE_PIN_PREFIX)))
+        })
+    }
+
+    /// CIDs of the states we share, until withdrawn
+    pub fn shared(&self) -> BTreeSet<String> {
+        self.pins
+            .list()
+            .unwrap_or_default()
+            .into_values()
+            .flatten()
+            .filter_map(|r| r.strip_prefix(SHARE_PIN_PREFIX).map(str::to_string))
+            .collect()
+    }
+
+    /// Serve the blocks of the shared state, pinning them until withdrawn
+    fn serve(&self, cid: &str, blocks: &[String]) -> anyhow::Result<()> {
+        let reason = share_pin_reason(cid);
+        for block in blocks {
+            self.pins.pin(block, &reason)?;
+        }
+        Ok(())
+    }
+
+    /// Stop serving the shared state, unpinning its blocks for garbage collection
+    pub fn withdraw(&self, cid: &str) -> anyhow::Result<()> {
+        let reason = share_pin_reason(cid);
+        let pinned = self.pins.list()?;
+        for (block, reasons) in pinned {
+            if reasons.contains(&reason) {
+                self.pins.unpin(&block, Some(&reason))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set our PeerId, put in announcements so receivers know where to fetch from
+    pub fn set_local_peer(&self, peer: String) {
+        self.local_peer.lock().unwrap().replace(peer);
+    }
+
+    fn local_peer(&self) -> Option<String> {
+        self.local_peer.lock().unwrap().clone()
+    }
+}
+
+fn share_pin_reason(cid: &str) -> String {
+    format!("{}{}", SHARE_PIN_PREFIX, cid)
+}
+
+/// Shares received and sent. Cheap to clone, clones share both.
+#[derive(Debug, Clone, Default)]
+pub struct Shares {
+    pub inbox: ShareInbox,
+    pub outbox: ShareOutbox,
+}
+
+impl Shares {
+    /// Handle a share protocol request from a peer, returning the response.
+    ///
+    /// The request names its own sender, so direct shares are unverified.
+    pub async fn answer(&self, request: &[u8], commander: &Commander<Blockstore>) -> Vec<u8> {
+        match ShareMessage::from_bytes(request) {
+            Some(ShareMessage::StateShare(share)) => {
+                self.inbox.push(share.from.clone(), false, share);
+                ACK.to_vec()
+            }
+            Some(ShareMessage::BlockRequest { cids }) => {
+                let blocks = self.blocks(&cids, commander).await;
+                ShareMessage::Blocks { blocks }
+                    .to_bytes()
+                    .unwrap_or_else(|_| ACK.to_vec())
+            }
+            _ => ACK.to_vec(),
+        }
+    }
+
+    /// The requested blocks we shared, up to [MAX_RESPONSE_BYTES]
+    async fn blocks(&self, cids: &[String], commander: &Commander<Blockstore>) -> Vec<BundleBlock> {
+        let mut blocks = Vec::new();
+        let mut size = 0;
+        for cid in cids {
+            if !self.outbox.serves(cid) {
+                tracing::debug!("Not serving {}, it wasn't shared", cid);
+                continue;
+            }
+            let Ok(key) = Cid::try_from(cid.as_str()) else {
+                continue;
+            };
+            let command = AllCommands::System(SystemCommand::Get { key: key.into() });
+            let Ok(ReturnValues::Data(bytes)) = commander.order(command).await else {
+                tracing::warn!("Shared block {} is missing", cid);
+                continue;
+            };
+            // always at least one, the receiver asks again for the rest
+            if !blocks.is_empty() && size + bytes.len() > MAX_RESPONSE_BYTES {
+                break;
+            }
+            size += bytes.len();
+            blocks.push(BundleBlock {
+                cid: cid.clone(),
+                data: BASE64.encode(&bytes),
+            });
+        }
+        blocks
+    }
+}
+
+/// Why the state can't be shared, None if it can.
+///
+/// The wallet and states encrypted at rest are never shared.
+pub fn refused(state: &State) -> Option<String> {
+    if state.is_wallet() {
+        return Some("The wallet's state is never shared".to_string());
+    }
+    if !state.is_public() {
+        return Some(format!(
+            "{} is encrypted at rest, make it public to share it",
+            state.name()
+        ));
+    }
+    if state.is_locked() {
+        return Some(format!("Unlock the wallet before sharing {}", state.name()));
+    }
+    None
+}
+
+/// The variables of the state which may be shared, leaving out the ones the plugin
+/// keeps in plaintext
+pub fn shareable_scope(state: &State) -> anyhow::Result<Scope<'static>> {
+    if let Some(reason) = refused(state) {
+        return Err(anyhow::anyhow!(reason))?;
+    }
+
+    let scope = state.clone().into_scope();
+    let (_plaintext, shareable) = history::split_scope(&scope, state.plaintext());
+    Ok(shareable)
+}
+
+/// Snapshot the plugin's shareable scope and announce it to the target.
+///
+/// Returns the CID of the shared state.
+pub async fn share(
+    state: &State,
+    target: ShareTarget,
+    outbox: &ShareOutbox,
+) -> anyhow::Result<String> {
+    let scope = serde_json::to_value(shareable_scope(state)?)?;
+    let bytes = serde_json::to_vec(&StateBlock::new(None, scope.clone()))?;
+
+    let ReturnValues::ID(cid) = state
+        .order(AllCommands::System(SystemCommand::Put { bytes }))
+        .await?
+    else {
+        return Err(anyhow::anyhow!("Failed to order command: Put"))?;
+    };
+    let cid = cid.to_string();
+
+    // the linked blocks we have, peers can't fetch the others from us anyway
+    let mut links = Vec::new();
+    bundle::collect_links(&scope, &mut links);
+    let mut linked = Vec::new();
+    bundle::add_linked_blocks(state, links, &mut linked).await;
+
+    let mut blocks = vec![cid.clone()];
+    blocks.extend(linked.into_iter().map(|block| block.cid));
+    outbox.serve(&cid, &blocks)?;
+
+    let from = match &target {
+        ShareTarget::Topic(_) => None,
+        ShareTarget::Peer(_) => match outbox.local_peer() {
+            Some(from) => Some(from),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Our PeerId isn't known yet, so the peer couldn't fetch the state"
+                ))?
+            }
+        },
+    };
+    let data = ShareMessage::StateShare(StateShare {
+        plugin: state.name().to_string(),
+        cid: cid.clone(),
+        blocks,
+        from,
+    })
+    .to_bytes()?;
+    let command = match target {
+        ShareTarget::Topic(topic) => AllCommands::Publish { topic, data },
+        ShareTarget::Peer(peer_id) => AllCommands::PeerRequest {
+            request: data,
+            peer_id,
+        },
+    };
+    state.order(command).await?;
+
+    Ok(cid)
+}
+
+/// Fetch the shared blocks from the sender into the local blockstore, verifying each
+/// against its CID, and decode the shared scope.
+pub async fn fetch(state: &State, received: &ReceivedShare) -> anyhow::Result<Scope<'static>> {
+    let Some(peer) = received.from.clone() else {
+        return Err(anyhow::anyhow!(
+            "The sender is unknown, so the state can't be fetched"
+        ))?;
+    };
+    let share = &received.share;
+    if !share.blocks.contains(&share.cid) {
+        return Err(bundle::BundleError::MissingBlock(share.cid.clone()))?;
+    }
+
+    let mut wanted = share.blocks.clone();
+    let mut root = None;
+    for _ in 0..MAX_FETCHES {
+        if wanted.is_empty() {
+            break;
+        }
+        let request = ShareMessage::BlockRequest {
+            cids: wanted.clone(),
+        }
+        .to_bytes()?;
+        let response = state
+            .order(AllCommands::PeerRequest {
+                request,
+                peer_id: peer.clone(),
+            })
+            .await?;
+        let ReturnValues::Data(bytes) = response else {
+            return Err(anyhow::anyhow!("Unexpected response from {}", peer))?;
+        };
+        let Some(ShareMessage::Blocks { blocks }) = ShareMessage::from_bytes(&bytes) else {
+            return Err(anyhow::anyhow!("Unexpected response from {}", peer))?;
+        };
+
+        let before = wanted.len();
+        for block in blocks {
+            if !wanted.contains(&block.cid) {
+                continue;
+            }
+            let cid = Cid::try_from(block.cid.as_str())
+                .map_err(|e| bundle::BundleError::Cid(block.cid.clone(), e))?;
+            let data = BASE64.decode(&block.data)?;
+            bundle::verify_block(&cid, &data)?;
+            if block.cid == share.cid {
+                root = Some(StateBlock::decode(&data)?.to_scope()?);
+            }
+            state.put_block(&cid, data).await?;
+            wanted.retain(|c| c != &block.cid);
+        }
+        if wanted.len() == before {
+            return Err(anyhow::anyhow!(
+                "{} no longer serves {} of the shared blocks",
+                peer,
+                wanted.len()
+            ))?;
+        }
+    }
+    if !wanted.is_empty() {
+        return Err(anyhow::anyhow!("Gave up fetching {} blocks", wanted.len()))?;
+    }
+
+    root.ok_or_else(|| bundle::BundleError::MissingBlock(share.cid.clone()).into())
+}
+
+/// Fetch the shared state and merge its scope into our own copy.
+///
+/// The wallet is never merged into, and the variables our plugin keeps in plaintext are
+/// left out. Returns the CID of our merged state.
+pub async fn merge(state: &State, received: &ReceivedShare) -> anyhow::Result<String> {
+    if state.is_wallet() {
+        return Err(anyhow::anyhow!(
+            "Shared states are never merged into the wallet"
+        ))?;
+    }
+    let scope = fetch(state, received).await?;
+    let (_plaintext, scope) = history::split_scope(&scope, state.plaintext());
+    state.merge(&scope).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::storage::MemoryStore;
+
+    #[test]
+    fn test_inbox_replaces_same_share() {
+        let share = StateShare {
+            plugin: "plugin.wasm".to_string(),
+            cid: "cid".to_string(),
+            blocks: vec!["cid".to_string()],
+            from: None,
+        };
+
+        let bytes = ShareMessage::StateShare(share.clone()).to_bytes().unwrap();
+        assert!(String::from_utf8_lossy(&bytes).contains("\"type\":\"state-share\""));
+        assert_eq!(StateShare::from_bytes(&bytes), Some(share.clone()));
+        assert!(StateShare::from_bytes(b"hello").is_none());
+        let request = ShareMessage::BlockRequest { cids: vec![] }
+            .to_bytes()
+            .unwrap();
+        assert!(StateShare::from_bytes(&request).is_none());
+
+        let inbox = ShareInbox::default();
+        inbox.push(Some("peer".to_string()), true, share.clone());
+        inbox.push(Some("peer".to_string()), true, share.clone());
+        inbox.push(Some("other".to_string()), false, share);
+        assert_eq!(inbox.len(), 2);
+    }
+
+    #[test]
+    fn test_inbox_drops_oldest_shares() {
+        let share = |cid: usize| StateShare {
+            plugin: "plugin.wasm".to_string(),
+            cid: cid.to_string(),
+            blocks: vec![cid.to_string()],
+            from: None,
+        };
+        let inbox = ShareInbox::default();
+
+        for cid in 0..MAX_SHARES_PER_PEER + 2 {
+            inbox.push(Some("flood".to_string()), false, share(cid));
+        }
+        let cids: Vec<_> = inbox.list().into_iter().map(|r| r.share.cid).collect();
+        assert_eq!(cids.len(), MAX_SHARES_PER_PEER);
+        assert_eq!(cids[0], "2");
+
+        for peer in 0..MAX_SHARES {
+            inbox.push(Some(peer.to_string()), true, share(peer));
+        }
+        let shares = inbox.list();
+        assert_eq!(shares.len(), MAX_SHARES);
+        assert!(shares.iter().all(|r| r.from.as_deref() != Some("flood")));
+    }
+
+    #[test]
+    fn test_withdraw_unpins_shared_blocks() {
+        let pins = Pins::new(Arc::new(MemoryStore::default()));
+        let outbox = ShareOutbox::new(pins.clone());
+        pins.pin("link", "plugin:a").unwrap();
+
+        outbox
+            .serve("root", &["root".to_string(), "link".to_string()])
+            .unwrap();
+        assert!(outbox.serves("root") && outbox.serves("link"));
+        assert_eq!(outbox.shared(), BTreeSet::from(["root".to_string()]));
+
+        outbox.withdraw("root").unwrap();
+        assert!(!outbox.serves("root") && !outbox.serves("link"));
+        assert!(outbox.shared().is_empty());
+        assert!(pins.is_pinned("link"));
+        assert!(!pins.is_pinned("root"));
+    }
+}
@@ -1,8 +1,8 @@
 //! Custom State for the RDX Plugins, Implements [rdx::layer::Inner] and custom Serialize/Deserialize
 //! so that the [rdx::layer::rhai::Scope] can be serialized and deserialized.
-use crate::app::platform;
+use crate::app::platform::blocks::{Pins, WASM_MAGIC};
 use crate::app::platform::piper::PeerPiper;
-use crate::app::platform::StringStore;
+use crate::app::platform::storage::{self, Storage};
 use peerpiper::core::events::AllCommands;
 use peerpiper::core::events::SystemCommand;
 use peerpiper::core::Cid;
@@ -14,13 +14,17 @@ use rdx::layer::{
     rhai::{Dynamic, Scope},
     Inner,
 };
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Duration;
 
 use tokio::sync::Mutex as AsyncMutex;
 
-use super::debouncer::Debouncer;
+use super::backup::{self, BackupError};
+use super::history::{self, Snapshot, StateBlock};
+use super::keyring::{self, Keyring, KeyringError};
+use super::quarantine::{self, Corrupted, LoadFailed};
+use super::{state_saves, LoadStatus};
 
 /// Serializable [State] struct that holds the [Scope] and [egui::Context]
 #[derive(Clone)]
@@ -37,9 +41,25 @@ pub struct State {
     /// Handler to PeerPiper SystemCommander
     peerpiper: Arc<AsyncMutex<PeerPiper>>,
     /// String Store map the name of the plugin to the CID of the state
-    cid_map: StringStore,
-    /// Canceller for deboucning saving state
-    cancel_save: Arc<AsyncMutex<Option<tokio::sync::oneshot::Sender<()>>>>,
+    cid_map: Arc<dyn Storage>,
+    /// The serialized scope as of the last save, so unchanged scopes don't add to the history
+    last_saved: Arc<Mutex<Option<serde_json::Value>>>,
+    /// Keyring used to encrypt the scope at rest
+    keyring: Keyring,
+    /// Variables which are never encrypted, even when the rest of the scope is
+    plaintext: Vec<String>,
+    /// Whether this is the wallet's state, which is never shared
+    wallet: bool,
+    /// Set whenever the wallet's state changes, so the keyring is only unlocked then
+    wallet_changed: Option<Arc<AtomicBool>>,
+    /// Whether the saved scope is encrypted and waiting on the wallet to be unlocked
+    locked: Arc<Mutex<bool>>,
+    /// Whether a save was held back while locked, so it's made once unlocked
+    held_back: Arc<Mutex<bool>>,
+    /// Whether this plugin opted out of encryption at rest, read once from the store
+    public: Arc<Mutex<bool>>,
+    /// Whether the saved scope is loaded and the plugin initialized
+    load_status: Arc<Mutex<LoadStatus>>,
 }
 
 impl State {
@@ -47,60 +67,305 @@ impl State {
         name: impl AsRef<str> + Clone,
         ctx: Option<egui::Context>,
         peerpiper: Arc<AsyncMutex<PeerPiper>>,
+        keyring: Keyring,
     ) -> Self {
-        let mut scope = Scope::new();
-
-        let cid_map = StringStore::new();
-
-        // Load the CID of the state from the platform storage
-        // filesystem, localstorage, etc.
-        // and load the state from the CID
-        // and set the scope to the loaded state
-        // if the CID is not found, create a new scope
-        // and set the scope to the new scope
-        if let Some(key) = cid_map.get_string(name.as_ref()) {
-            if let Ok(cid) = Cid::try_from(key.clone()) {
-                let (tx, rx) = std::sync::mpsc::channel();
-
-                let piper_clone = peerpiper.clone();
-
-                platform::spawn(async move {
-                    let command = AllCommands::System(SystemCommand::Get { key: cid.into() });
-                    let pp = { piper_clone.lock().await.commander.order(command).await };
-
-                    let Ok(ReturnValues::Data(bytes)) = pp else {
-                        tracing::warn!("Failed to get state from CID: {}", key);
-                        tx.send(None).unwrap();
-                        return;
-                    };
-
-                    // bytes to string, lossy is fine here
-                    let str = String::from_utf8_lossy(&bytes);
-
-                    let Ok(scope) = serde_json::from_str(&str) else {
-                        tracing::warn!("Failed to decode state scope from CID: {}", key);
-                        tx.send(None).unwrap();
-                        return;
-                    };
-
-                    tracing::info!("*** State loaded from disk.");
-                    tx.send(Some(scope)).unwrap();
-                });
-
-                if let Some(sco) = rx.recv().unwrap() {
-                    scope = sco;
-                }
-            }
-        }
+        Self::with_store(name, ctx, peerpiper, keyring, storage::open_default())
+    }
 
+    /// Same as [State::new], but maps the plugin name to its state CID in the given store
+    pub fn with_store(
+        name: impl AsRef<str> + Clone,
+        ctx: Option<egui::Context>,
+        peerpiper: Arc<AsyncMutex<PeerPiper>>,
+        keyring: Keyring,
+        cid_map: Arc<dyn Storage>,
+    ) -> Self {
+        let name = name.as_ref().to_string();
+        let public = keyring::is_public(cid_map.as_ref(), &name);
         Self {
-            scope: Arc::new(Mutex::new(scope)),
+            scope: Arc::new(Mutex::new(Scope::new())),
             egui_ctx: ctx,
-            name: name.clone().as_ref().to_string(),
+            name,
             peerpiper,
             cid_map,
-            cancel_save: Arc::new(AsyncMutex::new(None)),
+            last_saved: Arc::new(Mutex::new(None)),
+            keyring,
+            plaintext: vec![],
+            wallet: false,
+            wallet_changed: None,
+            locked: Arc::new(Mutex::new(false)),
+            held_back: Arc::new(Mutex::new(false)),
+            public: Arc::new(Mutex::new(public)),
+            load_status: Default::default(),
+        }
+    }
+
+    /// Keep the named variables in plaintext when the rest of the scope is encrypted.
+    pub fn with_plaintext(mut self, names: &[&str]) -> Self {
+        self.plaintext = names.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// Mark this as the wallet's state, which is never shared, setting `changed` whenever
+    /// the wallet emits a change
+    pub fn as_wallet(mut self, changed: Arc<AtomicBool>) -> Self {
+        self.wallet = true;
+        self.wallet_changed = Some(changed);
+        self
+    }
+
+    /// Whether this is the wallet's state
+    pub fn is_wallet(&self) -> bool {
+        self.wallet
+    }
+
+    /// Variables which are never encrypted, even when the rest of the scope is
+    pub fn plaintext(&self) -> &[String] {
+        &self.plaintext
+    }
+
+    /// Whether the saved scope is encrypted and waiting on the wallet to be unlocked
+    pub fn is_locked(&self) -> bool {
+        *self.locked.lock().unwrap()
+    }
+
+    /// Whether changes were made while locked, which are only saved once unlocked
+    pub fn is_held_back(&self) -> bool {
+        *self.held_back.lock().unwrap()
+    }
+
+    /// Whether the saved scope is loaded and the plugin initialized
+    pub fn load_status(&self) -> LoadStatus {
+        self.load_status.lock().unwrap().clone()
+    }
+
+    /// Set once the plugin is initialized, or failed to load
+    pub(crate) fn set_load_status(&self, status: LoadStatus) {
+        *self.load_status.lock().unwrap() = status;
+        if let Some(egui_ctx) = &self.egui_ctx {
+            egui_ctx.request_repaint();
+        }
+    }
+
+    /// Load the saved scope, if there is one.
+    ///
+    /// Until the plugin is initialized the scope is empty, so saving is refused rather
+    /// than overwriting the saved state with it.
+    pub async fn init(&self) -> anyhow::Result<()> {
+        let Some(head) = self.head() else {
+            tracing::info!("No state found for {:?}", self.name);
+            return Ok(());
+        };
+        let bytes = self.get_block(&head).await?;
+        let (scope, locked) =
+            match StateBlock::decode(&bytes).and_then(|block| block.load(&self.keyring)) {
+                Ok(loaded) => loaded,
+                // keep the block rather than replacing it with an empty scope on the next save
+                Err(e) => return Err(Corrupted::quarantine(&self.name, &head, &e))?,
+            };
+
+        if !locked {
+            *self.last_saved.lock().unwrap() = serde_json::to_value(&scope).ok();
+        }
+        *self.scope.lock().unwrap() = scope;
+        *self.locked.lock().unwrap() = locked;
+        tracing::info!("*** State loaded for {:?}", self.name);
+        Ok(())
+    }
+
+    /// Whether this plugin opted out of encryption at rest
+    pub fn is_public(&self) -> bool {
+        *self.public.lock().unwrap()
+    }
+
+    /// Opt in or out of encryption at rest, then save the state in the new form.
+    pub fn set_public(&self, public: bool) {
+        match keyring::set_public(self.cid_map.as_ref(), &self.name, public) {
+            Ok(()) => *self.public.lock().unwrap() = public,
+            Err(e) => tracing::error!("Error saving encryption setting: {:?}", e),
+        }
+        // force a new block, even though the scope is unchanged
+        self.last_saved.lock().unwrap().take();
+        self.save();
+    }
+
+    /// Decrypt the saved scope, now that the keyring is unlocked.
+    ///
+    /// Variables changed while locked are kept and saved now. Returns whether the state
+    /// was locked.
+    pub async fn unlock(&self) -> anyhow::Result<bool> {
+        if !self.is_locked() {
+            // changes made while the wallet was locked again
+            if self.is_held_back() {
+                self.async_save().await?;
+            }
+            return Ok(false);
+        }
+
+        if let Some(head) = self.head() {
+            let bytes = self.get_block(&head).await?;
+            let saved = StateBlock::decode(&bytes)?.open(&self.keyring)?;
+            history::merge_scope(&mut self.scope.lock().unwrap(), &saved);
+        }
+        *self.locked.lock().unwrap() = false;
+
+        if let Some(egui_ctx) = &self.egui_ctx {
+            egui_ctx.request_repaint();
+        }
+
+        self.async_save().await?;
+        Ok(true)
+    }
+
+    /// The name of the plugin this state belongs to
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The CID of the most recently saved state, if any
+    pub fn head(&self) -> Option<String> {
+        read_head(self.cid_map.as_ref(), &self.name)
+    }
+
+    /// Send any command to PeerPiper, such as publishing to a topic
+    pub async fn order(&self, command: AllCommands) -> anyhow::Result<ReturnValues> {
+        self.peerpiper
+            .lock()
+            .await
+            .commander
+            .order(command)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to order command: {:?}", e))
+    }
+
+    /// Put the plugin binary into the blockstore and pin it, so garbage collection keeps it.
+    /// The binary it replaces is unpinned.
+    pub async fn pin_binary(&self, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let ReturnValues::ID(cid) = self
+            .order(AllCommands::System(SystemCommand::Put { bytes }))
+            .await?
+        else {
+            return Err(anyhow::anyhow!("Failed to order command: Put"))?;
+        };
+        let cid = cid.to_string();
+        let pins = Pins::default();
+        pins.replace(&cid, &format!("binary:{}", self.name))?;
+
+        // binaries used to be pinned like the blocks the plugin puts itself
+        let reason = format!("plugin:{}", self.name);
+        for (pinned, reasons) in pins.list()? {
+            if pinned == cid || !reasons.contains(&reason) {
+                continue;
+            }
+            if self
+                .get_block(&pinned)
+                .await
+                .is_ok_and(|b| b.starts_with(WASM_MAGIC))
+            {
+                pins.unpin(&pinned, Some(&reason))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the bytes for the given CID from the blockstore
+    pub async fn get_block(&self, cid: &str) -> anyhow::Result<Vec<u8>> {
+        let cid = Cid::try_from(cid)?;
+        let command = AllCommands::System(SystemCommand::Get { key: cid.into() });
+        let Ok(ReturnValues::Data(bytes)) =
+            self.peerpiper.lock().await.commander.order(command).await
+        else {
+            return Err(anyhow::anyhow!("Failed to order command: Get"))?;
+        };
+        Ok(bytes)
+    }
+
+    /// Put the bytes into the blockstore under the given CID, such as an imported block
+    pub async fn put_block(&self, cid: &Cid, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let command = AllCommands::System(SystemCommand::PutKeyed {
+            key: cid.to_bytes(),
+            bytes,
+        });
+        let Ok(_) = self.peerpiper.lock().await.commander.order(command).await else {
+            return Err(anyhow::anyhow!("Failed to order command: PutKeyed"))?;
+        };
+        Ok(())
+    }
+
+    /// List up to `limit` saved snapshots of this state, newest first
+    pub async fn history(&self, limit: usize) -> anyhow::Result<Vec<Snapshot>> {
+        let Some(head) = self.head() else {
+            return Ok(vec![]);
+        };
+        history::walk(head, limit, |cid| async move { self.get_block(&cid).await })
+            .await?
+            .into_iter()
+            .map(|snapshot| {
+                Ok(Snapshot {
+                    block: snapshot.block.opened(&self.keyring)?,
+                    ..snapshot
+                })
+            })
+            .collect()
+    }
+
+    /// Roll the state back to the snapshot saved under the given CID.
+    ///
+    /// The rolled back scope is saved as a new snapshot, so the history is kept intact
+    /// and the rollback itself can be undone.
+    pub async fn rollback(&self, cid: &str) -> anyhow::Result<String> {
+        let bytes = self.get_block(cid).await?;
+        let scope = StateBlock::decode(&bytes)?.open(&self.keyring)?;
+
+        *self.scope.lock().unwrap() = scope;
+
+        if let Some(egui_ctx) = &self.egui_ctx {
+            egui_ctx.request_repaint();
+        }
+
+        self.async_save().await
+    }
+
+    /// Make the state saved under the given CID the head, and load it as on startup.
+    ///
+    /// Unlike [State::rollback] no new snapshot is saved, so the CID's own history is kept.
+    pub async fn checkout(&self, cid: &str) -> anyhow::Result<()> {
+        // a restored backup is loaded on restart, don't replace its head
+        if backup::restart_pending() {
+            return Err(BackupError::RestartPending)?;
+        }
+
+        let bytes = self.get_block(cid).await?;
+        let (scope, locked) = StateBlock::decode(&bytes)?.load(&self.keyring)?;
+
+        self.cid_map.set_string(&self.name, cid.to_string())?;
+
+        *self.last_saved.lock().unwrap() = match locked {
+            true => None,
+            false => Some(serde_json::to_value(&scope)?),
+        };
+        *self.scope.lock().unwrap() = scope;
+        *self.locked.lock().unwrap() = locked;
+
+        if let Some(egui_ctx) = &self.egui_ctx {
+            egui_ctx.request_repaint();
         }
+
+        Ok(())
+    }
+
+    /// Merge another copy of this plugin's scope into this one, then save it.
+    ///
+    /// Variables in `other` replace ours, variables only we have are kept. The merge is
+    /// saved as a new snapshot, so it can be rolled back.
+    pub async fn merge(&self, other: &Scope<'_>) -> anyhow::Result<String> {
+        history::overlay_scope(&mut self.scope.lock().unwrap(), other);
+
+        if let Some(egui_ctx) = &self.egui_ctx {
+            egui_ctx.request_repaint();
+        }
+
+        self.async_save().await
     }
 
     /// Persist the [rhai::Scope] state on disk
@@ -113,12 +378,58 @@ impl State {
         // Disadvantage is that we need to keep a mapping of plugin names to CIDs (a-la IPNS) when
         // data changes.
 
+        // a restored backup is loaded on restart, don't overwrite it with this state
+        if backup::restart_pending() {
+            return Err(BackupError::RestartPending)?;
+        }
+
+        // the saved scope isn't loaded yet, saving the empty one would replace it
+        if self.load_status() == LoadStatus::Loading {
+            return Err(anyhow::anyhow!("State of {} is still loading", self.name));
+        }
+
+        // the saved scope couldn't be loaded, saving would replace it until it's recovered
+        if let LoadStatus::Corrupted(corrupted) = self.load_status() {
+            return Err(corrupted)?;
+        }
+
+        // a transient error may have failed the load, saving would replace the saved state
+        if let LoadStatus::Failed(error) = self.load_status() {
+            return Err(LoadFailed {
+                name: self.name.clone(),
+                error,
+            })?;
+        }
+
+        // saving now would drop the sealed variables we haven't been able to decrypt yet,
+        // and the scope can't be sealed while the wallet is locked
+        if self.is_locked() || (!self.is_public() && !self.keyring.is_unlocked()) {
+            *self.held_back.lock().unwrap() = true;
+            return Err(KeyringError::Locked)?;
+        }
+        *self.held_back.lock().unwrap() = false;
+
         // get a clone of the scope
         let scope = self.scope.lock().unwrap().clone();
 
         tracing::trace!("Saving scope: {:?}", scope);
 
-        let str = serde_json::to_string(&scope)?;
+        let scope_json = serde_json::to_value(&scope)?;
+
+        // nothing changed since the last save, keep the current head
+        if let Some(head) = self.head() {
+            if self.last_saved.lock().unwrap().as_ref() == Some(&scope_json) {
+                return Ok(head);
+            }
+        }
+
+        // link this state to the previous one, so we can walk back through the history
+        let block = match self.is_public() {
+            true => StateBlock::new(self.head(), scope_json.clone()),
+            false => StateBlock::seal(self.head(), &scope, &self.plaintext, &self.keyring)?,
+        };
+
+        let str = serde_json::to_string(&block)?;
 
         tracing::trace!("State serialized: {:?}", str);
 
@@ -136,42 +447,50 @@ impl State {
             tracing::error!("Error saving state: {:?}", e);
         }
 
+        *self.last_saved.lock().unwrap() = Some(scope_json);
+
         Ok(cid.to_string())
     }
+
+    /// Save now, cancelling any pending debounced save, and wait until it's written.
+    ///
+    /// States which can't be saved right now, while still loading, while the keyring is
+    /// locked, while quarantined or after a backup was restored, are skipped rather than
+    /// reported as errors.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        state_saves().cancel(&self.name);
+        if self.load_status() == LoadStatus::Loading {
+            return Ok(());
+        }
+        match self.async_save().await {
+            Ok(_) => Ok(()),
+            Err(e)
+                if history::is_locked(&e)
+                    || backup::is_restart_pending(&e)
+                    || quarantine::is_corrupted(&e) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The state CID stored for the plugin, logging storage errors as a missing state
+fn read_head(cid_map: &dyn Storage, name: &str) -> Option<String> {
+    cid_map.get_string(name).unwrap_or_else(|e| {
+        tracing::error!("Error reading state CID for {}: {:?}", name, e);
+        None
+    })
 }
 
 impl Inner for State {
-    /// Saves the plugin rhai Scope to disk.
+    /// Saves the plugin rhai Scope to disk, once it stops changing.
     fn save(&self) {
-        let state_clone = self.clone();
-        let callback = move || {
-            let state = state_clone.clone();
-            platform::spawn(async move {
-                tracing::info!("Saving state");
-                match state.async_save().await {
-                    Ok(cid) => {
-                        tracing::info!("State saved to CID: {:?}", cid);
-                    }
-                    Err(e) => {
-                        tracing::error!("Error saving state: {:?}", e);
-                    }
-                }
-            });
-        };
-
-        let cancel_token = self.cancel_save.clone();
-        let callback = Arc::new(callback);
-
-        let debouncer = Debouncer {
-            cancel_token,
-            callback,
-            delay: Duration::from_millis(400),
-        };
-
-        // spawn a debouncer.debounce().await; task
-        platform::spawn(async move {
-            debouncer.debounce().await;
-        });
+        if let Some(changed) = &self.wallet_changed {
+            changed.store(true, Ordering::SeqCst);
+        }
+        state_saves().debounce(self.name.clone(), self.clone());
     }
 
     /// Updates the scope variable to the given value
@@ -211,4 +530,106 @@ impl Inner for State {
 
 // Example usage and tests
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use crate::app::platform::blocks::BlockIndex;
+    use crate::app::platform::platform::{Blockstore, Deferred};
+    use crate::app::platform::storage::MemoryStore;
+    use peerpiper_native::NativeBlockstoreBuilder;
+    use std::path::PathBuf;
+
+    /// A state with its blocks in the directory, or whose blockstore fails every call
+    fn state(cid_map: Arc<dyn Storage>, keyring: Keyring, blocks: Option<PathBuf>) -> State {
+        let open = async move {
+            match blocks {
+                Some(dir) => NativeBlockstoreBuilder::new(dir)
+                    .open()
+                    .await
+                    .map_err(|e| format!("{:?}", e)),
+                None => Err("no blockstore".to_string()),
+            }
+        };
+        let blockstore =
+            Blockstore::with_index(Deferred::new(open), BlockIndex::open(cid_map.clone()));
+        let peerpiper = PeerPiper::new(blockstore, Default::default(), Default::default());
+        State::with_store(
+            "plugin.wasm",
+            None,
+            Arc::new(AsyncMutex::new(peerpiper)),
+            keyring,
+            cid_map,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_save_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cid_map: Arc<dyn Storage> = Arc::new(MemoryStore::default());
+        let keyring = Keyring::default();
+        keyring.unlock(b"signature bytes");
+
+        let state = state(
+            cid_map.clone(),
+            keyring.clone(),
+            Some(dir.path().join("blocks")),
+        );
+        state.init().await.unwrap();
+        state.set_load_status(LoadStatus::Ready);
+        assert_eq!(state.head(), None);
+
+        state.scope.lock().unwrap().set_value("count", 1_i64);
+        let first = state.async_save().await.unwrap();
+        assert_eq!(state.head().as_deref(), Some(first.as_str()));
+        // an unchanged scope keeps the head
+        assert_eq!(state.async_save().await.unwrap(), first);
+
+        state.scope.lock().unwrap().set_value("count", 2_i64);
+        let second = state.async_save().await.unwrap();
+        assert_ne!(second, first);
+        assert_eq!(state.head().as_deref(), Some(second.as_str()));
+
+        // a new state over the same stores loads the saved scope and its history
+        let loaded = State::with_store(
+            "plugin.wasm",
+            None,
+            state.peerpiper.clone(),
+            keyring,
+            cid_map,
+        );
+        loaded.init().await.unwrap();
+        assert!(!loaded.is_locked());
+        assert_eq!(
+            loaded.scope.lock().unwrap().get_value::<i64>("count"),
+            Some(2)
+        );
+        let cids = loaded
+            .history(history::MAX_SNAPSHOTS)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|snapshot| snapshot.cid)
+            .collect::<Vec<_>>();
+        assert_eq!(cids, vec![second, first]);
+    }
+
+    #[tokio::test]
+    async fn test_save_refused_while_corrupted() {
+        let cid_map: Arc<dyn Storage> = Arc::new(MemoryStore::default());
+        cid_map
+            .set_string("plugin.wasm", "bafkhead".to_string())
+            .unwrap();
+        let state = state(cid_map, Keyring::default(), None);
+        let corrupted = Corrupted {
+            cid: "bafkhead".to_string(),
+            error: "invalid".to_string(),
+        };
+        state.set_load_status(LoadStatus::Corrupted(corrupted.clone()));
+
+        let e = state.async_save().await.unwrap_err();
+        assert_eq!(e.downcast_ref::<Corrupted>(), Some(&corrupted));
+        // the quarantined block stays the plugin's state
+        assert_eq!(state.head().as_deref(), Some("bafkhead"));
+        // and flushing skips it rather than failing
+        assert!(state.flush().await.is_ok());
+    }
+}
@@ -0,0 +1,217 @@
+//! Secrets such as API tokens, sealed with the keyring and kept in the platform storage.
+//!
+//! Settings and plugins hold a [SecretHandle] rather than the secret, and read it through
+//! the [Vault] only while the wallet is unlocked. Each secret is sealed under its own
+//! keyring context, so a sealed value can't be passed off as another handle's. Plugins are
+//! refused the wallet key the keyring derives from, see
+//! [is_host_key](super::keyring::is_host_key), so they can't open the vault themselves.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::backup;
+use super::keyring::{Keyring, KeyringError, Sealed};
+use crate::app::platform::storage::{Storage, StorageError};
+
+/// Storage key of the sealed secrets
+pub(crate) const VAULT_KEY: &str = "secrets-vault";
+
+/// Keyring context prefix, followed by the handle
+const VAULT_CONTEXT: &str = "vault";
+
+/// Vault errors
+#[derive(Debug, thiserror::Error)]
+pub enum VaultError {
+    /// Locked, or the secret couldn't be decrypted
+    #[error(transparent)]
+    Keyring(#[from] KeyringError),
+    /// Saving the sealed secrets failed
+    #[error("Vault storage error: {0}")]
+    Storage(#[from] StorageError),
+    /// The saved secrets are not valid JSON
+    #[error("Invalid vault: {0}")]
+    Json(#[from] serde_json::Error),
+    /// A backup was restored, secrets can't change until the app restarts
+    #[error("Restart to finish restoring the backup first")]
+    RestartPending,
+    /// The secret decrypted, but is not text
+    #[error("Secret {0} is not valid UTF-8")]
+    Utf8(String),
+    /// A plugin handle which could reach outside the plugin's own secrets
+    #[error("Invalid secret handle {0:?}, it can't be empty or contain '/'")]
+    InvalidHandle(String),
+}
+
+/// Name of a secret in the [Vault], what settings store instead of the secret
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct SecretHandle(String);
+
+impl SecretHandle {
+    pub fn new(handle: impl Into<String>) -> Self {
+        Self(handle.into())
+    }
+
+    /// A plugin's own handle, so plugins can't read app secrets or each other's.
+    ///
+    /// Neither part may contain '/', so one plugin's handles never name another's.
+    pub fn plugin(plugin: &str, handle: &str) -> Result<Self, VaultError> {
+        for part in [plugin, handle] {
+            if part.is_empty() || part.contains('/') {
+                return Err(VaultError::InvalidHandle(part.to_string()));
+            }
+        }
+        Ok(Self(format!("plugin/{}/{}", plugin, handle)))
+    }
+}
+
+impl std::fmt::Display for SecretHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Sealed secrets by handle, opened with the keyring.
+///
+/// Cheap to clone, all clones share the same secrets.
+#[derive(Clone)]
+pub struct Vault {
+    keyring: Keyring,
+    storage: Arc<dyn Storage>,
+    sealed: Arc<Mutex<BTreeMap<SecretHandle, Sealed>>>,
+}
+
+impl Vault {
+    /// Load the sealed secrets from the storage. They open once the keyring is unlocked.
+    pub fn new(keyring: Keyring, storage: Arc<dyn Storage>) -> Self {
+        let sealed = match storage.get_string(VAULT_KEY) {
+            Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                tracing::error!("Invalid vault, starting empty: {:?}", e);
+                BTreeMap::new()
+            }),
+            Ok(None) => BTreeMap::new(),
+            Err(e) => {
+                tracing::error!("Failed to read the vault, starting empty: {:?}", e);
+                BTreeMap::new()
+            }
+        };
+        Self {
+            keyring,
+            storage,
+            sealed: Arc::new(Mutex::new(sealed)),
+        }
+    }
+
+    /// Whether secrets can be read and written, which is once the wallet is unlocked
+    pub fn is_unlocked(&self) -> bool {
+        self.keyring.is_unlocked()
+    }
+
+    /// The secret, None if it was never set
+    pub fn get(&self, handle: &SecretHandle) -> Result<Option<String>, VaultError> {
+        let Some(sealed) = self.sealed.lock().unwrap().get(handle).cloned() else {
+            return Ok(None);
+        };
+        let secret = self.keyring.open(&context(handle), &sealed)?;
+        String::from_utf8(secret)
+            .map(Some)
+            .map_err(|_| VaultError::Utf8(handle.to_string()))
+    }
+
+    /// Seal and save the secret, removing it if empty.
+    ///
+    /// The secret only changes once it's saved, so a failed save leaves the old one.
+    pub fn set(&self, handle: &SecretHandle, secret: &str) -> Result<(), VaultError> {
+        if backup::restart_pending() {
+            return Err(VaultError::RestartPending);
+        }
+        let mut sealed = self.sealed.lock().unwrap();
+        let mut changed = sealed.clone();
+        if secret.is_empty() {
+            if changed.remove(handle).is_none() {
+                return Ok(());
+            }
+        } else {
+            let value = self.keyring.seal(&context(handle), secret.as_bytes())?;
+            changed.insert(handle.clone(), value);
+        }
+        self.storage
+            .set_string(VAULT_KEY, serde_json::to_string(&changed)?)?;
+        *sealed = changed;
+        Ok(())
+    }
+
+    /// Move a plaintext secret into the vault, emptying it. Does nothing if it's empty
+    /// or the vault is locked, so it can be called until it's moved.
+    pub fn migrate(&self, handle: &SecretHandle, plaintext: &mut String) {
+        if plaintext.is_empty() || !self.is_unlocked() {
+            return;
+        }
+        match self.set(handle, plaintext) {
+            Ok(()) => {
+                plaintext.clear();
+                tracing::info!("🔐 Moved {} into the vault", handle);
+            }
+            Err(e) => tracing::error!("Failed to move {} into the vault: {:?}", handle, e),
+        }
+    }
+}
+
+fn context(handle: &SecretHandle) -> String {
+    format!("{}/{}", VAULT_CONTEXT, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::platform::storage::MemoryStore;
+
+    #[test]
+    fn test_vault() {
+        let keyring = Keyring::default();
+        let storage = MemoryStore::default();
+        let vault = Vault::new(keyring.clone(), Arc::new(storage.clone()));
+        let token = SecretHandle::new("dnsaddr/cloudflare-api-token");
+
+        // plaintext stays put until the vault is unlocked
+        let mut plaintext = "hunter2".to_string();
+        vault.migrate(&token, &mut plaintext);
+        assert_eq!(plaintext, "hunter2");
+        assert!(matches!(
+            vault.set(&token, "hunter2"),
+            Err(VaultError::Keyring(KeyringError::Locked))
+        ));
+
+        keyring.unlock(b"signature bytes");
+        vault.migrate(&token, &mut plaintext);
+        assert!(plaintext.is_empty());
+        assert_eq!(vault.get(&token).unwrap().as_deref(), Some("hunter2"));
+
+        // only the sealed secret is stored, and it loads again
+        let saved = storage.get_string(VAULT_KEY).unwrap().unwrap();
+        assert!(!saved.contains("hunter2"));
+        let reloaded = Vault::new(keyring.clone(), Arc::new(storage.clone()));
+        assert_eq!(reloaded.get(&token).unwrap().as_deref(), Some("hunter2"));
+
+        // a sealed secret doesn't open under another handle
+        let other = SecretHandle::plugin("pipepad", "token").unwrap();
+        let stolen = vault.sealed.lock().unwrap()[&token].clone();
+        vault.sealed.lock().unwrap().insert(other.clone(), stolen);
+        assert!(vault.get(&other).is_err());
+
+        // secrets close again with the wallet
+        keyring.lock();
+        assert!(matches!(
+            vault.get(&token),
+            Err(VaultError::Keyring(KeyringError::Locked))
+        ));
+        keyring.unlock(b"signature bytes");
+
+        vault.set(&token, "").unwrap();
+        assert_eq!(vault.get(&token).unwrap(), None);
+
+        // plugins can't name handles outside their own
+        assert!(SecretHandle::plugin("pipepad", "../dnsaddr/token").is_err());
+        assert!(SecretHandle::plugin("x/y", "token").is_err());
+        assert!(SecretHandle::plugin("pipepad", "").is_err());
+    }
+}
@@ -1,9 +1,12 @@
 //! Custom State for the RDX Plugins, Implements [rdx::layer::Inner] and custom Serialize/Deserialize
 //! so that the [rdx::layer::rhai::Scope] can be serialized and deserialized.
-use crate::app::platform;
-use crate::app::platform::StringStore;
-use crate::app::rdx_runner::PeerPiperWired;
-use gloo_timers::callback::Timeout;
+use crate::app::platform::blocks::{Pins, WASM_MAGIC};
+use crate::app::platform::storage::{self, MemoryStore, Storage};
+use crate::app::rdx_runner::backup::{self, BackupError};
+use crate::app::rdx_runner::history::{self, Snapshot, StateBlock};
+use crate::app::rdx_runner::keyring::{self, Keyring, KeyringError};
+use crate::app::rdx_runner::quarantine::{self, Corrupted, LoadFailed};
+use crate::app::rdx_runner::{state_saves, LoadStatus, PeerPiperWired};
 use peerpiper::core::events::AllCommands;
 use peerpiper::core::events::SystemCommand;
 use peerpiper::core::Cid;
@@ -15,6 +18,8 @@ use rdx::layer::{
 use send_wrapper::SendWrapper;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 /// Serializable [State] struct that holds the [Scope] and [egui::Context]
 #[derive(Clone)]
@@ -25,13 +30,17 @@ pub struct State {
 
 impl Default for State {
     fn default() -> Self {
-        Self {
-            inner: SendWrapper::new(InnerState::default()),
-        }
+        Self::with_store(
+            "",
+            None,
+            PeerPiperWired::default(),
+            Keyring::default(),
+            Arc::new(MemoryStore::default()),
+        )
     }
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 struct InnerState {
     /// The unique name of this plugin, typically the filename
     name: String,
@@ -45,9 +54,25 @@ struct InnerState {
     /// Handler to PeerPiper SystemCommander
     peerpiper: PeerPiperWired,
     /// String Store map the name of the plugin to the CID of the state
-    cid_map: StringStore,
-    /// Debouncing mechanism to save the state but workaround many requests at once
-    timer: Rc<RefCell<Option<Timeout>>>,
+    cid_map: Arc<dyn Storage>,
+    /// The serialized scope as of the last save, so unchanged scopes don't add to the history
+    last_saved: Rc<RefCell<Option<serde_json::Value>>>,
+    /// Keyring used to encrypt the scope at rest
+    keyring: Keyring,
+    /// Variables which are never encrypted, even when the rest of the scope is
+    plaintext: Vec<String>,
+    /// Whether this is the wallet's state, which is never shared
+    wallet: bool,
+    /// Set whenever the wallet's state changes, so the keyring is only unlocked then
+    wallet_changed: Option<Arc<AtomicBool>>,
+    /// Whether the saved scope is encrypted and waiting on the wallet to be unlocked
+    locked: Rc<RefCell<bool>>,
+    /// Whether a save was held back while locked, so it's made once unlocked
+    held_back: Rc<RefCell<bool>>,
+    /// Whether this plugin opted out of encryption at rest, read once from the store
+    public: Rc<RefCell<bool>>,
+    /// Whether the saved scope is loaded and the plugin initialized
+    load_status: Rc<RefCell<LoadStatus>>,
 }
 
 //pub fn sleep(dur: web_time::Duration) -> impl futures::Future<Output = ()> {
@@ -59,69 +84,317 @@ impl State {
         name: impl AsRef<str> + Clone,
         ctx: Option<egui::Context>,
         peerpiper: PeerPiperWired,
+        keyring: Keyring,
     ) -> Self {
-        let scope = Rc::new(RefCell::new(Scope::new()));
+        Self::with_store(name, ctx, peerpiper, keyring, storage::open_default())
+    }
 
-        let cid_map = StringStore::new();
+    /// Same as [State::new], but maps the plugin name to its state CID in the given store
+    pub fn with_store(
+        name: impl AsRef<str> + Clone,
+        ctx: Option<egui::Context>,
+        peerpiper: PeerPiperWired,
+        keyring: Keyring,
+        cid_map: Arc<dyn Storage>,
+    ) -> Self {
+        let scope = Rc::new(RefCell::new(Scope::new()));
+        let name = name.as_ref().to_string();
+        let public = keyring::is_public(cid_map.as_ref(), &name);
 
         Self {
             inner: SendWrapper::new(InnerState {
                 scope,
                 egui_ctx: ctx,
-                name: name.clone().as_ref().to_string(),
+                name,
                 peerpiper,
                 cid_map,
-                timer: Rc::new(RefCell::new(None)),
+                last_saved: Rc::new(RefCell::new(None)),
+                keyring,
+                plaintext: vec![],
+                wallet: false,
+                wallet_changed: None,
+                locked: Rc::new(RefCell::new(false)),
+                held_back: Rc::new(RefCell::new(false)),
+                public: Rc::new(RefCell::new(public)),
+                load_status: Default::default(),
             }),
         }
     }
 
-    /// Initialize the state with a scope from storage, if it exists.
-    /// Same steps as in new(), but using self.* instead.
-    pub async fn init(&self) {
-        tracing::info!("State::init() called for {:?}", self.inner.name);
-        if let Some(key) = self.inner.cid_map.get_string(&self.inner.name) {
-            if let Ok(cid) = Cid::try_from(key.clone()) {
-                if self.inner.peerpiper.borrow().is_none() {
-                    tracing::warn!("Failed to get PeerPiper from State");
-                    return;
-                };
-
-                let name: String = self.inner.name.clone();
-                let pp = {
-                    tracing::info!("Borrow mut in init");
-                    let command = AllCommands::System(SystemCommand::Get { key: cid.into() });
-                    self.inner
-                        .peerpiper
-                        .borrow()
-                        .as_ref()
-                        .unwrap()
-                        .order(command)
-                        .await
-                };
-
-                let Ok(ReturnValues::Data(bytes)) = pp else {
-                    tracing::warn!("Failed to get state from CID: {}", key);
-                    return;
-                };
-
-                let str = String::from_utf8_lossy(&bytes);
-
-                let Ok(scope) = serde_json::from_str(&str) else {
-                    tracing::warn!("Failed to decode state scope from CID: {}", key);
-                    return;
-                };
-
-                // set the plugin scope to the loaded scope,
-                // this is how we load the state from disk into the plugin
-                *self.inner.scope.borrow_mut() = scope;
-                tracing::info!("*** State loaded for {:?} ", name,);
-            } else {
-                tracing::warn!("Failed to parse CID from string: {}", key);
+    /// Keep the named variables in plaintext when the rest of the scope is encrypted.
+    pub fn with_plaintext(mut self, names: &[&str]) -> Self {
+        self.inner.plaintext = names.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// Mark this as the wallet's state, which is never shared, setting `changed` whenever
+    /// the wallet emits a change
+    pub fn as_wallet(mut self, changed: Arc<AtomicBool>) -> Self {
+        self.inner.wallet = true;
+        self.inner.wallet_changed = Some(changed);
+        self
+    }
+
+    /// Whether this is the wallet's state
+    pub fn is_wallet(&self) -> bool {
+        self.inner.wallet
+    }
+
+    /// Variables which are never encrypted, even when the rest of the scope is
+    pub fn plaintext(&self) -> &[String] {
+        &self.inner.plaintext
+    }
+
+    /// Whether the saved scope is encrypted and waiting on the wallet to be unlocked
+    pub fn is_locked(&self) -> bool {
+        *self.inner.locked.borrow()
+    }
+
+    /// Whether changes were made while locked, which are only saved once unlocked
+    pub fn is_held_back(&self) -> bool {
+        *self.inner.held_back.borrow()
+    }
+
+    /// Whether the saved scope is loaded and the plugin initialized
+    pub fn load_status(&self) -> LoadStatus {
+        self.inner.load_status.borrow().clone()
+    }
+
+    /// Set once the plugin is initialized, or failed to load
+    pub(crate) fn set_load_status(&self, status: LoadStatus) {
+        *self.inner.load_status.borrow_mut() = status;
+        if let Some(egui_ctx) = &self.inner.egui_ctx {
+            egui_ctx.request_repaint();
+        }
+    }
+
+    /// Whether this plugin opted out of encryption at rest
+    pub fn is_public(&self) -> bool {
+        *self.inner.public.borrow()
+    }
+
+    /// Opt in or out of encryption at rest, then save the state in the new form.
+    pub fn set_public(&self, public: bool) {
+        match keyring::set_public(self.inner.cid_map.as_ref(), &self.inner.name, public) {
+            Ok(()) => *self.inner.public.borrow_mut() = public,
+            Err(e) => tracing::error!("Error saving encryption setting: {:?}", e),
+        }
+        // force a new block, even though the scope is unchanged
+        self.inner.last_saved.borrow_mut().take();
+        self.save();
+    }
+
+    /// Decrypt the saved scope, now that the keyring is unlocked.
+    ///
+    /// Variables changed while locked are kept and saved now. Returns whether the state
+    /// was locked.
+    pub async fn unlock(&self) -> anyhow::Result<bool> {
+        if !self.is_locked() {
+            // changes made while the wallet was locked again
+            if self.is_held_back() {
+                self.async_save().await?;
             }
-        } else {
-            tracing::warn!("No state found for {:?}", self.inner.name);
+            return Ok(false);
+        }
+
+        if let Some(head) = self.head() {
+            let bytes = self.get_block(&head).await?;
+            let saved = StateBlock::decode(&bytes)?.open(&self.inner.keyring)?;
+            history::merge_scope(&mut self.inner.scope.borrow_mut(), &saved);
+        }
+        *self.inner.locked.borrow_mut() = false;
+
+        if let Some(egui_ctx) = &self.inner.egui_ctx {
+            egui_ctx.request_repaint();
+        }
+
+        self.async_save().await?;
+        Ok(true)
+    }
+
+    /// The name of the plugin this state belongs to
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// The CID of the most recently saved state, if any
+    pub fn head(&self) -> Option<String> {
+        read_head(self.inner.cid_map.as_ref(), &self.inner.name)
+    }
+
+    /// Send any command to PeerPiper, such as publishing to a topic
+    pub async fn order(&self, command: AllCommands) -> anyhow::Result<ReturnValues> {
+        let Some(pipr) = self.inner.peerpiper.borrow().clone() else {
+            return Err(anyhow::anyhow!("PeerPiper is not ready yet"))?;
+        };
+        pipr.order(command)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to order command: {:?}", e))
+    }
+
+    /// Put the plugin binary into the blockstore and pin it, so garbage collection keeps it.
+    /// The binary it replaces is unpinned.
+    pub async fn pin_binary(&self, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let ReturnValues::ID(cid) = self
+            .order(AllCommands::System(SystemCommand::Put { bytes }))
+            .await?
+        else {
+            return Err(anyhow::anyhow!("Failed to order command: Put"))?;
+        };
+        let cid = cid.to_string();
+        let pins = Pins::default();
+        pins.replace(&cid, &format!("binary:{}", self.inner.name))?;
+
+        // binaries used to be pinned like the blocks the plugin puts itself
+        let reason = format!("plugin:{}", self.inner.name);
+        for (pinned, reasons) in pins.list()? {
+            if pinned == cid || !reasons.contains(&reason) {
+                continue;
+            }
+            if self
+                .get_block(&pinned)
+                .await
+                .is_ok_and(|b| b.starts_with(WASM_MAGIC))
+            {
+                pins.unpin(&pinned, Some(&reason))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the bytes for the given CID from the blockstore
+    pub async fn get_block(&self, cid: &str) -> anyhow::Result<Vec<u8>> {
+        let cid = Cid::try_from(cid)?;
+        let Some(pipr) = self.inner.peerpiper.borrow().clone() else {
+            return Err(anyhow::anyhow!("PeerPiper is not ready yet"))?;
+        };
+        let command = AllCommands::System(SystemCommand::Get { key: cid.into() });
+        let Ok(ReturnValues::Data(bytes)) = pipr.order(command).await else {
+            return Err(anyhow::anyhow!("Failed to order command: Get"))?;
+        };
+        Ok(bytes)
+    }
+
+    /// Put the bytes into the blockstore under the given CID, such as an imported block
+    pub async fn put_block(&self, cid: &Cid, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let Some(pipr) = self.inner.peerpiper.borrow().clone() else {
+            return Err(anyhow::anyhow!("PeerPiper is not ready yet"))?;
+        };
+        let command = AllCommands::System(SystemCommand::PutKeyed {
+            key: cid.to_bytes(),
+            bytes,
+        });
+        let Ok(_) = pipr.order(command).await else {
+            return Err(anyhow::anyhow!("Failed to order command: PutKeyed"))?;
+        };
+        Ok(())
+    }
+
+    /// List up to `limit` saved snapshots of this state, newest first
+    pub async fn history(&self, limit: usize) -> anyhow::Result<Vec<Snapshot>> {
+        let Some(head) = self.head() else {
+            return Ok(vec![]);
+        };
+        history::walk(head, limit, |cid| async move { self.get_block(&cid).await })
+            .await?
+            .into_iter()
+            .map(|snapshot| {
+                Ok(Snapshot {
+                    block: snapshot.block.opened(&self.inner.keyring)?,
+                    ..snapshot
+                })
+            })
+            .collect()
+    }
+
+    /// Roll the state back to the snapshot saved under the given CID.
+    ///
+    /// The rolled back scope is saved as a new snapshot, so the history is kept intact
+    /// and the rollback itself can be undone.
+    pub async fn rollback(&self, cid: &str) -> anyhow::Result<String> {
+        let bytes = self.get_block(cid).await?;
+        let scope = StateBlock::decode(&bytes)?.open(&self.inner.keyring)?;
+
+        *self.inner.scope.borrow_mut() = scope;
+
+        if let Some(egui_ctx) = &self.inner.egui_ctx {
+            egui_ctx.request_repaint();
+        }
+
+        self.async_save().await
+    }
+
+    /// Make the state saved under the given CID the head, and load it as on startup.
+    ///
+    /// Unlike [State::rollback] no new snapshot is saved, so the CID's own history is kept.
+    pub async fn checkout(&self, cid: &str) -> anyhow::Result<()> {
+        // a restored backup is loaded on restart, don't replace its head
+        if backup::restart_pending() {
+            return Err(BackupError::RestartPending)?;
+        }
+
+        let bytes = self.get_block(cid).await?;
+        let (scope, locked) = StateBlock::decode(&bytes)?.load(&self.inner.keyring)?;
+
+        self.inner
+            .cid_map
+            .set_string(&self.inner.name, cid.to_string())?;
+
+        *self.inner.last_saved.borrow_mut() = match locked {
+            true => None,
+            false => Some(serde_json::to_value(&scope)?),
+        };
+        *self.inner.scope.borrow_mut() = scope;
+        *self.inner.locked.borrow_mut() = locked;
+
+        if let Some(egui_ctx) = &self.inner.egui_ctx {
+            egui_ctx.request_repaint();
+        }
+
+        Ok(())
+    }
+
+    /// Merge another copy of this plugin's scope into this one, then save it.
+    ///
+    /// Variables in `other` replace ours, variables only we have are kept. The merge is
+    /// saved as a new snapshot, so it can be rolled back.
+    pub async fn merge(&self, other: &Scope<'_>) -> anyhow::Result<String> {
+        history::overlay_scope(&mut self.inner.scope.borrow_mut(), other);
+
+        if let Some(egui_ctx) = &self.inner.egui_ctx {
+            egui_ctx.request_repaint();
         }
+
+        self.async_save().await
+    }
+
+    /// Load the saved scope, if there is one.
+    ///
+    /// Until the plugin is initialized the scope is empty, so saving is refused rather
+    /// than overwriting the saved state with it.
+    pub async fn init(&self) -> anyhow::Result<()> {
+        let Some(head) = self.head() else {
+            tracing::info!("No state found for {:?}", self.inner.name);
+            return Ok(());
+        };
+        let bytes = self.get_block(&head).await?;
+        let (scope, locked) =
+            match StateBlock::decode(&bytes).and_then(|block| block.load(&self.inner.keyring)) {
+                Ok(loaded) => loaded,
+                // keep the block rather than replacing it with an empty scope on the next save
+                Err(e) => return Err(Corrupted::quarantine(&self.inner.name, &head, &e))?,
+            };
+
+        if !locked {
+            *self.inner.last_saved.borrow_mut() = serde_json::to_value(&scope).ok();
+        }
+
+        // set the plugin scope to the loaded scope,
+        // this is how we load the state from disk into the plugin
+        *self.inner.scope.borrow_mut() = scope;
+        *self.inner.locked.borrow_mut() = locked;
+        tracing::info!("*** State loaded for {:?}", self.inner.name);
+        Ok(())
     }
 
     /// Persist the [rhai::Scope] state on disk
@@ -133,7 +406,63 @@ impl State {
         // Advantage of Option B is we can content address share plugin state scope.
         // Disadvantage is that we need to keep a mapping of plugin names to CIDs (a-la IPNS) when
         // data changes.
-        let str = serde_json::to_string_pretty(&self.inner.scope.borrow().clone())?;
+
+        // a restored backup is loaded on restart, don't overwrite it with this state
+        if backup::restart_pending() {
+            return Err(BackupError::RestartPending)?;
+        }
+
+        // the saved scope isn't loaded yet, saving the empty one would replace it
+        if self.load_status() == LoadStatus::Loading {
+            return Err(anyhow::anyhow!(
+                "State of {} is still loading",
+                self.inner.name
+            ));
+        }
+
+        // the saved scope couldn't be loaded, saving would replace it until it's recovered
+        if let LoadStatus::Corrupted(corrupted) = self.load_status() {
+            return Err(corrupted)?;
+        }
+
+        // a transient error may have failed the load, saving would replace the saved state
+        if let LoadStatus::Failed(error) = self.load_status() {
+            return Err(LoadFailed {
+                name: self.inner.name.clone(),
+                error,
+            })?;
+        }
+
+        // saving now would drop the sealed variables we haven't been able to decrypt yet,
+        // and the scope can't be sealed while the wallet is locked
+        if self.is_locked() || (!self.is_public() && !self.inner.keyring.is_unlocked()) {
+            *self.inner.held_back.borrow_mut() = true;
+            return Err(KeyringError::Locked)?;
+        }
+        *self.inner.held_back.borrow_mut() = false;
+
+        let scope = self.inner.scope.borrow().clone();
+        let scope_json = serde_json::to_value(&scope)?;
+
+        // nothing changed since the last save, keep the current head
+        if let Some(head) = self.head() {
+            if self.inner.last_saved.borrow().as_ref() == Some(&scope_json) {
+                return Ok(head);
+            }
+        }
+
+        // link this state to the previous one, so we can walk back through the history
+        let block = match self.is_public() {
+            true => StateBlock::new(self.head(), scope_json.clone()),
+            false => StateBlock::seal(
+                self.head(),
+                &scope,
+                &self.inner.plaintext,
+                &self.inner.keyring,
+            )?,
+        };
+
+        let str = serde_json::to_string_pretty(&block)?;
         let bytes = str.as_bytes().to_vec();
 
         // Save the serialized state to disk, independent of the platform
@@ -155,37 +484,58 @@ impl State {
 
         // Save name:cid mapping to platform storage
         // filesystem, localstorage, etc.
-        self.inner
+        if let Err(e) = self
+            .inner
             .cid_map
-            .set_string(&self.inner.name, cid.to_string());
+            .set_string(&self.inner.name, cid.to_string())
+        {
+            tracing::error!("Error saving state: {:?}", e);
+        }
+
+        *self.inner.last_saved.borrow_mut() = Some(scope_json);
 
         Ok(cid.to_string())
     }
+
+    /// Save now, cancelling any pending debounced save, and wait until it's written.
+    ///
+    /// States which can't be saved right now, while still loading, while the keyring is
+    /// locked, while quarantined or after a backup was restored, are skipped rather than
+    /// reported as errors.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        state_saves().cancel(&self.inner.name);
+        if self.load_status() == LoadStatus::Loading {
+            return Ok(());
+        }
+        match self.async_save().await {
+            Ok(_) => Ok(()),
+            Err(e)
+                if history::is_locked(&e)
+                    || backup::is_restart_pending(&e)
+                    || quarantine::is_corrupted(&e) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The state CID stored for the plugin, logging storage errors as a missing state
+fn read_head(cid_map: &dyn Storage, name: &str) -> Option<String> {
+    cid_map.get_string(name).unwrap_or_else(|e| {
+        tracing::error!("Error reading state CID for {}: {:?}", name, e);
+        None
+    })
 }
 
 impl Inner for State {
-    /// Saves the state to disk
+    /// Saves the state to disk, once it stops changing
     fn save(&self) {
-        tracing::info!("[web.save] Saving state for {:?}", self.inner.name);
-        // Debounce the save operation by pushing the save time into the save_after field
-        // and then spawning a task to save the state after the debounce time
-        let self_clone = self.clone(); // our save() function lives here
-        let timer = self.inner.timer.clone();
-        let delay = 750u32; // ms
-
-        if let Some(t) = timer.borrow_mut().take() {
-            t.cancel();
-        }
-
-        let new_timer = Timeout::new(delay, || {
-            platform::spawn(async move {
-                tracing::info!("Saving state after gloo_timer");
-                let cid = self_clone.async_save().await;
-                tracing::info!("State saved to CID: {:?}", cid);
-            });
-        });
-
-        *timer.borrow_mut() = Some(new_timer);
+        if let Some(changed) = &self.inner.wallet_changed {
+            changed.store(true, Ordering::SeqCst);
+        }
+        state_saves().debounce(self.inner.name.clone(), self.clone());
     }
 
     /// Updates the scope variable to the given value
@@ -0,0 +1,236 @@
+//! Recovery window for a plugin state quarantined because its saved block can't be loaded.
+//!
+//! Shows the raw block for editing, then retries it, saves the edit, restores an earlier
+//! snapshot or starts the plugin empty.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::app::history::{format_timestamp, short_cid};
+use crate::app::platform;
+use crate::app::rdx_runner::history::Snapshot;
+use crate::app::rdx_runner::quarantine::{self, Recovery};
+use crate::app::rdx_runner::{LoadStatus, RdxRunner, State};
+
+/// The async loaded quarantined block
+#[derive(Default)]
+struct Loaded {
+    /// The block as text, as stored
+    text: String,
+    /// Snapshots saved before the block, if its link to them can still be read
+    snapshots: Vec<Snapshot>,
+    is_loading: bool,
+    error: Option<String>,
+}
+
+/// Window to recover a single plugin's quarantined state
+#[derive(Default)]
+pub(crate) struct RecoveryWindow {
+    /// The plugin being recovered. The window is closed when None.
+    plugin: Option<String>,
+    /// The block text being edited
+    text: String,
+    loaded: Arc<Mutex<Loaded>>,
+    /// Set when the block is loaded, so its text replaces the edit on the next frame
+    fresh: Arc<AtomicBool>,
+    /// Whether an action is running
+    busy: Arc<AtomicBool>,
+    /// Error from the last action
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl RecoveryWindow {
+    /// Open the window for the given quarantined plugin state, loading its block.
+    pub(crate) fn open(&mut self, ctx: &egui::Context, state: State) {
+        let LoadStatus::Corrupted(corrupted) = state.load_status() else {
+            return;
+        };
+        self.plugin = Some(state.name().to_string());
+        self.text.clear();
+        self.error.lock().unwrap().take();
+        *self.loaded.lock().unwrap() = Loaded {
+            is_loading: true,
+            ..Default::default()
+        };
+
+        let loaded = self.loaded.clone();
+        let fresh = self.fresh.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let result = match state.get_block(&corrupted.cid).await {
+                Ok(bytes) => quarantine::snapshots(&state, &bytes)
+                    .await
+                    .map(|snapshots| (quarantine::raw_text(&bytes), snapshots)),
+                Err(e) => Err(e),
+            };
+
+            let mut loaded = loaded.lock().unwrap();
+            loaded.is_loading = false;
+            match result {
+                Ok((text, snapshots)) => {
+                    loaded.text = text;
+                    loaded.snapshots = snapshots;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to load quarantined state: {:?}", e);
+                    loaded.error = Some(format!("Failed to load the block: {}", e));
+                }
+            }
+            fresh.store(true, Ordering::SeqCst);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Show the window, if open. Closes once the state is recovered.
+    pub(crate) fn show(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        let Some(plugin) = self.plugin.clone() else {
+            return;
+        };
+        let Some(state) = rdx_runner.state(&plugin) else {
+            self.plugin = None;
+            return;
+        };
+        let busy = self.busy.load(Ordering::SeqCst);
+        let LoadStatus::Corrupted(corrupted) = state.load_status() else {
+            if !busy {
+                self.plugin = None;
+            }
+            return;
+        };
+
+        let loaded = self.loaded.lock().unwrap();
+        if self.fresh.swap(false, Ordering::SeqCst) {
+            self.text = loaded.text.clone();
+        }
+
+        let mut action = None;
+        let mut open = true;
+        egui::Window::new(format!("Recover: {}", plugin))
+            .open(&mut open)
+            .resizable(true)
+            .default_width(480.0)
+            .show(ctx, |ui| {
+                ui.colored_label(
+                    ui.visuals().warn_fg_color,
+                    "The saved state can't be loaded. It's kept aside, and nothing is saved \
+                     for this plugin until it's recovered.",
+                );
+                ui.label(&corrupted.error);
+                ui.horizontal(|ui| {
+                    ui.label("Block:");
+                    ui.monospace(&corrupted.cid);
+                });
+
+                if loaded.is_loading {
+                    ui.spinner();
+                    return;
+                }
+                if let Some(error) = &loaded.error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .id_salt("raw_block")
+                    .max_height(280.0)
+                    .show(ui, |ui| {
+                        ui.add(
+                            egui::TextEdit::multiline(&mut self.text)
+                                .code_editor()
+                                .desired_rows(12)
+                                .desired_width(f32::INFINITY),
+                        );
+                    });
+
+                ui.add_enabled_ui(!busy, |ui| {
+                    ui.horizontal(|ui| {
+                        if ui
+                            .button("Save edit")
+                            .on_hover_text("Save the edited block as the current state")
+                            .clicked()
+                        {
+                            action = Some(Recovery::SaveEdit(self.text.clone()));
+                        }
+                        if ui.button("Undo edits").clicked() {
+                            self.text = loaded.text.clone();
+                        }
+                        if ui
+                            .button("Retry")
+                            .on_hover_text(
+                                "Load the saved block again, such as after unlocking the wallet",
+                            )
+                            .clicked()
+                        {
+                            action = Some(Recovery::Retry);
+                        }
+                        if ui
+                            .button("Start empty")
+                            .on_hover_text(
+                                "Keep the empty state, the next save replaces the saved one",
+                            )
+                            .clicked()
+                        {
+                            action = Some(Recovery::Discard);
+                        }
+                        if busy {
+                            ui.spinner();
+                        }
+                    });
+
+                    if !loaded.snapshots.is_empty() {
+                        ui.separator();
+                        ui.label("Earlier snapshots:");
+                        egui::Grid::new("recovery_snapshots")
+                            .num_columns(3)
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for snapshot in &loaded.snapshots {
+                                    ui.label(format_timestamp(snapshot.block.timestamp));
+                                    ui.monospace(short_cid(&snapshot.cid))
+                                        .on_hover_text(&snapshot.cid);
+                                    if ui.button("Restore").clicked() {
+                                        action = Some(Recovery::Restore(snapshot.cid.clone()));
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                    }
+                });
+
+                if let Some(error) = self.error.lock().unwrap().as_ref() {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+            });
+        drop(loaded);
+
+        if let Some(action) = action {
+            self.run(ctx, rdx_runner, &plugin, action);
+        }
+        if !open {
+            self.plugin = None;
+        }
+    }
+
+    /// Spawn the recovery, keeping its error to show
+    fn run(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner, plugin: &str, action: Recovery) {
+        let Some(recover) = rdx_runner.recover(plugin, action) else {
+            return;
+        };
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.error.lock().unwrap().take();
+
+        let busy = self.busy.clone();
+        let error = self.error.clone();
+        let ctx = ctx.clone();
+        let plugin = plugin.to_string();
+        platform::spawn(async move {
+            if let Err(e) = recover.await {
+                tracing::error!("Failed to recover state of {}: {:?}", plugin, e);
+                error.lock().unwrap().replace(format!("{:#}", e));
+            }
+            busy.store(false, Ordering::SeqCst);
+            ctx.request_repaint();
+        });
+    }
+}
@@ -0,0 +1,356 @@
+//! Share plugin state with peers, and view or merge the states peers share with us.
+use std::sync::{Arc, Mutex};
+
+use crate::app::history::{format_timestamp, short_cid};
+use crate::app::platform;
+use crate::app::rdx_runner::share::{
+    self, ReceivedShare, ShareInbox, ShareOutbox, ShareTarget, Shares, SHARE_TOPIC,
+};
+use crate::app::rdx_runner::{RdxRunner, State};
+
+/// A received share decoded for the read-only view
+struct SharedView {
+    /// Variable names and values, or the error verifying the share
+    variables: Result<Vec<(String, String)>, String>,
+}
+
+/// Windows to send a plugin's state and to browse the received shares
+pub(crate) struct ShareWindow {
+    /// The plugin whose state is being shared. The send window is closed when None.
+    plugin: Option<String>,
+    /// Send to a single peer instead of a topic
+    to_peer: bool,
+    /// Topic to publish to
+    topic: String,
+    /// PeerId to send to
+    peer_id: String,
+    /// Whether the inbox window is open
+    inbox_open: bool,
+    /// The share shown read-only, if any, filled in once its blocks are fetched
+    viewing: Arc<Mutex<Option<SharedView>>>,
+    /// Message about the last share or merge
+    status: Arc<Mutex<Option<String>>>,
+}
+
+impl Default for ShareWindow {
+    fn default() -> Self {
+        Self {
+            plugin: None,
+            to_peer: true,
+            topic: SHARE_TOPIC.to_string(),
+            peer_id: String::new(),
+            inbox_open: false,
+            viewing: Default::default(),
+            status: Default::default(),
+        }
+    }
+}
+
+impl ShareWindow {
+    /// Open the send window for the given plugin
+    pub(crate) fn open(&mut self, plugin: &str) {
+        self.plugin = Some(plugin.to_string());
+    }
+
+    /// Button which opens the inbox, showing how many shares are waiting
+    pub(crate) fn inbox_button(&mut self, ui: &mut egui::Ui, inbox: &ShareInbox) {
+        let label = match inbox.is_empty() {
+            true => "📥 Shared with me".to_string(),
+            false => format!("📥 Shared with me ({})", inbox.len()),
+        };
+        ui.toggle_value(&mut self.inbox_open, label);
+    }
+
+    /// Show the open windows
+    pub(crate) fn show(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner, shares: &Shares) {
+        self.show_send(ctx, rdx_runner, shares);
+        self.show_inbox(ctx, rdx_runner, &shares.inbox);
+    }
+
+    fn show_send(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner, shares: &Shares) {
+        let Some(plugin) = self.plugin.clone() else {
+            return;
+        };
+
+        let Some(state) = rdx_runner.state(&plugin) else {
+            self.plugin = None;
+            return;
+        };
+
+        let mut open = true;
+
+        egui::Window::new(format!("Share: {}", plugin))
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(
+                    "Announces the current state, the recipient fetches it from this node. \
+                     Only public states are shared, never the wallet's or its plaintext variables.",
+                );
+
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.to_peer, true, "Peer");
+                    ui.radio_value(&mut self.to_peer, false, "Topic")
+                        .on_hover_text("Everyone subscribed to the topic can fetch the state");
+                });
+
+                let target = match self.to_peer {
+                    false => {
+                        ui.text_edit_singleline(&mut self.topic);
+                        ShareTarget::Topic(self.topic.trim().to_string())
+                    }
+                    true => {
+                        ui.add(egui::TextEdit::singleline(&mut self.peer_id).hint_text("PeerId"));
+                        ShareTarget::Peer(self.peer_id.trim().to_string())
+                    }
+                };
+
+                let ready = match &target {
+                    ShareTarget::Topic(topic) => !topic.is_empty(),
+                    ShareTarget::Peer(peer_id) => !peer_id.is_empty(),
+                };
+
+                let refused = share::refused(&state);
+                let button = ui.add_enabled(ready && refused.is_none(), egui::Button::new("Share"));
+                if let Some(reason) = &refused {
+                    ui.small(reason);
+                }
+                if button.clicked() {
+                    self.send(ctx, state.clone(), target, shares.outbox.clone());
+                }
+
+                self.show_status(ui);
+                Self::show_shared(ui, &shares.outbox);
+            });
+
+        if !open {
+            self.plugin = None;
+        }
+    }
+
+    /// The states we share, each with a button to stop serving it
+    fn show_shared(ui: &mut egui::Ui, outbox: &ShareOutbox) {
+        let shared = outbox.shared();
+        if shared.is_empty() {
+            return;
+        }
+
+        ui.separator();
+        ui.label("Shared by me")
+            .on_hover_text("Peers can fetch these, and they are kept, until withdrawn");
+        for cid in shared {
+            ui.horizontal(|ui| {
+                ui.monospace(short_cid(&cid)).on_hover_text(&cid);
+                if ui.button("Withdraw").clicked() {
+                    if let Err(e) = outbox.withdraw(&cid) {
+                        tracing::error!("Error withdrawing share {}: {}", cid, e);
+                    }
+                }
+            });
+        }
+    }
+
+    fn show_inbox(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner, inbox: &ShareInbox) {
+        if !self.inbox_open {
+            return;
+        }
+
+        let shares = inbox.list();
+        let mut open = true;
+
+        egui::Window::new("Shared with me")
+            .open(&mut open)
+            .resizable(true)
+            .show(ctx, |ui| {
+                if shares.is_empty() {
+                    ui.label(format!(
+                        "Nothing yet. Peers share states on the {} topic.",
+                        SHARE_TOPIC
+                    ));
+                }
+
+                egui::Grid::new("shared_grid")
+                    .num_columns(4)
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for (index, received) in shares.iter().enumerate() {
+                            self.share_row(ctx, ui, rdx_runner, inbox, index, received);
+                            ui.end_row();
+                        }
+                    });
+
+                self.show_status(ui);
+
+                let viewing = self.viewing.lock().unwrap();
+                let Some(view) = viewing.as_ref() else {
+                    return;
+                };
+
+                ui.separator();
+
+                match &view.variables {
+                    Ok(variables) => {
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            egui::Grid::new("shared_view")
+                                .num_columns(2)
+                                .striped(true)
+                                .show(ui, |ui| {
+                                    for (name, value) in variables {
+                                        ui.strong(name);
+                                        ui.monospace(value);
+                                        ui.end_row();
+                                    }
+                                });
+                        });
+                    }
+                    Err(e) => {
+                        ui.colored_label(egui::Color32::RED, e);
+                    }
+                }
+            });
+
+        if !open {
+            self.inbox_open = false;
+            self.viewing.lock().unwrap().take();
+        }
+    }
+
+    fn share_row(
+        &mut self,
+        ctx: &egui::Context,
+        ui: &mut egui::Ui,
+        rdx_runner: &RdxRunner,
+        inbox: &ShareInbox,
+        index: usize,
+        received: &ReceivedShare,
+    ) {
+        let share = &received.share;
+        let cid = &share.cid;
+
+        ui.label(&share.plugin)
+            .on_hover_text(format!("Received {}", format_timestamp(received.received)));
+        ui.monospace(short_cid(cid)).on_hover_text(cid);
+        match (&received.from, received.verified) {
+            (Some(peer), true) => {
+                ui.label(short_cid(peer)).on_hover_text(peer);
+            }
+            (Some(peer), false) => {
+                ui.label(format!("⚠ {}", short_cid(peer)))
+                    .on_hover_text(format!(
+                        "Unverified: the share says it's from {}, which wasn't checked",
+                        peer
+                    ));
+            }
+            (None, _) => {
+                ui.label("unknown");
+            }
+        }
+
+        let state = rdx_runner.state(&share.plugin);
+        ui.horizontal(|ui| {
+            let view = ui
+                .add_enabled(state.is_some(), egui::Button::new("View"))
+                .on_disabled_hover_text("Load the plugin to fetch its shared state");
+            if view.clicked() {
+                if let Some(state) = &state {
+                    self.view(ctx, state.clone(), received.clone());
+                }
+            }
+
+            let merge = ui
+                .add_enabled(
+                    state
+                        .as_ref()
+                        .is_some_and(|s| !s.is_locked() && !s.is_wallet()),
+                    egui::Button::new("Merge"),
+                )
+                .on_hover_text("Shared variables replace ours, the rest are kept")
+                .on_disabled_hover_text(
+                    "Load the plugin and unlock the wallet to merge. Nothing merges into the wallet",
+                );
+            if merge.clicked() {
+                if let Some(state) = state {
+                    self.merge(ctx, state, received.clone());
+                }
+            }
+
+            if ui.button("Dismiss").clicked() {
+                inbox.remove(index);
+                // the indexes after this one have shifted
+                self.viewing.lock().unwrap().take();
+            }
+        });
+    }
+
+    /// Spawn a task to share the state with the target
+    fn send(&self, ctx: &egui::Context, state: State, target: ShareTarget, outbox: ShareOutbox) {
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match share::share(&state, target, &outbox).await {
+                Ok(cid) => format!("Shared {} as {}", state.name(), cid),
+                Err(e) => {
+                    tracing::error!("Error sharing state: {:?}", e);
+                    format!("Share failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Spawn a task to merge the received share into our own state
+    fn merge(&self, ctx: &egui::Context, state: State, received: ReceivedShare) {
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match share::merge(&state, &received).await {
+                Ok(cid) => format!("Merged into {}, saved as {}", state.name(), cid),
+                Err(e) => {
+                    tracing::error!("Error merging shared state: {:?}", e);
+                    format!("Merge failed: {}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            ctx.request_repaint();
+        });
+    }
+
+    /// Spawn a task to fetch the share's blocks from the sender and show its variables
+    fn view(&self, ctx: &egui::Context, state: State, received: ReceivedShare) {
+        let viewing = self.viewing.clone();
+        let status = self.status.clone();
+        status
+            .lock()
+            .unwrap()
+            .replace(format!("Fetching {}…", short_cid(&received.share.cid)));
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let variables = share::fetch(&state, &received)
+                .await
+                .map(|scope| {
+                    scope
+                        .iter()
+                        .map(|(name, _constant, value)| {
+                            let value =
+                                serde_json::to_string(&value).unwrap_or_else(|_| value.to_string());
+                            (name.to_string(), value)
+                        })
+                        .collect()
+                })
+                .map_err(|e| {
+                    tracing::error!("Error fetching shared state: {:?}", e);
+                    format!("Error: {}", e)
+                });
+            status.lock().unwrap().take();
+            viewing.lock().unwrap().replace(SharedView { variables });
+            ctx.request_repaint();
+        });
+    }
+
+    fn show_status(&self, ui: &mut egui::Ui) {
+        if let Some(message) = self.status.lock().unwrap().as_ref() {
+            ui.small(message);
+        }
+    }
+}
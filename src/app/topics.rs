@@ -0,0 +1,310 @@
+//! Pub/sub topics: subscribe, read the messages per topic and publish.
+//!
+//! The subscribed topics persist, and are subscribed again whenever the node connects.
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use peerpiper::core::events::{AllCommands, PublicEvent};
+
+use crate::app::history::format_timestamp;
+use crate::app::network::short_peer;
+use crate::app::platform;
+use crate::app::rdx_runner::RdxRunner;
+
+/// Most messages kept per topic, older ones are dropped
+pub(crate) const MESSAGES_PER_TOPIC: usize = 200;
+
+/// A message received on a topic
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TopicMessage {
+    /// Unix timestamp (seconds) of when it was received
+    pub received: i64,
+    pub peer: String,
+    pub data: Vec<u8>,
+}
+
+impl TopicMessage {
+    /// The data as text if it's UTF-8, otherwise as hex
+    pub(crate) fn text(&self) -> String {
+        match std::str::from_utf8(&self.data) {
+            Ok(text) => text.to_string(),
+            Err(_) => format!("0x{}", to_hex(&self.data)),
+        }
+    }
+}
+
+/// Messages received per topic, newest last. Cheap to clone, clones share the inbox.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TopicInbox(Arc<Mutex<BTreeMap<String, VecDeque<TopicMessage>>>>);
+
+impl TopicInbox {
+    /// Keep the event if it's a message
+    pub(crate) fn record(&self, event: &PublicEvent) {
+        let PublicEvent::Message { topic, data, peer } = event else {
+            return;
+        };
+        let received = chrono::Utc::now().timestamp();
+
+        let mut topics = self.0.lock().unwrap();
+        let messages = topics.entry(topic.to_string()).or_default();
+        if messages.len() == MESSAGES_PER_TOPIC {
+            messages.pop_front();
+        }
+        messages.push_back(TopicMessage {
+            received,
+            peer: peer.to_string(),
+            data: data.clone(),
+        });
+    }
+
+    /// Copy of the messages received on the topic
+    pub(crate) fn messages(&self, topic: &str) -> Vec<TopicMessage> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(topic)
+            .map(|messages| messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Number of messages received on the topic
+    pub(crate) fn count(&self, topic: &str) -> usize {
+        self.0.lock().unwrap().get(topic).map_or(0, VecDeque::len)
+    }
+
+    pub(crate) fn clear(&self, topic: &str) {
+        self.0.lock().unwrap().remove(topic);
+    }
+}
+
+/// Topics window, keeping the subscribed topics
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub(crate) struct TopicsPanel {
+    /// Subscribed topics, subscribed again on connect
+    subscriptions: BTreeSet<String>,
+
+    #[serde(skip)]
+    pub(crate) open: bool,
+    /// Topic being typed to subscribe to
+    #[serde(skip)]
+    new_topic: String,
+    /// Topic whose messages are shown
+    #[serde(skip)]
+    selected: Option<String>,
+    /// Payload being typed to publish
+    #[serde(skip)]
+    payload: String,
+    /// Whether the payload is hex rather than text
+    #[serde(skip)]
+    hex: bool,
+    /// Outcome of the last command
+    #[serde(skip)]
+    status: Arc<Mutex<Option<String>>>,
+}
+
+impl TopicsPanel {
+    /// Subscribe to every saved topic, such as once the node connects
+    pub(crate) fn resubscribe(&self, ctx: &egui::Context, rdx_runner: &RdxRunner) {
+        for topic in &self.subscriptions {
+            self.order(
+                ctx,
+                rdx_runner,
+                AllCommands::Subscribe {
+                    topic: topic.clone(),
+                },
+            );
+        }
+    }
+
+    pub(crate) fn show(&mut self, ctx: &egui::Context, rdx_runner: &RdxRunner, inbox: &TopicInbox) {
+        if !self.open {
+            return;
+        }
+
+        let mut open = self.open;
+        let mut commands = vec![];
+        egui::Window::new("Topics")
+            .open(&mut open)
+            .default_width(480.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::singleline(&mut self.new_topic).hint_text("Topic"));
+                    let topic = self.new_topic.trim().to_string();
+                    if ui
+                        .add_enabled(!topic.is_empty(), egui::Button::new("Subscribe"))
+                        .clicked()
+                    {
+                        self.subscriptions.insert(topic.clone());
+                        self.selected = Some(topic.clone());
+                        self.new_topic.clear();
+                        commands.push(AllCommands::Subscribe { topic });
+                    }
+                });
+                if let Some(status) = self.status.lock().unwrap().as_ref() {
+                    ui.label(status);
+                }
+
+                ui.separator();
+                let mut unsubscribe = None;
+                let mut select = None;
+                ui.horizontal_wrapped(|ui| {
+                    for topic in &self.subscriptions {
+                        let label = match inbox.count(topic) {
+                            0 => topic.clone(),
+                            n => format!("{} ({})", topic, n),
+                        };
+                        let selected = self.selected.as_ref() == Some(topic);
+                        let response = ui
+                            .selectable_label(selected, label)
+                            .on_hover_text("Right click to unsubscribe");
+                        if response.clicked() {
+                            select = Some(topic.clone());
+                        }
+                        response.context_menu(|ui| {
+                            if ui.button("Unsubscribe").clicked() {
+                                unsubscribe = Some(topic.clone());
+                                ui.close_menu();
+                            }
+                            if ui.button("Clear messages").clicked() {
+                                inbox.clear(topic);
+                                ui.close_menu();
+                            }
+                        });
+                    }
+                });
+                if select.is_some() {
+                    self.selected = select;
+                }
+                if let Some(topic) = unsubscribe {
+                    self.subscriptions.remove(&topic);
+                    if self.selected.as_ref() == Some(&topic) {
+                        self.selected = None;
+                    }
+                    commands.push(AllCommands::Unsubscribe { topic });
+                }
+
+                let Some(topic) = self.selected.clone() else {
+                    ui.label("Subscribe to a topic, or pick one, to see its messages.");
+                    return;
+                };
+
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        egui::Grid::new("topic_messages")
+                            .num_columns(3)
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for message in inbox.messages(&topic) {
+                                    ui.label(format_timestamp(message.received));
+                                    ui.monospace(short_peer(&message.peer))
+                                        .on_hover_text(&message.peer);
+                                    ui.label(message.text());
+                                    ui.end_row();
+                                }
+                            });
+                    });
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.payload).hint_text(if self.hex {
+                            "0a0b0c…"
+                        } else {
+                            "Message"
+                        }),
+                    );
+                    ui.checkbox(&mut self.hex, "Hex");
+                    if ui.button("Publish").clicked() {
+                        let data = match self.hex {
+                            true => from_hex(&self.payload),
+                            false => Ok(self.payload.as_bytes().to_vec()),
+                        };
+                        match data {
+                            Ok(data) => {
+                                self.payload.clear();
+                                commands.push(AllCommands::Publish { topic, data });
+                            }
+                            Err(e) => {
+                                self.status.lock().unwrap().replace(e);
+                            }
+                        }
+                    }
+                });
+            });
+        self.open = open;
+
+        for command in commands {
+            self.order(ctx, rdx_runner, command);
+        }
+    }
+
+    /// Spawn the command, showing its outcome
+    fn order(&self, ctx: &egui::Context, rdx_runner: &RdxRunner, command: AllCommands) {
+        let what = match &command {
+            AllCommands::Subscribe { topic } => format!("Subscribed to {}", topic),
+            AllCommands::Unsubscribe { topic } => format!("Unsubscribed from {}", topic),
+            AllCommands::Publish { topic, .. } => format!("Published to {}", topic),
+            _ => "Done".to_string(),
+        };
+        let task = rdx_runner.order(command);
+        let status = self.status.clone();
+        let ctx = ctx.clone();
+        platform::spawn(async move {
+            let message = match task.await {
+                Ok(_) => what,
+                Err(e) => {
+                    tracing::error!("Topic command failed: {:?}", e);
+                    format!("{:#}", e)
+                }
+            };
+            status.lock().unwrap().replace(message);
+            ctx.request_repaint();
+        });
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes of the hex string, ignoring whitespace and an optional `0x` prefix
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let hex: String = hex.split_whitespace().collect();
+    let hex = hex.strip_prefix("0x").unwrap_or(&hex);
+    if !hex.is_ascii() {
+        return Err("Hex payload must only have hex digits".to_string());
+    }
+    if hex.len() % 2 != 0 {
+        return Err("Hex payload must have an even number of digits".to_string());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| format!("Invalid hex digits: {}", &hex[i..i + 2]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_payloads() {
+        assert_eq!(from_hex("0x0a ff").unwrap(), [0x0a, 0xff]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+
+        let message = TopicMessage {
+            received: 0,
+            peer: "peer".to_string(),
+            data: vec![0xff, 0x00],
+        };
+        assert_eq!(message.text(), "0xff00");
+    }
+}
@@ -6,4 +6,7 @@
 include!(concat!(env!("OUT_DIR"), "/builtin_components.rs"));
 
 mod app;
-pub use app::MultinodeApp;
+pub use app::{Launcher, MultinodeApp, Profile, ProfileError};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use app::cli;
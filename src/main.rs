@@ -14,6 +14,27 @@ async fn main() -> eframe::Result {
 
     tracing::info!("Starting eframe multinode");
 
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    // skip the profile picker when the profile is given on the command line
+    let profile = match eframe_multinode::Profile::from_args(args.clone()) {
+        Some(Ok(profile)) => Some(profile),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+
+    // headless commands run instead of the GUI
+    if let Some(result) = eframe_multinode::cli::run(args, profile.clone()).await {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([400.0, 300.0])
@@ -31,7 +52,7 @@ async fn main() -> eframe::Result {
     let _ = eframe::run_native(
         "PeerPiper-Multinode",
         native_options,
-        Box::new(|cc| Ok(Box::new(eframe_multinode::MultinodeApp::new(cc)))),
+        Box::new(move |cc| Ok(Box::new(eframe_multinode::Launcher::new(cc, profile)))),
     );
 
     // Shutdown the ollama server
@@ -70,7 +91,7 @@ fn main() {
             .start(
                 canvas,
                 web_options,
-                Box::new(|cc| Ok(Box::new(eframe_multinode::MultinodeApp::new(cc)))),
+                Box::new(|cc| Ok(Box::new(eframe_multinode::Launcher::new(cc, None)))),
             )
             .await;
 